@@ -0,0 +1,533 @@
+// Standard Library
+use std::f32;
+
+// External Library
+use cgmath::{Point3, Vector3, Matrix4};
+use collision::{Aabb3, Frustum, Plane, Relation};
+
+use util::graphics::{Vertex, SkinnedVertex};
+use util::math::{dot, sub};
+
+
+// Anything that has a model space position
+pub trait HasPosition {
+    fn position(&self) -> [f32; 3];
+}
+
+impl HasPosition for [f32; 3] {
+    fn position(&self) -> [f32; 3] {
+        *self
+    }
+}
+
+impl HasPosition for Vertex {
+    fn position(&self) -> [f32; 3] {
+        self.position
+    }
+}
+
+impl HasPosition for SkinnedVertex {
+    fn position(&self) -> [f32; 3] {
+        self.position
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+// Oriented box, the axes are orthonormal and sorted by decreasing variance
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Obb {
+    pub center: Point3<f32>,
+    pub axes: [Vector3<f32>; 3],
+    pub half_extents: Vector3<f32>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Bounds {
+    pub aabb: Aabb3<f32>,
+    pub sphere: BoundingSphere,
+    pub obb: Obb,
+}
+
+#[inline]
+fn dist2(a: [f32; 3], b: [f32; 3]) -> f32 {
+    let d = sub(a, b);
+    dot(d, d)
+}
+
+#[inline]
+fn to_array(v: Vector3<f32>) -> [f32; 3] {
+    [v.x, v.y, v.z]
+}
+
+#[inline]
+fn transform_point(m: &Matrix4<f32>, p: [f32; 3]) -> [f32; 3] {
+    [m.x.x * p[0] + m.y.x * p[1] + m.z.x * p[2] + m.w.x,
+     m.x.y * p[0] + m.y.y * p[1] + m.z.y * p[2] + m.w.y,
+     m.x.z * p[0] + m.y.z * p[1] + m.z.z * p[2] + m.w.z]
+}
+
+#[inline]
+fn transform_vector(m: &Matrix4<f32>, v: [f32; 3]) -> [f32; 3] {
+    [m.x.x * v[0] + m.y.x * v[1] + m.z.x * v[2],
+     m.x.y * v[0] + m.y.y * v[1] + m.z.y * v[2],
+     m.x.z * v[0] + m.y.z * v[1] + m.z.z * v[2]]
+}
+
+// Min and max corner in a single pass, None for empty input
+pub fn min_max<P: HasPosition>(points: &[P]) -> Option<([f32; 3], [f32; 3])> {
+    let mut iter = points.iter();
+    let first = match iter.next() {
+        Some(p) => p.position(),
+        None => return None,
+    };
+    let mut min = first;
+    let mut max = first;
+    for p in iter {
+        let p = p.position();
+        for i in 0..3 {
+            if p[i] < min[i] {
+                min[i] = p[i];
+            }
+            if p[i] > max[i] {
+                max[i] = p[i];
+            }
+        }
+    }
+    Some((min, max))
+}
+
+// Ritter's bounding sphere, at most a few percent larger than the optimal one
+pub fn ritter_sphere<P: HasPosition>(points: &[P]) -> Option<([f32; 3], f32)> {
+    if points.is_empty() {
+        return None;
+    }
+    let farthest = |from: [f32; 3]| {
+        let mut best = points[0].position();
+        let mut best_d = dist2(best, from);
+        for p in points.iter() {
+            let p = p.position();
+            let d = dist2(p, from);
+            if d > best_d {
+                best = p;
+                best_d = d;
+            }
+        }
+        best
+    };
+    let y = farthest(points[0].position());
+    let z = farthest(y);
+
+    let mut center = [(y[0] + z[0]) * 0.5, (y[1] + z[1]) * 0.5, (y[2] + z[2]) * 0.5];
+    let mut radius = dist2(y, z).sqrt() * 0.5;
+
+    for p in points.iter() {
+        let p = p.position();
+        let d = dist2(p, center).sqrt();
+        if d > radius {
+            // Grow the sphere just enough to touch p on the far side
+            let new_radius = (radius + d) * 0.5;
+            let k = (new_radius - radius) / d;
+            for i in 0..3 {
+                center[i] += (p[i] - center[i]) * k;
+            }
+            radius = new_radius;
+        }
+    }
+    Some((center, radius))
+}
+
+// Eigen decomposition of a symmetric 3x3 matrix with cyclic Jacobi rotations.
+// Returns the eigenvalues and the eigenvectors as columns.
+pub fn symmetric_eigen(m: [[f32; 3]; 3]) -> ([f32; 3], [[f32; 3]; 3]) {
+    let mut a = m;
+    let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    for _ in 0..32 {
+        let off = a[0][1] * a[0][1] + a[0][2] * a[0][2] + a[1][2] * a[1][2];
+        if off < 1e-12 {
+            break;
+        }
+        for &(p, q) in [(0, 1), (0, 2), (1, 2)].iter() {
+            if a[p][q].abs() < 1e-12 {
+                continue;
+            }
+            let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+            let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+            let c = 1.0 / (t * t + 1.0).sqrt();
+            let s = t * c;
+
+            // a = J^T a J
+            for k in 0..3 {
+                let akp = a[k][p];
+                let akq = a[k][q];
+                a[k][p] = c * akp - s * akq;
+                a[k][q] = s * akp + c * akq;
+            }
+            for k in 0..3 {
+                let apk = a[p][k];
+                let aqk = a[q][k];
+                a[p][k] = c * apk - s * aqk;
+                a[q][k] = s * apk + c * aqk;
+            }
+            for k in 0..3 {
+                let vkp = v[k][p];
+                let vkq = v[k][q];
+                v[k][p] = c * vkp - s * vkq;
+                v[k][q] = s * vkp + c * vkq;
+            }
+        }
+    }
+
+    let values = [a[0][0], a[1][1], a[2][2]];
+    let vectors = [[v[0][0], v[1][0], v[2][0]], [v[0][1], v[1][1], v[2][1]], [v[0][2], v[1][2], v[2][2]]];
+    (values, vectors)
+}
+
+// Oriented box along the principal axes of the point covariance
+pub fn pca_box<P: HasPosition>(points: &[P]) -> Option<([f32; 3], [[f32; 3]; 3], [f32; 3])> {
+    if points.is_empty() {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mut sum = [0.0f64; 3];
+    let mut sum_sq = [[0.0f64; 3]; 3];
+    for p in points.iter() {
+        let p = p.position();
+        for i in 0..3 {
+            sum[i] += p[i] as f64;
+            for j in 0..3 {
+                sum_sq[i][j] += p[i] as f64 * p[j] as f64;
+            }
+        }
+    }
+    let mean = [sum[0] / n, sum[1] / n, sum[2] / n];
+    let mut cov = [[0.0f32; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            cov[i][j] = (sum_sq[i][j] / n - mean[i] * mean[j]) as f32;
+        }
+    }
+
+    let (values, vectors) = symmetric_eigen(cov);
+    let mut order = [0, 1, 2];
+    order.sort_by(|a, b| values[*b].partial_cmp(&values[*a]).unwrap_or(::std::cmp::Ordering::Equal));
+    let mut axes = [vectors[order[0]], vectors[order[1]], vectors[order[2]]];
+    // Keep the frame right handed
+    axes[2] = [axes[0][1] * axes[1][2] - axes[0][2] * axes[1][1],
+               axes[0][2] * axes[1][0] - axes[0][0] * axes[1][2],
+               axes[0][0] * axes[1][1] - axes[0][1] * axes[1][0]];
+
+    let mut lo = [f32::MAX; 3];
+    let mut hi = [f32::MIN; 3];
+    for p in points.iter() {
+        let p = p.position();
+        for i in 0..3 {
+            let d = dot(p, axes[i]);
+            lo[i] = lo[i].min(d);
+            hi[i] = hi[i].max(d);
+        }
+    }
+
+    let mut center = [0.0; 3];
+    let mut half = [0.0; 3];
+    for i in 0..3 {
+        let mid = (lo[i] + hi[i]) * 0.5;
+        half[i] = (hi[i] - lo[i]) * 0.5;
+        for k in 0..3 {
+            center[k] += axes[i][k] * mid;
+        }
+    }
+    Some((center, axes, half))
+}
+
+impl Bounds {
+    // Linear in the number of points, None for empty input
+    pub fn compute<P: HasPosition>(points: &[P]) -> Option<Bounds> {
+        let (min, max) = match min_max(points) {
+            Some(m) => m,
+            None => return None,
+        };
+        let (center, radius) = ritter_sphere(points).unwrap();
+        let (obb_center, axes, half) = pca_box(points).unwrap();
+
+        Some(Bounds {
+            aabb: Aabb3::new(Point3::new(min[0], min[1], min[2]),
+                             Point3::new(max[0], max[1], max[2])),
+            sphere: BoundingSphere {
+                center: Point3::new(center[0], center[1], center[2]),
+                radius: radius,
+            },
+            obb: Obb {
+                center: Point3::new(obb_center[0], obb_center[1], obb_center[2]),
+                axes: [Vector3::new(axes[0][0], axes[0][1], axes[0][2]),
+                       Vector3::new(axes[1][0], axes[1][1], axes[1][2]),
+                       Vector3::new(axes[2][0], axes[2][1], axes[2][2])],
+                half_extents: Vector3::new(half[0], half[1], half[2]),
+            },
+        })
+    }
+
+    // Degenerate bounds at the origin, used in place of empty meshes
+    pub fn empty() -> Bounds {
+        let origin = Point3::new(0.0, 0.0, 0.0);
+        Bounds {
+            aabb: Aabb3::new(origin, origin),
+            sphere: BoundingSphere {
+                center: origin,
+                radius: 0.0,
+            },
+            obb: Obb {
+                center: origin,
+                axes: [Vector3::new(1.0, 0.0, 0.0),
+                       Vector3::new(0.0, 1.0, 0.0),
+                       Vector3::new(0.0, 0.0, 1.0)],
+                half_extents: Vector3::new(0.0, 0.0, 0.0),
+            },
+        }
+    }
+
+    pub fn obb_corners(&self) -> [[f32; 3]; 8] {
+        let c = [self.obb.center.x, self.obb.center.y, self.obb.center.z];
+        let h = self.obb.half_extents;
+        let a = [to_array(self.obb.axes[0] * h.x),
+                 to_array(self.obb.axes[1] * h.y),
+                 to_array(self.obb.axes[2] * h.z)];
+        let mut corners = [[0.0; 3]; 8];
+        for i in 0..8 {
+            for k in 0..3 {
+                let sx = if i & 1 == 0 { -1.0 } else { 1.0 };
+                let sy = if i & 2 == 0 { -1.0 } else { 1.0 };
+                let sz = if i & 4 == 0 { -1.0 } else { 1.0 };
+                corners[i][k] = c[k] + sx * a[0][k] + sy * a[1][k] + sz * a[2][k];
+            }
+        }
+        corners
+    }
+
+    // Bounds of the transformed object, the aabb is refit around the transformed obb
+    pub fn transform(&self, m: &Matrix4<f32>) -> Bounds {
+        let center = transform_point(m, [self.obb.center.x, self.obb.center.y, self.obb.center.z]);
+        let mut axes = [[0.0; 3]; 3];
+        let mut half = [0.0; 3];
+        let h = to_array(self.obb.half_extents);
+        for i in 0..3 {
+            let a = transform_vector(m, to_array(self.obb.axes[i]));
+            let len = dot(a, a).sqrt();
+            half[i] = h[i] * len;
+            axes[i] = if len > 0.0 {
+                [a[0] / len, a[1] / len, a[2] / len]
+            } else {
+                to_array(self.obb.axes[i])
+            };
+        }
+
+        let mut max_scale: f32 = 0.0;
+        for i in 0..3 {
+            let col = transform_vector(m, [if i == 0 { 1.0 } else { 0.0 },
+                                           if i == 1 { 1.0 } else { 0.0 },
+                                           if i == 2 { 1.0 } else { 0.0 }]);
+            max_scale = max_scale.max(dot(col, col).sqrt());
+        }
+        let sphere_center = transform_point(m, [self.sphere.center.x,
+                                                self.sphere.center.y,
+                                                self.sphere.center.z]);
+
+        let mut b = Bounds {
+            aabb: self.aabb,
+            sphere: BoundingSphere {
+                center: Point3::new(sphere_center[0], sphere_center[1], sphere_center[2]),
+                radius: self.sphere.radius * max_scale,
+            },
+            obb: Obb {
+                center: Point3::new(center[0], center[1], center[2]),
+                axes: [Vector3::new(axes[0][0], axes[0][1], axes[0][2]),
+                       Vector3::new(axes[1][0], axes[1][1], axes[1][2]),
+                       Vector3::new(axes[2][0], axes[2][1], axes[2][2])],
+                half_extents: Vector3::new(half[0], half[1], half[2]),
+            },
+        };
+        let (min, max) = min_max(&b.obb_corners()).unwrap();
+        b.aabb = Aabb3::new(Point3::new(min[0], min[1], min[2]),
+                            Point3::new(max[0], max[1], max[2]));
+        b
+    }
+
+    // The object is culled if any of the volumes lies fully outside of a plane,
+    // the sphere and box tests are cheaper and tighter than the aabb for most meshes.
+    pub fn in_frustum(&self, frustum: &Frustum<f32>) -> bool {
+        let planes = [&frustum.left,
+                      &frustum.right,
+                      &frustum.bottom,
+                      &frustum.top,
+                      &frustum.near,
+                      &frustum.far];
+        for plane in planes.iter() {
+            if sphere_outside(&self.sphere, plane) || obb_outside(&self.obb, plane) {
+                return false;
+            }
+        }
+        match frustum.contains(self.aabb) {
+            Relation::Out => false,
+            _ => true,
+        }
+    }
+}
+
+// Planes keep the inside at n.p > d, the same convention the aabb test uses
+#[inline]
+fn signed_distance(plane: &Plane<f32>, p: Point3<f32>) -> (f32, f32) {
+    let n = to_array(plane.n);
+    (dot(n, [p.x, p.y, p.z]) - plane.d, dot(n, n).sqrt())
+}
+
+#[inline]
+fn sphere_outside(sphere: &BoundingSphere, plane: &Plane<f32>) -> bool {
+    let (d, n_len) = signed_distance(plane, sphere.center);
+    d < -sphere.radius * n_len
+}
+
+#[inline]
+fn obb_outside(obb: &Obb, plane: &Plane<f32>) -> bool {
+    let (d, _) = signed_distance(plane, obb.center);
+    let n = to_array(plane.n);
+    let h = to_array(obb.half_extents);
+    let mut r = 0.0;
+    for i in 0..3 {
+        r += dot(n, to_array(obb.axes[i])).abs() * h[i];
+    }
+    d < -r
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector3;
+    use collision::{Frustum, Plane};
+
+    use super::{Bounds, dist2, dot, pca_box, ritter_sphere};
+
+    // Deterministic points in [-1, 1]^3
+    fn points(count: usize) -> Vec<[f32; 3]> {
+        let mut seed = 12345u32;
+        let mut next = || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 8) as f32 / (1u32 << 23) as f32 - 1.0
+        };
+        (0..count).map(|_| [next() * 3.0, next(), next() * 0.5]).collect()
+    }
+
+    // Rotation by `a` around z followed by `b` around x, as columns
+    fn rotation(a: f32, b: f32) -> [[f32; 3]; 3] {
+        let (sa, ca) = a.sin_cos();
+        let (sb, cb) = b.sin_cos();
+        [[ca, sa * cb, sa * sb], [-sa, ca * cb, ca * sb], [0.0, -sb, cb]]
+    }
+
+    fn rotate(r: &[[f32; 3]; 3], p: [f32; 3]) -> [f32; 3] {
+        let mut out = [0.0; 3];
+        for k in 0..3 {
+            out[k] = r[0][k] * p[0] + r[1][k] * p[1] + r[2][k] * p[2];
+        }
+        out
+    }
+
+    // Grid on a box with the given half extents, rotated and moved to `center`
+    fn rotated_box(half: [f32; 3], r: &[[f32; 3]; 3], center: [f32; 3]) -> Vec<[f32; 3]> {
+        let steps = 8;
+        let mut out = Vec::new();
+        for i in 0..steps + 1 {
+            for j in 0..steps + 1 {
+                for k in 0..steps + 1 {
+                    let t = |n: usize, h: f32| h * (2.0 * n as f32 / steps as f32 - 1.0);
+                    let p = rotate(r, [t(i, half[0]), t(j, half[1]), t(k, half[2])]);
+                    out.push([p[0] + center[0], p[1] + center[1], p[2] + center[2]]);
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn ritter_sphere_contains_all_points() {
+        assert!(ritter_sphere::<[f32; 3]>(&[]).is_none());
+        for &count in [1, 2, 17, 1000].iter() {
+            let points = points(count);
+            let (center, radius) = ritter_sphere(&points).unwrap();
+            for p in points.iter() {
+                assert!(dist2(*p, center).sqrt() <= radius * (1.0 + 1e-5) + 1e-6,
+                        "{:?} outside of {:?} {}",
+                        p,
+                        center,
+                        radius);
+            }
+        }
+    }
+
+    #[test]
+    fn pca_box_finds_the_axes_of_a_rotated_box() {
+        let r = rotation(0.5, 0.3);
+        let center = [1.0, -2.0, 3.0];
+        let (obb_center, axes, half) = pca_box(&rotated_box([4.0, 2.0, 1.0], &r, center)).unwrap();
+        for i in 0..3 {
+            // Sorted by decreasing extent, the sign of an axis is arbitrary
+            assert!((dot(axes[i], r[i]).abs() - 1.0).abs() < 1e-4, "axis {} {:?}", i, axes[i]);
+            assert!((half[i] - [4.0, 2.0, 1.0][i]).abs() < 1e-3, "{:?}", half);
+            assert!((obb_center[i] - center[i]).abs() < 1e-3, "{:?}", obb_center);
+        }
+        // Right handed
+        let c = axes[2];
+        let n = [axes[0][1] * axes[1][2] - axes[0][2] * axes[1][1],
+                 axes[0][2] * axes[1][0] - axes[0][0] * axes[1][2],
+                 axes[0][0] * axes[1][1] - axes[0][1] * axes[1][0]];
+        assert!((dot(c, n) - 1.0).abs() < 1e-4);
+    }
+
+    fn plane(n: [f32; 3], d: f32) -> Plane<f32> {
+        Plane::new(Vector3::new(n[0], n[1], n[2]), d)
+    }
+
+    // 90 degree field of view looking down -z, from 1 to 100
+    fn frustum() -> Frustum<f32> {
+        Frustum::new(plane([1.0, 0.0, -1.0], 0.0),
+                     plane([-1.0, 0.0, -1.0], 0.0),
+                     plane([0.0, 1.0, -1.0], 0.0),
+                     plane([0.0, -1.0, -1.0], 0.0),
+                     plane([0.0, 0.0, -1.0], 1.0),
+                     plane([0.0, 0.0, 1.0], -100.0))
+    }
+
+    fn bounds(half: [f32; 3], r: &[[f32; 3]; 3], center: [f32; 3]) -> Bounds {
+        Bounds::compute(&rotated_box(half, r, center)).unwrap()
+    }
+
+    #[test]
+    fn in_frustum_culls_outside_boxes() {
+        let frustum = frustum();
+        let identity = rotation(0.0, 0.0);
+        assert!(bounds([1.0; 3], &identity, [0.0, 0.0, -10.0]).in_frustum(&frustum));
+        // Crossing a plane is still visible
+        assert!(bounds([1.0; 3], &identity, [0.0, 0.0, -1.0]).in_frustum(&frustum));
+        assert!(bounds([1.0; 3], &identity, [50.5, 0.0, -50.0]).in_frustum(&frustum));
+        // Outside of a single plane
+        assert!(!bounds([1.0; 3], &identity, [0.0, 0.0, 5.0]).in_frustum(&frustum));
+        assert!(!bounds([1.0; 3], &identity, [0.0, 70.0, -50.0]).in_frustum(&frustum));
+        assert!(!bounds([1.0; 3], &identity, [0.0, 0.0, -150.0]).in_frustum(&frustum));
+    }
+
+    #[test]
+    fn in_frustum_uses_the_oriented_box() {
+        // A thin stick parallel to the left plane, just outside of it. Its
+        // aabb reaches into the frustum, the oriented box does not.
+        let h = 0.5f32.sqrt();
+        let stick = [[h, 0.0, h], [0.0, 1.0, 0.0], [-h, 0.0, h]];
+        let b = bounds([5.0, 0.2, 0.1], &stick, [-11.0, 0.0, -10.0]);
+        assert!(b.aabb.max.x - b.aabb.min.z > 0.0);
+        assert!(!b.in_frustum(&frustum()));
+        // Moved inside it is kept
+        assert!(bounds([5.0, 0.2, 0.1], &stick, [-9.0, 0.0, -10.0]).in_frustum(&frustum()));
+    }
+}
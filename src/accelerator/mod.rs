@@ -4,6 +4,7 @@ use collision;
 
 pub mod octree;
 pub mod octreenode;
+pub mod bounds;
 
 pub trait OctreeItem {
     fn index(&self) -> cgmath::Point3<f32>;
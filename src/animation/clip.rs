@@ -0,0 +1,214 @@
+// External Library
+use cgmath::*;
+
+use animation::{Pose, lerp_vector, slerp};
+use animation::skeleton::Skeleton;
+
+
+#[derive(Copy, Clone, Debug)]
+pub struct Keyframe<T> {
+    pub time: f32,
+    pub value: T,
+}
+
+impl<T> Keyframe<T> {
+    pub fn new(time: f32, value: T) -> Keyframe<T> {
+        Keyframe {
+            time: time,
+            value: value,
+        }
+    }
+}
+
+// Finds the keyframes around `time` and the blend factor between them.
+// Keyframes have to be sorted by time.
+fn bracket<T>(keys: &[Keyframe<T>], time: f32) -> Option<(usize, usize, f32)> {
+    if keys.is_empty() {
+        return None;
+    }
+    if time <= keys[0].time {
+        return Some((0, 0, 0.0));
+    }
+    let last = keys.len() - 1;
+    if time >= keys[last].time {
+        return Some((last, last, 0.0));
+    }
+    let next = keys.iter().position(|k| k.time > time).unwrap_or(last);
+    let prev = next - 1;
+    let span = keys[next].time - keys[prev].time;
+    let t = if span > 0.0 {
+        (time - keys[prev].time) / span
+    } else {
+        0.0
+    };
+    Some((prev, next, t))
+}
+
+// Keyframes of a single joint, a missing channel keeps the rest pose value
+#[derive(Clone, Debug)]
+pub struct JointTrack {
+    pub joint: usize,
+    pub translations: Vec<Keyframe<Vector3<f32>>>,
+    pub rotations: Vec<Keyframe<Quaternion<f32>>>,
+    pub scales: Vec<Keyframe<Vector3<f32>>>,
+}
+
+impl JointTrack {
+    pub fn new(joint: usize) -> JointTrack {
+        JointTrack {
+            joint: joint,
+            translations: Vec::new(),
+            rotations: Vec::new(),
+            scales: Vec::new(),
+        }
+    }
+
+    pub fn translation(mut self, time: f32, t: Vector3<f32>) -> JointTrack {
+        self.translations.push(Keyframe::new(time, t));
+        self
+    }
+    pub fn rotation(mut self, time: f32, r: Quaternion<f32>) -> JointTrack {
+        self.rotations.push(Keyframe::new(time, r));
+        self
+    }
+    pub fn scale(mut self, time: f32, s: Vector3<f32>) -> JointTrack {
+        self.scales.push(Keyframe::new(time, s));
+        self
+    }
+
+    fn end_time(&self) -> f32 {
+        let last = |t: Option<f32>| t.unwrap_or(0.0);
+        last(self.translations.last().map(|k| k.time))
+            .max(last(self.rotations.last().map(|k| k.time)))
+            .max(last(self.scales.last().map(|k| k.time)))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Clip {
+    pub name: String,
+    pub duration: f32,
+    pub tracks: Vec<JointTrack>,
+}
+
+impl Clip {
+    pub fn new(name: &str) -> Clip {
+        Clip {
+            name: name.to_string(),
+            duration: 0.0,
+            tracks: Vec::new(),
+        }
+    }
+
+    // Adds a track and extends the duration to its last keyframe
+    pub fn track(mut self, track: JointTrack) -> Clip {
+        self.duration = self.duration.max(track.end_time());
+        self.tracks.push(track);
+        self
+    }
+
+    // Evaluates the clip at `time` on top of the rest pose of the skeleton.
+    // Looping clips wrap the time into [0, duration).
+    pub fn sample(&self, skeleton: &Skeleton, time: f32, looping: bool) -> Pose {
+        let mut pose = skeleton.rest_pose();
+        let time = if looping && self.duration > 0.0 {
+            let t = time % self.duration;
+            if t < 0.0 { t + self.duration } else { t }
+        } else {
+            time.max(0.0).min(self.duration)
+        };
+
+        for track in self.tracks.iter() {
+            let local = match pose.local.get_mut(track.joint) {
+                Some(l) => l,
+                None => continue,
+            };
+            if let Some((a, b, t)) = bracket(&track.translations, time) {
+                local.translation = lerp_vector(track.translations[a].value,
+                                                track.translations[b].value,
+                                                t);
+            }
+            if let Some((a, b, t)) = bracket(&track.rotations, time) {
+                local.rotation = slerp(track.rotations[a].value, track.rotations[b].value, t);
+            }
+            if let Some((a, b, t)) = bracket(&track.scales, time) {
+                local.scale = lerp_vector(track.scales[a].value, track.scales[b].value, t);
+            }
+        }
+        pose
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::*;
+
+    use super::{Clip, JointTrack};
+    use animation::Transform;
+    use animation::skeleton::Skeleton;
+
+    fn close(a: Vector3<f32>, b: Vector3<f32>) -> bool {
+        (a.x - b.x).abs() < 1e-5 && (a.y - b.y).abs() < 1e-5 && (a.z - b.z).abs() < 1e-5
+    }
+
+    fn skeleton() -> Skeleton {
+        let mut skeleton = Skeleton::new();
+        let root = skeleton.add_joint("root", None, Transform::identity());
+        let rest = Transform::new(Vector3::new(0.0, 1.0, 0.0),
+                                  Quaternion::new(1.0, 0.0, 0.0, 0.0),
+                                  Vector3::new(2.0, 2.0, 2.0));
+        skeleton.add_joint("child", Some(root), rest);
+        skeleton
+    }
+
+    fn clip() -> Clip {
+        Clip::new("move").track(JointTrack::new(0)
+                                    .translation(0.0, Vector3::new(0.0, 0.0, 0.0))
+                                    .translation(1.0, Vector3::new(2.0, 0.0, 0.0))
+                                    .translation(2.0, Vector3::new(2.0, 4.0, 0.0)))
+    }
+
+    #[test]
+    fn interpolates_between_keyframes() {
+        let clip = clip();
+        assert_eq!(clip.duration, 2.0);
+        let pose = clip.sample(&skeleton(), 0.25, false);
+        assert!(close(pose.local[0].translation, Vector3::new(0.5, 0.0, 0.0)));
+        let pose = clip.sample(&skeleton(), 1.5, false);
+        assert!(close(pose.local[0].translation, Vector3::new(2.0, 2.0, 0.0)));
+    }
+
+    #[test]
+    fn clamps_or_wraps_the_time() {
+        let clip = clip();
+        let skeleton = skeleton();
+        assert!(close(clip.sample(&skeleton, -1.0, false).local[0].translation,
+                      Vector3::new(0.0, 0.0, 0.0)));
+        assert!(close(clip.sample(&skeleton, 3.0, false).local[0].translation,
+                      Vector3::new(2.0, 4.0, 0.0)));
+        assert!(close(clip.sample(&skeleton, 2.5, true).local[0].translation,
+                      Vector3::new(1.0, 0.0, 0.0)));
+        assert!(close(clip.sample(&skeleton, -0.5, true).local[0].translation,
+                      Vector3::new(2.0, 2.0, 0.0)));
+    }
+
+    #[test]
+    fn untracked_channels_keep_the_rest_pose() {
+        let skeleton = skeleton();
+        let pose = clip().sample(&skeleton, 0.5, false);
+        assert_eq!(pose.local[0].rotation, Quaternion::new(1.0, 0.0, 0.0, 0.0));
+        assert_eq!(pose.local[1], skeleton.joints()[1].rest);
+    }
+
+    #[test]
+    fn rotations_take_the_shorter_arc() {
+        let half = 0.5f32.sqrt();
+        // A quarter turn around z given once with the negated quaternion
+        let clip = Clip::new("turn").track(JointTrack::new(0)
+                                               .rotation(0.0, Quaternion::new(1.0, 0.0, 0.0, 0.0))
+                                               .rotation(1.0, Quaternion::new(-half, 0.0, 0.0, -half)));
+        let q = clip.sample(&skeleton(), 0.5, false).local[0].rotation;
+        let angle = 2.0 * q.v.z.atan2(q.s);
+        assert!((angle.abs() - 0.25 * ::std::f32::consts::PI).abs() < 1e-4, "{:?}", q);
+    }
+}
@@ -0,0 +1,117 @@
+// External Library
+use cgmath::*;
+
+use util::graphics::SkinnedVertex;
+
+pub mod skeleton;
+pub mod clip;
+
+pub use self::skeleton::{Joint, Skeleton};
+pub use self::clip::{Clip, Keyframe, JointTrack};
+
+
+// Translation, rotation and scale of a joint relative to its parent
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transform {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Transform {
+    pub fn new(translation: Vector3<f32>,
+               rotation: Quaternion<f32>,
+               scale: Vector3<f32>)
+               -> Transform {
+        Transform {
+            translation: translation,
+            rotation: rotation,
+            scale: scale,
+        }
+    }
+
+    pub fn identity() -> Transform {
+        Transform {
+            translation: Vector3::new(0.0, 0.0, 0.0),
+            rotation: Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    // T * R * S
+    pub fn to_matrix(&self) -> Matrix4<f32> {
+        let mut m = Matrix4::from(Matrix3::from(normalize_quaternion(self.rotation)));
+        m.x = m.x * self.scale.x;
+        m.y = m.y * self.scale.y;
+        m.z = m.z * self.scale.z;
+        m.w.x = self.translation.x;
+        m.w.y = self.translation.y;
+        m.w.z = self.translation.z;
+        m
+    }
+}
+
+// Local transforms of every joint, indexed like Skeleton::joints
+#[derive(Clone, Debug)]
+pub struct Pose {
+    pub local: Vec<Transform>,
+}
+
+impl Pose {
+    pub fn len(&self) -> usize {
+        self.local.len()
+    }
+}
+
+#[inline]
+pub fn lerp_vector(a: Vector3<f32>, b: Vector3<f32>, t: f32) -> Vector3<f32> {
+    a + (b - a) * t
+}
+
+#[inline]
+pub fn normalize_quaternion(q: Quaternion<f32>) -> Quaternion<f32> {
+    let len = (q.s * q.s + q.v.x * q.v.x + q.v.y * q.v.y + q.v.z * q.v.z).sqrt();
+    if len == 0.0 {
+        Quaternion::new(1.0, 0.0, 0.0, 0.0)
+    } else {
+        Quaternion::new(q.s / len, q.v.x / len, q.v.y / len, q.v.z / len)
+    }
+}
+
+// Spherical interpolation along the shorter arc
+pub fn slerp(a: Quaternion<f32>, b: Quaternion<f32>, t: f32) -> Quaternion<f32> {
+    let mut dot = a.s * b.s + a.v.x * b.v.x + a.v.y * b.v.y + a.v.z * b.v.z;
+    let b = if dot < 0.0 {
+        dot = -dot;
+        Quaternion::new(-b.s, -b.v.x, -b.v.y, -b.v.z)
+    } else {
+        b
+    };
+
+    let (wa, wb) = if dot > 0.9995 {
+        // Nearly parallel, fall back to a normalized lerp
+        (1.0 - t, t)
+    } else {
+        let theta = dot.acos();
+        let sin_theta = theta.sin();
+        (((1.0 - t) * theta).sin() / sin_theta, (t * theta).sin() / sin_theta)
+    };
+
+    normalize_quaternion(Quaternion::new(wa * a.s + wb * b.s,
+                                         wa * a.v.x + wb * b.v.x,
+                                         wa * a.v.y + wb * b.v.y,
+                                         wa * a.v.z + wb * b.v.z))
+}
+
+// CPU reference of the linear blend skinning done by the SKINNING permutation
+pub fn skin_position(vertex: &SkinnedVertex, matrices: &[Matrix4<f32>]) -> [f32; 3] {
+    let p = Vector4::new(vertex.position[0], vertex.position[1], vertex.position[2], 1.0);
+    let mut out = Vector4::new(0.0, 0.0, 0.0, 0.0);
+    for i in 0..4 {
+        let w = vertex.weights[i];
+        if w != 0.0 {
+            out = out + (&matrices[vertex.joints[i] as usize] * &p) * w;
+        }
+    }
+    [out.x, out.y, out.z]
+}
@@ -0,0 +1,192 @@
+// External Library
+use cgmath::*;
+
+use animation::{Pose, Transform};
+
+
+#[derive(Clone, Debug)]
+pub struct Joint {
+    pub name: String,
+    pub parent: Option<usize>,
+    // Transforms from model space into the joint space of the bind pose
+    pub inverse_bind: Matrix4<f32>,
+    pub rest: Transform,
+}
+
+// Joints are stored parents first, so a single forward pass
+// over the list is enough to accumulate global transforms.
+#[derive(Clone, Debug)]
+pub struct Skeleton {
+    joints: Vec<Joint>,
+}
+
+impl Skeleton {
+    pub fn new() -> Skeleton {
+        Skeleton { joints: Vec::new() }
+    }
+
+    // Adds a joint and derives its inverse bind matrix from the rest pose.
+    // Returns the index of the joint, panics if the parent was not added yet.
+    pub fn add_joint(&mut self, name: &str, parent: Option<usize>, rest: Transform) -> usize {
+        let local = rest.to_matrix();
+        let bind = match parent {
+            Some(p) => {
+                assert!(p < self.joints.len(), "Parent joint has to be added first");
+                let parent_bind = self.joints[p]
+                                      .inverse_bind
+                                      .invert()
+                                      .expect("Parent bind matrix not invertible");
+                &parent_bind * &local
+            }
+            None => local,
+        };
+        self.joints.push(Joint {
+            name: name.to_string(),
+            parent: parent,
+            inverse_bind: bind.invert().expect("Bind matrix not invertible"),
+            rest: rest,
+        });
+        self.joints.len() - 1
+    }
+
+    // Adds a joint with an explicitly given inverse bind matrix, e.g. from a file
+    pub fn add_joint_with_bind(&mut self,
+                               name: &str,
+                               parent: Option<usize>,
+                               rest: Transform,
+                               inverse_bind: Matrix4<f32>)
+                               -> usize {
+        if let Some(p) = parent {
+            assert!(p < self.joints.len(), "Parent joint has to be added first");
+        }
+        self.joints.push(Joint {
+            name: name.to_string(),
+            parent: parent,
+            inverse_bind: inverse_bind,
+            rest: rest,
+        });
+        self.joints.len() - 1
+    }
+
+    pub fn joints(&self) -> &[Joint] {
+        &self.joints[..]
+    }
+
+    pub fn len(&self) -> usize {
+        self.joints.len()
+    }
+
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.joints.iter().position(|j| j.name == name)
+    }
+
+    pub fn rest_pose(&self) -> Pose {
+        Pose { local: self.joints.iter().map(|j| j.rest).collect() }
+    }
+
+    // Model space transform of every joint
+    pub fn global_matrices(&self, pose: &Pose) -> Vec<Matrix4<f32>> {
+        let mut global: Vec<Matrix4<f32>> = Vec::with_capacity(self.joints.len());
+        for (i, joint) in self.joints.iter().enumerate() {
+            let local = pose.local.get(i).unwrap_or(&joint.rest).to_matrix();
+            let m = match joint.parent {
+                Some(p) => &global[p] * &local,
+                None => local,
+            };
+            global.push(m);
+        }
+        global
+    }
+
+    // Matrices uploaded to the vertex shader, identity for the rest pose
+    pub fn skinning_matrices(&self, pose: &Pose) -> Vec<Matrix4<f32>> {
+        self.global_matrices(pose)
+            .iter()
+            .zip(self.joints.iter())
+            .map(|(g, j)| g * &j.inverse_bind)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::*;
+
+    use super::Skeleton;
+    use animation::{Pose, Transform, skin_position};
+    use util::graphics::SkinnedVertex;
+    use util::math;
+
+    fn close(a: &Matrix4<f32>, b: &Matrix4<f32>) -> bool {
+        let (a, b) = (math::to_mat4(a), math::to_mat4(b));
+        (0..4).all(|c| (0..4).all(|r| (a[c][r] - b[c][r]).abs() < 1e-5))
+    }
+
+    fn translation(x: f32, y: f32, z: f32) -> Transform {
+        Transform::new(Vector3::new(x, y, z),
+                       Quaternion::new(1.0, 0.0, 0.0, 0.0),
+                       Vector3::new(1.0, 1.0, 1.0))
+    }
+
+    // root at (1, 0, 0), arm 2 above it and hand 1 to the side of the arm
+    fn arm() -> Skeleton {
+        let mut skeleton = Skeleton::new();
+        let root = skeleton.add_joint("root", None, translation(1.0, 0.0, 0.0));
+        let arm = skeleton.add_joint("arm", Some(root), translation(0.0, 2.0, 0.0));
+        skeleton.add_joint("hand", Some(arm), translation(1.0, 0.0, 0.0));
+        skeleton
+    }
+
+    #[test]
+    fn rest_pose_skins_to_identity() {
+        let skeleton = arm();
+        let identity = Transform::identity().to_matrix();
+        for m in skeleton.skinning_matrices(&skeleton.rest_pose()).iter() {
+            assert!(close(m, &identity));
+        }
+    }
+
+    #[test]
+    fn global_matrices_follow_the_parents() {
+        let skeleton = arm();
+        assert_eq!(skeleton.find("hand"), Some(2));
+        let mut pose = skeleton.rest_pose();
+        let half = 0.5f32.sqrt();
+        // Quarter turn of the root around z moves arm and hand with it
+        pose.local[0].rotation = Quaternion::new(half, 0.0, 0.0, half);
+        let global = skeleton.global_matrices(&pose);
+        let expected = [[1.0, 0.0, 0.0], [-1.0, 0.0, 0.0], [-1.0, 1.0, 0.0]];
+        for (m, e) in global.iter().zip(expected.iter()) {
+            let o = [m.w.x, m.w.y, m.w.z];
+            assert!((0..3).all(|i| (o[i] - e[i]).abs() < 1e-5), "{:?} instead of {:?}", o, e);
+        }
+    }
+
+    #[test]
+    fn missing_joints_use_the_rest_pose() {
+        let skeleton = arm();
+        let partial = Pose { local: vec![translation(1.0, 0.0, 0.0)] };
+        let full = skeleton.global_matrices(&skeleton.rest_pose());
+        for (a, b) in skeleton.global_matrices(&partial).iter().zip(full.iter()) {
+            assert!(close(a, b));
+        }
+    }
+
+    #[test]
+    fn skinned_vertices_move_with_their_joint() {
+        let skeleton = arm();
+        let mut pose = skeleton.rest_pose();
+        pose.local[1].translation = Vector3::new(0.0, 3.0, 0.0);
+        let matrices = skeleton.skinning_matrices(&pose);
+        // A vertex at the hand, half bound to the arm and half to the root
+        let vertex = SkinnedVertex::new([2.0, 2.0, 0.0],
+                                        [0.0, 0.0, 1.0],
+                                        [0.0, 0.0],
+                                        [1, 0, 0, 0],
+                                        [0.5, 0.5, 0.0, 0.0]);
+        let p = skin_position(&vertex, &matrices);
+        assert!((p[0] - 2.0).abs() < 1e-5 && (p[1] - 2.5).abs() < 1e-5 && p[2].abs() < 1e-5,
+                "{:?}",
+                p);
+    }
+}
@@ -1,7 +1,9 @@
 #[allow(dead_code)]
 // Standard Library
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::path::Path;
+use std::rc::Rc;
 use std::marker::PhantomData;
 
 // External libraries
@@ -9,26 +11,37 @@ use glium;
 use collision::Aabb3;
 
 // Importing modules of this project
-use assets::Drawable;
+use assets::{Drawable, Pass};
 use assets::group::Group;
-use assets::{loader, build_aabb};
+use assets::{loader, build_bounds};
+use accelerator::bounds::Bounds;
+use assets::morph::MorphTarget;
 use shader;
-use util::graphics::{Vertex, Material, TexturePBR, BaseUniform};
+use util::graphics::{Vertex, VertexAttributes, Material, TexturePBR, BaseUniform};
 
 
 pub struct Asset<'a> {
     name: String,
     volume: Aabb3<f32>,
+    bounds: Bounds,
     vbo: glium::vertex::VertexBuffer<Vertex>,
+    // Colors and second uv set, only present if the mesh provides them
+    attributes: Option<glium::vertex::VertexBuffer<VertexAttributes>>,
     prim_type: glium::index::PrimitiveType,
     param: glium::DrawParameters<'a>,
     group: Vec<Group<'a>>,
+    // The vertex data is only kept on the CPU if there are targets to blend
+    morph_targets: Vec<MorphTarget>,
+    base_vertices: Vec<Vertex>,
 }
 
 impl<'a> Asset<'a> {
     pub fn get_volume(&self) -> &Aabb3<f32> {
         &self.volume
     }
+    pub fn get_bounds(&self) -> &Bounds {
+        &self.bounds
+    }
 
     pub fn get_groups(&self) -> &[Group] {
         &self.group[..]
@@ -60,6 +73,107 @@ impl<'a> Asset<'a> {
     pub fn get_vbo(&self) -> &glium::VertexBuffer<Vertex> {
         &self.vbo
     }
+
+    pub fn morph_targets(&self) -> &[MorphTarget] {
+        &self.morph_targets[..]
+    }
+    pub fn find_morph_target(&self, name: &str) -> Option<usize> {
+        self.morph_targets.iter().position(|t| t.name == name)
+    }
+    pub fn base_vertices(&self) -> &[Vertex] {
+        &self.base_vertices[..]
+    }
+
+    pub fn get_attributes(&self) -> Option<&glium::VertexBuffer<VertexAttributes>> {
+        self.attributes.as_ref()
+    }
+
+    pub fn has_blended(&self) -> bool {
+        self.group.iter().any(|g| g.is_blended())
+    }
+
+    // Draws the groups from a different vertex buffer with the same layout,
+    // used by instances that blend morph targets.
+    #[inline]
+    pub fn draw_from<S>(&self,
+                        target: &mut S,
+                        display: &glium::Display,
+                        uniforms: BaseUniform,
+                        vbo: &glium::VertexBuffer<Vertex>)
+        where S: glium::Surface
+    {
+        self.draw_pass_from(target, display, uniforms, vbo, Pass::Opaque);
+        self.draw_pass_from(target, display, uniforms, vbo, Pass::Blend);
+    }
+
+    #[inline]
+    pub fn draw_pass<S>(&self, target: &mut S, display: &glium::Display, uniforms: BaseUniform, pass: Pass)
+        where S: glium::Surface
+    {
+        self.draw_pass_from(target, display, uniforms, &self.vbo, pass);
+    }
+
+    #[inline]
+    pub fn draw_pass_from<S>(&self,
+                             target: &mut S,
+                             display: &glium::Display,
+                             uniforms: BaseUniform,
+                             vbo: &glium::VertexBuffer<Vertex>,
+                             pass: Pass)
+        where S: glium::Surface
+    {
+        for g in self.group.iter().filter(|g| pass.includes(g)) {
+            self.draw_group_from(target, display, uniforms, g, vbo);
+        }
+    }
+
+    // Draws the opaque groups with a program of their own, used for the
+    // depth passes of the shadow maps. Masked groups cast solid shadows.
+    pub fn draw_depth_from<S, U>(&self,
+                                 target: &mut S,
+                                 vbo: &glium::VertexBuffer<Vertex>,
+                                 program: &glium::Program,
+                                 uniforms: &U,
+                                 params: &glium::DrawParameters)
+        where S: glium::Surface,
+              U: glium::uniforms::Uniforms
+    {
+        for g in self.group.iter().filter(|g| !g.is_blended()) {
+            target.draw(vbo.slice(g.get_range()).unwrap(),
+                        glium::index::NoIndices(self.prim_type),
+                        program,
+                        uniforms,
+                        params)
+                  .unwrap();
+        }
+    }
+
+    #[inline]
+    fn draw_group_from<S>(&self,
+                          target: &mut S,
+                          display: &glium::Display,
+                          uniforms: BaseUniform,
+                          group: &Group,
+                          vbo: &glium::VertexBuffer<Vertex>)
+        where S: glium::Surface
+    {
+        let vertices = vbo.slice(group.get_range()).unwrap();
+        if let Some(ref attributes) = self.attributes {
+            group.draw(target,
+                       uniforms,
+                       display,
+                       (vertices, attributes.slice(group.get_range()).unwrap()),
+                       &self.param,
+                       &self.prim_type);
+        } else {
+            group.draw(target,
+                       uniforms,
+                       display,
+                       vertices,
+                       &self.param,
+                       &self.prim_type);
+        }
+    }
 }
 
 impl<'a> Drawable for Asset<'a> {
@@ -67,14 +181,7 @@ impl<'a> Drawable for Asset<'a> {
     fn draw<S>(&self, target: &mut S, display: &glium::Display, uniforms: BaseUniform)
         where S: glium::Surface
     {
-        for g in self.group.iter() {
-            g.draw(target,
-                   uniforms,
-                   display,
-                   self.vbo.slice(g.get_range()).unwrap(),
-                   &self.param,
-                   &self.prim_type);
-        }
+        self.draw_from(target, display, uniforms, &self.vbo);
     }
 
     #[inline]
@@ -85,12 +192,7 @@ impl<'a> Drawable for Asset<'a> {
                      group: &Group)
         where S: glium::Surface
     {
-        group.draw(target,
-                   uniforms,
-                   display,
-                   self.vbo.slice(group.get_range()).unwrap(),
-                   &self.param,
-                   &self.prim_type);
+        self.draw_group_from(target, display, uniforms, group, &self.vbo);
     }
 }
 
@@ -98,10 +200,13 @@ pub struct AssetLoader<'a, T: 'a> {
     name: String,
     display: &'a glium::Display,
     vertex_data: Vec<Vertex>,
+    attributes: Vec<VertexAttributes>,
     group: Vec<Group<'a>>,
     param: Option<glium::DrawParameters<'a>>,
     prim_type: Option<glium::index::PrimitiveType>,
     volume: Aabb3<f32>,
+    bounds: Bounds,
+    morph_targets: Vec<MorphTarget>,
     phantom: PhantomData<&'a T>,
 }
 
@@ -112,15 +217,19 @@ impl<'a, T: 'a> AssetLoader<'a, T> {
                material_map: &'a HashMap<String, Material>,
                program: &'a shader::Program)
                -> AssetLoader<'a, T> {
-        let (vertex, group) = loader(path, texture_map, material_map, program);
+        let (vertex, attributes, group) = loader(path, texture_map, material_map, program);
+        let bounds = build_bounds(&vertex);
         AssetLoader {
             name: "".to_string(),
             display: display,
-            volume: build_aabb(&vertex),
+            volume: bounds.aabb,
+            bounds: bounds,
             vertex_data: vertex,
+            attributes: attributes,
             group: group,
             param: None,
             prim_type: None,
+            morph_targets: Vec::new(),
             phantom: PhantomData,
         }
     }
@@ -132,9 +241,10 @@ impl<'a, T: 'a> AssetLoader<'a, T> {
                   texture_specular: Option<&'a glium::texture::Texture2d>,
                   texture_normal: Option<&'a glium::texture::Texture2d>,
                   texture_gloss: Option<&'a glium::texture::Texture2d>,
-                  program: &'a glium::Program)
+                  program: Rc<glium::Program>)
                   -> AssetLoader<'a, T> {
-        let vol = build_aabb(&vertex_data);
+        let bounds = build_bounds(&vertex_data);
+        let vol = bounds.aabb;
         let range = 0..vertex_data.len();
         let al = match (texture_albedo,
                         texture_specular,
@@ -145,14 +255,17 @@ impl<'a, T: 'a> AssetLoader<'a, T> {
                     name: name,
                     display: display,
                     volume: vol.clone(),
+                    bounds: bounds,
                     vertex_data: vertex_data,
+                    attributes: Vec::new(),
                     group: vec![Group::new(range,
                                            Some(TexturePBR::AlbedoSpecularNormalGloss(a, s, n, g)),
-                                           Some(material),
+                                           Some(Cow::Borrowed(material)),
                                            program,
-                                           vol)],
+                                           bounds)],
                     param: None,
                     prim_type: None,
+                    morph_targets: Vec::new(),
                     phantom: PhantomData,
                 }
             }
@@ -161,10 +274,13 @@ impl<'a, T: 'a> AssetLoader<'a, T> {
                     name: name,
                     display: display,
                     volume: vol.clone(),
+                    bounds: bounds,
                     vertex_data: vertex_data,
-                    group: vec![Group::new(range, None, Some(material), program, vol)],
+                    attributes: Vec::new(),
+                    group: vec![Group::new(range, None, Some(Cow::Borrowed(material)), program, bounds)],
                     param: None,
                     prim_type: None,
+                    morph_targets: Vec::new(),
                     phantom: PhantomData,
                 }
             }
@@ -188,6 +304,17 @@ impl<'a, T: 'a> AssetLoader<'a, T> {
         self.volume = volume;
         self
     }
+    // Colors and second uv set for the VERTEX_COLOR permutation
+    pub fn attributes(mut self, attributes: Vec<VertexAttributes>) -> AssetLoader<'a, T> {
+        assert_eq!(attributes.len(), self.vertex_data.len(), "Vertex attributes do not match the vertex count");
+        self.attributes = attributes;
+        self
+    }
+    pub fn morph_target(mut self, target: MorphTarget) -> AssetLoader<'a, T> {
+        assert_eq!(target.len(), self.vertex_data.len(), "Morph target does not match the vertex count");
+        self.morph_targets.push(target);
+        self
+    }
     pub fn load(mut self) -> Asset<'a> {
         if let None = self.param {
             self.param = Some(glium::DrawParameters {
@@ -206,10 +333,22 @@ impl<'a, T: 'a> AssetLoader<'a, T> {
         let mut asset = Asset {
             name: self.name,
             volume: self.volume,
+            bounds: self.bounds,
             vbo: glium::vertex::VertexBuffer::immutable(self.display, &self.vertex_data).unwrap(),
+            attributes: if self.attributes.is_empty() {
+                None
+            } else {
+                Some(glium::vertex::VertexBuffer::immutable(self.display, &self.attributes).unwrap())
+            },
             prim_type: self.prim_type.unwrap(),
             param: self.param.unwrap(),
             group: self.group,
+            base_vertices: if self.morph_targets.is_empty() {
+                Vec::new()
+            } else {
+                self.vertex_data
+            },
+            morph_targets: self.morph_targets,
         };
         asset.sort_group();
         asset
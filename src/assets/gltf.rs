@@ -0,0 +1,452 @@
+// Standard Library
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::path::Path;
+
+// External Library
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::Json;
+
+use assets::{GroupData, MeshData};
+use util::graphics::Vertex;
+
+
+// glTF 2.0 meshes from .gltf files with external or embedded buffers and
+// from binary .glb files. Every triangle primitive becomes a group, reading
+// POSITION, NORMAL, TEXCOORD_0, TEXCOORD_1 and COLOR_0. Meshes are read in
+// their own space, node transforms and materials are not applied.
+
+const GLB_MAGIC: u32 = 0x46546C67;
+const CHUNK_JSON: u32 = 0x4E4F534A;
+const CHUNK_BIN: u32 = 0x004E4942;
+const MODE_TRIANGLES: u64 = 4;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> io::Result<u32> {
+    if bytes.len() < at + 4 {
+        return Err(invalid("truncated glb file".to_string()));
+    }
+    Ok(bytes[at] as u32 | (bytes[at + 1] as u32) << 8 | (bytes[at + 2] as u32) << 16 |
+       (bytes[at + 3] as u32) << 24)
+}
+
+fn get<'a>(json: &'a Json, key: &str) -> io::Result<&'a Json> {
+    json.find(key).ok_or(invalid(format!("missing '{}'", key)))
+}
+
+fn get_index(json: &Json, key: &str) -> io::Result<usize> {
+    try!(get(json, key)).as_u64().map(|i| i as usize).ok_or(invalid(format!("'{}' is not an index", key)))
+}
+
+fn get_array<'a>(json: &'a Json, key: &str) -> &'a [Json] {
+    json.find(key).and_then(|a| a.as_array()).map(|a| &a[..]).unwrap_or(&[])
+}
+
+fn nth<'a>(json: &'a Json, key: &str, index: usize) -> io::Result<&'a Json> {
+    get_array(json, key).get(index).ok_or(invalid(format!("{} {} does not exist", key, index)))
+}
+
+// Splits a .glb file into its json and binary chunk
+fn split_glb(bytes: &[u8]) -> io::Result<(String, Option<Vec<u8>>)> {
+    let length = try!(read_u32(bytes, 8)) as usize;
+    let bytes = &bytes[..length.min(bytes.len())];
+    let mut json = None;
+    let mut bin = None;
+    let mut at = 12;
+    while at + 8 <= bytes.len() {
+        let chunk_length = try!(read_u32(bytes, at)) as usize;
+        let chunk_type = try!(read_u32(bytes, at + 4));
+        let start = at + 8;
+        if start + chunk_length > bytes.len() {
+            return Err(invalid("truncated glb chunk".to_string()));
+        }
+        let data = &bytes[start..start + chunk_length];
+        if chunk_type == CHUNK_JSON {
+            json = Some(String::from_utf8_lossy(data).into_owned());
+        } else if chunk_type == CHUNK_BIN && bin.is_none() {
+            bin = Some(data.to_vec());
+        }
+        at = start + chunk_length;
+    }
+    match json {
+        Some(j) => Ok((j, bin)),
+        None => Err(invalid("glb file without json chunk".to_string())),
+    }
+}
+
+// Buffers from data uris, files next to the gltf file or the glb binary chunk
+fn load_buffers(json: &Json, dir: &Path, mut bin: Option<Vec<u8>>) -> io::Result<Vec<Vec<u8>>> {
+    let mut buffers = Vec::new();
+    for buffer in get_array(json, "buffers").iter() {
+        let data = match buffer.find("uri").and_then(|u| u.as_string()) {
+            Some(uri) if uri.starts_with("data:") => {
+                let start = try!(uri.find(";base64,").ok_or(invalid("data uri is not base64".to_string())));
+                try!(uri[start + 8..].from_base64().map_err(|e| invalid(format!("bad base64 data: {:?}", e))))
+            }
+            Some(uri) => {
+                let mut data = Vec::new();
+                try!(try!(File::open(dir.join(uri))).read_to_end(&mut data));
+                data
+            }
+            None => try!(bin.take().ok_or(invalid("buffer without uri outside of a glb file".to_string()))),
+        };
+        buffers.push(data);
+    }
+    Ok(buffers)
+}
+
+// Typed view into a buffer as described by an accessor
+struct Accessor<'a> {
+    data: &'a [u8],
+    stride: usize,
+    count: usize,
+    components: usize,
+    component_type: u64,
+    normalized: bool,
+}
+
+impl<'a> Accessor<'a> {
+    fn new(json: &Json, buffers: &'a [Vec<u8>], index: usize) -> io::Result<Accessor<'a>> {
+        let accessor = try!(nth(json, "accessors", index));
+        let view_index = try!(get_index(accessor, "bufferView")
+                                  .map_err(|_| invalid(format!("accessor {} has no buffer view", index))));
+        let view = try!(nth(json, "bufferViews", view_index));
+        let buffer = try!(buffers.get(try!(get_index(view, "buffer")))
+                                 .ok_or(invalid(format!("buffer view {} has no buffer", view_index))));
+
+        let component_type = try!(try!(get(accessor, "componentType"))
+                                      .as_u64()
+                                      .ok_or(invalid("bad componentType".to_string())));
+        let size = match component_type {
+            5120 | 5121 => 1,
+            5122 | 5123 => 2,
+            5125 | 5126 => 4,
+            t => return Err(invalid(format!("unknown component type {}", t))),
+        };
+        let components = match try!(get(accessor, "type")).as_string() {
+            Some("SCALAR") => 1,
+            Some("VEC2") => 2,
+            Some("VEC3") => 3,
+            Some("VEC4") => 4,
+            t => return Err(invalid(format!("unsupported accessor type {:?}", t))),
+        };
+        let count = try!(get_index(accessor, "count"));
+        let offset = view.find("byteOffset").and_then(|o| o.as_u64()).unwrap_or(0) as usize +
+                     accessor.find("byteOffset").and_then(|o| o.as_u64()).unwrap_or(0) as usize;
+        let stride = view.find("byteStride").and_then(|s| s.as_u64()).unwrap_or(0) as usize;
+        let stride = if stride == 0 { components * size } else { stride };
+
+        let end = if count == 0 { offset } else { offset + (count - 1) * stride + components * size };
+        if end > buffer.len() {
+            return Err(invalid(format!("accessor {} reads past its buffer", index)));
+        }
+        Ok(Accessor {
+            data: &buffer[offset..end],
+            stride: stride,
+            count: count,
+            components: components,
+            component_type: component_type,
+            normalized: accessor.find("normalized").and_then(|n| n.as_boolean()).unwrap_or(false),
+        })
+    }
+
+    fn raw(&self, i: usize, c: usize) -> f32 {
+        let at = i * self.stride;
+        let d = self.data;
+        match self.component_type {
+            5120 => d[at + c] as i8 as f32,
+            5121 => d[at + c] as f32,
+            5122 => (d[at + 2 * c] as u16 | (d[at + 2 * c + 1] as u16) << 8) as i16 as f32,
+            5123 => (d[at + 2 * c] as u16 | (d[at + 2 * c + 1] as u16) << 8) as f32,
+            _ => {
+                let b = &d[at + 4 * c..at + 4 * c + 4];
+                let u = b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24;
+                if self.component_type == 5126 {
+                    unsafe { mem::transmute::<u32, f32>(u) }
+                } else {
+                    u as f32
+                }
+            }
+        }
+    }
+
+    // Integer colors and uvs are always normalized, other attributes only if flagged
+    fn value(&self, i: usize, c: usize, normalize: bool) -> f32 {
+        let v = self.raw(i, c);
+        if !(normalize || self.normalized) {
+            return v;
+        }
+        match self.component_type {
+            5120 => (v / 127.0).max(-1.0),
+            5121 => v / 255.0,
+            5122 => (v / 32767.0).max(-1.0),
+            5123 => v / 65535.0,
+            _ => v,
+        }
+    }
+
+    // Indices are unsigned bytes, shorts or ints
+    fn index(&self, i: usize) -> io::Result<usize> {
+        let at = i * self.stride;
+        let d = self.data;
+        match self.component_type {
+            5121 => Ok(d[at] as usize),
+            5123 => Ok((d[at] as usize) | (d[at + 1] as usize) << 8),
+            5125 => {
+                Ok((d[at] as usize) | (d[at + 1] as usize) << 8 | (d[at + 2] as usize) << 16 |
+                   (d[at + 3] as usize) << 24)
+            }
+            t => Err(invalid(format!("unsupported index component type {}", t))),
+        }
+    }
+}
+
+pub fn load(path: &Path) -> io::Result<MeshData> {
+    let mut bytes = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut bytes));
+    let dir = path.parent().unwrap_or(Path::new(""));
+    parse(&bytes, dir)
+}
+
+// Parses .gltf or .glb data, relative buffer uris are resolved against `dir`
+pub fn parse(bytes: &[u8], dir: &Path) -> io::Result<MeshData> {
+    let (text, bin) = if bytes.len() >= 4 && try!(read_u32(bytes, 0)) == GLB_MAGIC {
+        try!(split_glb(bytes))
+    } else {
+        (String::from_utf8_lossy(bytes).into_owned(), None)
+    };
+    let json = try!(Json::from_str(&text).map_err(|e| invalid(format!("bad json: {:?}", e))));
+    let buffers = try!(load_buffers(&json, dir, bin));
+
+    let mut vertices = Vec::new();
+    let mut colors = Vec::new();
+    let mut texcoords1 = Vec::new();
+    let mut has_colors = false;
+    let mut has_texcoords1 = false;
+    let mut groups = Vec::new();
+
+    for (m, mesh) in get_array(&json, "meshes").iter().enumerate() {
+        let object = mesh.find("name").and_then(|n| n.as_string()).map(|n| n.to_string());
+        let object = object.unwrap_or(format!("mesh{}", m));
+        for (p, primitive) in get_array(mesh, "primitives").iter().enumerate() {
+            let mode = primitive.find("mode").and_then(|m| m.as_u64()).unwrap_or(MODE_TRIANGLES);
+            if mode != MODE_TRIANGLES {
+                let _ = writeln!(&mut io::stderr(), "{}: skipping primitive {} of mode {}", object, p, mode);
+                continue;
+            }
+            let attributes = try!(get(primitive, "attributes"));
+            let attribute = |name: &str| {
+                match attributes.find(name).and_then(|a| a.as_u64()) {
+                    Some(i) => Accessor::new(&json, &buffers, i as usize).map(Some),
+                    None => Ok(None),
+                }
+            };
+            let positions = try!(try!(attribute("POSITION")).ok_or(invalid(format!("{} has no positions", object))));
+            let normals = try!(attribute("NORMAL"));
+            let uv0 = try!(attribute("TEXCOORD_0"));
+            let uv1 = try!(attribute("TEXCOORD_1"));
+            let color = try!(attribute("COLOR_0"));
+            for a in [&normals, &uv0, &uv1, &color].iter() {
+                if let Some(ref a) = **a {
+                    if a.count < positions.count {
+                        return Err(invalid(format!("{}: attribute shorter than the positions", object)));
+                    }
+                }
+            }
+
+            let indices: Vec<usize> = match primitive.find("indices").and_then(|i| i.as_u64()) {
+                Some(i) => {
+                    let accessor = try!(Accessor::new(&json, &buffers, i as usize));
+                    try!((0..accessor.count).map(|k| accessor.index(k)).collect::<io::Result<_>>())
+                }
+                None => (0..positions.count).collect(),
+            };
+            if let Some(&i) = indices.iter().find(|&&i| i >= positions.count) {
+                return Err(invalid(format!("{}: index {} out of range", object, i)));
+            }
+
+            let first = vertices.len();
+            for &i in indices.iter().take(indices.len() / 3 * 3) {
+                let read = |a: &Accessor, n: usize, normalize: bool| {
+                    let mut v = [0.0; 4];
+                    for c in 0..n.min(a.components) {
+                        v[c] = a.value(i, c, normalize);
+                    }
+                    v
+                };
+                let position = read(&positions, 3, false);
+                let normal = normals.as_ref().map(|a| read(a, 3, false)).unwrap_or([0.0; 4]);
+                let texture = uv0.as_ref().map(|a| read(a, 2, true)).unwrap_or([0.0; 4]);
+                let vertex = Vertex::new([position[0], position[1], position[2]],
+                                         [normal[0], normal[1], normal[2]],
+                                         [texture[0], texture[1]]);
+                vertices.push(vertex);
+
+                let c = match color {
+                    Some(ref a) => {
+                        let mut c = read(a, 4, true);
+                        if a.components == 3 {
+                            c[3] = 1.0;
+                        }
+                        c
+                    }
+                    None => [1.0; 4],
+                };
+                colors.push(c);
+                texcoords1.push(match uv1 {
+                    Some(ref a) => {
+                        let t = read(a, 2, true);
+                        [t[0], t[1]]
+                    }
+                    None => vertex.texture,
+                });
+            }
+            has_colors = has_colors || color.is_some();
+            has_texcoords1 = has_texcoords1 || uv1.is_some();
+            groups.push(GroupData {
+                object: object.clone(),
+                name: primitive.find("material")
+                               .and_then(|m| m.as_u64())
+                               .and_then(|m| get_array(&json, "materials").get(m as usize))
+                               .and_then(|m| m.find("name"))
+                               .and_then(|n| n.as_string())
+                               .map(|n| n.to_string())
+                               .unwrap_or(format!("primitive{}", p)),
+                range: first..vertices.len(),
+                material: None,
+            });
+        }
+    }
+
+    if !has_colors {
+        colors.clear();
+    }
+    if !has_texcoords1 {
+        texcoords1.clear();
+    }
+    Ok(MeshData {
+        vertices: vertices,
+        colors: colors,
+        texcoords1: texcoords1,
+        groups: groups,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use rustc_serialize::base64::{STANDARD, ToBase64};
+
+    use super::parse;
+
+    fn floats(values: &[f32]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for v in values.iter() {
+            let b: u32 = unsafe { ::std::mem::transmute(*v) };
+            out.extend_from_slice(&[b as u8, (b >> 8) as u8, (b >> 16) as u8, (b >> 24) as u8]);
+        }
+        out
+    }
+
+    // One indexed triangle with float positions, byte colors and two uv sets
+    fn triangle() -> (String, Vec<u8>) {
+        let mut buffer = floats(&[0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+        buffer.extend(floats(&[0.0, 0.0, 1.0, 0.0, 0.0, 1.0]));
+        buffer.extend(floats(&[0.5, 0.5, 0.75, 0.5, 0.5, 0.75]));
+        buffer.extend_from_slice(&[255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 128]);
+        buffer.extend_from_slice(&[0, 0, 2, 0, 1, 0, 0, 0]);
+        let json = r#"{
+            "asset": {"version": "2.0"},
+            "meshes": [{"name": "tri", "primitives": [{
+                "attributes": {"POSITION": 0, "TEXCOORD_0": 1, "TEXCOORD_1": 2, "COLOR_0": 3},
+                "indices": 4, "material": 0}]}],
+            "materials": [{"name": "red"}],
+            "accessors": [
+                {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3"},
+                {"bufferView": 0, "byteOffset": 36, "componentType": 5126, "count": 3, "type": "VEC2"},
+                {"bufferView": 0, "byteOffset": 60, "componentType": 5126, "count": 3, "type": "VEC2"},
+                {"bufferView": 0, "byteOffset": 84, "componentType": 5121, "count": 3, "type": "VEC4",
+                 "normalized": true},
+                {"bufferView": 0, "byteOffset": 96, "componentType": 5123, "count": 3, "type": "SCALAR"}],
+            "bufferViews": [{"buffer": 0, "byteLength": 104}],
+            "buffers": [URI]
+        }"#;
+        (json.to_string(), buffer)
+    }
+
+    #[test]
+    fn reads_colors_and_both_uv_sets() {
+        let (json, buffer) = triangle();
+        let uri = format!("{{\"byteLength\": {}, \"uri\": \"data:application/octet-stream;base64,{}\"}}",
+                          buffer.len(),
+                          buffer.to_base64(STANDARD));
+        let mesh = parse(json.replace("URI", &uri).as_bytes(), Path::new("")).unwrap();
+        assert_eq!(mesh.vertices.len(), 3);
+        assert_eq!(mesh.groups[0].object, "tri");
+        assert_eq!(mesh.groups[0].name, "red");
+        // Indices 0, 2, 1
+        assert_eq!(mesh.vertices[1].position, [0.0, 1.0, 0.0]);
+        assert_eq!(mesh.vertices[1].texture, [0.0, 1.0]);
+        assert_eq!(mesh.texcoords1[1], [0.5, 0.75]);
+        assert_eq!(mesh.colors[0], [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(mesh.colors[1][2], 1.0);
+        assert!((mesh.colors[1][3] - 128.0 / 255.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn reads_glb_files() {
+        let (json, buffer) = triangle();
+        let json = json.replace("URI", &format!("{{\"byteLength\": {}}}", buffer.len()));
+        let mut json = json.into_bytes();
+        while json.len() % 4 != 0 {
+            json.push(b' ');
+        }
+        let le = |v: usize| vec![v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8];
+        let mut glb = b"glTF".to_vec();
+        glb.extend(le(2));
+        glb.extend(le(12 + 8 + json.len() + 8 + buffer.len()));
+        glb.extend(le(json.len()));
+        glb.extend_from_slice(b"JSON");
+        glb.extend(json);
+        glb.extend(le(buffer.len()));
+        glb.extend_from_slice(b"BIN\0");
+        glb.extend(buffer);
+        let mesh = parse(&glb, Path::new("")).unwrap();
+        assert_eq!(mesh.vertices.len(), 3);
+        assert_eq!(mesh.texcoords1.len(), 3);
+        assert_eq!(mesh.vertices[2].position, [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn rejects_out_of_range_accessors() {
+        let (json, buffer) = triangle();
+        let uri = format!("{{\"byteLength\": {}, \"uri\": \"data:application/octet-stream;base64,{}\"}}",
+                          buffer.len(),
+                          buffer[..90].to_base64(STANDARD));
+        assert!(parse(json.replace("URI", &uri).as_bytes(), Path::new("")).is_err());
+    }
+
+    #[test]
+    fn rejects_signed_and_float_indices() {
+        let (json, buffer) = triangle();
+        let uri = format!("{{\"byteLength\": {}, \"uri\": \"data:application/octet-stream;base64,{}\"}}",
+                          buffer.len(),
+                          buffer.to_base64(STANDARD));
+        let json = json.replace("URI", &uri);
+        let indices = "\"componentType\": 5123, \"count\": 3, \"type\": \"SCALAR\"";
+        assert!(parse(json.as_bytes(), Path::new("")).is_ok());
+        for &(component_type, count) in [(5120, 3), (5122, 3), (5126, 2)].iter() {
+            let bad = format!("\"componentType\": {}, \"count\": {}, \"type\": \"SCALAR\"", component_type, count);
+            match parse(json.replace(indices, &bad).as_bytes(), Path::new("")) {
+                Err(e) => assert_eq!(e.to_string(), format!("unsupported index component type {}", component_type)),
+                Ok(_) => panic!("{} indices parsed", component_type),
+            }
+        }
+    }
+}
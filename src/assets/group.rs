@@ -1,21 +1,167 @@
 
+use std::borrow::Cow;
 use std::ops::Range;
+use std::rc::Rc;
 use std::fmt::{Debug, Formatter, Result};
 use std::cmp::*;
 
 use glium;
-use collision::{Frustum, Aabb3, Relation};
+use collision::{Frustum, Aabb3};
 use cgmath;
 
-use util::graphics::{BaseUniform, Material, Vertex, TexturePBR};
+use util::graphics::{BaseUniform, Material, SkinnedVertex, JointMatrices, TexturePBR, AlphaMode};
+use texture::pack::Channel;
 use accelerator::OctreeItem;
+use accelerator::bounds::Bounds;
+
+// Binds the textures of a group on top of the given uniforms and issues the draw call.
+// TODO The way that uniforms are handeled leads to this verbouse draw process_input
+// As far as I know right know, returning the uniform from a function is not possible,
+// since the type changes depending on the values. Maybe this can be done with generics..
+macro_rules! draw_textured {
+    ($group:expr, $target:expr, $uniform:expr, $vertices:expr, $params:expr, $prim_type:expr) => {{
+        if let Some(ref tex) = $group.tex {
+            match tex {
+                &TexturePBR::AlbedoSpecularNormalGloss(a, s, n, g) => {
+                    let uniform = $uniform.add("dagger_albedo", a);
+                    let uniform = uniform.add("dagger_specular", s);
+                    let uniform = uniform.add("dagger_normal", n);
+                    let uniform = uniform.add("dagger_gloss", g);
+
+                    $target.draw($vertices,
+                                 glium::index::NoIndices($prim_type.clone()),
+                                 &*$group.program,
+                                 &uniform,
+                                 $params)
+                           .unwrap();
+                }
+                &TexturePBR::AlbedoNormalPacked(a, n, p, layout) => {
+                    let fallback = [layout.fallback(Channel::Roughness),
+                                    layout.fallback(Channel::Metalness),
+                                    layout.fallback(Channel::Occlusion)];
+                    let uniform = $uniform.add("tex_albedo", a);
+                    let uniform = uniform.add("tex_normal", n);
+                    let uniform = uniform.add("tex_packed", p);
+                    let uniform = uniform.add("roughness_mask", layout.mask(Channel::Roughness));
+                    let uniform = uniform.add("metalness_mask", layout.mask(Channel::Metalness));
+                    let uniform = uniform.add("occlusion_mask", layout.mask(Channel::Occlusion));
+                    let uniform = uniform.add("packed_fallback", fallback);
+
+                    $target.draw($vertices,
+                                 glium::index::NoIndices($prim_type.clone()),
+                                 &*$group.program,
+                                 &uniform,
+                                 $params)
+                           .unwrap();
+                }
+                &TexturePBR::MetallicRoughness(ref mr) => {
+                    let uniform = $uniform.add("tex_base_color", mr.texture(&mr.base_color));
+                    let uniform = uniform.add("tex_metallic", mr.texture(&mr.metallic));
+                    let uniform = uniform.add("tex_roughness", mr.texture(&mr.roughness));
+                    let uniform = uniform.add("tex_occlusion", mr.texture(&mr.occlusion));
+                    let uniform = uniform.add("tex_emissive", mr.texture(&mr.emissive));
+                    let uniform = uniform.add("tex_normal", mr.normal_texture());
+                    let uniform = uniform.add("base_color_factor", mr.base_color.factor);
+                    let uniform = uniform.add("metallic_factor", mr.metallic.factor);
+                    let uniform = uniform.add("roughness_factor", mr.roughness.factor);
+                    let uniform = uniform.add("occlusion_factor", mr.occlusion.factor);
+                    let uniform = uniform.add("emissive_factor", mr.emissive.factor);
+                    let uniform = uniform.add("clearcoat", mr.lobes.clearcoat);
+                    let uniform = uniform.add("clearcoat_roughness", mr.lobes.clearcoat_roughness);
+                    let uniform = uniform.add("sheen_color", mr.lobes.sheen_color);
+                    let uniform = uniform.add("sheen_roughness", mr.lobes.sheen_roughness);
+                    let uniform = uniform.add("anisotropy", mr.lobes.anisotropy);
+                    let uniform = uniform.add("anisotropy_rotation", mr.lobes.anisotropy_rotation);
+
+                    $target.draw($vertices,
+                                 glium::index::NoIndices($prim_type.clone()),
+                                 &*$group.program,
+                                 &uniform,
+                                 $params)
+                           .unwrap();
+                }
+            }
+        } else {
+            $target.draw($vertices,
+                         glium::index::NoIndices($prim_type.clone()),
+                         &*$group.program,
+                         &$uniform,
+                         $params)
+                   .unwrap();
+        }
+    }};
+}
+
+// Binds the multiple scattering table, or turns the compensation off, then
+// draws like draw_textured
+macro_rules! draw_compensated {
+    ($group:expr, $target:expr, $uniform:expr, $base:expr, $vertices:expr, $params:expr, $prim_type:expr) => {{
+        match $base.energy {
+            Some(energy) => {
+                let uniform = $uniform.add("energy_lut", energy.table());
+                let uniform = uniform.add("multiscatter", energy.enabled);
+                draw_textured!($group, $target, uniform, $vertices, $params, $prim_type);
+            }
+            None => {
+                let uniform = $uniform.add("multiscatter", false);
+                draw_textured!($group, $target, uniform, $vertices, $params, $prim_type);
+            }
+        }
+    }};
+}
+
+// Binds the shadow maps, or turns the shadow lookups off, then draws like
+// draw_compensated
+macro_rules! draw_shadowed {
+    ($group:expr, $target:expr, $uniform:expr, $base:expr, $vertices:expr, $params:expr, $prim_type:expr) => {{
+        match $base.shadows {
+            Some(shadows) => {
+                let uniform = $uniform.add("point_shadows", shadows.cubes());
+                let uniform = uniform.add("sun_shadows", shadows.cascades());
+                let uniform = uniform.add("Cascades", shadows.cascade_block());
+                let uniform = uniform.add("point_shadow_far", shadows.point_far());
+                let uniform = uniform.add("shadow_bias", shadows.bias());
+                let uniform = uniform.add("shadows", shadows.enabled);
+                draw_compensated!($group, $target, uniform, $base, $vertices, $params, $prim_type);
+            }
+            None => {
+                let uniform = $uniform.add("shadows", false);
+                draw_compensated!($group, $target, uniform, $base, $vertices, $params, $prim_type);
+            }
+        }
+    }};
+}
+
+// Binds the maps of the environment, or turns the ambient term off, then
+// draws like draw_shadowed
+macro_rules! draw_lit {
+    ($group:expr, $target:expr, $uniform:expr, $base:expr, $vertices:expr, $params:expr, $prim_type:expr) => {{
+        match $base.environment {
+            Some(env) => {
+                let uniform = $uniform.add("irradiance_map", env.irradiance());
+                let uniform = uniform.add("prefiltered_map", env.prefiltered());
+                let uniform = uniform.add("prefiltered_levels", env.levels());
+                let uniform = uniform.add("brdf_lut", env.brdf_lut());
+                let uniform = uniform.add("ibl_intensity", env.intensity);
+                let uniform = uniform.add("view_to_world", $base.view_to_world);
+                let uniform = uniform.add("SphericalHarmonics", env.harmonics());
+                let uniform = uniform.add("diffuse_sh", env.diffuse_sh);
+                draw_shadowed!($group, $target, uniform, $base, $vertices, $params, $prim_type);
+            }
+            None => {
+                let uniform = $uniform.add("ibl_intensity", 0.0f32);
+                draw_shadowed!($group, $target, uniform, $base, $vertices, $params, $prim_type);
+            }
+        }
+    }};
+}
 
 pub struct Group<'a> {
     range: Range<usize>,
     tex: Option<TexturePBR<'a>>,
-    mat: Option<&'a Material>,
-    program: &'a glium::Program,
-    volume: Aabb3<f32>,
+    mat: Option<Cow<'a, Material>>,
+    program: Rc<glium::Program>,
+    bounds: Bounds,
 }
 
 impl<'a> Debug for Group<'a> {
@@ -27,31 +173,107 @@ impl<'a> Debug for Group<'a> {
 impl<'a> Group<'a> {
     pub fn new(r: Range<usize>,
                tex: Option<TexturePBR<'a>>,
-               mat: Option<&'a Material>,
-               program: &'a glium::Program,
-               volume: Aabb3<f32>)
+               mat: Option<Cow<'a, Material>>,
+               program: Rc<glium::Program>,
+               bounds: Bounds)
                -> Group<'a> {
         Group {
             range: r,
             tex: tex,
             mat: mat,
             program: program,
-            volume: volume,
+            bounds: bounds,
         }
     }
     pub fn get_range(&self) -> Range<usize> {
         self.range.clone()
     }
+    pub fn get_volume(&self) -> &Aabb3<f32> {
+        &self.bounds.aabb
+    }
+    pub fn get_bounds(&self) -> &Bounds {
+        &self.bounds
+    }
+    // Groups sharing a permutation are drawn next to each other
+    fn program_ptr(&self) -> *const glium::Program {
+        &*self.program as *const glium::Program
+    }
+    pub fn alpha_mode(&self) -> AlphaMode {
+        self.mat.as_ref().map(|m| m.alpha_mode).unwrap_or(AlphaMode::Opaque)
+    }
+    pub fn is_blended(&self) -> bool {
+        self.alpha_mode() == AlphaMode::Blend
+    }
 
+    // Blended groups do not write depth so the ones behind them still show up.
+    // Light passes after the first add their lights to what the first one
+    // drew, on the surfaces that are already in the depth buffer. So every
+    // light pass of the opaque groups has to be drawn before the blended
+    // groups, see instance::draw_sorted and Scene::draw.
+    fn blend_params<'p>(&self,
+                        params: &glium::DrawParameters<'p>,
+                        light_pass: usize)
+                        -> Option<glium::DrawParameters<'p>> {
+        use glium::{Blend, BlendingFunction, DepthTest, LinearBlendingFactor};
+        if !self.is_blended() && light_pass == 0 {
+            return None;
+        }
+        let mut p = params.clone();
+        let source = if self.is_blended() {
+            LinearBlendingFactor::SourceAlpha
+        } else {
+            LinearBlendingFactor::One
+        };
+        p.blend = if light_pass == 0 {
+            Blend {
+                color: BlendingFunction::Addition {
+                    source: LinearBlendingFactor::SourceAlpha,
+                    destination: LinearBlendingFactor::OneMinusSourceAlpha,
+                },
+                alpha: BlendingFunction::Addition {
+                    source: LinearBlendingFactor::One,
+                    destination: LinearBlendingFactor::OneMinusSourceAlpha,
+                },
+                constant_value: (0.0, 0.0, 0.0, 0.0),
+            }
+        } else {
+            p.depth.test = DepthTest::IfLessOrEqual;
+            Blend {
+                color: BlendingFunction::Addition {
+                    source: source,
+                    destination: LinearBlendingFactor::One,
+                },
+                alpha: BlendingFunction::Addition {
+                    source: LinearBlendingFactor::Zero,
+                    destination: LinearBlendingFactor::One,
+                },
+                constant_value: (0.0, 0.0, 0.0, 0.0),
+            }
+        };
+        p.depth.write = false;
+        Some(p)
+    }
+
+    // Fragments below the cutoff are discarded by the ALPHA_MASK permutation
+    fn alpha_cutoff(&self) -> f32 {
+        match self.alpha_mode() {
+            AlphaMode::Mask => self.mat.as_ref().map(|m| m.alpha_cutoff).unwrap_or(0.0),
+            _ => 0.0,
+        }
+    }
+
+    // `vertices` is a slice of a Vertex buffer, optionally paired with a
+    // slice of a VertexAttributes buffer for programs reading colors.
     #[inline]
-    pub fn draw<S>(&self,
-                   target: &mut S,
-                   uniforms: BaseUniform,
-                   display: &glium::Display,
-                   vertex_slice: glium::vertex::VertexBufferSlice<Vertex>,
-                   params: &glium::DrawParameters,
-                   prim_type: &glium::index::PrimitiveType)
-        where S: glium::Surface
+    pub fn draw<'b, S, V>(&self,
+                          target: &mut S,
+                          uniforms: BaseUniform,
+                          display: &glium::Display,
+                          vertices: V,
+                          params: &glium::DrawParameters,
+                          prim_type: &glium::index::PrimitiveType)
+        where S: glium::Surface,
+              V: glium::vertex::MultiVerticesSource<'b>
     {
 
         let light_buffer = glium::uniforms::UniformBuffer::new(display, uniforms.lights).unwrap();
@@ -60,47 +282,64 @@ impl<'a> Group<'a> {
             modelview: uniforms.modelview,
             modelviewperspective: uniforms.modelviewperspective,
             normalmatrix: uniforms.normalmatrix,
-
-            ka: self.mat.unwrap().ka,
-            kd: self.mat.unwrap().kd,
-            ks: self.mat.unwrap().ks,
-            f0: uniforms.ior,
+
+            ka: self.mat.as_ref().unwrap().ka,
+            kd: self.mat.as_ref().unwrap().kd,
+            ks: self.mat.as_ref().unwrap().ks,
+            f0: uniforms.ior.unwrap_or(self.mat.as_ref().unwrap().f0()),
+            alpha_cutoff: self.alpha_cutoff(),
+            base_pass: if uniforms.light_pass == 0 { 1.0f32 } else { 0.0 },
+            sun_direction: uniforms.sun_direction,
+            sun_color: uniforms.sun_color,
             Block: &light_buffer,
         };
 
-        // TODO The way that uniforms are handeled leads to this verbouse draw process_input
-        // As far as I know right know, returning the uniform from a function is not possible,
-        // since the type changes depending on the values. Maybe this can be done with generics..
-        if let Some(ref tex) = self.tex {
-            match tex {
-                &TexturePBR::AlbedoSpecularNormalGloss(a, s, n, g) => {
-                    let uniform = base_uniform.add("dagger_albedo", a);
-                    let uniform = uniform.add("dagger_specular", s);
-                    let uniform = uniform.add("dagger_normal", n);
-                    let uniform = uniform.add("dagger_gloss", g);
+        let blended = self.blend_params(params, uniforms.light_pass);
+        let params = blended.as_ref().unwrap_or(params);
+        draw_lit!(self, target, base_uniform, uniforms, vertices, params, prim_type);
+    }
 
-                    target.draw(vertex_slice,
-                                glium::index::NoIndices(prim_type.clone()),
-                                self.program,
-                                &uniform,
-                                params)
-                          .unwrap();
-                }
-            }
-        } else {
-            target.draw(vertex_slice,
-                        glium::index::NoIndices(prim_type.clone()),
-                        self.program,
-                        &base_uniform,
-                        params)
-                  .unwrap();
-        }
+    // Same as draw, additionally binds the joint matrices used by the SKINNING permutation
+    #[inline]
+    pub fn draw_skinned<S>(&self,
+                           target: &mut S,
+                           uniforms: BaseUniform,
+                           display: &glium::Display,
+                           joints: &glium::uniforms::UniformBuffer<JointMatrices>,
+                           vertex_slice: glium::vertex::VertexBufferSlice<SkinnedVertex>,
+                           params: &glium::DrawParameters,
+                           prim_type: &glium::index::PrimitiveType)
+        where S: glium::Surface
+    {
+
+        let light_buffer = glium::uniforms::UniformBuffer::new(display, uniforms.lights).unwrap();
+        let base_uniform = uniform!{
+            model: uniforms.model,
+            modelview: uniforms.modelview,
+            modelviewperspective: uniforms.modelviewperspective,
+            normalmatrix: uniforms.normalmatrix,
+
+            ka: self.mat.as_ref().unwrap().ka,
+            kd: self.mat.as_ref().unwrap().kd,
+            ks: self.mat.as_ref().unwrap().ks,
+            f0: uniforms.ior.unwrap_or(self.mat.as_ref().unwrap().f0()),
+            alpha_cutoff: self.alpha_cutoff(),
+            base_pass: if uniforms.light_pass == 0 { 1.0f32 } else { 0.0 },
+            sun_direction: uniforms.sun_direction,
+            sun_color: uniforms.sun_color,
+            Block: &light_buffer,
+            Joints: joints,
+        };
+
+        let blended = self.blend_params(params, uniforms.light_pass);
+        let params = blended.as_ref().unwrap_or(params);
+        draw_lit!(self, target, base_uniform, uniforms, vertex_slice, params, prim_type);
     }
 }
 
 impl<'a> PartialEq for Group<'a> {
     fn eq(&self, other: &Self) -> bool {
-        if self.program as *const _ == other.program as *const _ {
+        if self.program_ptr() == other.program_ptr() {
             true
         } else {
             false
@@ -116,9 +355,9 @@ impl<'a> PartialOrd for Group<'a> {
 
 impl<'a> Ord for Group<'a> {
     fn cmp(&self, other: &Self) -> Ordering {
-        if self.program as *const _ < other.program as *const _ {
+        if self.program_ptr() < other.program_ptr() {
             Ordering::Less
-        } else if self.program as *const _ == other.program as *const _ {
+        } else if self.program_ptr() == other.program_ptr() {
             self.tex.cmp(&other.tex)
         } else {
             Ordering::Greater
@@ -130,20 +369,17 @@ impl<'a> Eq for Group<'a> {}
 
 impl<'b, 'a: 'b> OctreeItem for &'b Group<'a> {
     fn index(&self) -> cgmath::Point3<f32> {
-        self.volume.min + ((self.volume.max - self.volume.min) / 2.0)
+        self.bounds.aabb.min + ((self.bounds.aabb.max - self.bounds.aabb.min) / 2.0)
     }
 
     fn is_equal(&self, other: &Self) -> bool {
-        if self.volume == other.volume {
+        if self.bounds.aabb == other.bounds.aabb {
             true
         } else {
             false
         }
     }
     fn in_frustum(&self, frustum: &Frustum<f32>) -> bool {
-        match frustum.contains(self.volume) {
-            Relation::Out => false,
-            _ => true,
-        }
+        self.bounds.in_frustum(frustum)
     }
 }
@@ -0,0 +1,392 @@
+// Standard Library
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use assets::{load_mesh, build_bounds, MeshData};
+use accelerator::bounds::Bounds;
+
+
+// Summary of a mesh file, built without a display so it can be used from tools
+pub struct MeshReport {
+    pub path: PathBuf,
+    pub vertices: usize,
+    pub triangles: usize,
+    pub vertex_colors: bool,
+    pub groups: Vec<GroupReport>,
+    pub bounds: Bounds,
+    pub warnings: Vec<String>,
+}
+
+pub struct GroupReport {
+    pub object: String,
+    pub name: String,
+    pub first: usize,
+    pub count: usize,
+    pub material: Option<String>,
+    // (slot, file, file exists)
+    pub textures: Vec<(&'static str, String, bool)>,
+}
+
+pub fn inspect(path: &Path) -> io::Result<MeshReport> {
+    let mesh = try!(load_mesh(path));
+    Ok(report(path, &mesh))
+}
+
+pub fn report(path: &Path, mesh: &MeshData) -> MeshReport {
+    let dir = path.parent().map(|p| p.to_path_buf()).unwrap_or(PathBuf::new());
+    let mut warnings = validate(mesh);
+    let mut groups = Vec::with_capacity(mesh.groups.len());
+
+    for g in mesh.groups.iter() {
+        let mut textures = Vec::new();
+        if let Some(ref m) = g.material {
+            let slots = [("map_Ka", &m.map_ka),
+                         ("map_Kd", &m.map_kd),
+                         ("map_Ks", &m.map_ks),
+                         ("map_Ke", &m.map_ke),
+                         ("map_Ns", &m.map_ns),
+                         ("map_d", &m.map_d),
+                         ("map_Bump", &m.map_bump),
+                         ("refl", &m.map_refl)];
+            for &(slot, file) in slots.iter() {
+                if let Some(ref file) = *file {
+                    let exists = dir.join(file).exists();
+                    if !exists {
+                        warnings.push(format!("group '{}': {} texture '{}' not found",
+                                              g.name,
+                                              slot,
+                                              file));
+                    }
+                    textures.push((slot, file.clone(), exists));
+                }
+            }
+        } else {
+            warnings.push(format!("group '{}' has no material", g.name));
+        }
+        groups.push(GroupReport {
+            object: g.object.clone(),
+            name: g.name.clone(),
+            first: g.range.start,
+            count: g.range.end - g.range.start,
+            material: g.material.as_ref().map(|m| m.name.clone()),
+            textures: textures,
+        });
+    }
+
+    MeshReport {
+        path: path.to_path_buf(),
+        vertices: mesh.vertices.len(),
+        triangles: mesh.vertices.len() / 3,
+        vertex_colors: mesh.has_colors(),
+        groups: groups,
+        bounds: build_bounds(&mesh.vertices),
+        warnings: warnings,
+    }
+}
+
+// Problems that do not stop loading but usually show up as rendering artifacts
+pub fn validate(mesh: &MeshData) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if mesh.vertices.is_empty() {
+        warnings.push("mesh has no triangles".to_string());
+    }
+
+    let mut non_finite = 0;
+    let mut missing_normals = 0;
+    let mut degenerate = 0;
+    for v in mesh.vertices.iter() {
+        if v.position.iter().chain(v.normal.iter()).chain(v.texture.iter()).any(|x| !x.is_finite()) {
+            non_finite += 1;
+        }
+        if v.normal == [0.0, 0.0, 0.0] {
+            missing_normals += 1;
+        }
+    }
+    for tri in mesh.vertices.chunks(3) {
+        if tri.len() < 3 {
+            continue;
+        }
+        let (a, b, c) = (tri[0].position, tri[1].position, tri[2].position);
+        let e1 = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+        let e2 = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+        let n = [e1[1] * e2[2] - e1[2] * e2[1],
+                 e1[2] * e2[0] - e1[0] * e2[2],
+                 e1[0] * e2[1] - e1[1] * e2[0]];
+        if n[0] * n[0] + n[1] * n[1] + n[2] * n[2] == 0.0 {
+            degenerate += 1;
+        }
+    }
+
+    if non_finite > 0 {
+        warnings.push(format!("{} vertices contain NaN or infinite values", non_finite));
+    }
+    if missing_normals > 0 {
+        warnings.push(format!("{} vertices have no normal", missing_normals));
+    }
+    if degenerate > 0 {
+        warnings.push(format!("{} degenerate triangles", degenerate));
+    }
+    for g in mesh.groups.iter() {
+        if g.range.start == g.range.end {
+            warnings.push(format!("group '{}' is empty", g.name));
+        }
+    }
+    warnings
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// JSON has no NaN or infinity, broken meshes report them as null
+fn json_f32(v: f32) -> String {
+    if v.is_finite() {
+        format!("{}", v)
+    } else {
+        "null".to_string()
+    }
+}
+
+fn json_vec3(v: [f32; 3]) -> String {
+    format!("[{}, {}, {}]", json_f32(v[0]), json_f32(v[1]), json_f32(v[2]))
+}
+
+impl MeshReport {
+    pub fn to_text(&self) -> String {
+        let b = &self.bounds;
+        let mut s = String::new();
+        let _ = writeln!(s, "{}", self.path.display());
+        let _ = writeln!(s, "  vertices:  {}", self.vertices);
+        let _ = writeln!(s, "  triangles: {}", self.triangles);
+        let _ = writeln!(s, "  colors:    {}", if self.vertex_colors { "yes" } else { "no" });
+        let _ = writeln!(s,
+                         "  aabb:      [{}, {}, {}] - [{}, {}, {}]",
+                         b.aabb.min.x,
+                         b.aabb.min.y,
+                         b.aabb.min.z,
+                         b.aabb.max.x,
+                         b.aabb.max.y,
+                         b.aabb.max.z);
+        let _ = writeln!(s,
+                         "  sphere:    center [{}, {}, {}] radius {}",
+                         b.sphere.center.x,
+                         b.sphere.center.y,
+                         b.sphere.center.z,
+                         b.sphere.radius);
+        let _ = writeln!(s,
+                         "  obb:       center [{}, {}, {}] half extents [{}, {}, {}]",
+                         b.obb.center.x,
+                         b.obb.center.y,
+                         b.obb.center.z,
+                         b.obb.half_extents.x,
+                         b.obb.half_extents.y,
+                         b.obb.half_extents.z);
+        let _ = writeln!(s, "  groups:    {}", self.groups.len());
+        for g in self.groups.iter() {
+            let _ = writeln!(s,
+                             "    {}/{}: vertices {}..{} ({} triangles), material {}",
+                             g.object,
+                             g.name,
+                             g.first,
+                             g.first + g.count,
+                             g.count / 3,
+                             g.material.as_ref().map(|m| &m[..]).unwrap_or("<none>"));
+            for &(slot, ref file, exists) in g.textures.iter() {
+                let _ = writeln!(s,
+                                 "      {:<9} {}{}",
+                                 slot,
+                                 file,
+                                 if exists { "" } else { " (missing)" });
+            }
+        }
+        if !self.warnings.is_empty() {
+            let _ = writeln!(s, "  warnings:");
+            for w in self.warnings.iter() {
+                let _ = writeln!(s, "    {}", w);
+            }
+        }
+        s
+    }
+
+    pub fn to_json(&self) -> String {
+        let b = &self.bounds;
+        let mut s = String::new();
+        s.push_str("{");
+        let _ = write!(s, "\"path\": {}, ", json_string(&self.path.to_string_lossy()));
+        let _ = write!(s, "\"vertices\": {}, ", self.vertices);
+        let _ = write!(s, "\"triangles\": {}, ", self.triangles);
+        let _ = write!(s, "\"vertex_colors\": {}, ", self.vertex_colors);
+        let _ = write!(s,
+                       "\"bounds\": {{\"aabb\": {{\"min\": {}, \"max\": {}}}, ",
+                       json_vec3([b.aabb.min.x, b.aabb.min.y, b.aabb.min.z]),
+                       json_vec3([b.aabb.max.x, b.aabb.max.y, b.aabb.max.z]));
+        let _ = write!(s,
+                       "\"sphere\": {{\"center\": {}, \"radius\": {}}}, ",
+                       json_vec3([b.sphere.center.x, b.sphere.center.y, b.sphere.center.z]),
+                       json_f32(b.sphere.radius));
+        let _ = write!(s,
+                       "\"obb\": {{\"center\": {}, \"axes\": [{}, {}, {}], \"half_extents\": {}}}}}, ",
+                       json_vec3([b.obb.center.x, b.obb.center.y, b.obb.center.z]),
+                       json_vec3([b.obb.axes[0].x, b.obb.axes[0].y, b.obb.axes[0].z]),
+                       json_vec3([b.obb.axes[1].x, b.obb.axes[1].y, b.obb.axes[1].z]),
+                       json_vec3([b.obb.axes[2].x, b.obb.axes[2].y, b.obb.axes[2].z]),
+                       json_vec3([b.obb.half_extents.x, b.obb.half_extents.y, b.obb.half_extents.z]));
+
+        s.push_str("\"groups\": [");
+        for (i, g) in self.groups.iter().enumerate() {
+            if i > 0 {
+                s.push_str(", ");
+            }
+            let _ = write!(s,
+                           "{{\"object\": {}, \"name\": {}, \"first\": {}, \"count\": {}, \"material\": {}, \"textures\": [",
+                           json_string(&g.object),
+                           json_string(&g.name),
+                           g.first,
+                           g.count,
+                           g.material.as_ref().map(|m| json_string(m)).unwrap_or("null".to_string()));
+            for (j, &(slot, ref file, exists)) in g.textures.iter().enumerate() {
+                if j > 0 {
+                    s.push_str(", ");
+                }
+                let _ = write!(s,
+                               "{{\"slot\": {}, \"file\": {}, \"exists\": {}}}",
+                               json_string(slot),
+                               json_string(file),
+                               exists);
+            }
+            s.push_str("]}");
+        }
+        s.push_str("], \"warnings\": [");
+        for (i, w) in self.warnings.iter().enumerate() {
+            if i > 0 {
+                s.push_str(", ");
+            }
+            s.push_str(&json_string(w));
+        }
+        s.push_str("]}");
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32;
+    use std::path::PathBuf;
+
+    use rustc_serialize::json::Json;
+
+    use accelerator::bounds::Bounds;
+    use assets::{GroupData, MeshData};
+    use util::graphics::Vertex;
+    use super::{validate, GroupReport, MeshReport};
+
+    fn mesh(positions: &[[f32; 3]], groups: Vec<(&str, usize, usize)>) -> MeshData {
+        MeshData {
+            vertices: positions.iter().map(|&p| Vertex::new(p, [0.0, 0.0, 1.0], [0.0, 0.0])).collect(),
+            colors: Vec::new(),
+            texcoords1: Vec::new(),
+            groups: groups.into_iter()
+                          .map(|(name, start, end)| {
+                              GroupData {
+                                  object: "object".to_string(),
+                                  name: name.to_string(),
+                                  range: start..end,
+                                  material: None,
+                              }
+                          })
+                          .collect(),
+        }
+    }
+
+    const TRIANGLE: [[f32; 3]; 3] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+
+    #[test]
+    fn clean_mesh_has_no_warnings() {
+        assert!(validate(&mesh(&TRIANGLE, vec![("all", 0, 3)])).is_empty());
+    }
+
+    #[test]
+    fn empty_mesh() {
+        assert_eq!(validate(&mesh(&[], vec![])), vec!["mesh has no triangles".to_string()]);
+    }
+
+    #[test]
+    fn broken_mesh_warnings() {
+        let positions = [TRIANGLE[0],
+                         TRIANGLE[1],
+                         [f32::NAN, 1.0, 0.0],
+                         [1.0, 1.0, 1.0],
+                         [1.0, 1.0, 1.0],
+                         [2.0, 0.0, 0.0]];
+        let mut broken = mesh(&positions, vec![("first", 0, 3), ("second", 3, 6), ("empty", 6, 6)]);
+        broken.vertices[1].normal = [0.0, 0.0, 0.0];
+        assert_eq!(validate(&broken),
+                   vec!["1 vertices contain NaN or infinite values".to_string(),
+                        "1 vertices have no normal".to_string(),
+                        "1 degenerate triangles".to_string(),
+                        "group 'empty' is empty".to_string()]);
+    }
+
+    fn report(bounds: Bounds) -> MeshReport {
+        MeshReport {
+            path: PathBuf::from("dir/\"quoted\".obj"),
+            vertices: 3,
+            triangles: 1,
+            vertex_colors: false,
+            groups: vec![GroupReport {
+                             object: "object".to_string(),
+                             name: "group".to_string(),
+                             first: 0,
+                             count: 3,
+                             material: None,
+                             textures: vec![("map_Kd", "albedo.png".to_string(), false)],
+                         }],
+            bounds: bounds,
+            warnings: vec!["line\nbreak".to_string()],
+        }
+    }
+
+    #[test]
+    fn json_output_parses() {
+        let json = Json::from_str(&report(Bounds::empty()).to_json()).unwrap();
+        assert_eq!(json.find("path").and_then(|p| p.as_string()), Some("dir/\"quoted\".obj"));
+        assert_eq!(json.find("vertices").and_then(|v| v.as_u64()), Some(3));
+        assert_eq!(json.find("warnings").and_then(|w| w.as_array()).map(|w| w.len()), Some(1));
+
+        let groups = json.find("groups").and_then(|g| g.as_array()).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert!(groups[0].find("material").unwrap().is_null());
+        let textures = groups[0].find("textures").and_then(|t| t.as_array()).unwrap();
+        assert_eq!(textures[0].find("exists").and_then(|e| e.as_boolean()), Some(false));
+    }
+
+    #[test]
+    fn non_finite_bounds_are_null() {
+        let mut bounds = Bounds::empty();
+        bounds.aabb.max.x = f32::INFINITY;
+        bounds.sphere.radius = f32::NAN;
+        let json = Json::from_str(&report(bounds).to_json()).unwrap();
+        let bounds = json.find("bounds").unwrap();
+        let max = bounds.find("aabb").and_then(|a| a.find("max")).and_then(|m| m.as_array()).unwrap();
+        assert!(max[0].is_null());
+        assert_eq!(max[1].as_f64(), Some(0.0));
+        assert!(bounds.find("sphere").and_then(|s| s.find("radius")).unwrap().is_null());
+    }
+}
@@ -1,21 +1,25 @@
 extern crate glutin;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell, RefMut};
 use std::f64::consts::PI;
 use std::f32;
 use std::fmt;
+use std::cmp::Ordering;
 use rand;
 
 use glium;
 use collision::{Frustum, Relation, Aabb3};
 use cgmath::*;
 
-use util::math::{rotate_matrix, rotation_matrix};
-use util::graphics::BaseUniform;
+use util::math::{self, rotate_matrix, rotation_matrix};
+use util::graphics::{BaseUniform, Vertex};
+use shadow::ShadowPass;
 use accelerator::OctreeItem;
+use accelerator::bounds::Bounds;
 use assets::asset::Asset;
 use assets::group::Group;
-use assets::Drawable;
+use assets::{Drawable, Pass};
+use assets::morph;
 
 pub struct InstanceLoader<'b, 'a: 'b> {
     asset: &'b Asset<'a>,
@@ -106,6 +110,9 @@ impl<'b, 'a: 'b> InstanceLoader<'b, 'a> {
             asset: self.asset,
             volume: self.volume.unwrap(),
             to_world: self.to_world.unwrap(),
+            morph_weights: vec![0.0; self.asset.morph_targets().len()],
+            morphed: RefCell::new(None),
+            morph_dirty: Cell::new(false),
         }
     }
 }
@@ -115,6 +122,10 @@ pub struct AssetInstance<'b, 'a: 'b> {
     asset: &'b Asset<'a>,
     volume: Aabb3<f32>,
     to_world: Matrix4<f32>,
+    morph_weights: Vec<f32>,
+    // Blended vertices of this instance, rebuilt on the next draw after a weight changed
+    morphed: RefCell<Option<glium::VertexBuffer<Vertex>>>,
+    morph_dirty: Cell<bool>,
 }
 
 impl<'b, 'a: 'b> OctreeItem for &'b AssetInstance<'b, 'a> {
@@ -130,10 +141,7 @@ impl<'b, 'a: 'b> OctreeItem for &'b AssetInstance<'b, 'a> {
         }
     }
     fn in_frustum(&self, frustum: &Frustum<f32>) -> bool {
-        match frustum.contains(self.volume) {
-            Relation::Out => false,
-            _ => true,
-        }
+        self.world_bounds().in_frustum(frustum)
     }
 }
 
@@ -154,6 +162,45 @@ impl<'a, 'b> AssetInstance<'a, 'b> {
     pub fn get_volume(&self) -> &Aabb3<f32> {
         &self.volume
     }
+
+    // Bounds of the asset moved along with the current to_world matrix
+    pub fn world_bounds(&self) -> Bounds {
+        self.asset.get_bounds().transform(&self.to_world)
+    }
+
+    pub fn morph_weights(&self) -> &[f32] {
+        &self.morph_weights[..]
+    }
+    // Returns false if the asset has no morph target with this index
+    pub fn set_morph_weight(&mut self, index: usize, weight: f32) -> bool {
+        match self.morph_weights.get_mut(index) {
+            Some(w) => {
+                if *w != weight {
+                    *w = weight;
+                    self.morph_dirty.set(true);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+    // Returns false if the asset has no morph target with this name
+    pub fn set_morph_weight_by_name(&mut self, name: &str, weight: f32) -> bool {
+        match self.asset.find_morph_target(name) {
+            Some(i) => self.set_morph_weight(i, weight),
+            None => false,
+        }
+    }
+    pub fn is_morphed(&self) -> bool {
+        self.morph_weights.iter().any(|w| *w != 0.0)
+    }
+    // Vertices with the current weights applied, computed on the CPU
+    pub fn blended_vertices(&self) -> Vec<Vertex> {
+        morph::blend(self.asset.base_vertices(),
+                     self.asset.morph_targets(),
+                     &self.morph_weights)
+    }
+
     pub fn translate(&mut self, x: f32, y: f32, z: f32) {
         self.to_world.w.x += x;
         self.to_world.w.y += y;
@@ -410,7 +457,8 @@ impl<'a, 'b> Drawable for AssetInstance<'a, 'b> {
     fn draw<S>(&self, target: &mut S, display: &glium::Display, uniforms: BaseUniform)
         where S: glium::Surface
     {
-        self.asset.draw(target, display, uniforms);
+        self.draw_pass(target, display, uniforms, Pass::Opaque);
+        self.draw_pass(target, display, uniforms, Pass::Blend);
     }
 
 
@@ -424,6 +472,104 @@ impl<'a, 'b> Drawable for AssetInstance<'a, 'b> {
         self.asset.draw_group(target, display, uniforms, group);
     }
 }
+
+impl<'a, 'b> AssetInstance<'a, 'b> {
+    // Blends the morph targets into the dynamic vertex buffer if the weights
+    // changed since the last draw
+    fn update_morphed(&self, display: &glium::Display) -> RefMut<Option<glium::VertexBuffer<Vertex>>> {
+        let mut morphed = self.morphed.borrow_mut();
+        if morphed.is_none() || self.morph_dirty.get() {
+            let vertices = self.blended_vertices();
+            let reuse = if let Some(ref vbo) = *morphed {
+                vbo.write(&vertices);
+                true
+            } else {
+                false
+            };
+            if !reuse {
+                *morphed = Some(glium::VertexBuffer::dynamic(display, &vertices).unwrap());
+            }
+            self.morph_dirty.set(false);
+        }
+        morphed
+    }
+
+    pub fn draw_pass<S>(&self, target: &mut S, display: &glium::Display, uniforms: BaseUniform, pass: Pass)
+        where S: glium::Surface
+    {
+        if !self.is_morphed() {
+            return self.asset.draw_pass(target, display, uniforms, pass);
+        }
+
+        let morphed = self.update_morphed(display);
+        self.asset.draw_pass_from(target, display, uniforms, morphed.as_ref().unwrap(), pass);
+    }
+
+    // Draws the opaque groups into one layer of the shadow maps, see
+    // ShadowMaps::render
+    pub fn draw_depth<S>(&self, target: &mut S, display: &glium::Display, program: &glium::Program, pass: &ShadowPass)
+        where S: glium::Surface
+    {
+        let uniforms = uniform!{
+            model: math::to_mat4(&self.to_world),
+            light_matrix: pass.matrix,
+            light_position: pass.light_position,
+            far: pass.far,
+        };
+        // Both faces cast shadows, the bias covers the acne
+        let params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::draw_parameters::DepthTest::IfLess,
+                write: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        if !self.is_morphed() {
+            return self.asset.draw_depth_from(target, self.asset.get_vbo(), program, &uniforms, &params);
+        }
+
+        let morphed = self.update_morphed(display);
+        self.asset.draw_depth_from(target, morphed.as_ref().unwrap(), program, &uniforms, &params);
+    }
+
+    pub fn has_blended(&self) -> bool {
+        self.asset.has_blended()
+    }
+}
+
+// Draws the opaque groups of all instances first, then the blended groups
+// back to front by the distance of the instance bounds to the eye.
+// `uniforms` builds the uniforms of one instance, one per light block as
+// returned by Lights::blocks.
+pub fn draw_sorted<'e, 'c, 'b: 'c, 'a: 'b, S, F>(target: &mut S,
+                                                display: &glium::Display,
+                                                instances: &[&'c AssetInstance<'b, 'a>],
+                                                eye: Point3<f32>,
+                                                uniforms: F)
+    where S: glium::Surface,
+          F: Fn(&AssetInstance<'b, 'a>) -> Vec<BaseUniform<'e>>
+{
+    for i in instances.iter() {
+        for u in uniforms(i) {
+            i.draw_pass(target, display, u, Pass::Opaque);
+        }
+    }
+
+    let mut blended: Vec<(f32, &'c AssetInstance<'b, 'a>)> = instances.iter()
+        .filter(|i| i.has_blended())
+        .map(|i| {
+            let d = i.world_bounds().sphere.center - eye;
+            (d.x * d.x + d.y * d.y + d.z * d.z, *i)
+        })
+        .collect();
+    blended.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+    for &(_, i) in blended.iter() {
+        for u in uniforms(i) {
+            i.draw_pass(target, display, u, Pass::Blend);
+        }
+    }
+}
 // ***************************************************************************
 // ***************************************************************************
 // ***************************************************************************
@@ -511,6 +657,12 @@ impl<'a, 'b> AssetInstance2<'a, 'b> {
         *self.volume.borrow()
     }
 
+    pub fn draw_pass<S>(&self, target: &mut S, display: &glium::Display, uniforms: BaseUniform, pass: Pass)
+        where S: glium::Surface
+    {
+        self.asset.draw_pass(target, display, uniforms, pass);
+    }
+
     pub fn translate(&self, x: f32, y: f32, z: f32) {
         self.to_world.borrow_mut().x.w += x;
         self.to_world.borrow_mut().y.w += y;
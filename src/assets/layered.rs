@@ -0,0 +1,498 @@
+// Standard Library
+use std::collections::HashMap;
+
+// External Library
+use image::{Rgba, RgbaImage};
+
+use util::graphics::{Material, MaterialTextures};
+
+// Material layering, e.g. rust over painted metal or dirt on stone.
+// Layers blend the material parameters, not shaded colors, so the result
+// is again a single metallic/roughness material. It can be evaluated on
+// the CPU at any uv or baked into textures for TexturePBR::MetallicRoughness.
+
+// CPU copies of the textures referenced by the materials, keyed by file name
+pub type ImageMap = HashMap<String, RgbaImage>;
+
+// Blended parameters at one point
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Parameters {
+    pub base_color: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+    pub occlusion: f32,
+    pub emissive: [f32; 3],
+    pub alpha: f32,
+    pub ior: f32,
+    // Tangent space normal
+    pub normal: [f32; 3],
+    pub height: f32,
+}
+
+impl Parameters {
+    pub fn lerp(&self, other: &Parameters, t: f32) -> Parameters {
+        let mix = |a: f32, b: f32| a + (b - a) * t;
+        let mix3 = |a: [f32; 3], b: [f32; 3]| [mix(a[0], b[0]), mix(a[1], b[1]), mix(a[2], b[2])];
+        let n = mix3(self.normal, other.normal);
+        let len = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+        Parameters {
+            base_color: mix3(self.base_color, other.base_color),
+            metallic: mix(self.metallic, other.metallic),
+            // Interpolating alpha = roughness^2 keeps the highlight width
+            // of a half covered surface between the two layers
+            roughness: mix(self.roughness * self.roughness, other.roughness * other.roughness).sqrt(),
+            occlusion: mix(self.occlusion, other.occlusion),
+            emissive: mix3(self.emissive, other.emissive),
+            alpha: mix(self.alpha, other.alpha),
+            ior: mix(self.ior, other.ior),
+            normal: if len > 0.0 {
+                [n[0] / len, n[1] / len, n[2] / len]
+            } else {
+                [0.0, 0.0, 1.0]
+            },
+            height: mix(self.height, other.height),
+        }
+    }
+}
+
+// Bilinear lookup with wrapping, the image origin is the top left corner
+// while uv (0, 0) is the bottom left one as in the uploaded textures.
+// Empty images read as zero.
+pub fn sample(image: &RgbaImage, uv: [f32; 2]) -> [f32; 4] {
+    let (w, h) = image.dimensions();
+    if w == 0 || h == 0 {
+        return [0.0; 4];
+    }
+    let x = uv[0] * w as f32 - 0.5;
+    let y = (1.0 - uv[1]) * h as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let wrap = |v: f32, size: u32| (((v as i64) % size as i64 + size as i64) % size as i64) as u32;
+    let texel = |x: f32, y: f32| {
+        let Rgba { data } = *image.get_pixel(wrap(x, w), wrap(y, h));
+        [data[0] as f32 / 255.0, data[1] as f32 / 255.0, data[2] as f32 / 255.0, data[3] as f32 / 255.0]
+    };
+    let (a, b, c, d) = (texel(x0, y0), texel(x0 + 1.0, y0), texel(x0, y0 + 1.0), texel(x0 + 1.0, y0 + 1.0));
+    let mut out = [0.0; 4];
+    for i in 0..4 {
+        let top = a[i] + (b[i] - a[i]) * fx;
+        let bottom = c[i] + (d[i] - c[i]) * fx;
+        out[i] = top + (bottom - top) * fy;
+    }
+    out
+}
+
+// Empty images are treated like missing ones
+fn lookup<'a>(images: &'a ImageMap, file: &Option<String>) -> Option<&'a RgbaImage> {
+    file.as_ref()
+        .and_then(|f| images.get(f))
+        .and_then(|img| if img.width() > 0 && img.height() > 0 { Some(img) } else { None })
+}
+
+// Parameters of a single material, maps are scaled by their factors the same
+// way CT_FRAG_PERMUTED does it. `height` is an optional height map of the material.
+pub fn evaluate_material(material: &Material,
+                         height: &Option<String>,
+                         images: &ImageMap,
+                         uv: [f32; 2])
+                         -> Parameters {
+    let t = &material.textures;
+    let scalar = |file: &Option<String>, factor: f32| {
+        match lookup(images, file) {
+            Some(img) => sample(img, uv)[0] * factor,
+            None => factor,
+        }
+    };
+    let color = |file: &Option<String>, factor: [f32; 3]| {
+        match lookup(images, file) {
+            Some(img) => {
+                let s = sample(img, uv);
+                [s[0] * factor[0], s[1] * factor[1], s[2] * factor[2]]
+            }
+            None => factor,
+        }
+    };
+    let (base_color, alpha) = match lookup(images, &t.base_color) {
+        Some(img) => {
+            let s = sample(img, uv);
+            let c = material.base_color;
+            ([s[0] * c[0], s[1] * c[1], s[2] * c[2]], s[3] * material.alpha)
+        }
+        None => (material.base_color, material.alpha),
+    };
+    let normal = match lookup(images, &t.normal) {
+        Some(img) => {
+            let s = sample(img, uv);
+            let n = [s[0] * 2.0 - 1.0, s[1] * 2.0 - 1.0, s[2] * 2.0 - 1.0];
+            let len = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+            if len > 0.0 {
+                [n[0] / len, n[1] / len, n[2] / len]
+            } else {
+                [0.0, 0.0, 1.0]
+            }
+        }
+        None => [0.0, 0.0, 1.0],
+    };
+    Parameters {
+        base_color: base_color,
+        metallic: scalar(&t.metallic, material.metallic).max(0.0).min(1.0),
+        roughness: scalar(&t.roughness, material.roughness).max(0.0).min(1.0),
+        occlusion: scalar(&t.occlusion, 1.0),
+        emissive: color(&t.emissive, material.emissive),
+        alpha: alpha,
+        ior: material.ior,
+        normal: normal,
+        // Materials without a height map sit in the middle
+        height: lookup(images, height).map(|img| sample(img, uv)[0]).unwrap_or(0.5),
+    }
+}
+
+// Where the coverage of a layer comes from
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlendMask {
+    Constant(f32),
+    // Red channel of a mask texture
+    Texture(String),
+    // One channel of the vertex color, 0 to 3
+    VertexColor(usize),
+}
+
+pub struct Layer {
+    pub material: Material,
+    pub mask: BlendMask,
+    pub height_map: Option<String>,
+    // With height blending the layer first fills the cavities of the
+    // layers below. Smaller values give sharper transitions, None blends
+    // by the mask alone.
+    pub height_contrast: Option<f32>,
+}
+
+impl Layer {
+    pub fn new(material: Material, mask: BlendMask) -> Layer {
+        Layer {
+            material: material,
+            mask: mask,
+            height_map: None,
+            height_contrast: None,
+        }
+    }
+
+    pub fn height(mut self, height_map: String, contrast: f32) -> Layer {
+        self.height_map = Some(height_map);
+        self.height_contrast = Some(contrast.max(1e-4));
+        self
+    }
+}
+
+// Coverage of the upper layer for a mask value, following the height blend of
+// "Advanced Terrain Texture Splatting" (Mikhail Mikhailov)
+pub fn height_weight(mask: f32, below: f32, above: f32, contrast: f32) -> f32 {
+    let a = below + (1.0 - mask);
+    let b = above + mask;
+    let top = if a > b { a } else { b } - contrast.max(1e-4);
+    let wa = (a - top).max(0.0);
+    let wb = (b - top).max(0.0);
+    if wa + wb > 0.0 {
+        wb / (wa + wb)
+    } else {
+        mask
+    }
+}
+
+pub struct LayeredMaterial {
+    pub name: String,
+    pub base: Material,
+    pub base_height: Option<String>,
+    pub layers: Vec<Layer>,
+}
+
+impl LayeredMaterial {
+    pub fn new(name: String, base: Material) -> LayeredMaterial {
+        LayeredMaterial {
+            name: name,
+            base: base,
+            base_height: None,
+            layers: Vec::new(),
+        }
+    }
+
+    pub fn base_height(mut self, height_map: String) -> LayeredMaterial {
+        self.base_height = Some(height_map);
+        self
+    }
+
+    pub fn layer(mut self, layer: Layer) -> LayeredMaterial {
+        self.layers.push(layer);
+        self
+    }
+
+    // File names of every map used by the materials and masks
+    pub fn texture_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        {
+            let mut add = |m: &Material, height: &Option<String>| {
+                let t = &m.textures;
+                for f in [&t.base_color, &t.metallic, &t.roughness, &t.normal, &t.occlusion, &t.emissive, height]
+                             .iter() {
+                    if let Some(ref f) = **f {
+                        if !names.contains(f) {
+                            names.push(f.clone());
+                        }
+                    }
+                }
+            };
+            add(&self.base, &self.base_height);
+            for l in self.layers.iter() {
+                add(&l.material, &l.height_map);
+            }
+        }
+        for l in self.layers.iter() {
+            if let BlendMask::Texture(ref f) = l.mask {
+                if !names.contains(f) {
+                    names.push(f.clone());
+                }
+            }
+        }
+        names
+    }
+
+    // Blended parameters at uv, layers are applied bottom to top
+    pub fn evaluate(&self, images: &ImageMap, uv: [f32; 2], vertex_color: [f32; 4]) -> Parameters {
+        let mut result = evaluate_material(&self.base, &self.base_height, images, uv);
+        for l in self.layers.iter() {
+            let above = evaluate_material(&l.material, &l.height_map, images, uv);
+            let mask = match l.mask {
+                BlendMask::Constant(c) => c,
+                BlendMask::Texture(ref f) => {
+                    images.get(f).map(|img| sample(img, uv)[0]).unwrap_or(0.0)
+                }
+                BlendMask::VertexColor(channel) => vertex_color[channel.min(3)],
+            };
+            let mask = mask.max(0.0).min(1.0);
+            let weight = match l.height_contrast {
+                Some(contrast) => height_weight(mask, result.height, above.height, contrast),
+                None => mask,
+            };
+            result = result.lerp(&above, weight);
+        }
+        result
+    }
+
+    // Evaluates every texel so the result can be drawn with the metallic/roughness
+    // program, see BakedLayers::material. Vertex color masks can not be baked
+    // per texel and use `vertex_color`.
+    pub fn bake(&self, images: &ImageMap, width: u32, height: u32, vertex_color: [f32; 4]) -> BakedLayers {
+        let mut baked = BakedLayers {
+            base_color: RgbaImage::new(width, height),
+            metallic: RgbaImage::new(width, height),
+            roughness: RgbaImage::new(width, height),
+            occlusion: RgbaImage::new(width, height),
+            normal: RgbaImage::new(width, height),
+            emissive: RgbaImage::new(width, height),
+            height: RgbaImage::new(width, height),
+            base: self.base.clone(),
+        };
+        let to_u8 = |v: f32| (v.max(0.0).min(1.0) * 255.0 + 0.5) as u8;
+        let gray = |v: f32| Rgba { data: [to_u8(v), to_u8(v), to_u8(v), 255] };
+        for y in 0..height {
+            for x in 0..width {
+                let uv = [(x as f32 + 0.5) / width as f32, 1.0 - (y as f32 + 0.5) / height as f32];
+                let p = self.evaluate(images, uv, vertex_color);
+                let c = p.base_color;
+                let e = p.emissive;
+                let n = p.normal;
+                baked.base_color.put_pixel(x, y, Rgba { data: [to_u8(c[0]), to_u8(c[1]), to_u8(c[2]), to_u8(p.alpha)] });
+                baked.emissive.put_pixel(x, y, Rgba { data: [to_u8(e[0]), to_u8(e[1]), to_u8(e[2]), 255] });
+                baked.normal.put_pixel(x,
+                                       y,
+                                       Rgba { data: [to_u8(n[0] * 0.5 + 0.5), to_u8(n[1] * 0.5 + 0.5), to_u8(n[2] * 0.5 + 0.5), 255] });
+                baked.metallic.put_pixel(x, y, gray(p.metallic));
+                baked.roughness.put_pixel(x, y, gray(p.roughness));
+                baked.occlusion.put_pixel(x, y, gray(p.occlusion));
+                baked.height.put_pixel(x, y, gray(p.height));
+            }
+        }
+        baked
+    }
+}
+
+// Result of LayeredMaterial::bake. Scalar maps are gray, the metallic/roughness
+// program reads them from the red channel. Height is not drawn, it is kept
+// for parallax or further blending.
+pub struct BakedLayers {
+    pub base_color: RgbaImage,
+    pub metallic: RgbaImage,
+    pub roughness: RgbaImage,
+    pub occlusion: RgbaImage,
+    pub normal: RgbaImage,
+    pub emissive: RgbaImage,
+    pub height: RgbaImage,
+    // Alpha mode, ior and lobes of the baked material come from the base
+    base: Material,
+}
+
+impl BakedLayers {
+    // File names of the maps for a prefix, e.g. "rust_metallic.png"
+    pub fn images(&self, prefix: &str) -> Vec<(String, &RgbaImage)> {
+        vec![(format!("{}_base_color.png", prefix), &self.base_color),
+             (format!("{}_metallic.png", prefix), &self.metallic),
+             (format!("{}_roughness.png", prefix), &self.roughness),
+             (format!("{}_occlusion.png", prefix), &self.occlusion),
+             (format!("{}_normal.png", prefix), &self.normal),
+             (format!("{}_emissive.png", prefix), &self.emissive),
+             (format!("{}_height.png", prefix), &self.height)]
+    }
+
+    // Material that draws the maps of `images` with every factor at 1, the
+    // maps have to be uploaded under their file names
+    pub fn material(&self, name: &str, prefix: &str) -> Material {
+        let file = |slot: &str| Some(format!("{}_{}.png", prefix, slot));
+        Material {
+            name: name.to_string(),
+            base_color: [1.0; 3],
+            metallic: 1.0,
+            roughness: 1.0,
+            emissive: [1.0; 3],
+            alpha: 1.0,
+            textures: MaterialTextures {
+                base_color: file("base_color"),
+                metallic: file("metallic"),
+                roughness: file("roughness"),
+                normal: file("normal"),
+                occlusion: file("occlusion"),
+                emissive: file("emissive"),
+                ..Default::default()
+            },
+            ..self.base.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::{BlendMask, ImageMap, Layer, LayeredMaterial, height_weight, sample};
+    use util::graphics::Material;
+
+    fn material(metallic: f32, roughness: f32) -> Material {
+        let mut m = Material::default();
+        m.metallic = metallic;
+        m.roughness = roughness;
+        m
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} instead of {}", a, b);
+    }
+
+    // 1x1 image of a gray value in 1/255 steps
+    fn gray(v: u8) -> RgbaImage {
+        let mut img = RgbaImage::new(1, 1);
+        img.put_pixel(0, 0, Rgba { data: [v, v, v, 255] });
+        img
+    }
+
+    #[test]
+    fn constant_mask_mixes_the_layers() {
+        let layered = LayeredMaterial::new("l".to_string(), material(0.0, 0.2))
+                          .layer(Layer::new(material(1.0, 0.8), BlendMask::Constant(0.25)));
+        let p = layered.evaluate(&ImageMap::new(), [0.5, 0.5], [1.0; 4]);
+        assert_close(p.metallic, 0.25);
+        // Roughness is mixed as alpha = roughness^2
+        assert_close(p.roughness, (0.04f32 + (0.64 - 0.04) * 0.25).sqrt());
+    }
+
+    #[test]
+    fn vertex_color_masks() {
+        let layered = LayeredMaterial::new("l".to_string(), material(0.0, 0.5))
+                          .layer(Layer::new(material(1.0, 0.5), BlendMask::VertexColor(1)));
+        let at = |color: [f32; 4]| layered.evaluate(&ImageMap::new(), [0.5, 0.5], color).metallic;
+        assert_close(at([1.0, 0.75, 0.0, 0.0]), 0.75);
+        assert_close(at([1.0, 0.0, 1.0, 1.0]), 0.0);
+        // Out of range channels read alpha, colors are clamped
+        let alpha = LayeredMaterial::new("l".to_string(), material(0.0, 0.5))
+                        .layer(Layer::new(material(1.0, 0.5), BlendMask::VertexColor(7)));
+        assert_close(alpha.evaluate(&ImageMap::new(), [0.5, 0.5], [0.0, 0.0, 0.0, 0.5]).metallic, 0.5);
+        assert_close(at([0.0, 2.0, 0.0, 0.0]), 1.0);
+    }
+
+    #[test]
+    fn height_blend_transition_widths() {
+        // The layer is lower than the base, both reach the same level at mask 0.7
+        let (below, above) = (0.6, 0.2);
+        let masks = [0.0, 0.25, 0.5, 0.65, 0.75, 0.9, 1.0];
+        // Width 0 switches from the base to the layer at once
+        for &mask in masks.iter() {
+            let expected = if mask < 0.7 { 0.0 } else { 1.0 };
+            assert_close(height_weight(mask, below, above, 0.0), expected);
+        }
+        // Width 1 fades over the whole range
+        let weights: Vec<f32> = masks.iter().map(|&m| height_weight(m, below, above, 1.0)).collect();
+        assert_close(height_weight(0.7, below, above, 1.0), 0.5);
+        for w in weights.windows(2) {
+            assert!(w[0] < w[1], "{:?}", weights);
+        }
+        assert!(weights[1] > 0.0 && weights[5] < 1.0, "{:?}", weights);
+        // Without a height difference the layers meet halfway
+        assert_close(height_weight(0.5, 0.4, 0.4, 0.0), 0.5);
+        assert_close(height_weight(0.5, 0.4, 0.4, 1.0), 0.5);
+    }
+
+    #[test]
+    fn height_maps_drive_the_blend() {
+        let mut images = ImageMap::new();
+        images.insert("high.png".to_string(), gray(153));
+        images.insert("low.png".to_string(), gray(51));
+        let layered = |contrast: f32, mask: BlendMask| {
+            LayeredMaterial::new("l".to_string(), material(0.0, 0.5))
+                .base_height("high.png".to_string())
+                .layer(Layer::new(material(1.0, 0.5), mask).height("low.png".to_string(), contrast))
+        };
+        // Half covered by vertex color, the low layer stays in the cavities of the base
+        let sharp = layered(0.0, BlendMask::VertexColor(0));
+        assert_close(sharp.evaluate(&images, [0.5, 0.5], [0.5; 4]).metallic, 0.0);
+        assert_close(sharp.evaluate(&images, [0.5, 0.5], [0.75; 4]).metallic, 1.0);
+        let soft = layered(1.0, BlendMask::Constant(0.5)).evaluate(&images, [0.5, 0.5], [1.0; 4]);
+        assert_close(soft.metallic, height_weight(0.5, 0.6, 0.2, 1.0));
+        assert_close(soft.height, 0.6 + (0.2 - 0.6) * height_weight(0.5, 0.6, 0.2, 1.0));
+    }
+
+    #[test]
+    fn empty_images_read_as_missing() {
+        assert_eq!(sample(&RgbaImage::new(0, 0), [0.3, 0.7]), [0.0; 4]);
+        let mut images = ImageMap::new();
+        images.insert("empty.png".to_string(), RgbaImage::new(0, 4));
+        let mut base = material(0.25, 0.5);
+        base.textures.roughness = Some("empty.png".to_string());
+        let layered = LayeredMaterial::new("l".to_string(), base)
+                          .layer(Layer::new(material(1.0, 0.5), BlendMask::Texture("empty.png".to_string())));
+        let p = layered.evaluate(&images, [0.5, 0.5], [1.0; 4]);
+        assert_eq!((p.metallic, p.roughness), (0.25, 0.5));
+    }
+
+    #[test]
+    fn baked_maps_hold_one_parameter_each() {
+        let mut images = ImageMap::new();
+        // Left half masked
+        let mut mask = RgbaImage::new(2, 1);
+        mask.put_pixel(0, 0, Rgba { data: [255, 255, 255, 255] });
+        images.insert("mask.png".to_string(), mask);
+        let layered = LayeredMaterial::new("l".to_string(), material(0.0, 0.2))
+                          .layer(Layer::new(material(1.0, 0.8), BlendMask::Texture("mask.png".to_string())));
+        let baked = layered.bake(&images, 2, 1, [1.0; 4]);
+        let red = |img: &RgbaImage, x: u32| img.get_pixel(x, 0).data[0];
+        assert_eq!((red(&baked.metallic, 0), red(&baked.metallic, 1)), (255, 0));
+        assert_eq!((red(&baked.roughness, 0), red(&baked.roughness, 1)), (204, 51));
+        assert_eq!(red(&baked.occlusion, 0), 255);
+
+        let m = baked.material("baked", "l");
+        assert_eq!((m.metallic, m.roughness), (1.0, 1.0));
+        assert_eq!(m.textures.roughness, Some("l_roughness.png".to_string()));
+        let names: Vec<String> = baked.images("l").into_iter().map(|(name, _)| name).collect();
+        for file in [&m.textures.base_color, &m.textures.metallic, &m.textures.occlusion, &m.textures.emissive]
+                        .iter() {
+            assert!(names.contains(file.as_ref().unwrap()));
+        }
+    }
+}
@@ -0,0 +1,557 @@
+// Standard Library
+use std::collections::{HashMap, VecDeque};
+use std::f32;
+use std::fmt;
+
+use util::graphics::{Vertex, VertexExt};
+use util::math::{cross, dot, normalize, sub};
+
+
+// Generates a second, non overlapping uv set for baking lighting of static meshes.
+// The triangle list is split into charts of similar normals, every chart is
+// flattened with least squares conformal maps and the charts are packed into [0, 1].
+#[derive(Copy, Clone, Debug)]
+pub struct Unwrapper {
+    // Largest angle between a triangle and the average normal of its chart
+    max_angle: f32,
+    // Lightmap size in texels, used for the gutter and utilization
+    resolution: u32,
+    // Empty texels kept around every chart to avoid bleeding while filtering
+    padding: u32,
+}
+
+#[derive(Debug)]
+pub enum UnwrapError {
+    // Padding on both sides of a chart leaves no texels of the resolution for it
+    Padding(u32, u32),
+    // Number of charts whose gutters alone need more texels than the resolution has,
+    // with the padding and the resolution
+    Gutter(usize, u32, u32),
+}
+
+impl fmt::Display for UnwrapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UnwrapError::Padding(padding, resolution) => {
+                write!(f,
+                       "padding of {} texels does not fit a {} texel lightmap",
+                       padding,
+                       resolution)
+            }
+            UnwrapError::Gutter(charts, padding, resolution) => {
+                write!(f,
+                       "{} charts with {} texels of padding do not fit a {} texel lightmap",
+                       charts,
+                       padding,
+                       resolution)
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct UnwrapReport {
+    pub charts: usize,
+    // Fraction of the lightmap covered by triangles
+    pub utilization: f32,
+}
+
+struct Chart {
+    triangles: Vec<usize>,
+    // Welded vertex id -> uv in world units
+    uv: HashMap<usize, [f32; 2]>,
+    min: [f32; 2],
+    max: [f32; 2],
+}
+
+#[inline]
+fn area2(a: [f32; 2], b: [f32; 2], c: [f32; 2]) -> f32 {
+    (b[0] - a[0]) * (c[1] - a[1]) - (c[0] - a[0]) * (b[1] - a[1])
+}
+
+// Gives corners with bitwise equal positions the same id
+fn weld(vertices: &[Vertex]) -> (Vec<usize>, Vec<[f32; 3]>) {
+    let mut ids = Vec::with_capacity(vertices.len());
+    let mut positions = Vec::new();
+    let mut map: HashMap<[u32; 3], usize> = HashMap::new();
+    for v in vertices.iter() {
+        let key = [v.position[0].to_bits(), v.position[1].to_bits(), v.position[2].to_bits()];
+        let next = positions.len();
+        let id = *map.entry(key).or_insert(next);
+        if id == next {
+            positions.push(v.position);
+        }
+        ids.push(id);
+    }
+    (ids, positions)
+}
+
+// Solves the least squares system A x = b for the free unknowns with
+// conjugate gradients on the normal equations. A is given as sparse rows.
+fn solve_least_squares(rows: &[Vec<(usize, f32)>], b: &[f32], n: usize) -> Vec<f32> {
+    let mul_a = |x: &[f32]| -> Vec<f32> {
+        rows.iter().map(|r| r.iter().map(|&(j, a)| a * x[j]).sum()).collect()
+    };
+    let mul_at = |y: &[f32]| -> Vec<f32> {
+        let mut out = vec![0.0; n];
+        for (r, &yi) in rows.iter().zip(y.iter()) {
+            for &(j, a) in r.iter() {
+                out[j] += a * yi;
+            }
+        }
+        out
+    };
+    let vdot = |a: &[f32], b: &[f32]| -> f32 { a.iter().zip(b.iter()).map(|(x, y)| x * y).sum() };
+
+    let mut x = vec![0.0; n];
+    let mut r = mul_at(b);
+    let mut p = r.clone();
+    let mut rr = vdot(&r, &r);
+    let tolerance = rr * 1e-12;
+    for _ in 0..(4 * n + 16) {
+        if rr <= tolerance || rr == 0.0 {
+            break;
+        }
+        let ap = mul_at(&mul_a(&p));
+        let pap = vdot(&p, &ap);
+        if pap <= 0.0 {
+            break;
+        }
+        let alpha = rr / pap;
+        for i in 0..n {
+            x[i] += alpha * p[i];
+            r[i] -= alpha * ap[i];
+        }
+        let rr_new = vdot(&r, &r);
+        let beta = rr_new / rr;
+        for i in 0..n {
+            p[i] = r[i] + beta * p[i];
+        }
+        rr = rr_new;
+    }
+    x
+}
+
+// Least squares conformal map of a chart (Levy et al. 2002).
+// Two distant vertices are pinned, the result is rescaled to the 3D surface area.
+fn lscm(triangles: &[[usize; 3]], positions: &[[f32; 3]]) -> Option<HashMap<usize, [f32; 2]>> {
+    let mut local: HashMap<usize, usize> = HashMap::new();
+    let mut global = Vec::new();
+    for t in triangles.iter() {
+        for &v in t.iter() {
+            if !local.contains_key(&v) {
+                local.insert(v, global.len());
+                global.push(v);
+            }
+        }
+    }
+    let count = global.len();
+    if count < 3 {
+        return None;
+    }
+
+    // Pin the two vertices furthest apart along the major axis of the bounding box
+    let (mut min, mut max) = ([f32::MAX; 3], [f32::MIN; 3]);
+    for &g in global.iter() {
+        for k in 0..3 {
+            min[k] = min[k].min(positions[g][k]);
+            max[k] = max[k].max(positions[g][k]);
+        }
+    }
+    let extent = sub(max, min);
+    let axis = if extent[0] >= extent[1] && extent[0] >= extent[2] {
+        0
+    } else if extent[1] >= extent[2] {
+        1
+    } else {
+        2
+    };
+    let mut pin0 = 0;
+    let mut pin1 = 0;
+    for (i, &g) in global.iter().enumerate() {
+        if positions[g][axis] < positions[global[pin0]][axis] {
+            pin0 = i;
+        }
+        if positions[g][axis] > positions[global[pin1]][axis] {
+            pin1 = i;
+        }
+    }
+    if pin0 == pin1 {
+        return None;
+    }
+    let pin_uv = [[0.0, 0.0], [1.0, 0.0]];
+
+    // Unknowns are (u, v) of every free vertex
+    let mut column = vec![usize::max_value(); count];
+    let mut free = 0;
+    for i in 0..count {
+        if i != pin0 && i != pin1 {
+            column[i] = free;
+            free += 1;
+        }
+    }
+    let n = free * 2;
+
+    let mut rows = Vec::with_capacity(triangles.len() * 2);
+    let mut rhs = Vec::with_capacity(triangles.len() * 2);
+    let mut area3d = 0.0;
+    for t in triangles.iter() {
+        let p = [positions[t[0]], positions[t[1]], positions[t[2]]];
+        let e1 = sub(p[1], p[0]);
+        let e2 = sub(p[2], p[0]);
+        let n3 = cross(e1, e2);
+        let double_area = dot(n3, n3).sqrt();
+        if double_area <= 0.0 {
+            continue;
+        }
+        area3d += double_area * 0.5;
+
+        // Triangle in its own orthonormal frame
+        let x_axis = normalize(e1);
+        let y_axis = normalize(cross(normalize(n3), x_axis));
+        let q = [[0.0, 0.0], [dot(e1, x_axis), 0.0], [dot(e2, x_axis), dot(e2, y_axis)]];
+        let scale = 1.0 / double_area.sqrt();
+
+        // Real and imaginary rows of sum_j W_j (u_j + i v_j) = 0
+        let mut re = Vec::with_capacity(6);
+        let mut im = Vec::with_capacity(6);
+        let mut b_re = 0.0;
+        let mut b_im = 0.0;
+        for j in 0..3 {
+            let a = (q[(j + 2) % 3][0] - q[(j + 1) % 3][0]) * scale;
+            let b = (q[(j + 2) % 3][1] - q[(j + 1) % 3][1]) * scale;
+            let l = local[&t[j]];
+            if l == pin0 || l == pin1 {
+                let uv = if l == pin0 { pin_uv[0] } else { pin_uv[1] };
+                b_re -= a * uv[0] - b * uv[1];
+                b_im -= b * uv[0] + a * uv[1];
+            } else {
+                let c = column[l];
+                re.push((2 * c, a));
+                re.push((2 * c + 1, -b));
+                im.push((2 * c, b));
+                im.push((2 * c + 1, a));
+            }
+        }
+        rows.push(re);
+        rhs.push(b_re);
+        rows.push(im);
+        rhs.push(b_im);
+    }
+
+    let x = solve_least_squares(&rows, &rhs, n);
+    let mut uv = Vec::with_capacity(count);
+    for i in 0..count {
+        if i == pin0 {
+            uv.push(pin_uv[0]);
+        } else if i == pin1 {
+            uv.push(pin_uv[1]);
+        } else {
+            uv.push([x[2 * column[i]], x[2 * column[i] + 1]]);
+        }
+    }
+
+    // Rescale to world units so charts get texels proportional to their area,
+    // and mirror charts that came out flipped.
+    let mut area2d = 0.0;
+    for t in triangles.iter() {
+        area2d += area2(uv[local[&t[0]]], uv[local[&t[1]]], uv[local[&t[2]]]) * 0.5;
+    }
+    if area2d == 0.0 || !area2d.is_finite() {
+        return None;
+    }
+    let flip = if area2d < 0.0 { -1.0 } else { 1.0 };
+    let s = (area3d / area2d.abs()).sqrt();
+
+    let mut out = HashMap::with_capacity(count);
+    for (i, &g) in global.iter().enumerate() {
+        out.insert(g, [uv[i][0] * s * flip, uv[i][1] * s]);
+    }
+    Some(out)
+}
+
+// Fallback for charts LSCM cannot handle, projects onto the plane of the average normal
+fn planar(triangles: &[[usize; 3]], positions: &[[f32; 3]], normal: [f32; 3]) -> HashMap<usize, [f32; 2]> {
+    let helper = if normal[0].abs() < 0.9 { [1.0, 0.0, 0.0] } else { [0.0, 1.0, 0.0] };
+    let x_axis = normalize(cross(helper, normal));
+    let y_axis = cross(normal, x_axis);
+    let mut out = HashMap::new();
+    for t in triangles.iter() {
+        for &v in t.iter() {
+            out.insert(v, [dot(positions[v], x_axis), dot(positions[v], y_axis)]);
+        }
+    }
+    out
+}
+
+impl Unwrapper {
+    pub fn new() -> Unwrapper {
+        Unwrapper {
+            max_angle: 66.0f32.to_radians(),
+            resolution: 1024,
+            padding: 2,
+        }
+    }
+
+    pub fn max_angle(mut self, radians: f32) -> Unwrapper {
+        self.max_angle = radians;
+        self
+    }
+    pub fn resolution(mut self, texels: u32) -> Unwrapper {
+        self.resolution = texels;
+        self
+    }
+    pub fn padding(mut self, texels: u32) -> Unwrapper {
+        self.padding = texels;
+        self
+    }
+
+    // Grows charts over shared edges as long as the triangle normal stays
+    // within max_angle of the average normal of the chart.
+    fn segment(&self, tris: &[[usize; 3]], normals: &[[f32; 3]]) -> Vec<Vec<usize>> {
+        let mut edges: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        for (i, t) in tris.iter().enumerate() {
+            for j in 0..3 {
+                let (a, b) = (t[j], t[(j + 1) % 3]);
+                let key = if a < b { (a, b) } else { (b, a) };
+                edges.entry(key).or_insert(Vec::new()).push(i);
+            }
+        }
+
+        let cos_max = self.max_angle.cos();
+        let mut chart_of = vec![usize::max_value(); tris.len()];
+        let mut charts = Vec::new();
+        for seed in 0..tris.len() {
+            if chart_of[seed] != usize::max_value() {
+                continue;
+            }
+            let id = charts.len();
+            let mut members = vec![seed];
+            let mut sum = normals[seed];
+            chart_of[seed] = id;
+            let mut queue = VecDeque::new();
+            queue.push_back(seed);
+            while let Some(t) = queue.pop_front() {
+                for j in 0..3 {
+                    let (a, b) = (tris[t][j], tris[t][(j + 1) % 3]);
+                    let key = if a < b { (a, b) } else { (b, a) };
+                    for &other in edges[&key].iter() {
+                        if chart_of[other] != usize::max_value() {
+                            continue;
+                        }
+                        if dot(normalize(sum), normals[other]) < cos_max {
+                            continue;
+                        }
+                        chart_of[other] = id;
+                        members.push(other);
+                        for k in 0..3 {
+                            sum[k] += normals[other][k];
+                        }
+                        queue.push_back(other);
+                    }
+                }
+            }
+            charts.push(members);
+        }
+        charts
+    }
+
+    // Shelf packing of the chart rectangles into a square of side `side`,
+    // returns the offsets or None if they do not fit.
+    fn pack(&self, sizes: &[[f32; 2]], order: &[usize], side: f32, gutter: f32) -> Option<Vec<[f32; 2]>> {
+        let mut offsets = vec![[0.0, 0.0]; sizes.len()];
+        let mut x = 0.0;
+        let mut y = 0.0;
+        let mut shelf = 0.0;
+        for &i in order.iter() {
+            let w = sizes[i][0] + gutter;
+            let h = sizes[i][1] + gutter;
+            if w > side {
+                return None;
+            }
+            if x + w > side {
+                y += shelf;
+                x = 0.0;
+                shelf = 0.0;
+            }
+            if y + h > side {
+                return None;
+            }
+            offsets[i] = [x + gutter * 0.5, y + gutter * 0.5];
+            x += w;
+            if h > shelf {
+                shelf = h;
+            }
+        }
+        Some(offsets)
+    }
+
+    // Fails if the resolution is not larger than the padding on both sides,
+    // no square would be large enough to hold a chart then. The gutter is a
+    // fixed fraction of the square, growing the square only shrinks the
+    // charts, so the gutters of too many charts never fit either.
+    pub fn unwrap(&self, vertices: &[Vertex]) -> Result<(Vec<VertexExt>, UnwrapReport), UnwrapError> {
+        if self.resolution <= 2 * self.padding {
+            return Err(UnwrapError::Padding(self.padding, self.resolution));
+        }
+        let (ids, positions) = weld(vertices);
+        let tris: Vec<[usize; 3]> = ids.chunks(3)
+                                       .filter(|c| c.len() == 3)
+                                       .map(|c| [c[0], c[1], c[2]])
+                                       .collect();
+        let normals: Vec<[f32; 3]> = tris.iter()
+                                         .map(|t| {
+                                             normalize(cross(sub(positions[t[1]], positions[t[0]]),
+                                                             sub(positions[t[2]], positions[t[0]])))
+                                         })
+                                         .collect();
+
+        let mut charts = Vec::new();
+        for members in self.segment(&tris, &normals) {
+            let chart_tris: Vec<[usize; 3]> = members.iter().map(|&t| tris[t]).collect();
+            let mut avg = [0.0; 3];
+            for &t in members.iter() {
+                for k in 0..3 {
+                    avg[k] += normals[t][k];
+                }
+            }
+            let uv = match lscm(&chart_tris, &positions) {
+                Some(uv) => uv,
+                None => planar(&chart_tris, &positions, normalize(avg)),
+            };
+            let (mut min, mut max) = ([f32::MAX; 2], [f32::MIN; 2]);
+            for p in uv.values() {
+                for k in 0..2 {
+                    min[k] = min[k].min(p[k]);
+                    max[k] = max[k].max(p[k]);
+                }
+            }
+            charts.push(Chart {
+                triangles: members,
+                uv: uv,
+                min: min,
+                max: max,
+            });
+        }
+
+        // Tallest charts first, grow the square until everything fits
+        let sizes: Vec<[f32; 2]> = charts.iter()
+                                         .map(|c| [c.max[0] - c.min[0], c.max[1] - c.min[1]])
+                                         .collect();
+        let mut order: Vec<usize> = (0..charts.len()).collect();
+        order.sort_by(|a, b| sizes[*b][1].partial_cmp(&sizes[*a][1]).unwrap_or(::std::cmp::Ordering::Equal));
+        let total: f32 = sizes.iter().map(|s| s[0] * s[1]).sum();
+        let largest = sizes.iter().fold(0.0f32, |m, s| m.max(s[0]).max(s[1]));
+        let mut side = total.sqrt().max(largest).max(f32::MIN_POSITIVE);
+        let texel = self.padding as f32 / self.resolution as f32;
+        let mut packed = self.pack(&sizes, &order, side, side * texel * 2.0);
+        // 1.05^300 shrinks the charts to nothing next to their gutters
+        let mut grown = 0;
+        while packed.is_none() {
+            if grown == 300 {
+                return Err(UnwrapError::Gutter(charts.len(), self.padding, self.resolution));
+            }
+            side *= 1.05;
+            grown += 1;
+            packed = self.pack(&sizes, &order, side, side * texel * 2.0);
+        }
+        let offsets = packed.unwrap();
+
+        let mut out: Vec<VertexExt> = vertices.iter()
+                                              .map(|v| VertexExt::from_vertex(v, [1.0; 4]))
+                                              .collect();
+        let mut covered = 0.0;
+        for (c, chart) in charts.iter().enumerate() {
+            for &t in chart.triangles.iter() {
+                let mut corner_uv = [[0.0; 2]; 3];
+                for j in 0..3 {
+                    let p = chart.uv[&tris[t][j]];
+                    let uv = [(p[0] - chart.min[0] + offsets[c][0]) / side,
+                              (p[1] - chart.min[1] + offsets[c][1]) / side];
+                    out[t * 3 + j].texcoord1 = uv;
+                    corner_uv[j] = uv;
+                }
+                covered += area2(corner_uv[0], corner_uv[1], corner_uv[2]).abs() * 0.5;
+            }
+        }
+
+        Ok((out,
+            UnwrapReport {
+                charts: charts.len(),
+                utilization: covered,
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Unwrapper, UnwrapError};
+    use util::graphics::Vertex;
+
+    // Two triangles of a unit quad and one of a tilted triangle
+    fn vertices() -> Vec<Vertex> {
+        let v = |p: [f32; 3]| Vertex::new(p, [0.0, 0.0, 1.0], [0.0, 0.0]);
+        vec![v([0.0, 0.0, 0.0]), v([1.0, 0.0, 0.0]), v([1.0, 1.0, 0.0]),
+             v([0.0, 0.0, 0.0]), v([1.0, 1.0, 0.0]), v([0.0, 1.0, 0.0]),
+             v([3.0, 0.0, 0.0]), v([3.0, 1.0, 1.0]), v([3.0, 0.0, 1.0])]
+    }
+
+    #[test]
+    fn padding_has_to_fit_the_resolution() {
+        for &(resolution, padding) in [(0, 0), (0, 2), (4, 2), (8, 5)].iter() {
+            match Unwrapper::new().resolution(resolution).padding(padding).unwrap(&vertices()) {
+                Err(UnwrapError::Padding(p, r)) => assert_eq!((p, r), (padding, resolution)),
+                Err(e) => panic!("{}", e),
+                Ok(_) => panic!("{} texels of padding at {} texels", padding, resolution),
+            }
+        }
+        // Each gutter takes 4 of the 5 texels, two charts cannot sit side by side
+        match Unwrapper::new().resolution(5).padding(2).unwrap(&vertices()) {
+            Err(UnwrapError::Gutter(charts, padding, resolution)) => {
+                assert_eq!((charts, padding, resolution), (2, 2, 5))
+            }
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("two charts in 5 texels"),
+        }
+        assert!(Unwrapper::new().resolution(16).padding(2).unwrap(&vertices()).is_ok());
+    }
+
+    // Triangles sharing no edges, one chart each
+    fn scattered(count: usize) -> Vec<Vertex> {
+        let mut vertices = Vec::new();
+        for i in 0..count {
+            let x = 2.0 * i as f32;
+            for &p in [[x, 0.0, 0.0], [x + 1.0, 0.0, 0.0], [x, 1.0, 0.0]].iter() {
+                vertices.push(Vertex::new(p, [0.0, 0.0, 1.0], [0.0, 0.0]));
+            }
+        }
+        vertices
+    }
+
+    #[test]
+    fn many_charts_need_room_for_their_gutters() {
+        // A 16 texel map with 4 texels per gutter holds at most 4 x 4 charts
+        match Unwrapper::new().resolution(16).padding(2).unwrap(&scattered(20)) {
+            Err(UnwrapError::Gutter(charts, _, _)) => assert_eq!(charts, 20),
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("20 charts in 16 texels"),
+        }
+        let (_, report) = Unwrapper::new().resolution(64).padding(2).unwrap(&scattered(20)).unwrap();
+        assert_eq!(report.charts, 20);
+    }
+
+    #[test]
+    fn charts_stay_inside_the_unit_square() {
+        let (out, report) = Unwrapper::new().resolution(64).padding(4).unwrap(&vertices()).unwrap();
+        assert_eq!(report.charts, 2);
+        assert!(report.utilization > 0.0 && report.utilization <= 1.0);
+        let gutter = 4.0 / 64.0;
+        for v in out.iter() {
+            for k in 0..2 {
+                assert!(v.texcoord1[k] >= gutter * 0.99 && v.texcoord1[k] <= 1.0 - gutter * 0.99,
+                        "{:?}",
+                        v.texcoord1);
+            }
+        }
+    }
+}
@@ -0,0 +1,374 @@
+// Standard Library
+use std::fmt;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use util::graphics::{Material, AlphaMode};
+use texture::metallic::roughness_from_exponent;
+use texture::pack::{Channel, ChannelLayout};
+
+// Reading and writing of .pbrmtl files. The format follows the mtl layout
+// so it can be edited by hand:
+//
+//     # rusty panel
+//     newmtl panel
+//     base_color 0.8 0.3 0.1
+//     metallic 1
+//     roughness 0.45
+//     map_roughness panel_rough.png
+//     alpha_mode mask
+//     alpha_cutoff 0.3
+//     map_packed panel_rmo.png
+//     packed_layout r m o 1
+//
+// Maps are scaled by the matching factor, a metallic map needs "metallic 1".
+// A packed map replaces the roughness, metallic and occlusion maps, its
+// channels are given by the letters r, m, o, h, 0 and 1 (see texture::pack).
+// Materials with map_specular and map_gloss are drawn with the spec/gloss
+// workflow instead, using the base color map as albedo.
+// Keys of the mtl PBR extension (Pr, Pm, Ke, Ni, d, map_Kd, norm, ...) are
+// accepted as well, files are always written with the long names. A Phong
+// exponent Ns is converted to roughness like mtl files, unless the material
+// gives a roughness.
+
+pub enum MaterialError {
+    Io(String),
+    // Line number starting at 1 and a description
+    Syntax(usize, String),
+}
+
+impl fmt::Display for MaterialError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MaterialError::Io(ref e) => write!(f, "{}", e),
+            MaterialError::Syntax(line, ref e) => write!(f, "line {}: {}", line, e),
+        }
+    }
+}
+
+impl fmt::Debug for MaterialError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+fn floats(line: usize, key: &str, values: &[&str], count: usize) -> Result<Vec<f32>, MaterialError> {
+    if values.len() != count {
+        return Err(MaterialError::Syntax(line, format!("'{}' expects {} values, found {}", key, count, values.len())));
+    }
+    let mut out = Vec::with_capacity(count);
+    for v in values.iter() {
+        match v.parse::<f32>() {
+            Ok(f) => out.push(f),
+            Err(_) => return Err(MaterialError::Syntax(line, format!("'{}' is not a number", v))),
+        }
+    }
+    Ok(out)
+}
+
+fn vec3(line: usize, key: &str, values: &[&str]) -> Result<[f32; 3], MaterialError> {
+    let v = try!(floats(line, key, values, 3));
+    Ok([v[0], v[1], v[2]])
+}
+
+fn scalar(line: usize, key: &str, values: &[&str]) -> Result<f32, MaterialError> {
+    let v = try!(floats(line, key, values, 1));
+    Ok(v[0])
+}
+
+fn layout(line: usize, values: &[&str]) -> Result<ChannelLayout, MaterialError> {
+    let channels: Vec<Channel> = values.iter().filter_map(|v| Channel::from_letter(v)).collect();
+    if values.len() != 4 || channels.len() != 4 {
+        return Err(MaterialError::Syntax(line, "'packed_layout' expects four of r, m, o, h, 0 and 1".to_string()));
+    }
+    Ok(ChannelLayout::new(channels[0], channels[1], channels[2], channels[3]))
+}
+
+fn file_name(line: usize, key: &str, values: &[&str]) -> Result<Option<String>, MaterialError> {
+    // Options like "-bm 1.0" are not supported, the last word is the file
+    match values.last() {
+        Some(f) => Ok(Some(f.to_string())),
+        None => Err(MaterialError::Syntax(line, format!("'{}' expects a file name", key))),
+    }
+}
+
+pub fn parse(source: &str) -> Result<Vec<Material>, MaterialError> {
+    let mut materials: Vec<Material> = Vec::new();
+    // Whether the current material has a roughness that Ns must not override
+    let mut has_roughness = false;
+
+    for (i, raw) in source.lines().enumerate() {
+        let line = i + 1;
+        let content = match raw.find('#') {
+            Some(pos) => &raw[..pos],
+            None => raw,
+        };
+        let words: Vec<&str> = content.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let key = words[0];
+        let values = &words[1..];
+
+        if key == "newmtl" {
+            if values.len() != 1 {
+                return Err(MaterialError::Syntax(line, "'newmtl' expects a name".to_string()));
+            }
+            materials.push(Material { name: values[0].to_string(), ..Default::default() });
+            has_roughness = false;
+            continue;
+        }
+        let m = match materials.last_mut() {
+            Some(m) => m,
+            None => return Err(MaterialError::Syntax(line, format!("'{}' before 'newmtl'", key))),
+        };
+
+        match &key.to_lowercase()[..] {
+            "ka" => m.ka = try!(vec3(line, key, values)),
+            // Kd is the base color in the mtl PBR extension
+            "kd" => {
+                m.kd = try!(vec3(line, key, values));
+                m.base_color = m.kd;
+            }
+            "ks" => m.ks = try!(vec3(line, key, values)),
+            "base_color" => m.base_color = try!(vec3(line, key, values)),
+            "metallic" | "pm" => m.metallic = try!(scalar(line, key, values)),
+            "roughness" | "pr" => {
+                m.roughness = try!(scalar(line, key, values));
+                has_roughness = true;
+            }
+            "ns" => {
+                let ns = try!(scalar(line, key, values));
+                if !has_roughness {
+                    m.roughness = roughness_from_exponent(ns);
+                }
+            }
+            "ior" | "ni" => m.ior = try!(scalar(line, key, values)),
+            "emissive" | "ke" => m.emissive = try!(vec3(line, key, values)),
+            "alpha" | "d" => m.alpha = try!(scalar(line, key, values)),
+            "clearcoat" | "pc" => m.lobes.clearcoat = try!(scalar(line, key, values)),
+            "clearcoat_roughness" | "pcr" => m.lobes.clearcoat_roughness = try!(scalar(line, key, values)),
+            "sheen" => m.lobes.sheen_color = try!(vec3(line, key, values)),
+            "ps" => m.lobes.sheen_color = [try!(scalar(line, key, values)); 3],
+            "sheen_roughness" => m.lobes.sheen_roughness = try!(scalar(line, key, values)),
+            "anisotropy" | "aniso" => m.lobes.anisotropy = try!(scalar(line, key, values)),
+            "anisotropy_rotation" | "anisor" => m.lobes.anisotropy_rotation = try!(scalar(line, key, values)),
+            "alpha_mode" => {
+                m.alpha_mode = match values.first().map(|v| &v[..]) {
+                    Some("opaque") if values.len() == 1 => AlphaMode::Opaque,
+                    Some("mask") if values.len() == 1 => AlphaMode::Mask,
+                    Some("blend") if values.len() == 1 => AlphaMode::Blend,
+                    _ => return Err(MaterialError::Syntax(line, "'alpha_mode' expects opaque, mask or blend".to_string())),
+                }
+            }
+            "alpha_cutoff" => m.alpha_cutoff = try!(scalar(line, key, values)),
+            "tr" => m.alpha = 1.0 - try!(scalar(line, key, values)),
+            "map_base_color" | "map_kd" => m.textures.base_color = try!(file_name(line, key, values)),
+            "map_metallic" | "map_pm" => m.textures.metallic = try!(file_name(line, key, values)),
+            "map_roughness" | "map_pr" => m.textures.roughness = try!(file_name(line, key, values)),
+            "map_normal" | "norm" | "map_bump" | "bump" => m.textures.normal = try!(file_name(line, key, values)),
+            "map_occlusion" | "map_ka" => m.textures.occlusion = try!(file_name(line, key, values)),
+            "map_emissive" | "map_ke" => m.textures.emissive = try!(file_name(line, key, values)),
+            "map_specular" | "map_ks" => m.textures.specular = try!(file_name(line, key, values)),
+            "map_gloss" | "map_ns" => m.textures.gloss = try!(file_name(line, key, values)),
+            "map_packed" => m.textures.packed = try!(file_name(line, key, values)),
+            "packed_layout" => m.textures.packed_layout = try!(layout(line, values)),
+            // Phong only parameters that have no counterpart here
+            "illum" | "tf" | "map_d" => {}
+            _ => return Err(MaterialError::Syntax(line, format!("unknown key '{}'", key))),
+        }
+    }
+    Ok(materials)
+}
+
+pub fn write(materials: &[Material]) -> String {
+    let mut s = String::new();
+    for (i, m) in materials.iter().enumerate() {
+        if i > 0 {
+            s.push('\n');
+        }
+        let _ = writeln!(s, "newmtl {}", m.name);
+        let _ = writeln!(s, "Ka {} {} {}", m.ka[0], m.ka[1], m.ka[2]);
+        let _ = writeln!(s, "Kd {} {} {}", m.kd[0], m.kd[1], m.kd[2]);
+        let _ = writeln!(s, "Ks {} {} {}", m.ks[0], m.ks[1], m.ks[2]);
+        let _ = writeln!(s, "base_color {} {} {}", m.base_color[0], m.base_color[1], m.base_color[2]);
+        let _ = writeln!(s, "metallic {}", m.metallic);
+        let _ = writeln!(s, "roughness {}", m.roughness);
+        let _ = writeln!(s, "ior {}", m.ior);
+        let _ = writeln!(s, "emissive {} {} {}", m.emissive[0], m.emissive[1], m.emissive[2]);
+        let _ = writeln!(s, "alpha {}", m.alpha);
+        match m.alpha_mode {
+            AlphaMode::Opaque => {
+                let _ = writeln!(s, "alpha_mode opaque");
+            }
+            AlphaMode::Mask => {
+                let _ = writeln!(s, "alpha_mode mask");
+            }
+            AlphaMode::Blend => {
+                let _ = writeln!(s, "alpha_mode blend");
+            }
+        }
+        let _ = writeln!(s, "alpha_cutoff {}", m.alpha_cutoff);
+
+        // The optional lobes are only written when they are used
+        let l = &m.lobes;
+        if l.clearcoat != 0.0 {
+            let _ = writeln!(s, "clearcoat {}", l.clearcoat);
+            let _ = writeln!(s, "clearcoat_roughness {}", l.clearcoat_roughness);
+        }
+        if l.sheen_color != [0.0; 3] {
+            let _ = writeln!(s, "sheen {} {} {}", l.sheen_color[0], l.sheen_color[1], l.sheen_color[2]);
+            let _ = writeln!(s, "sheen_roughness {}", l.sheen_roughness);
+        }
+        if l.anisotropy != 0.0 {
+            let _ = writeln!(s, "anisotropy {}", l.anisotropy);
+            let _ = writeln!(s, "anisotropy_rotation {}", l.anisotropy_rotation);
+        }
+
+        let t = &m.textures;
+        let maps = [("map_base_color", &t.base_color),
+                    ("map_metallic", &t.metallic),
+                    ("map_roughness", &t.roughness),
+                    ("map_normal", &t.normal),
+                    ("map_occlusion", &t.occlusion),
+                    ("map_emissive", &t.emissive),
+                    ("map_specular", &t.specular),
+                    ("map_gloss", &t.gloss),
+                    ("map_packed", &t.packed)];
+        for &(key, file) in maps.iter() {
+            if let Some(ref file) = *file {
+                let _ = writeln!(s, "{} {}", key, file);
+            }
+        }
+        if t.packed_layout != ChannelLayout::default() {
+            let c = t.packed_layout.channels();
+            let _ = writeln!(s,
+                             "packed_layout {} {} {} {}",
+                             c[0].letter(),
+                             c[1].letter(),
+                             c[2].letter(),
+                             c[3].letter());
+        }
+    }
+    s
+}
+
+pub fn load(path: &Path) -> Result<Vec<Material>, MaterialError> {
+    let mut source = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut source)) {
+        Ok(_) => parse(&source),
+        Err(e) => Err(MaterialError::Io(format!("{}: {}", path.display(), e))),
+    }
+}
+
+pub fn save(path: &Path, materials: &[Material]) -> Result<(), MaterialError> {
+    File::create(path)
+        .and_then(|mut f| f.write_all(write(materials).as_bytes()))
+        .map_err(|e| MaterialError::Io(format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, write};
+    use util::graphics::{Material, AlphaMode};
+    use texture::metallic::roughness_from_exponent;
+    use texture::pack::{Channel, ChannelLayout};
+
+    fn panel() -> Material {
+        let mut m = Material::new([0.1, 0.1, 0.1], [0.8, 0.3, 0.1], [0.04, 0.04, 0.04]);
+        m.name = "panel".to_string();
+        m.base_color = [0.7, 0.25, 0.125];
+        m.metallic = 1.0;
+        m.roughness = 0.45;
+        m.ior = 1.33;
+        m.emissive = [0.5, 0.0, 0.25];
+        m.alpha = 0.75;
+        m.alpha_mode = AlphaMode::Mask;
+        m.alpha_cutoff = 0.3;
+        m.lobes.clearcoat = 0.5;
+        m.lobes.clearcoat_roughness = 0.1;
+        m.lobes.sheen_color = [0.2, 0.3, 0.4];
+        m.lobes.sheen_roughness = 0.6;
+        m.lobes.anisotropy = -0.5;
+        m.lobes.anisotropy_rotation = 0.25;
+        m.textures.base_color = Some("panel_color.png".to_string());
+        m.textures.normal = Some("panel_normal.png".to_string());
+        m.textures.emissive = Some("panel_glow.png".to_string());
+        m.textures.packed = Some("panel_rmo.png".to_string());
+        m.textures.packed_layout = ChannelLayout::new(Channel::Occlusion,
+                                                      Channel::Roughness,
+                                                      Channel::Metalness,
+                                                      Channel::One);
+        m
+    }
+
+    #[test]
+    fn written_materials_read_back() {
+        let mut plain = Material::new([0.0; 3], [0.5; 3], [0.0; 3]);
+        plain.name = "plain".to_string();
+        plain.alpha_mode = AlphaMode::Blend;
+        plain.textures.roughness = Some("rough.png".to_string());
+        plain.textures.metallic = Some("metal.png".to_string());
+        plain.textures.occlusion = Some("ao.png".to_string());
+        plain.textures.specular = Some("spec.png".to_string());
+        plain.textures.gloss = Some("gloss.png".to_string());
+        let materials = vec![panel(), plain, Material { name: "default".to_string(), ..Default::default() }];
+
+        let text = write(&materials);
+        assert!(text.contains("map_packed panel_rmo.png\n"));
+        assert!(text.contains("packed_layout o r m 1\n"));
+        assert_eq!(parse(&text).unwrap(), materials);
+    }
+
+    #[test]
+    fn reads_mtl_extension_keys() {
+        let source = "newmtl ext # comment\nKd 0.5 0.5 0.5\nPm 0.25\nPr 0.75\nNi 2\nd 0.5\n\
+                      map_Kd color.png\nnorm -bm 1.0 normal.png\nmap_Ks spec.png\nmap_Ns gloss.png\nNs 10\n";
+        let m = &parse(source).unwrap()[0];
+        assert_eq!(m.name, "ext");
+        assert_eq!(m.base_color, [0.5; 3]);
+        assert_eq!((m.metallic, m.roughness, m.ior, m.alpha), (0.25, 0.75, 2.0, 0.5));
+        assert_eq!(m.textures.base_color, Some("color.png".to_string()));
+        assert_eq!(m.textures.normal, Some("normal.png".to_string()));
+        assert_eq!(m.textures.specular, Some("spec.png".to_string()));
+        assert_eq!(m.textures.gloss, Some("gloss.png".to_string()));
+    }
+
+    #[test]
+    fn phong_exponent_becomes_roughness() {
+        let m = &parse("newmtl phong\nNs 10\n").unwrap()[0];
+        assert_eq!(m.roughness, roughness_from_exponent(10.0));
+        // An explicit roughness wins in either order
+        for source in ["newmtl a\nNs 10\nPr 0.75\n", "newmtl a\nroughness 0.75\nNs 10\n"].iter() {
+            assert_eq!(parse(source).unwrap()[0].roughness, 0.75);
+        }
+        // but only within its material
+        let materials = parse("newmtl a\nPr 0.75\nnewmtl b\nNs 10\n").unwrap();
+        assert_eq!(materials[1].roughness, roughness_from_exponent(10.0));
+        assert!(parse("newmtl a\nNs\n").is_err());
+    }
+
+    #[test]
+    fn alpha_cutoff_keeps_the_mode() {
+        let m = &parse("newmtl a\nalpha_cutoff 0.2\nalpha_mode blend\n").unwrap()[0];
+        assert_eq!((m.alpha_mode, m.alpha_cutoff), (AlphaMode::Blend, 0.2));
+        let m = &parse("newmtl a\nalpha_cutoff 0.2\n").unwrap()[0];
+        assert_eq!(m.alpha_mode, AlphaMode::Opaque);
+        let m = &parse("newmtl a\nalpha_cutoff 0.2\nalpha_mode mask\n").unwrap()[0];
+        assert_eq!((m.alpha_mode, m.alpha_cutoff), (AlphaMode::Mask, 0.2));
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        let errors = ["metallic 1\n", "newmtl a\nroughness\n", "newmtl a\n\npacked_layout r m x 1\n",
+                      "newmtl a\nfoo 1\n"];
+        for (source, line) in errors.iter().zip([1, 2, 3, 2].iter()) {
+            match parse(source) {
+                Err(super::MaterialError::Syntax(l, _)) => assert_eq!(l, *line, "{}", source),
+                _ => panic!("{} parsed", source),
+            }
+        }
+    }
+}
@@ -1,22 +1,38 @@
 //Standard Library
 use std::fs;
 use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::borrow::Cow;
+use std::ops::Range;
+use std::rc::Rc;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
 //External Library
 use glium;
 use glium::{Display, Surface};
-use collision::Aabb3;
 use image;
+use obj;
 
 use shader;
+use shader::permutation::Features;
 use assets::group::{Group};
-use util::graphics::{Vertex, BaseUniform, Material, TexturePBR};
+use util::graphics::{Vertex, VertexExt, VertexAttributes, BaseUniform, Material, TexturePBR};
+use accelerator::bounds::{Bounds, HasPosition};
+use texture::metallic::{self, MetallicRoughness};
 
 pub mod asset;
 pub mod instance;
 pub mod group;
+pub mod skinned;
+pub mod morph;
+pub mod info;
+pub mod lightmap;
+pub mod material;
+pub mod layered;
+pub mod ply;
+pub mod gltf;
 
 
 pub trait Drawable {
@@ -26,44 +42,119 @@ pub trait Drawable {
         where S: Surface;
 }
 
+// Opaque and masked groups are drawn in the first pass, blended groups in a
+// second one after everything opaque is in the depth buffer
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Pass {
+    Opaque,
+    Blend,
+}
+
+impl Pass {
+    pub fn includes(&self, group: &Group) -> bool {
+        (*self == Pass::Blend) == group.is_blended()
+    }
+}
+
+// Empty meshes get degenerate bounds at the origin instead of panicking
 #[inline]
-fn build_aabb(vertex_data: &[Vertex]) -> Aabb3<f32> {
-    use std::cmp::Ordering;
-    use cgmath;
+pub fn build_bounds<P: HasPosition>(vertex_data: &[P]) -> Bounds {
+    Bounds::compute(vertex_data).unwrap_or(Bounds::empty())
+}
 
-    let mut pos_x = Vec::new();
-    let mut pos_y = Vec::new();
-    let mut pos_z = Vec::new();
+// GPU independent result of parsing a mesh file, every group owns a
+// contiguous range of the triangle list in `vertices`. `colors` and
+// `texcoords1` run parallel to `vertices` and are empty if the file has
+// no vertex colors or no second uv set.
+pub struct MeshData {
+    pub vertices: Vec<Vertex>,
+    pub colors: Vec<[f32; 4]>,
+    pub texcoords1: Vec<[f32; 2]>,
+    pub groups: Vec<GroupData>,
+}
 
-    for p in vertex_data.iter() {
-        let tmp = &p.position[..];
-        pos_x.push(tmp[0]);
-        pos_y.push(tmp[1]);
-        pos_z.push(tmp[2]);
+impl MeshData {
+    pub fn has_colors(&self) -> bool {
+        !self.colors.is_empty()
     }
 
-    pos_x.sort_by(|a, b| a.partial_cmp(&b).unwrap_or(Ordering::Less));
-    pos_y.sort_by(|a, b| a.partial_cmp(&b).unwrap_or(Ordering::Less));
-    pos_z.sort_by(|a, b| a.partial_cmp(&b).unwrap_or(Ordering::Less));
+    pub fn has_texcoords1(&self) -> bool {
+        !self.texcoords1.is_empty()
+    }
 
+    // Meshes with a single uv set copy it into texcoord1
+    pub fn attributes(&self) -> Vec<VertexAttributes> {
+        self.extended().iter().map(|v| v.attributes()).collect()
+    }
 
-    Aabb3::new(cgmath::Point3::new(pos_x[0], pos_y[0], pos_z[0]),
-               cgmath::Point3::new(pos_x.last().unwrap().clone(),
-                                   pos_y.last().unwrap().clone(),
-                                   pos_z.last().unwrap().clone()))
+    pub fn extended(&self) -> Vec<VertexExt> {
+        self.vertices
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                let mut ext = VertexExt::from_vertex(v, self.colors.get(i).cloned().unwrap_or([1.0; 4]));
+                if let Some(uv) = self.texcoords1.get(i) {
+                    ext.texcoord1 = *uv;
+                }
+                ext
+            })
+            .collect()
+    }
 }
 
-#[inline]
-pub fn loader<'b, 'a: 'b>(path: &Path,
-                          texture_map: &'a HashMap<String, glium::texture::Texture2d>,
-                          material_map: &'a HashMap<String, Material>,
-                          program: &'a shader::Program)
-                          -> (Vec<Vertex>, Vec<Group<'b>>) {
+pub struct GroupData {
+    pub object: String,
+    pub name: String,
+    pub range: Range<usize>,
+    pub material: Option<Rc<obj::Material>>,
+}
+
+// Vertex colors as written by MeshLab and ZBrush: "v x y z r g b [a]".
+// The obj crate drops the extra components, so they are read separately.
+// Returns one color per position, or nothing if no position has a color.
+pub fn load_vertex_colors(path: &Path) -> io::Result<Vec<[f32; 4]>> {
+    use std::io::{BufRead, BufReader};
+
+    let file = try!(File::open(path));
+    let mut colors = Vec::new();
+    let mut found = false;
+    for line in BufReader::new(file).lines() {
+        let line = try!(line);
+        let mut words = line.split_whitespace();
+        if words.next() != Some("v") {
+            continue;
+        }
+        let values: Vec<f32> = words.filter_map(|w| w.parse().ok()).collect();
+        if values.len() >= 6 {
+            found = true;
+            colors.push([values[3], values[4], values[5], *values.get(6).unwrap_or(&1.0)]);
+        } else {
+            colors.push([1.0; 4]);
+        }
+    }
+    if !found {
+        colors.clear();
+    }
+    Ok(colors)
+}
+
+// Loads OBJ, PLY and glTF (.gltf and .glb) files by their extension
+pub fn load_mesh(path: &Path) -> io::Result<MeshData> {
+    match path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase()) {
+        Some(ref e) if e == "ply" => ply::load(path),
+        Some(ref e) if e == "gltf" || e == "glb" => gltf::load(path),
+        _ => load_obj(path),
+    }
+}
+
+fn load_obj(path: &Path) -> io::Result<MeshData> {
     use obj;
     use genmesh;
 
-    let data = obj::load::<genmesh::Polygon<obj::IndexTuple>>(path).unwrap();
+    let data = try!(obj::load::<genmesh::Polygon<obj::IndexTuple>>(path));
+    let position_colors = try!(load_vertex_colors(path));
     let mut vertex_data = Vec::new();
+    let mut colors = Vec::new();
     let mut groups = Vec::new();
     let mut group_index = 0;
 
@@ -83,6 +174,9 @@ pub fn loader<'b, 'a: 'b>(path: &Path,
                             let normal = normal.unwrap_or([0.0, 0.0, 0.0]);
 
                             vertex_data.push(Vertex::new(position, normal, texture));
+                            if let Some(c) = position_colors.get(v.0) {
+                                colors.push(*c);
+                            }
                         }
                     }
                     &genmesh::Polygon::PolyQuad(genmesh::Quad{x: v1, y: v2, z: v3, w: v4}) => {
@@ -96,33 +190,134 @@ pub fn loader<'b, 'a: 'b>(path: &Path,
                             let normal = normal.unwrap_or([0.0, 0.0, 0.0]);
 
                             vertex_data.push(Vertex::new(position, normal, texture));
+                            if let Some(c) = position_colors.get(v.0) {
+                                colors.push(*c);
+                            }
                         }
                     }
                 }
             }
-            let group_range = group_index..group_index + group_len;
-            let albedo = texture_map.get("Dagger_Albedo.png");
-            let specular = texture_map.get("Dagger_Specular.png");
-            let normal = texture_map.get("Dagger_Normals.png");
-            let gloss = texture_map.get("Dagger_Gloss.png");
-            println!("{:?} {:?} {:?}  {:?}", albedo, specular, normal, gloss);
-
-            let (program_ref, texture) = match (albedo, specular, normal, gloss) {
-                (Some(a), Some(s), Some(n), Some(g)) => {
-                    println!("found some textures");
-                    (&program.ambient_diffuse_bump, Some(TexturePBR::AlbedoSpecularNormalGloss(a, s, n, g)))
-                },
-                _ => (&program.none, None)
-            };
-            groups.push(Group::new(group_range.clone(),
-                                   texture,
-                                   material_map.get("base_material"),
-                                   program_ref,
-                                  build_aabb(&vertex_data[group_range])));
+            groups.push(GroupData {
+                object: object.name.clone(),
+                name: group.name.clone(),
+                range: group_index..group_index + group_len,
+                material: group.material.clone(),
+            });
             group_index += group_len;
         }
     }
-    (vertex_data, groups)
+    Ok(MeshData {
+        vertices: vertex_data,
+        colors: colors,
+        texcoords1: Vec::new(),
+        groups: groups,
+    })
+}
+
+// Base color, normal and packed maps of a material, None unless all three are loaded
+fn packed_texture<'a>(material: &Material,
+                      texture_map: &'a HashMap<String, glium::texture::Texture2d>)
+                      -> Option<TexturePBR<'a>> {
+    let t = &material.textures;
+    if t.packed.is_none() {
+        return None;
+    }
+    match (metallic::lookup(&t.base_color, texture_map),
+           metallic::lookup(&t.normal, texture_map),
+           metallic::lookup(&t.packed, texture_map)) {
+        (Some(a), Some(n), Some(p)) => Some(TexturePBR::AlbedoNormalPacked(a, n, p, t.packed_layout)),
+        _ => {
+            let _ = writeln!(&mut io::stderr(),
+                             "{}: the packed map needs loaded base color, normal and packed maps",
+                             material.name);
+            None
+        }
+    }
+}
+
+fn spec_gloss_texture<'a>(material: &Material,
+                          texture_map: &'a HashMap<String, glium::texture::Texture2d>)
+                          -> Option<TexturePBR<'a>> {
+    let t = &material.textures;
+    if t.gloss.is_none() {
+        return None;
+    }
+    match (metallic::lookup(&t.base_color, texture_map),
+           metallic::lookup(&t.specular, texture_map),
+           metallic::lookup(&t.normal, texture_map),
+           metallic::lookup(&t.gloss, texture_map)) {
+        (Some(a), Some(s), Some(n), Some(g)) => Some(TexturePBR::AlbedoSpecularNormalGloss(a, s, n, g)),
+        _ => {
+            let _ = writeln!(&mut io::stderr(),
+                             "{}: the gloss map needs loaded base color, specular, normal and gloss maps",
+                             material.name);
+            None
+        }
+    }
+}
+
+// A packed map of the material comes first, then the spec/gloss maps.
+// Everything else goes through the metallic/roughness workflow, missing
+// maps are replaced by the factors of the material.
+fn group_texture<'a>(material: Option<&Material>,
+                     texture_map: &'a HashMap<String, glium::texture::Texture2d>)
+                     -> Option<TexturePBR<'a>> {
+    let texture = material.and_then(|m| packed_texture(m, texture_map).or_else(|| spec_gloss_texture(m, texture_map)));
+    if texture.is_some() {
+        return texture;
+    }
+    match (texture_map.get(metallic::DEFAULT_WHITE), material) {
+        (Some(w), Some(m)) => Some(TexturePBR::MetallicRoughness(MetallicRoughness::from_material(w, m, texture_map))),
+        (Some(w), None) => Some(TexturePBR::MetallicRoughness(MetallicRoughness::new(w))),
+        _ => None,
+    }
+}
+
+#[inline]
+pub fn loader<'b, 'a: 'b>(path: &Path,
+                          texture_map: &'a HashMap<String, glium::texture::Texture2d>,
+                          material_map: &'a HashMap<String, Material>,
+                          program: &'a shader::Program)
+                          -> (Vec<Vertex>, Vec<VertexAttributes>, Vec<Group<'b>>) {
+    let mesh = load_mesh(path).unwrap();
+    let attributes = if mesh.has_colors() || mesh.has_texcoords1() {
+        mesh.attributes()
+    } else {
+        Vec::new()
+    };
+    let mut groups = Vec::new();
+
+    for group in mesh.groups.iter() {
+        let group_range = group.range.clone();
+        // Materials of the material map take precedence over the mtl file of the mesh
+        let group_material = group.material
+                                  .as_ref()
+                                  .and_then(|m| material_map.get(&m.name));
+        // Anything else is converted from the mtl file, for the textures as well as the group
+        let converted = match (group_material, group.material.as_ref()) {
+            (None, Some(m)) => Some(Material::from(m)),
+            _ => None,
+        };
+        let texture = group_texture(group_material.or(converted.as_ref()), texture_map);
+        let material = match converted {
+            Some(m) => Some(Cow::Owned(m)),
+            None => group_material.or(material_map.get("base_material")).map(Cow::Borrowed),
+        };
+        let features = Features::for_group(&texture, material.as_ref().map(|m| &**m), mesh.has_colors());
+        let program_ref = match program.get(features) {
+            Ok(p) => p,
+            Err(e) => {
+                let _ = writeln!(&mut io::stderr(), "{}: skipping group {}: {}", path.display(), group.name, e);
+                continue;
+            }
+        };
+        groups.push(Group::new(group_range.clone(),
+                               texture,
+                               material,
+                               program_ref,
+                               build_bounds(&mesh.vertices[group_range])));
+    }
+    (mesh.vertices, attributes, groups)
 }
 
 pub fn image_format(path: &PathBuf) -> Option<image::ImageFormat> {
@@ -143,6 +338,7 @@ pub fn build_texture_map(display: &glium::Display, path: &Path) -> HashMap<Strin
                     .expect("Could not read directory in build_texture_map");
 
     let mut texture_map = HashMap::new();
+    metallic::insert_defaults(display, &mut texture_map);
     for p in paths {
         let path = p.expect("Panic in build_texture_map on path extraction").path();
         // TODO Rework the types to remove this abomination
@@ -164,6 +360,7 @@ pub fn build_texture_map(display: &glium::Display, path: &Path) -> HashMap<Strin
     texture_map
 }
 
+// Reads .mtl files and the .pbrmtl files described in assets::material
 pub fn build_material_map(path: &Path) -> HashMap<String, Material> {
     use std::io::BufReader;
     use obj;
@@ -174,13 +371,23 @@ pub fn build_material_map(path: &Path) -> HashMap<String, Material> {
     let mut material_map = HashMap::new();
     material_map.insert("base_material".into(),
                         Material {
-                            ka: [0.2, 0.0, 0.0],
-                            kd: [0.7, 0.0, 0.0],
-                            ks: [1.0, 1.0, 1.0],
+                            name: "base_material".into(),
+                            ..Material::new([0.2, 0.0, 0.0], [0.7, 0.0, 0.0], [1.0, 1.0, 1.0])
                         });
     for p in paths {
 
         let path = p.expect("Panic in build_texture_map on path extraction").path();
+        if path.extension().and_then(|e| e.to_str()) == Some("pbrmtl") {
+            match material::load(&path) {
+                Ok(materials) => {
+                    for m in materials.into_iter() {
+                        material_map.insert(m.name.clone(), m);
+                    }
+                }
+                Err(e) => println!("Skipping material file {}: {}", path.display(), e),
+            }
+            continue;
+        }
         let file = File::open(path)
                        .expect("Path supplied to build_material_map(path: &Path) was wrong");
 
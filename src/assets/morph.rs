@@ -0,0 +1,128 @@
+use util::graphics::Vertex;
+use util::math::{normalize, sub};
+
+
+// Per vertex offsets of a blend shape, indexed like the vertex data of the asset
+#[derive(Clone, Debug)]
+pub struct MorphTarget {
+    pub name: String,
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+}
+
+impl MorphTarget {
+    pub fn new(name: &str, positions: Vec<[f32; 3]>, normals: Vec<[f32; 3]>) -> MorphTarget {
+        MorphTarget {
+            name: name.to_string(),
+            positions: positions,
+            normals: normals,
+        }
+    }
+
+    // Builds the deltas from a sculpted copy of the base mesh
+    pub fn from_shape(name: &str, base: &[Vertex], shape: &[Vertex]) -> MorphTarget {
+        assert_eq!(base.len(), shape.len());
+        let mut positions = Vec::with_capacity(base.len());
+        let mut normals = Vec::with_capacity(base.len());
+        for (b, s) in base.iter().zip(shape.iter()) {
+            positions.push(sub(s.position, b.position));
+            normals.push(sub(s.normal, b.normal));
+        }
+        MorphTarget::new(name, positions, normals)
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+}
+
+#[inline]
+fn add_scaled(a: &mut [f32; 3], b: [f32; 3], w: f32) {
+    a[0] += b[0] * w;
+    a[1] += b[1] * w;
+    a[2] += b[2] * w;
+}
+
+// Reference blending: base + sum(weight_i * delta_i), normals are renormalized.
+// Targets without weight or with zero weight are skipped.
+pub fn blend(base: &[Vertex], targets: &[MorphTarget], weights: &[f32]) -> Vec<Vertex> {
+    let mut out = base.to_vec();
+    let mut blended = false;
+    for (target, &w) in targets.iter().zip(weights.iter()) {
+        if w == 0.0 {
+            continue;
+        }
+        blended = true;
+        assert_eq!(target.len(), base.len(), "Morph target does not match the vertex count");
+        for (i, v) in out.iter_mut().enumerate() {
+            add_scaled(&mut v.position, target.positions[i], w);
+            if let Some(n) = target.normals.get(i) {
+                add_scaled(&mut v.normal, *n, w);
+            }
+        }
+    }
+    if !blended {
+        return out;
+    }
+    for v in out.iter_mut() {
+        v.normal = normalize(v.normal);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MorphTarget, blend};
+    use util::graphics::Vertex;
+
+    fn base() -> Vec<Vertex> {
+        vec![Vertex::new([0.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 0.0]),
+             Vertex::new([1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [1.0, 0.0])]
+    }
+
+    fn close(a: [f32; 3], b: [f32; 3]) -> bool {
+        (0..3).all(|i| (a[i] - b[i]).abs() < 1e-5)
+    }
+
+    #[test]
+    fn zero_weights_keep_the_base() {
+        let base = base();
+        let target = MorphTarget::new("up", vec![[0.0, 1.0, 0.0]; 2], vec![[1.0, 0.0, 0.0]; 2]);
+        for weights in [vec![], vec![0.0]].iter() {
+            let out = blend(&base, &[target.clone()], weights);
+            for (a, b) in out.iter().zip(base.iter()) {
+                assert_eq!(a.position, b.position);
+                assert_eq!(a.normal, b.normal);
+            }
+        }
+    }
+
+    #[test]
+    fn weighted_deltas_add_up() {
+        let base = base();
+        let up = MorphTarget::new("up", vec![[0.0, 1.0, 0.0]; 2], vec![]);
+        let out = MorphTarget::new("out", vec![[0.0, 0.0, 2.0], [1.0, 0.0, 0.0]], vec![]);
+        let blended = blend(&base, &[up, out], &[0.5, 0.25]);
+        assert!(close(blended[0].position, [0.0, 0.5, 0.5]));
+        assert!(close(blended[1].position, [1.25, 0.5, 0.0]));
+        assert_eq!(blended[0].texture, base[0].texture);
+    }
+
+    #[test]
+    fn blended_normals_are_renormalized() {
+        let base = base();
+        let mut shape = base.clone();
+        for v in shape.iter_mut() {
+            v.normal = [1.0, 0.0, 0.0];
+        }
+        let target = MorphTarget::from_shape("turn", &base, &shape);
+        assert!(close(target.normals[0], [1.0, 0.0, -1.0]));
+        let half = 0.5f32.sqrt();
+        for v in blend(&base, &[target.clone()], &[0.5]).iter() {
+            assert!(close(v.normal, [half, 0.0, half]));
+        }
+        for v in blend(&base, &[target], &[1.0]).iter() {
+            assert!(close(v.normal, [1.0, 0.0, 0.0]));
+        }
+    }
+}
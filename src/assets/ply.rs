@@ -0,0 +1,380 @@
+// Standard Library
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::path::Path;
+use std::str::SplitWhitespace;
+
+use assets::{GroupData, MeshData};
+use util::graphics::Vertex;
+
+
+// Stanford PLY meshes in ascii or binary encoding. Vertices may carry normals,
+// a uv set (s/t, u/v or texture_u/texture_v) and colors (red/green/blue/alpha,
+// integer colors are scaled to [0, 1]). Polygons are triangulated as fans and
+// the mesh becomes a single group without material.
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Format {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Option<Scalar> {
+        match name {
+            "char" | "int8" => Some(Scalar::I8),
+            "uchar" | "uint8" => Some(Scalar::U8),
+            "short" | "int16" => Some(Scalar::I16),
+            "ushort" | "uint16" => Some(Scalar::U16),
+            "int" | "int32" => Some(Scalar::I32),
+            "uint" | "uint32" => Some(Scalar::U32),
+            "float" | "float32" => Some(Scalar::F32),
+            "double" | "float64" => Some(Scalar::F64),
+            _ => None,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match *self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+
+    // Colors stored as integers are divided by the largest value of their type
+    fn color_scale(&self) -> f64 {
+        match *self {
+            Scalar::I8 => 127.0,
+            Scalar::U8 => 255.0,
+            Scalar::I16 => 32767.0,
+            Scalar::U16 => 65535.0,
+            Scalar::I32 => 2147483647.0,
+            Scalar::U32 => 4294967295.0,
+            Scalar::F32 | Scalar::F64 => 1.0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Kind {
+    Scalar(Scalar),
+    // Type of the item count and of the items
+    List(Scalar, Scalar),
+}
+
+struct Property {
+    name: String,
+    kind: Kind,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn parse_header(header: &str) -> io::Result<(Format, Vec<Element>)> {
+    let mut lines = header.lines();
+    if lines.next().map(|l| l.trim()) != Some("ply") {
+        return Err(invalid("missing ply magic".to_string()));
+    }
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.first().cloned() {
+            Some("format") if words.len() >= 2 => {
+                format = match words[1] {
+                    "ascii" => Some(Format::Ascii),
+                    "binary_little_endian" => Some(Format::LittleEndian),
+                    "binary_big_endian" => Some(Format::BigEndian),
+                    f => return Err(invalid(format!("unknown format {}", f))),
+                };
+            }
+            Some("element") if words.len() >= 3 => {
+                let count = try!(words[2].parse().map_err(|_| invalid(format!("bad element count in '{}'", line))));
+                elements.push(Element {
+                    name: words[1].to_string(),
+                    count: count,
+                    properties: Vec::new(),
+                });
+            }
+            Some("property") => {
+                let scalar = |name: &str| Scalar::parse(name).ok_or(invalid(format!("unknown type {}", name)));
+                let (kind, name) = if words.len() >= 5 && words[1] == "list" {
+                    (Kind::List(try!(scalar(words[2])), try!(scalar(words[3]))), words[4])
+                } else if words.len() >= 3 {
+                    (Kind::Scalar(try!(scalar(words[1]))), words[2])
+                } else {
+                    return Err(invalid(format!("bad property '{}'", line)));
+                };
+                match elements.last_mut() {
+                    Some(e) => {
+                        e.properties.push(Property {
+                            name: name.to_string(),
+                            kind: kind,
+                        })
+                    }
+                    None => return Err(invalid("property outside of an element".to_string())),
+                }
+            }
+            _ => {}
+        }
+    }
+    match format {
+        Some(f) => Ok((f, elements)),
+        None => Err(invalid("missing format".to_string())),
+    }
+}
+
+// Values of the body, either whitespace separated text or packed binary
+enum Source<'a> {
+    Ascii(SplitWhitespace<'a>),
+    Binary(&'a [u8], bool),
+}
+
+impl<'a> Source<'a> {
+    fn read(&mut self, scalar: Scalar) -> io::Result<f64> {
+        let eof = || invalid("unexpected end of file".to_string());
+        match *self {
+            Source::Ascii(ref mut words) => {
+                let word = try!(words.next().ok_or(eof()));
+                word.parse::<f64>().map_err(|_| invalid(format!("bad value {}", word)))
+            }
+            Source::Binary(ref mut data, big_endian) => {
+                let size = scalar.size();
+                if data.len() < size {
+                    return Err(eof());
+                }
+                let mut b = [0u8; 8];
+                b[..size].copy_from_slice(&data[..size]);
+                if big_endian {
+                    b[..size].reverse();
+                }
+                let rest: &'a [u8] = *data;
+                *data = &rest[size..];
+                let u16_ = b[0] as u16 | (b[1] as u16) << 8;
+                let u32_ = b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24;
+                let u64_ = u32_ as u64 | (b[4] as u64) << 32 | (b[5] as u64) << 40 | (b[6] as u64) << 48 |
+                           (b[7] as u64) << 56;
+                Ok(match scalar {
+                    Scalar::I8 => b[0] as i8 as f64,
+                    Scalar::U8 => b[0] as f64,
+                    Scalar::I16 => u16_ as i16 as f64,
+                    Scalar::U16 => u16_ as f64,
+                    Scalar::I32 => u32_ as i32 as f64,
+                    Scalar::U32 => u32_ as f64,
+                    Scalar::F32 => unsafe { mem::transmute::<u32, f32>(u32_) as f64 },
+                    Scalar::F64 => unsafe { mem::transmute::<u64, f64>(u64_) },
+                })
+            }
+        }
+    }
+}
+
+pub fn load(path: &Path) -> io::Result<MeshData> {
+    let mut bytes = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut bytes));
+    let name = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or(String::new());
+    parse(&bytes, &name)
+}
+
+pub fn parse(bytes: &[u8], name: &str) -> io::Result<MeshData> {
+    let marker = b"end_header";
+    let end = try!(bytes.windows(marker.len())
+                        .position(|w| w == &marker[..])
+                        .ok_or(invalid("missing end_header".to_string())));
+    let body_start = bytes[end..].iter().position(|&b| b == b'\n').map(|i| end + i + 1).unwrap_or(bytes.len());
+    let header = String::from_utf8_lossy(&bytes[..end]);
+    let (format, elements) = try!(parse_header(&header));
+
+    let body = &bytes[body_start..];
+    let mut source = match format {
+        Format::Ascii => {
+            let text = try!(::std::str::from_utf8(body).map_err(|_| invalid("body is not text".to_string())));
+            Source::Ascii(text.split_whitespace())
+        }
+        Format::LittleEndian => Source::Binary(body, false),
+        Format::BigEndian => Source::Binary(body, true),
+    };
+
+    let mut vertices: Vec<Vertex> = Vec::new();
+    let mut vertex_colors: Vec<[f32; 4]> = Vec::new();
+    let mut has_colors = false;
+    let mut faces: Vec<Vec<usize>> = Vec::new();
+    for element in elements.iter() {
+        let is_vertex = element.name == "vertex";
+        let is_face = element.name == "face";
+        if is_vertex {
+            has_colors = element.properties.iter().any(|p| p.name == "red");
+        }
+        for _ in 0..element.count {
+            let mut v = Vertex::empty();
+            let mut color = [1.0; 4];
+            let mut indices = Vec::new();
+            for property in element.properties.iter() {
+                match property.kind {
+                    Kind::Scalar(scalar) => {
+                        let value = try!(source.read(scalar));
+                        if !is_vertex {
+                            continue;
+                        }
+                        let c = (value / scalar.color_scale()) as f32;
+                        match &property.name[..] {
+                            "x" => v.position[0] = value as f32,
+                            "y" => v.position[1] = value as f32,
+                            "z" => v.position[2] = value as f32,
+                            "nx" => v.normal[0] = value as f32,
+                            "ny" => v.normal[1] = value as f32,
+                            "nz" => v.normal[2] = value as f32,
+                            "s" | "u" | "texture_u" => v.texture[0] = value as f32,
+                            "t" | "v" | "texture_v" => v.texture[1] = value as f32,
+                            "red" => color[0] = c,
+                            "green" => color[1] = c,
+                            "blue" => color[2] = c,
+                            "alpha" => color[3] = c,
+                            _ => {}
+                        }
+                    }
+                    Kind::List(count, item) => {
+                        let n = try!(source.read(count)) as usize;
+                        let keep = is_face && (property.name == "vertex_indices" || property.name == "vertex_index");
+                        for _ in 0..n {
+                            let index = try!(source.read(item));
+                            if keep {
+                                indices.push(index as usize);
+                            }
+                        }
+                    }
+                }
+            }
+            if is_vertex {
+                vertices.push(v);
+                vertex_colors.push(color);
+            } else if is_face {
+                faces.push(indices);
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    let mut colors = Vec::new();
+    for face in faces.iter() {
+        if let Some(&i) = face.iter().find(|&&i| i >= vertices.len()) {
+            return Err(invalid(format!("face references vertex {} of {}", i, vertices.len())));
+        }
+        for i in 1..face.len().saturating_sub(1) {
+            for &index in [face[0], face[i], face[i + 1]].iter() {
+                out.push(vertices[index]);
+                if has_colors {
+                    colors.push(vertex_colors[index]);
+                }
+            }
+        }
+    }
+
+    let count = out.len();
+    Ok(MeshData {
+        vertices: out,
+        colors: colors,
+        texcoords1: Vec::new(),
+        groups: vec![GroupData {
+                         object: name.to_string(),
+                         name: "default".to_string(),
+                         range: 0..count,
+                         material: None,
+                     }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use super::parse;
+
+    const ASCII: &'static str = "ply
+format ascii 1.0
+comment a colored quad
+element vertex 4
+property float x
+property float y
+property float z
+property float s
+property float t
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 0 0 255 0 0
+1 0 0 1 0 0 255 0
+1 1 0 1 1 0 0 255
+0 1 0 0 1 255 255 255
+4 0 1 2 3
+";
+
+    #[test]
+    fn ascii_quads_become_colored_triangles() {
+        let mesh = parse(ASCII.as_bytes(), "quad").unwrap();
+        assert_eq!(mesh.vertices.len(), 6);
+        assert_eq!(mesh.colors.len(), 6);
+        assert_eq!(mesh.groups[0].range, 0..6);
+        assert_eq!(mesh.vertices[4].position, [1.0, 1.0, 0.0]);
+        assert_eq!(mesh.vertices[4].texture, [1.0, 1.0]);
+        assert_eq!(mesh.colors[1], [0.0, 1.0, 0.0, 1.0]);
+        assert_eq!(mesh.colors[5], [1.0, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn binary_matches_ascii() {
+        let mut bytes = b"ply\nformat binary_big_endian 1.0\nelement vertex 3\nproperty float x\n\
+                          property float y\nproperty float z\nproperty ushort red\n\
+                          element face 1\nproperty list uchar uint vertex_indices\nend_header\n"
+                            .to_vec();
+        for (i, p) in [[0.0f32, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]].iter().enumerate() {
+            for x in p.iter() {
+                let b: u32 = unsafe { mem::transmute(*x) };
+                bytes.extend_from_slice(&[(b >> 24) as u8, (b >> 16) as u8, (b >> 8) as u8, b as u8]);
+            }
+            let red = [0u16, 65535, 32768][i];
+            bytes.extend_from_slice(&[(red >> 8) as u8, red as u8]);
+        }
+        bytes.extend_from_slice(&[3, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 1]);
+        let mesh = parse(&bytes, "triangle").unwrap();
+        assert_eq!(mesh.vertices.len(), 3);
+        assert_eq!(mesh.vertices[1].position, [0.0, 1.0, 0.0]);
+        assert_eq!(mesh.colors[2][0], 1.0);
+        assert_eq!(mesh.colors[1][1], 1.0);
+        assert!((mesh.colors[1][0] - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn rejects_broken_files() {
+        assert!(parse(b"ply\nend_header\n", "x").is_err());
+        assert!(parse(ASCII.replace("4 0 1 2 3", "3 0 1 7").as_bytes(), "x").is_err());
+        assert!(parse(ASCII.replace("4 0 1 2 3\n", "").as_bytes(), "x").is_err());
+    }
+}
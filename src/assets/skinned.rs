@@ -0,0 +1,210 @@
+// Standard Library
+use std::borrow::Cow;
+use std::rc::Rc;
+
+// External libraries
+use glium;
+use collision::Aabb3;
+use cgmath::*;
+
+// Importing modules of this project
+use assets::group::Group;
+use assets::build_bounds;
+use animation::{Clip, Pose, Skeleton};
+use shader::{self, ShaderError};
+use shader::permutation::{Features, SKINNING};
+use util::graphics::{SkinnedVertex, JointMatrices, Material, TexturePBR, BaseUniform};
+
+
+pub struct SkinnedAsset<'a> {
+    name: String,
+    volume: Aabb3<f32>,
+    vbo: glium::vertex::VertexBuffer<SkinnedVertex>,
+    prim_type: glium::index::PrimitiveType,
+    param: glium::DrawParameters<'a>,
+    group: Vec<Group<'a>>,
+    skeleton: Skeleton,
+    clips: Vec<Clip>,
+}
+
+impl<'a> SkinnedAsset<'a> {
+    pub fn get_volume(&self) -> &Aabb3<f32> {
+        &self.volume
+    }
+    pub fn get_groups(&self) -> &[Group] {
+        &self.group[..]
+    }
+    pub fn skeleton(&self) -> &Skeleton {
+        &self.skeleton
+    }
+    pub fn clips(&self) -> &[Clip] {
+        &self.clips[..]
+    }
+    pub fn find_clip(&self, name: &str) -> Option<usize> {
+        self.clips.iter().position(|c| c.name == name)
+    }
+
+    #[inline]
+    pub fn draw<S>(&self,
+                   target: &mut S,
+                   display: &glium::Display,
+                   uniforms: BaseUniform,
+                   pose: &Pose)
+        where S: glium::Surface
+    {
+        let matrices = JointMatrices::new(&self.skeleton.skinning_matrices(pose));
+        let joints = glium::uniforms::UniformBuffer::new(display, matrices).unwrap();
+        for g in self.group.iter() {
+            g.draw_skinned(target,
+                           uniforms,
+                           display,
+                           &joints,
+                           self.vbo.slice(g.get_range()).unwrap(),
+                           &self.param,
+                           &self.prim_type);
+        }
+    }
+}
+
+pub struct SkinnedAssetLoader<'a> {
+    name: String,
+    display: &'a glium::Display,
+    vertex_data: Vec<SkinnedVertex>,
+    group: Vec<Group<'a>>,
+    param: Option<glium::DrawParameters<'a>>,
+    volume: Aabb3<f32>,
+    skeleton: Skeleton,
+    clips: Vec<Clip>,
+}
+
+impl<'a> SkinnedAssetLoader<'a> {
+    // Picks the SKINNING permutation for the material and texture of the mesh
+    pub fn new(display: &'a glium::Display,
+               name: String,
+               vertex_data: Vec<SkinnedVertex>,
+               skeleton: Skeleton,
+               material: &'a Material,
+               texture: Option<TexturePBR<'a>>,
+               program: &'a shader::Program)
+               -> Result<SkinnedAssetLoader<'a>, ShaderError> {
+        let features = Features::for_group(&texture, Some(material), false) | SKINNING;
+        let program = try!(program.get(features));
+        Ok(SkinnedAssetLoader::custom(display, name, vertex_data, skeleton, material, texture, program))
+    }
+
+    pub fn custom(display: &'a glium::Display,
+                  name: String,
+                  vertex_data: Vec<SkinnedVertex>,
+                  skeleton: Skeleton,
+                  material: &'a Material,
+                  texture: Option<TexturePBR<'a>>,
+                  program: Rc<glium::Program>)
+                  -> SkinnedAssetLoader<'a> {
+        // The volume is taken from the bind pose
+        let bounds = build_bounds(&vertex_data);
+        let vol = bounds.aabb;
+        let range = 0..vertex_data.len();
+        SkinnedAssetLoader {
+            name: name,
+            display: display,
+            volume: vol.clone(),
+            vertex_data: vertex_data,
+            group: vec![Group::new(range, texture, Some(Cow::Borrowed(material)), program, bounds)],
+            param: None,
+            skeleton: skeleton,
+            clips: Vec::new(),
+        }
+    }
+
+    pub fn clip(mut self, clip: Clip) -> SkinnedAssetLoader<'a> {
+        self.clips.push(clip);
+        self
+    }
+    pub fn param(mut self, param: glium::DrawParameters<'a>) -> SkinnedAssetLoader<'a> {
+        self.param = Some(param);
+        self
+    }
+
+    pub fn load(self) -> SkinnedAsset<'a> {
+        let param = self.param.unwrap_or(glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::draw_parameters::DepthTest::IfLessOrEqual,
+                write: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        SkinnedAsset {
+            name: self.name,
+            volume: self.volume,
+            vbo: glium::vertex::VertexBuffer::immutable(self.display, &self.vertex_data).unwrap(),
+            prim_type: glium::index::PrimitiveType::TrianglesList,
+            param: param,
+            group: self.group,
+            skeleton: self.skeleton,
+            clips: self.clips,
+        }
+    }
+}
+
+// Placement of a skinned asset together with its playback state
+pub struct SkinnedInstance<'b, 'a: 'b> {
+    asset: &'b SkinnedAsset<'a>,
+    to_world: Matrix4<f32>,
+    clip: Option<usize>,
+    time: f32,
+    speed: f32,
+    looping: bool,
+}
+
+impl<'b, 'a: 'b> SkinnedInstance<'b, 'a> {
+    pub fn new(asset: &'b SkinnedAsset<'a>) -> SkinnedInstance<'b, 'a> {
+        SkinnedInstance {
+            asset: asset,
+            to_world: Matrix4::one(),
+            clip: None,
+            time: 0.0,
+            speed: 1.0,
+            looping: true,
+        }
+    }
+
+    pub fn set_to_world(&mut self, to_world: Matrix4<f32>) {
+        self.to_world = to_world;
+    }
+    pub fn get_to_world(&self) -> &Matrix4<f32> {
+        &self.to_world
+    }
+
+    // Starts the named clip from the beginning, returns false if it does not exist
+    pub fn play(&mut self, name: &str, looping: bool) -> bool {
+        self.clip = self.asset.find_clip(name);
+        self.time = 0.0;
+        self.looping = looping;
+        self.clip.is_some()
+    }
+    pub fn stop(&mut self) {
+        self.clip = None;
+    }
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed;
+    }
+
+    // Advances the playback by `dt` seconds
+    pub fn update(&mut self, dt: f32) {
+        self.time += dt * self.speed;
+    }
+
+    pub fn pose(&self) -> Pose {
+        match self.clip {
+            Some(c) => self.asset.clips[c].sample(&self.asset.skeleton, self.time, self.looping),
+            None => self.asset.skeleton.rest_pose(),
+        }
+    }
+
+    pub fn draw<S>(&self, target: &mut S, display: &glium::Display, uniforms: BaseUniform)
+        where S: glium::Surface
+    {
+        self.asset.draw(target, display, uniforms, &self.pose());
+    }
+}
@@ -0,0 +1,30 @@
+extern crate pbr;
+
+use std::env;
+use std::path::Path;
+use std::process;
+
+use pbr::texture::hdr;
+use pbr::texture::ibl::BrdfLut;
+
+// pbr-brdf --lut <out.hdr> [size] writes the split sum table with the scale
+// in red and the bias in green. The BRDF, the table and the multiple
+// scattering compensation are tested in shader::brdf, texture::ibl and
+// texture::multiscatter.
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 || args[1] != "--lut" {
+        println!("usage: pbr-brdf --lut <out.hdr> [size]");
+        process::exit(1);
+    }
+    let size = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(64);
+    let lut = BrdfLut::bake(size, 1024);
+    match hdr::save(Path::new(&args[2]), &lut.to_hdr()) {
+        Ok(()) => println!("Wrote {}x{} split sum table to {}", size, size, args[2]),
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+extern crate pbr;
+
+use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+
+use pbr::texture::convert::{self, ConvertError, GlossEncoding, MetalRough, RoundTrip, SpecGloss};
+use pbr::texture::pack::ChannelLayout;
+
+fn usage() -> ! {
+    println!("Usage: pbr-convert [options] to-metal <albedo> <specular> <gloss> <base_color_out> <packed_out>");
+    println!("       pbr-convert [options] to-spec <base_color> <packed> <albedo_out> <specular_out> <gloss_out>");
+    println!("Converts between specular/glossiness and metallic/roughness textures");
+    println!("and reports the per pixel error of converting back.");
+    println!("");
+    println!("Options:");
+    println!("  --glossiness    the gloss texture stores glossiness instead of roughness");
+    println!("  --error <file>  writes the largest channel error of every pixel");
+    process::exit(2);
+}
+
+fn report(round_trip: &RoundTrip, error_image: &Option<String>) -> Result<(), ConvertError> {
+    println!("round trip error: max {:.4}, mean {:.4}, rmse {:.4}",
+             round_trip.max,
+             round_trip.mean,
+             round_trip.rmse);
+    if let Some(ref f) = *error_image {
+        try!(round_trip.image.save(Path::new(f)).map_err(|e| ConvertError::Save(format!("{}: {}", f, e))));
+    }
+    Ok(())
+}
+
+fn run(args: &[String], encoding: GlossEncoding, error_image: &Option<String>) -> Result<(), ConvertError> {
+    let p = |i: usize| Path::new(&args[i]);
+    if args[0] == "to-metal" && args.len() == 6 {
+        let input = try!(SpecGloss::load(p(1), p(2), p(3)));
+        let output = try!(input.to_metal_rough(encoding, ChannelLayout::default()));
+        try!(output.save(p(4), p(5)));
+        let (_, round_trip) = try!(convert::round_trip_spec_gloss(&input, encoding, ChannelLayout::default()));
+        report(&round_trip, error_image)
+    } else if args[0] == "to-spec" && args.len() == 6 {
+        let input = try!(MetalRough::load(p(1), p(2), ChannelLayout::default()));
+        let output = try!(input.to_spec_gloss(encoding));
+        try!(output.save(p(3), p(4), p(5)));
+        let (_, round_trip) = try!(convert::round_trip_metal_rough(&input, encoding));
+        report(&round_trip, error_image)
+    } else {
+        usage()
+    }
+}
+
+fn main() {
+    let mut encoding = GlossEncoding::Roughness;
+    let mut error_image = None;
+    let mut args = Vec::new();
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match &arg[..] {
+            "--glossiness" => encoding = GlossEncoding::Glossiness,
+            "--error" => {
+                match iter.next() {
+                    Some(f) => error_image = Some(f),
+                    None => usage(),
+                }
+            }
+            "-h" | "--help" => usage(),
+            _ => args.push(arg),
+        }
+    }
+    if args.is_empty() {
+        usage();
+    }
+
+    if let Err(e) = run(&args, encoding, &error_image) {
+        let _ = writeln!(&mut io::stderr(), "pbr-convert: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,54 @@
+extern crate pbr;
+
+use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+
+use pbr::assets::info;
+
+fn usage() -> ! {
+    println!("Usage: pbr-info [--json] <mesh.obj>...");
+    println!("Prints vertex/triangle counts, groups, materials, texture slots,");
+    println!("bounds and validation warnings without opening a window.");
+    process::exit(2);
+}
+
+fn main() {
+    let mut json = false;
+    let mut files = Vec::new();
+    for arg in env::args().skip(1) {
+        match &arg[..] {
+            "--json" => json = true,
+            "-h" | "--help" => usage(),
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        usage();
+    }
+
+    let mut failed = false;
+    let mut reports = Vec::new();
+    for f in files.iter() {
+        match info::inspect(Path::new(f)) {
+            Ok(r) => reports.push(r),
+            Err(e) => {
+                let _ = writeln!(&mut io::stderr(), "{}: {}", f, e);
+                failed = true;
+            }
+        }
+    }
+
+    if json {
+        let body: Vec<String> = reports.iter().map(|r| r.to_json()).collect();
+        println!("[{}]", body.join(", "));
+    } else {
+        for r in reports.iter() {
+            print!("{}", r.to_text());
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,78 @@
+extern crate pbr;
+
+use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+
+use pbr::shader::Sources;
+use pbr::shader::glsl::Diagnostic;
+use pbr::shader::permutation::Features;
+use pbr::shader::registry;
+
+fn usage() -> ! {
+    println!("Usage: pbr-shaders [<shader dir>]...");
+    println!("Checks the built-in shaders and every <name>.vert/<name>.frag pair in the");
+    println!("given directories with a GLSL front end, for every feature permutation.");
+    println!("No GPU is needed. Exits with 1 if any shader has errors.");
+    process::exit(2);
+}
+
+// Checks all permutations of a program, a diagnostic is printed once with the
+// first feature set that shows it. Returns the number of diagnostics.
+fn check(name: &str, sources: &Sources, includes: &[(String, String)]) -> usize {
+    let mut seen: Vec<Diagnostic> = Vec::new();
+    for features in Features::combinations() {
+        let diagnostics = match sources.validate(includes, features) {
+            Ok(d) => d,
+            Err(e) => {
+                println!("{}: {}", name, e);
+                return seen.len() + 1;
+            }
+        };
+        for d in diagnostics {
+            if !seen.contains(&d) {
+                println!("{}: {} (with {:?})", name, d, features.names());
+                seen.push(d);
+            }
+        }
+    }
+    seen.len()
+}
+
+fn main() {
+    let mut dirs = Vec::new();
+    for arg in env::args().skip(1) {
+        match &arg[..] {
+            "-h" | "--help" => usage(),
+            _ => dirs.push(arg),
+        }
+    }
+
+    let mut errors = 0;
+    let mut programs = 0;
+    for sources in Sources::builtin() {
+        let name = format!("{}+{}", sources.vertex_name, sources.fragment_name);
+        errors += check(&name, &sources, &[]);
+        programs += 1;
+    }
+    for dir in dirs.iter() {
+        let (found, includes, scan_errors) = registry::scan(Path::new(dir));
+        for e in scan_errors.iter() {
+            let _ = writeln!(&mut io::stderr(), "pbr-shaders: {}", e);
+        }
+        errors += scan_errors.len();
+        for (name, sources) in found {
+            errors += check(&name, &sources, &includes);
+            programs += 1;
+        }
+    }
+
+    println!("{} programs, {} permutations each, {} errors",
+             programs,
+             Features::combinations().len(),
+             errors);
+    if errors > 0 {
+        process::exit(1);
+    }
+}
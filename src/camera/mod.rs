@@ -65,6 +65,10 @@ impl Camera {
         }
     }
 
+    pub fn position(&self) -> Point3<f32> {
+        Point3::new(self.position.x, self.position.y, self.position.z)
+    }
+
     pub fn set_position(&mut self, position: (f32, f32, f32)) {
         self.position = Vector3::from(position);
     }
@@ -0,0 +1,22 @@
+#![allow(dead_code)]
+#[macro_use]
+extern crate glium;
+extern crate genmesh;
+extern crate obj;
+extern crate collision;
+extern crate cgmath;
+extern crate image;
+extern crate time;
+extern crate rand;
+extern crate rustc_serialize;
+
+//Modules of this project
+pub mod shader;
+pub mod assets;
+pub mod util;
+pub mod camera;
+pub mod accelerator;
+pub mod scene;
+pub mod texture;
+pub mod animation;
+pub mod shadow;
@@ -1,31 +1,22 @@
 #![allow(dead_code)]
-#[macro_use]
 extern crate glium;
-extern crate genmesh;
-extern crate obj;
-extern crate collision;
 extern crate cgmath;
-extern crate image;
-extern crate time;
-extern crate rand;
-
-//Modules of this project
-mod shader;
-mod assets;
-mod util;
-mod camera;
-mod accelerator;
-mod scene;
+extern crate pbr;
+
+use pbr::{shader, assets, util, camera};
+use pbr::shadow::{ShadowMaps, ShadowSettings};
+use pbr::texture::{hdr, ibl, multiscatter};
 
 use glium::Surface;
 use glium::glutin::{Event, ElementState, VirtualKeyCode};
 use cgmath::*;
 
 use assets::{Drawable, asset, instance};
-use util::graphics::BaseUniform;
+use util::graphics::{BaseUniform, DirectionalLight};
 
 fn main() {
     use glium::{DisplayBuild, Surface};
+    use std::env;
     use std::path::Path;
 
     use cgmath::*;
@@ -47,26 +38,62 @@ fn main() {
     let dagger_path = Path::new("/home/robert/Projects/rust/pbr/src/resource/test/Dagger.obj");
     let texture_path = Path::new("/home/robert/Projects/rust/pbr/src/resource/texture");
     let material_path = Path::new("/home/robert/Projects/rust/pbr/src/resource/mtl");
+    let shader_path = Path::new("/home/robert/Projects/rust/pbr/src/resource/shader");
 
     let texture_map = assets::build_texture_map(&display, texture_path);
 
     let material_map = assets::build_material_map(material_path);
 
+    // An equirectangular .hdr given on the command line adds ambient light
+    let mut environment = match env::args().nth(1) {
+        Some(path) => {
+            match hdr::load(Path::new(&path)) {
+                Ok(image) => {
+                    println!("Baking {}", path);
+                    let maps = ibl::IblBaker::new().bake(&image);
+                    Some(ibl::Environment::upload(&display, &maps))
+                }
+                Err(e) => {
+                    println!("{}", e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut energy = multiscatter::EnergyCompensation::upload(&display, &multiscatter::bake(32, 256));
+
+    let mut shadows = match ShadowMaps::new(&display, ShadowSettings::new()) {
+        Ok(shadows) => Some(shadows),
+        Err(e) => {
+            println!("{}", e);
+            None
+        }
+    };
+    let mut sun = DirectionalLight::new([0.0, 20.0, 0.0], [-0.3, -1.0, -0.4], [0.8, 0.8, 0.75]);
+    sun.set_cast_shadows(true);
 
-    println!("Creating Program map");
-    let program_map = shader::program_map(&display);
+    println!("Creating Program registry");
+    let (programs, shader_errors) = shader::registry::Registry::load(&display, shader_path);
+    for e in shader_errors.iter() {
+        println!("{}", e);
+    }
 
 
     println!("\nStarting AssetLoader");
 
-    let dagger = asset::AssetLoader::<f32>::new(&display, dagger_path, &texture_map, &material_map, program_map.get("cooktorrance").unwrap()).load();
+    let dagger = asset::AssetLoader::<f32>::new(&display, dagger_path, &texture_map, &material_map, programs.get("cooktorrance").unwrap()).load();
     let mut dagger_instance = instance::InstanceLoader::new(&dagger).load();
 
     let mut entity = instance::Entity::new();
-    let mut lights = util::graphics::Lights::default();
+    let mut lights = util::graphics::Lights::three_point();
+    // Lights added with 3, removed newest first with 4
+    let mut added = Vec::new();
     let mut camera = camera::Camera::new();
     let mut t:f32 = 0.0;
-    let mut ior = 0.72;
+    // Materials use their own reflectance until 1 or 2 overrides it, 0 goes back
+    let mut ior: Option<f32> = None;
     let two_pi:f32 = 2.0 * 3.14159265358979323846264338;
     util::start_loop(|| {
     // Passed parameter represents movespeed
@@ -76,25 +103,44 @@ fn main() {
             let perspective = camera.perspective();
             let view = camera.view();
 
+            if let Some(ref mut shadows) = shadows {
+                shadows.render(&display, &lights, Some(&sun), &camera, |target, program, pass| {
+                    dagger_instance.draw_depth(target, &display, program, pass);
+                });
+            }
+
             let mut target = display.draw();
             target.clear_color_and_depth((1.0,1.0,1.0,1.0), 1.0);
 
             let rotation = cgmath::Matrix3::from_angle_y(Rad{s: t});
 
             //let model = &cgmath::Matrix4::from(rotation) * dagger_instance.get_to_world();
-            let model = dagger_instance.get_to_world();
-            let model_view = &view * model;
-            let model_view_perspective = &perspective * &model_view;
-
-            let uniform = BaseUniform::new(
-                &model,
-                &model_view,
-                &model_view_perspective,
-                util::math::from_mat4(&model_view),
-                lights,
-                ior,
-            );
-            dagger_instance.draw(&mut target, &display, uniform);
+            // One pass per block of MAX_LIGHTS lights
+            let blocks = lights.blocks();
+            let (environment, shadows, energy) = (environment.as_ref(), shadows.as_ref(), &energy);
+            instance::draw_sorted(&mut target, &display, &[&dagger_instance], camera.position(), |i| {
+                let model = i.get_to_world();
+                let model_view = &view * model;
+                let model_view_perspective = &perspective * &model_view;
+                blocks.iter().enumerate().map(|(pass, block)| {
+                    let mut uniform = BaseUniform::new(
+                        model,
+                        &model_view,
+                        &model_view_perspective,
+                        util::math::from_mat4(&model_view),
+                        *block,
+                        pass,
+                        ior,
+                    ).with_energy_compensation(energy).with_sun(&sun, &view);
+                    if let Some(environment) = environment {
+                        uniform = uniform.with_environment(environment, &view);
+                    }
+                    if let Some(shadows) = shadows {
+                        uniform = uniform.with_shadows(shadows);
+                    }
+                    uniform
+                }).collect()
+            });
             if t > two_pi {
                 t = 0.0
             } else {
@@ -106,16 +152,58 @@ fn main() {
             match event {
                 Event::Closed => return util::Action::Stop,
                 Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Key1)) => {
-                    ior += 0.05;
+                    ior = Some((ior.unwrap_or(0.72) + 0.05).min(1.0));
+                    println!("reflectance {:?}", ior);
                 }
                 Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Key2)) => {
-                    ior -= 0.05;
+                    ior = Some((ior.unwrap_or(0.72) - 0.05).max(0.0));
+                    println!("reflectance {:?}", ior);
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Key0)) => {
+                    ior = None;
+                    println!("reflectance of the materials");
                 }
                 Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Space)) => {
-                    lights = util::graphics::Lights::gen_random();
+                    lights = util::graphics::Lights::gen_random(5);
+                    added.clear();
                 }
                 Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::X)) => {
-                    lights = util::graphics::Lights::default();
+                    lights = util::graphics::Lights::three_point();
+                    added.clear();
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Key3)) => {
+                    let random = util::graphics::Lights::gen_random(1).to_vec();
+                    added.push(lights.add(random[0]));
+                    println!("{} lights", lights.len());
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Key4)) => {
+                    if let Some(id) = added.pop() {
+                        lights.remove(id);
+                    }
+                    println!("{} lights", lights.len());
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Key5)) => {
+                    if let Some(ref mut environment) = environment {
+                        environment.intensity += 0.1;
+                    }
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Key6)) => {
+                    if let Some(ref mut environment) = environment {
+                        environment.intensity = (environment.intensity - 0.1).max(0.0);
+                    }
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Key7)) => {
+                    if let Some(ref mut environment) = environment {
+                        environment.diffuse_sh = !environment.diffuse_sh;
+                    }
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Key8)) => {
+                    energy.enabled = !energy.enabled;
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Key9)) => {
+                    if let Some(ref mut shadows) = shadows {
+                        shadows.enabled = !shadows.enabled;
+                    }
                 }
                 x => {
                     //TODO move this into one function
@@ -8,9 +8,10 @@ use cgmath::{Vector3, Rad};
 use rand;
 use time;
 
-use assets::{asset, instance};
+use assets::{asset, instance, Pass};
 use camera;
-use util::graphics::Lights;
+use util::math;
+use util::graphics::{BaseUniform, LightId, Lights, PointLight};
 
 pub enum Action {
     Stop,
@@ -86,12 +87,48 @@ impl<'b, 'a: 'b> Scene<'b, 'a> {
             instances: instance_list,
             entity: 0,
             camera: RefCell::new(camera::Camera2::new(0.01, 0.01)),
-            lights: RefCell::new(Lights::gen_random()),
+            lights: RefCell::new(Lights::gen_random(5)),
         }
     }
+    pub fn add_light(&self, light: PointLight) -> LightId {
+        self.lights.borrow_mut().add(light)
+    }
+
+    pub fn remove_light(&self, id: LightId) -> Option<PointLight> {
+        self.lights.borrow_mut().remove(id)
+    }
+
     pub fn instances(&self) -> &[instance::AssetInstance2<'b, 'a>] {
         &self.instances[..]
     }
+
+    // Draws every instance once per block of Lights::blocks, lights added
+    // or removed show up in the next frame. All light passes of the opaque
+    // groups are drawn before the blended groups.
+    pub fn draw<S>(&self, target: &mut S, display: &glium::Display)
+        where S: glium::Surface
+    {
+        let view = self.camera_view();
+        let perspective = self.camera_perspective();
+        let blocks = self.lights.borrow().blocks();
+        for &pass in [Pass::Opaque, Pass::Blend].iter() {
+            for i in self.instances.iter() {
+                let model = i.get_to_world();
+                let model_view = &view * &model;
+                let model_view_perspective = &perspective * &model_view;
+                for (light_pass, block) in blocks.iter().enumerate() {
+                    let uniforms = BaseUniform::new(&model,
+                                                    &model_view,
+                                                    &model_view_perspective,
+                                                    math::from_mat4(&model_view),
+                                                    *block,
+                                                    light_pass,
+                                                    None);
+                    i.draw_pass(target, display, uniforms, pass);
+                }
+            }
+        }
+    }
     pub fn process_input2(&self, event: Event) {
         const RAD0: Rad<f32> = Rad { s: 0.5 * f32::consts::PI / 180.0 };
         const RAD1: Rad<f32> = Rad { s: 0.0 };
@@ -0,0 +1,552 @@
+// CPU versions of the BRDF terms in the brdf.glsl snippet and of the shading
+// in CT_FRAG_PBR and CT_FRAG_PERMUTED. They follow the GLSL line by line so
+// they can be used to check shader changes without a GPU, the tests check
+// them against closed-form values.
+use std::f32::consts::PI;
+
+use util::graphics::{Lobes, PointLight};
+use util::math::{cross, dot, normalize, sub};
+
+pub fn schlick_frensel(v_dot_h: f32, spec_reflectance: f32) -> f32 {
+    spec_reflectance + (1.0 - spec_reflectance) * (1.0 - v_dot_h).powi(5)
+}
+
+pub fn schlick_approx(v_dot_h: f32, spec_reflectance: f32) -> f32 {
+    let exponent = (-5.55473 * v_dot_h - 6.98316) * v_dot_h;
+    spec_reflectance + (1.0 - spec_reflectance) * 2.0f32.powf(exponent)
+}
+
+pub fn ggx_trowbridge_reitz(alpha: f32, n_dot_h: f32) -> f32 {
+    let divisor = PI * (n_dot_h.powi(2) * (alpha.powi(2) - 1.0) + 1.0).powi(2);
+    alpha.powi(2) / divisor
+}
+
+// Geometry term including NdotL * NdotV
+pub fn schlick(roughness: f32, n_dot_l: f32, n_dot_v: f32) -> f32 {
+    let k = (roughness + 1.0).powi(2) / 8.0;
+    let nl = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    let nv = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    nl * nv
+}
+
+pub fn schlick_simplified(x: f32, n_dot_l: f32, n_dot_v: f32) -> f32 {
+    let k = (x + 1.0).powi(2) / 8.0;
+    let nl = 1.0 / (n_dot_l * (1.0 - k) + k);
+    let nv = 1.0 / (n_dot_v * (1.0 - k) + k);
+    nl * nv / 4.0
+}
+
+// GGX_anisotropic, t_dot_h and b_dot_h are measured in an orthonormal frame
+pub fn ggx_anisotropic(alpha: f32, aniso: f32, n_dot_h: f32, t_dot_h: f32, b_dot_h: f32) -> f32 {
+    let at = (alpha * (1.0 + aniso)).max(0.001);
+    let ab = (alpha * (1.0 - aniso)).max(0.001);
+    let d = (t_dot_h / at).powi(2) + (b_dot_h / ab).powi(2) + n_dot_h.powi(2);
+    1.0 / (PI * at * ab * d * d)
+}
+
+// Sheen_Charlie, distribution times visibility
+pub fn sheen_charlie(r: f32, n_dot_h: f32, n_dot_l: f32, n_dot_v: f32) -> f32 {
+    let inv_r = 1.0 / (r * r).max(0.001);
+    let sin2 = (1.0 - n_dot_h * n_dot_h).max(0.0);
+    let d = (2.0 + inv_r) * sin2.powf(0.5 * inv_r) / (2.0 * PI);
+    let v = 1.0 / (4.0 * (n_dot_l + n_dot_v - n_dot_l * n_dot_v));
+    d * v
+}
+
+pub fn kelemen_visibility(v_dot_h: f32) -> f32 {
+    0.25 / (v_dot_h * v_dot_h).max(0.001)
+}
+
+// Clearcoat specular and the fraction of light it reflects, the layers below
+// are scaled by (1 - fresnel)
+pub fn clearcoat(strength: f32, roughness: f32, n_dot_h: f32, v_dot_h: f32) -> (f32, f32) {
+    let fresnel = strength * schlick_approx(v_dot_h, 0.04);
+    let alpha = roughness * roughness;
+    (fresnel * ggx_trowbridge_reitz(alpha, n_dot_h) * kelemen_visibility(v_dot_h), fresnel)
+}
+
+// Inputs of the metallic/roughness model as seen by CT_FRAG_PERMUTED after the maps
+// have been applied
+#[derive(Copy, Clone, Debug)]
+pub struct Surface {
+    pub base_color: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+    pub occlusion: f32,
+    pub f0: f32,
+    pub lobes: Lobes,
+}
+
+// Tangent of the anisotropic lobe as built in CT_FRAG_PERMUTED
+pub fn rotated_tangent(normal: [f32; 3], tangent: [f32; 3], bitangent: [f32; 3], rotation: f32) -> [f32; 3] {
+    let (s, c) = rotation.sin_cos();
+    let t = [c * tangent[0] + s * bitangent[0], c * tangent[1] + s * bitangent[1], c * tangent[2] + s * bitangent[2]];
+    let d = dot(normal, t);
+    let t = [t[0] - normal[0] * d, t[1] - normal[1] * d, t[2] - normal[2] * d];
+    if dot(t, t) > 1e-8 {
+        normalize(t)
+    } else {
+        let axis = if normal[0].abs() < 0.9 { [1.0, 0.0, 0.0] } else { [0.0, 1.0, 0.0] };
+        normalize(cross(normal, axis))
+    }
+}
+
+// BRDF of CT_FRAG_PERMUTED times NdotL for one light, without the light color and
+// attenuation. All directions are normalized and point away from the surface.
+pub fn evaluate(surface: &Surface,
+                normal: [f32; 3],
+                tangent: [f32; 3],
+                light_dir: [f32; 3],
+                view_dir: [f32; 3])
+                -> [f32; 3] {
+    let lobes = &surface.lobes;
+    let half = normalize([light_dir[0] + view_dir[0], light_dir[1] + view_dir[1], light_dir[2] + view_dir[2]]);
+    let bitangent = cross(normal, tangent);
+
+    let roughness = surface.roughness;
+    let alpha = roughness * roughness;
+    let n_dot_l = dot(normal, light_dir).max(0.0);
+    let n_dot_v = dot(normal, view_dir).max(0.001);
+    let n_dot_h = dot(normal, half).max(0.0);
+    let v_dot_h = dot(view_dir, half).max(0.0);
+
+    let d_term = ggx_anisotropic(alpha, lobes.anisotropy, n_dot_h, dot(tangent, half), dot(bitangent, half));
+    let g_term = schlick_simplified(roughness, n_dot_l, n_dot_v);
+    let schlick = schlick_approx(v_dot_h, 0.0);
+    let sheen = sheen_charlie(lobes.sheen_roughness, n_dot_h, n_dot_l, n_dot_v);
+    let (cc_spec, cc_fresnel) = clearcoat(lobes.clearcoat, lobes.clearcoat_roughness, n_dot_h, v_dot_h);
+
+    let mut out = [0.0; 3];
+    for i in 0..3 {
+        let c = surface.base_color[i];
+        let diffuse = c * (1.0 - surface.metallic);
+        let specular = surface.f0 + (c - surface.f0) * surface.metallic;
+        let f_term = specular + (1.0 - specular) * schlick;
+        let base = surface.occlusion * diffuse / PI + d_term * g_term * f_term + lobes.sheen_color[i] * sheen;
+        out[i] = n_dot_l * (base * (1.0 - cc_fresnel) + cc_spec);
+    }
+    out
+}
+
+// light_attenuation of the lighting.glsl snippet
+pub fn light_attenuation(light: &PointLight, position: [f32; 3]) -> f32 {
+    let d = sub(light.pos, position);
+    let distance = dot(d, d).sqrt();
+    1.0 / (light.attn[0] + light.attn[1] * distance + light.attn[2] * distance * distance)
+}
+
+// Specular part of the Cook-Torrance BRDF as in CT_FRAG_PBR, without NdotL.
+// Symmetric in light_dir and view_dir away from grazing angles.
+pub fn cook_torrance(roughness: f32,
+                     f0: f32,
+                     normal: [f32; 3],
+                     light_dir: [f32; 3],
+                     view_dir: [f32; 3])
+                     -> f32 {
+    let half = normalize([light_dir[0] + view_dir[0], light_dir[1] + view_dir[1], light_dir[2] + view_dir[2]]);
+    let alpha = roughness * roughness;
+    let n_dot_l = dot(normal, light_dir).max(0.0);
+    let n_dot_v = dot(normal, view_dir).max(0.001);
+    let n_dot_h = dot(normal, half).max(0.0);
+    let v_dot_h = dot(view_dir, half).max(0.0);
+    ggx_trowbridge_reitz(alpha, n_dot_h) * schlick_simplified(roughness, n_dot_l, n_dot_v) *
+    schlick_approx(v_dot_h, f0)
+}
+
+// Texel values read by CT_FRAG_PBR
+#[derive(Copy, Clone, Debug)]
+pub struct SpecGlossSample {
+    pub albedo: [f32; 3],
+    pub specular: [f32; 3],
+    // tex_gloss, r is the roughness and g * b the occlusion
+    pub gloss: [f32; 3],
+    pub f0: f32,
+}
+
+// Color written by CT_FRAG_PBR for all lights of one pass, passes add up.
+// Lights are taken as unshadowed and the sun as off. `normal` is the mapped
+// normal, `position`
+// and `frag_position` the interpolated v_position and frag_position. As in
+// the shader the light position is used as a direction and the view
+// direction is normalize(v_position).
+pub fn ct_frag_pbr(sample: &SpecGlossSample,
+                   normal: [f32; 3],
+                   position: [f32; 3],
+                   frag_position: [f32; 3],
+                   lights: &[PointLight])
+                   -> [f32; 3] {
+    let view_dir = normalize(position);
+    let mut color = [0.0; 3];
+    for light in lights.iter() {
+        let light_dir = normalize(light.pos);
+        let roughness = sample.gloss[0];
+        let n_dot_l = dot(normal, light_dir).max(0.0);
+        let spec = cook_torrance(roughness, sample.f0, normal, light_dir, view_dir);
+        let attenuation = light_attenuation(light, frag_position);
+        let occlusion = sample.gloss[1] * sample.gloss[2];
+        for i in 0..3 {
+            color[i] += n_dot_l * light.col[i] * attenuation *
+                        (occlusion * sample.albedo[i] / PI + sample.specular[i] * spec);
+        }
+    }
+    color
+}
+
+// Direction with the given cosine to the z axis and azimuth
+fn spherical(cos_theta: f32, phi: f32) -> [f32; 3] {
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    [sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta]
+}
+
+// Integral of f(light_dir) * NdotL over the hemisphere around +z, midpoint
+// rule on an n by 4n grid of cos(theta) and phi
+pub fn integrate_hemisphere<F>(n: usize, f: F) -> f32
+    where F: Fn([f32; 3]) -> f32
+{
+    let mut sum = 0.0f64;
+    let d_cos = 1.0 / n as f32;
+    let d_phi = 2.0 * PI / (4 * n) as f32;
+    for i in 0..n {
+        let cos_theta = (i as f32 + 0.5) * d_cos;
+        for j in 0..4 * n {
+            let phi = (j as f32 + 0.5) * d_phi;
+            sum += (f(spherical(cos_theta, phi)) * cos_theta) as f64;
+        }
+    }
+    (sum * (d_cos * d_phi) as f64) as f32
+}
+
+// Fraction of light reflected by the specular lobe towards a viewer at
+// n_dot_v, should not exceed 1 for f0 = 1
+pub fn directional_albedo(roughness: f32, f0: f32, n_dot_v: f32, n: usize) -> f32 {
+    let view_dir = spherical(n_dot_v, 0.0);
+    integrate_hemisphere(n, |l| cook_torrance(roughness, f0, [0.0, 0.0, 1.0], l, view_dir))
+}
+
+// Integral of D(h) * NdotH over the hemisphere, 1 for a normalized NDF
+pub fn ggx_normalization(alpha: f32, n: usize) -> f32 {
+    integrate_hemisphere(n, |h| ggx_trowbridge_reitz(alpha, h[2]))
+}
+
+// Point i of an n point Hammersley set in [0, 1)^2
+pub fn hammersley(i: u32, n: u32) -> [f32; 2] {
+    let mut bits = i;
+    bits = (bits << 16) | (bits >> 16);
+    bits = ((bits & 0x55555555) << 1) | ((bits & 0xAAAAAAAA) >> 1);
+    bits = ((bits & 0x33333333) << 2) | ((bits & 0xCCCCCCCC) >> 2);
+    bits = ((bits & 0x0F0F0F0F) << 4) | ((bits & 0xF0F0F0F0) >> 4);
+    bits = ((bits & 0x00FF00FF) << 8) | ((bits & 0xFF00FF00) >> 8);
+    [i as f32 / n as f32, bits as f32 * 2.3283064e-10]
+}
+
+// Half vector around `normal` distributed with pdf D(h) * NdotH for
+// ggx_trowbridge_reitz(alpha, _), `xi` is a point in [0, 1)^2
+pub fn importance_sample_ggx(xi: [f32; 2], alpha: f32, normal: [f32; 3]) -> [f32; 3] {
+    let phi = 2.0 * PI * xi[0];
+    let cos_theta = ((1.0 - xi[1]) / (1.0 + (alpha * alpha - 1.0) * xi[1])).sqrt();
+    let h = spherical(cos_theta, phi);
+    let up = if normal[2].abs() < 0.999 { [0.0, 0.0, 1.0] } else { [1.0, 0.0, 0.0] };
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+    [tangent[0] * h[0] + bitangent[0] * h[1] + normal[0] * h[2],
+     tangent[1] * h[0] + bitangent[1] * h[1] + normal[1] * h[2],
+     tangent[2] * h[0] + bitangent[2] * h[1] + normal[2] * h[2]]
+}
+
+// Calls f(NdotL, NdotH, VdotH) for the light directions of GGX importance
+// sampled half vectors around +z that lie above the surface
+fn reflected_samples<F>(n_dot_v: f32, roughness: f32, samples: u32, mut f: F)
+    where F: FnMut(f32, f32, f32)
+{
+    let view_dir = [(1.0 - n_dot_v * n_dot_v).max(0.0).sqrt(), 0.0, n_dot_v];
+    for i in 0..samples {
+        let h = importance_sample_ggx(hammersley(i, samples), roughness * roughness, [0.0, 0.0, 1.0]);
+        let v_dot_h = dot(view_dir, h);
+        let n_dot_l = 2.0 * v_dot_h * h[2] - n_dot_v;
+        if n_dot_l > 0.0 && v_dot_h > 0.0 {
+            f(n_dot_l, h[2], v_dot_h);
+        }
+    }
+}
+
+// Scale and bias of f0 in the split sum approximation, the specular BRDF of
+// the brdf.glsl terms integrated against a white environment reflects
+// f0 * x + y. The geometry term is Schlick_simplified as in the shader, so
+// the table matches the direct lighting. With GGX importance sampling D and
+// the pdf cancel and every sample weighs G * VdotH / (NdotH * NdotV).
+pub fn split_sum(n_dot_v: f32, roughness: f32, samples: u32) -> [f32; 2] {
+    let mut scale = 0.0;
+    let mut bias = 0.0;
+    reflected_samples(n_dot_v, roughness, samples, |n_dot_l, n_dot_h, v_dot_h| {
+        let g_vis = schlick(roughness, n_dot_l, n_dot_v) * v_dot_h / (n_dot_h * n_dot_v);
+        let fresnel = schlick_approx(v_dot_h, 0.0);
+        scale += (1.0 - fresnel) * g_vis;
+        bias += fresnel * g_vis;
+    });
+    [scale / samples as f32, bias / samples as f32]
+}
+
+// Directional albedo of cook_torrance for f0 = 1, the energy a single
+// scattering event off the microfacets keeps. Same as directional_albedo
+// but importance sampled.
+pub fn specular_albedo(n_dot_v: f32, roughness: f32, samples: u32) -> f32 {
+    let mut sum = 0.0;
+    reflected_samples(n_dot_v, roughness, samples, |n_dot_l, n_dot_h, v_dot_h| {
+        sum += schlick(roughness, n_dot_l, n_dot_v) * v_dot_h / (n_dot_h * n_dot_v);
+    });
+    sum / samples as f32
+}
+
+// average_fresnel of the multiscatter.glsl snippet, the cosine weighted
+// mean of schlick_frensel. schlick_approx averages about 2% higher.
+pub fn average_fresnel(f0: f32) -> f32 {
+    f0 + (1.0 - f0) / 21.0
+}
+
+// Multiscatter of the multiscatter.glsl snippet after the table lookups.
+// Kulla and Conty's lobe (1 - E(NdotL)) * (1 - E(NdotV)) / (pi * (1 - e_avg))
+// reflects what single scattering loses, scaled for the Fresnel of the
+// repeated bounces.
+pub fn multiscatter(e_o: f32, e_i: f32, e_avg: f32, f_avg: f32) -> f32 {
+    let f_ms = f_avg * f_avg * e_avg / (1.0 - f_avg * (1.0 - e_avg));
+    f_ms * (1.0 - e_o) * (1.0 - e_i) / (PI * (1.0 - e_avg).max(1e-4))
+}
+
+// Specular reflectance of ibl_ambient in a white environment, `env` is the
+// split sum lookup. Fdez-Aguera's multiple scattering adds the missing
+// 1 - scale - bias times a Fresnel averaged over the bounces.
+pub fn environment_reflectance(env: [f32; 2], specular: f32, multiscatter: bool) -> f32 {
+    let single = specular * env[0] + env[1];
+    if !multiscatter {
+        return single;
+    }
+    let e_ms = 1.0 - env[0] - env[1];
+    let f_avg = average_fresnel(specular);
+    single + single * f_avg / (1.0 - e_ms * f_avg) * e_ms
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use util::graphics::PointLight;
+    use util::math::{dot, normalize};
+    use super::{clearcoat, cook_torrance, ct_frag_pbr, directional_albedo, ggx_anisotropic, ggx_normalization,
+                ggx_trowbridge_reitz, hammersley, importance_sample_ggx, integrate_hemisphere, rotated_tangent,
+                schlick, schlick_approx, schlick_frensel, schlick_simplified, sheen_charlie, spherical, split_sum,
+                SpecGlossSample};
+
+    fn assert_close(value: f32, expected: f32, tolerance: f32) {
+        assert!((value - expected).abs() <= tolerance,
+                "{} instead of {} (tolerance {})",
+                value,
+                expected,
+                tolerance);
+    }
+
+    #[test]
+    fn closed_form_values() {
+        for &alpha in [0.1f32, 0.3, 0.5, 1.0].iter() {
+            assert_close(ggx_trowbridge_reitz(alpha, 1.0), 1.0 / (PI * alpha * alpha), 1e-3 / (alpha * alpha));
+        }
+        // alpha 1 is uniform
+        for &n_dot_h in [0.0f32, 0.3, 0.7].iter() {
+            assert_close(ggx_trowbridge_reitz(1.0, n_dot_h), 1.0 / PI, 1e-6);
+        }
+        for &f0 in [0.04f32, 0.5, 1.0].iter() {
+            assert_close(schlick_frensel(1.0, f0), f0, 1e-6);
+            assert_close(schlick_frensel(0.0, f0), 1.0, 1e-6);
+            assert_close(schlick_approx(1.0, f0), f0, 2e-4);
+            for i in 0..11 {
+                let v_dot_h = i as f32 / 10.0;
+                assert_close(schlick_approx(v_dot_h, f0), schlick_frensel(v_dot_h, f0), 0.01);
+            }
+        }
+        for &roughness in [0.1f32, 0.5, 1.0].iter() {
+            assert_close(schlick(roughness, 1.0, 1.0), 1.0, 1e-6);
+            for &(n_dot_l, n_dot_v) in [(0.2f32, 0.9f32), (0.5, 0.5), (1.0, 0.1)].iter() {
+                assert_close(schlick_simplified(roughness, n_dot_l, n_dot_v) * 4.0 * n_dot_l * n_dot_v,
+                             schlick(roughness, n_dot_l, n_dot_v),
+                             1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn cook_torrance_is_reciprocal() {
+        let normal = [0.0, 0.0, 1.0];
+        for &roughness in [0.2f32, 0.5, 0.9].iter() {
+            for i in 1..6 {
+                for j in 1..6 {
+                    let l = spherical(i as f32 / 5.0, 0.3 * i as f32);
+                    let v = spherical(j as f32 / 5.0, 2.0 + 0.7 * j as f32);
+                    let lv = cook_torrance(roughness, 0.04, normal, l, v);
+                    let vl = cook_torrance(roughness, 0.04, normal, v, l);
+                    assert_close(lv, vl, 1e-5 * lv.max(1.0));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn energy_is_bounded() {
+        assert_close(integrate_hemisphere(200, |_| 1.0 / PI), 1.0, 1e-4);
+        for &alpha in [0.3f32, 0.6, 1.0].iter() {
+            assert_close(ggx_normalization(alpha, 400), 1.0, 1e-3);
+        }
+        for &roughness in [0.3f32, 0.5, 0.7, 1.0].iter() {
+            for &n_dot_v in [0.1f32, 0.3, 0.6, 1.0].iter() {
+                let albedo = directional_albedo(roughness, 1.0, n_dot_v, 400);
+                assert!(albedo <= 1.0 + 1e-3, "roughness {} NdotV {}: {}", roughness, n_dot_v, albedo);
+            }
+        }
+    }
+
+    #[test]
+    fn split_sum_of_a_mirror() {
+        // Every sample is the reflection of the view direction, weighted
+        // by the geometry term and 1 - F or F
+        for &n_dot_v in [0.1f32, 0.4, 0.7, 1.0].iter() {
+            let fresnel = schlick_approx(n_dot_v, 0.0);
+            let g = schlick(0.0, n_dot_v, n_dot_v);
+            let lut = split_sum(n_dot_v, 0.0, 64);
+            assert_close(lut[0], (1.0 - fresnel) * g, 1e-5);
+            assert_close(lut[1], fresnel * g, 1e-5);
+        }
+    }
+
+    #[test]
+    fn split_sum_at_roughness_one() {
+        // D is 1 / pi and k is 1/2, the white albedo integrates to
+        // 2 (1 - ln 2) / (1 + NdotV)
+        for &n_dot_v in [0.2f32, 0.5, 1.0].iter() {
+            let lut = split_sum(n_dot_v, 1.0, 2048);
+            assert_close(lut[0] + lut[1], 2.0 * (1.0 - 2.0f32.ln()) / (1.0 + n_dot_v), 2e-3);
+        }
+    }
+
+    #[test]
+    fn split_sum_matches_the_hemisphere_integral() {
+        // Scale plus bias is the albedo for f0 = 1 and the bias the albedo
+        // for f0 = 0, both integrated on a grid instead of importance sampled
+        for &roughness in [0.5f32, 0.7, 1.0].iter() {
+            for &n_dot_v in [0.3f32, 0.6, 0.9].iter() {
+                let lut = split_sum(n_dot_v, roughness, 1024);
+                assert_close(lut[0] + lut[1], directional_albedo(roughness, 1.0, n_dot_v, 300), 0.01);
+                assert_close(lut[1], directional_albedo(roughness, 0.0, n_dot_v, 300), 0.005);
+            }
+        }
+    }
+
+    #[test]
+    fn lights_add_up() {
+        let sample = SpecGlossSample {
+            albedo: [0.8, 0.5, 0.2],
+            specular: [0.04, 0.04, 0.04],
+            gloss: [0.5, 1.0, 1.0],
+            f0: 0.04,
+        };
+        let normal = [0.0, 0.0, 1.0];
+        let position = [0.0, 0.0, 1.0];
+        let above = PointLight::new([0.0, 0.0, 2.0], [1.0, 1.0, 1.0], 1.0, 0.0, 0.0);
+        let color = ct_frag_pbr(&sample, normal, position, [0.0; 3], &[above]);
+        // Light, view and normal coincide: NdotL = VdotH = 1
+        let spec = ggx_trowbridge_reitz(0.25, 1.0) * schlick_simplified(0.5, 1.0, 1.0) * schlick_approx(1.0, 0.04);
+        for i in 0..3 {
+            assert_close(color[i], sample.albedo[i] / PI + 0.04 * spec, 1e-5);
+        }
+
+        let side = PointLight::new([1.0, 0.0, 1.0], [0.5, 0.5, 1.0], 1.0, 0.1, 0.01);
+        let both = ct_frag_pbr(&sample, normal, position, [0.5, 0.5, 0.0], &[above, side]);
+        let first = ct_frag_pbr(&sample, normal, position, [0.5, 0.5, 0.0], &[above]);
+        let second = ct_frag_pbr(&sample, normal, position, [0.5, 0.5, 0.0], &[side]);
+        for i in 0..3 {
+            assert_close(both[i], first[i] + second[i], 1e-5);
+        }
+        // Every light counts, not only the first few
+        let mut many = Vec::new();
+        let mut sum = [0.0; 3];
+        for i in 0..8 {
+            let light = PointLight::new([i as f32 - 3.5, 1.0, 2.0], [1.0, 0.8, 0.6], 1.0, 0.05, 0.01);
+            let single = ct_frag_pbr(&sample, normal, position, [0.0; 3], &[light]);
+            for j in 0..3 {
+                sum[j] += single[j];
+            }
+            many.push(light);
+        }
+        let all = ct_frag_pbr(&sample, normal, position, [0.0; 3], &many);
+        for i in 0..3 {
+            assert_close(all[i], sum[i], 1e-4);
+        }
+
+        let below = PointLight::new([1.0, 0.0, -1.0], [1.0, 1.0, 1.0], 1.0, 0.0, 0.0);
+        let unlit = ct_frag_pbr(&sample, normal, position, [0.0; 3], &[below]);
+        assert_eq!(unlit, [0.0; 3]);
+    }
+
+    #[test]
+    fn isotropic_ggx_anisotropic_is_ggx() {
+        for &alpha in [0.05, 0.3, 0.7, 1.0].iter() {
+            for &(theta, phi) in [(0.0f32, 0.0f32), (0.3, 1.0), (0.8, 2.5), (1.4, 4.0)].iter() {
+                let (n_dot_h, sin_theta) = (theta.cos(), theta.sin());
+                let d = ggx_anisotropic(alpha, 0.0, n_dot_h, sin_theta * phi.cos(), sin_theta * phi.sin());
+                let expected = ggx_trowbridge_reitz(alpha, n_dot_h);
+                assert!((d - expected).abs() <= 1e-4 * expected, "{} {}: {} != {}", alpha, theta, d, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn sheen_distribution_is_normalized() {
+        // With NdotL = NdotV = 1 the visibility term is 1/4
+        for &r in [0.3, 0.5, 1.0].iter() {
+            let integral = integrate_hemisphere(256, |h| 4.0 * sheen_charlie(r, h[2], 1.0, 1.0));
+            assert!((integral - 1.0).abs() < 0.01, "{}: {}", r, integral);
+        }
+        // Sheen is strongest at grazing half vectors
+        assert_eq!(sheen_charlie(0.5, 1.0, 1.0, 1.0), 0.0);
+        assert!(sheen_charlie(0.5, 0.1, 0.5, 0.5) > sheen_charlie(0.5, 0.9, 0.5, 0.5));
+    }
+
+    #[test]
+    fn clearcoat_reflects_its_fresnel() {
+        assert_eq!(clearcoat(0.0, 0.1, 0.9, 0.7), (0.0, 0.0));
+        let (spec, fresnel) = clearcoat(1.0, 0.5, 1.0, 1.0);
+        assert!((fresnel - schlick_approx(1.0, 0.04)).abs() < 1e-6);
+        assert!((fresnel - 0.04).abs() < 1e-3);
+        // Kelemen visibility is 1/4 for VdotH = 1
+        let expected = fresnel * ggx_trowbridge_reitz(0.25, 1.0) / 4.0;
+        assert!((spec - expected).abs() < 1e-6);
+        let (half_spec, half_fresnel) = clearcoat(0.5, 0.5, 1.0, 1.0);
+        assert!((half_spec - spec / 2.0).abs() < 1e-6 && (half_fresnel - fresnel / 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn zero_vectors_stay_finite() {
+        assert_eq!(normalize([0.0; 3]), [0.0; 3]);
+        let t = rotated_tangent([0.0, 0.0, 1.0], [0.0; 3], [0.0; 3], 0.5);
+        assert!((t[0] * t[0] + t[1] * t[1] + t[2] * t[2] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn ggx_samples_follow_the_distribution() {
+        let n = 4096;
+        for &alpha in [0.3f32, 0.5, 1.0].iter() {
+            // E[NdotH] under the pdf D * NdotH against the integral
+            let mut mean = 0.0;
+            for i in 0..n {
+                mean += importance_sample_ggx(hammersley(i, n), alpha, [0.0, 0.0, 1.0])[2] / n as f32;
+            }
+            let expected = integrate_hemisphere(400, |h| ggx_trowbridge_reitz(alpha, h[2]) * h[2]);
+            assert_close(mean, expected, 2e-3);
+
+            // Unit half vectors around a tilted normal
+            let normal = [0.48, 0.6, -0.64];
+            for i in 0..64 {
+                let h = importance_sample_ggx(hammersley(i, 64), alpha, normal);
+                assert_close(dot(h, h), 1.0, 2e-4);
+                assert!(dot(h, normal) >= 0.0);
+            }
+        }
+    }
+}
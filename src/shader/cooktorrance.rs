@@ -30,7 +30,6 @@ pub static CT_VERT: &'static str = r#"
 
 pub static CT_FRAG: &'static str = r#"
     #version 140
-    #define M_PI 3.1415926535897932384626433832795
 
     in vec3 v_normal;
     in vec3 v_position;
@@ -42,24 +41,9 @@ pub static CT_FRAG: &'static str = r#"
 
     const vec3 position = vec3(2.0, 4.0, 4.0);
 
-    float Schlick_approx(float VdotH, float spec_reflectance) {
-        float exponent = (-5.55473 * VdotH - 6.98316) * VdotH;
-        return spec_reflectance + (1 - spec_reflectance) * pow(2, exponent);
-    }
-
-    float Schlick(float roughness, float NdotL, float NdotV) {
-        float k = pow(roughness + 1, 2) / 8;
-        float nl = NdotL / (NdotL * (1 - k) + k);
-        float nv = NdotV / (NdotV * (1 - k) + k);
-
-        return nl * nv;
-    }
+    #include "brdf.glsl"
+    #include "tonemap.glsl"
 
-    float GGX_Trowbridge_Reitz(float alpha, float NdotH) {
-        float divisor = M_PI * pow(pow(NdotH, 2) * (pow(alpha, 2) - 1) + 1, 2);
-
-        return pow(alpha, 2) / divisor;
-    }
     void main() {
          vec3 light_c = vec3(0.4,0.4,0.4);
 
@@ -96,13 +80,12 @@ pub static CT_FRAG: &'static str = r#"
             f_term = Schlick_approx(VdotH, 1.31);
             spec = clamp(d_term * g_term *  f_term / (4 * NdotL * NdotV), 0, 1);
         }
-        color =vec4(clamp(ka +  NdotL * kd / M_PI +  ks * vec3(spec), 0, 1), 1.0);
+        color = vec4(tonemap_clamp(ka +  NdotL * kd / M_PI +  ks * vec3(spec)), 1.0);
     }
 
 "#;
 pub static CT_FRAG_DIFF: &'static str = r#"
     #version 140
-    #define M_PI 3.1415926535897932384626433832795
 
     in vec3 v_normal;
     in vec3 v_position;
@@ -117,24 +100,9 @@ pub static CT_FRAG_DIFF: &'static str = r#"
 
     const vec3 position = vec3(0.0, 4.0, 4.0);
 
-    float Schlick_approx(float VdotH, float spec_reflectance) {
-        float exponent = (-5.55473 * VdotH - 6.98316) * VdotH;
-        return spec_reflectance + (1 - spec_reflectance) * pow(2, exponent);
-    }
-
-    float Schlick(float roughness, float NdotL, float NdotV) {
-        float k = pow(roughness + 1, 2) / 8;
-        float nl = NdotL / (NdotL * (1 - k) + k);
-        float nv = NdotV / (NdotV * (1 - k) + k);
+    #include "brdf.glsl"
+    #include "tonemap.glsl"
 
-        return nl * nv;
-    }
-
-    float GGX_Trowbridge_Reitz(float alpha, float NdotH) {
-        float divisor = M_PI * pow(pow(NdotH, 2) * (pow(alpha, 2) - 1) + 1, 2);
-
-        return pow(alpha, 2) / divisor;
-    }
     void main() {
         vec3 light_c = vec3(0.001,0.007,0.001);
         vec4 tex = texture(texkd, v_tex_coords);
@@ -172,14 +140,13 @@ pub static CT_FRAG_DIFF: &'static str = r#"
             f_term = Schlick_approx(VdotH, 1.31);
             spec = clamp(d_term * g_term *  f_term / (4 * NdotL * NdotV), 0, 1);
         }
-        color = vec4(clamp(NdotL * tex.rgb * kd / M_PI +  ks * vec3(spec), 0, 1), 1.0);
+        color = vec4(tonemap_clamp(NdotL * tex.rgb * kd / M_PI +  ks * vec3(spec)), 1.0);
     }
 
 "#;
 
 pub static CT_FRAG_PBR: &'static str = r#"
     #version 140
-    #define M_PI 3.1415926535897932384626433832795
 
     in vec3 v_normal;
     in vec3 v_position;
@@ -188,56 +155,20 @@ pub static CT_FRAG_PBR: &'static str = r#"
 
     out vec4 color;
 
-    struct PointLight {
-        vec3 pos;
-        vec3 col;
-        vec3 attn;
-    };
-
     uniform sampler2D dagger_albedo;
     uniform sampler2D dagger_specular;
     uniform sampler2D dagger_normal;
     uniform sampler2D dagger_gloss;
     uniform float f0;
+    // 0 in the additive passes of scenes with more than MAX_LIGHTS lights
+    uniform float base_pass;
 
-    uniform Block {
-        PointLight lights[5];
-    };
-
-    float Schlick_Frensel(float VdotH, float spec_reflectance) {
-        return spec_reflectance + (1 - spec_reflectance) * pow(1 - VdotH, 5);
-    }
-    float Schlick_approx(float VdotH, float spec_reflectance) {
-        float exponent = (-5.55473 * VdotH - 6.98316) * VdotH;
-        return spec_reflectance + (1 - spec_reflectance) * pow(2, exponent);
-    }
-
-    float Schlick_simplified(float x, float NdotL, float NdotV) {
-        float k = pow(x + 1, 2) / 8;
-        float nl = 1 / (NdotL * (1 - k) + k);
-        float nv = 1 / (NdotV * (1 - k) + k);
-
-        return nl * nv / 4;
-    }
-
-    float GGX_Trowbridge_Reitz(float alpha, float NdotH) {
-        float divisor = M_PI * pow(pow(NdotH, 2) * (pow(alpha, 2) - 1) + 1, 2);
-
-        return pow(alpha, 2) / divisor;
-    }
-
-    mat3 cotangent_frame(vec3 normal, vec3 pos, vec2 uv) {
-            vec3 dp1 = dFdx(pos);
-            vec3 dp2 = dFdy(pos);
-            vec2 duv1 = dFdx(uv);
-            vec2 duv2 = dFdy(uv);
-            vec3 dp2perp = cross(dp2, normal);
-            vec3 dp1perp = cross(normal, dp1);
-            vec3 T = dp2perp * duv1.x + dp1perp * duv2.x;
-            vec3 B = dp2perp * duv1.y + dp1perp * duv2.y;
-            float invmax = inversesqrt(max(dot(T, T), dot(B, B)));
-            return mat3(T * invmax, B * invmax, normal);
-    }
+    #include "brdf.glsl"
+    #include "lighting.glsl"
+    #include "tangent_frame.glsl"
+    #include "shadow.glsl"
+    #include "multiscatter.glsl"
+    #include "ibl.glsl"
 
     void main() {
         vec4 tex_albedo = texture(dagger_albedo, v_tex_coords);
@@ -251,8 +182,17 @@ pub static CT_FRAG_PBR: &'static str = r#"
         vec3 view_dir = normalize(v_position);
         vec3 temp_color = vec3(0.0);
 
-        for (int i = 0; i < 3; i++) {
-            vec3 light_dir = normalize(lights[i].pos);
+        for (int i = 0; i <= light_count; i++) {
+            vec3 light_dir;
+            vec3 light_color;
+            if (i < light_count) {
+                light_dir = normalize(lights[i].pos);
+                light_color = lights[i].col * light_attenuation(lights[i], frag_position) * point_shadow(lights[i], frag_position);
+            } else {
+                // The last iteration is the sun, added by the first pass only
+                light_dir = sun_direction;
+                light_color = sun_color * base_pass * sun_shadow(frag_position);
+            }
             vec3 half_direction = normalize(light_dir + view_dir);
 
             float  roughness = tex_gloss.r;
@@ -273,11 +213,314 @@ pub static CT_FRAG_PBR: &'static str = r#"
             float f_term = Schlick_approx(VdotH, f0);
             spec = d_term * g_term * f_term;
 
-            float distance = length(lights[i].pos - frag_position);
-            float attenuation = 1.0f  / (lights[i].attn[0] + lights[i].attn[1] * distance + lights[i].attn[2] * distance * distance);
-            temp_color += vec3(NdotL * lights[i].col * attenuation * (tex_gloss.g * tex_gloss.b * tex_albedo.rgb / M_PI + tex_specular.rgb * spec));
+            vec3 spec_ms = Multiscatter(roughness, NdotL, NdotV, tex_specular.rgb * average_fresnel(vec3(f0)));
+            temp_color += vec3(NdotL * light_color * (tex_gloss.g * tex_gloss.b * tex_albedo.rgb / M_PI + tex_specular.rgb * spec + spec_ms));
         }
+        vec3 ambient = ibl_ambient(normal, view_dir, tex_albedo.rgb, tex_specular.rgb, tex_gloss.r);
+        temp_color += tex_gloss.g * tex_gloss.b * ambient * base_pass;
         color = vec4(temp_color, 1.0);
     }
 
 "#;
+
+// Vertex shader of the permutation system, see shader::permutation.
+// SKINNING and VERTEX_COLOR select the extra inputs, the outputs are
+// those of CT_VERT plus the VertexAttributes when VERTEX_COLOR is set.
+pub static CT_VERT_PERMUTED: &'static str = r#"
+    #version 140
+
+    in vec3 position;
+    in vec3 normal;
+    in vec2 texture;
+#ifdef SKINNING
+    in uvec4 joints;
+    in vec4 weights;
+#endif
+#ifdef VERTEX_COLOR
+    in vec2 texcoord1;
+    in vec4 color;
+
+    out vec2 v_tex_coords1;
+    out vec4 v_color;
+#endif
+
+    out vec3 v_normal;
+    out vec3 v_position;
+    out vec3 frag_position;
+    out vec2 v_tex_coords;
+
+
+    uniform mat4 model;
+    uniform mat4 modelview;
+    uniform mat4 modelviewperspective;
+    uniform mat3 normalmatrix;
+
+#ifdef SKINNING
+    uniform Joints {
+        mat4 joint_matrices[64];
+    };
+#endif
+
+
+    void main() {
+#ifdef SKINNING
+        mat4 skin = weights.x * joint_matrices[joints.x]
+                  + weights.y * joint_matrices[joints.y]
+                  + weights.z * joint_matrices[joints.z]
+                  + weights.w * joint_matrices[joints.w];
+
+        vec4 object_position = skin * vec4(position, 1.0);
+        vec3 object_normal = mat3(skin) * normal;
+#else
+        vec4 object_position = vec4(position, 1.0);
+        vec3 object_normal = normal;
+#endif
+
+        v_normal = normalize(normalmatrix * object_normal);
+        gl_Position = modelviewperspective * object_position;
+        v_position = gl_Position.xyz / gl_Position.w;
+        frag_position = vec3(model * object_position);
+        v_tex_coords = texture;
+#ifdef VERTEX_COLOR
+        v_tex_coords1 = texcoord1;
+        v_color = color;
+#endif
+    }
+"#;
+
+// Fragment shader of the permutation system. SPEC_GLOSS picks the textures of
+// CT_FRAG_PBR and PACKED those of TexturePBR::AlbedoNormalPacked, without
+// either the metallic/roughness slots are read. The remaining features add
+// the optional parts on top of every workflow.
+pub static CT_FRAG_PERMUTED: &'static str = r#"
+    #version 140
+
+    in vec3 v_normal;
+    in vec3 v_position;
+    in vec3 frag_position;
+    in vec2 v_tex_coords;
+#ifdef VERTEX_COLOR
+    in vec2 v_tex_coords1;
+    in vec4 v_color;
+#endif
+
+    out vec4 color;
+
+#if defined(SPEC_GLOSS)
+    uniform sampler2D dagger_albedo;
+    uniform sampler2D dagger_specular;
+    uniform sampler2D dagger_normal;
+    uniform sampler2D dagger_gloss;
+    #define NORMAL_TEXTURE dagger_normal
+#elif defined(PACKED)
+    uniform sampler2D tex_albedo;
+    uniform sampler2D tex_normal;
+    uniform sampler2D tex_packed;
+
+    uniform vec4 roughness_mask;
+    uniform vec4 metalness_mask;
+    uniform vec4 occlusion_mask;
+    uniform vec3 packed_fallback;
+    #define NORMAL_TEXTURE tex_normal
+
+    // Product of the masked channels times the fallback, see ChannelLayout::value
+    float packed_value(vec4 texel, vec4 mask, float fallback) {
+        vec4 v = mix(vec4(1.0), texel, mask);
+        return v.r * v.g * v.b * v.a * fallback;
+    }
+#else
+    uniform sampler2D tex_base_color;
+    uniform sampler2D tex_metallic;
+    uniform sampler2D tex_roughness;
+    uniform sampler2D tex_occlusion;
+    uniform sampler2D tex_emissive;
+    uniform sampler2D tex_normal;
+
+    uniform vec4 base_color_factor;
+    uniform float metallic_factor;
+    uniform float roughness_factor;
+    uniform float occlusion_factor;
+    uniform vec3 emissive_factor;
+    #define NORMAL_TEXTURE tex_normal
+#endif
+#ifdef ALPHA_MASK
+    uniform float alpha_cutoff;
+#endif
+#ifdef CLEARCOAT
+    uniform float clearcoat;
+    uniform float clearcoat_roughness;
+#endif
+#ifdef SHEEN
+    uniform vec3 sheen_color;
+    uniform float sheen_roughness;
+#endif
+#ifdef ANISOTROPY
+    uniform float anisotropy;
+    uniform float anisotropy_rotation;
+#endif
+    uniform float f0;
+    // 0 in the additive passes of scenes with more than MAX_LIGHTS lights
+    uniform float base_pass;
+
+    #include "brdf.glsl"
+    #include "lighting.glsl"
+    #include "tangent_frame.glsl"
+    #include "shadow.glsl"
+    #include "multiscatter.glsl"
+    #include "ibl.glsl"
+
+    void main() {
+#if defined(SPEC_GLOSS)
+        vec4 base_color = texture(dagger_albedo, v_tex_coords);
+#elif defined(PACKED)
+        vec4 base_color = texture(tex_albedo, v_tex_coords);
+#else
+        vec4 base_color = texture(tex_base_color, v_tex_coords) * base_color_factor;
+#endif
+#ifdef VERTEX_COLOR
+        base_color *= v_color;
+#endif
+#ifdef ALPHA_MASK
+        if (base_color.a < alpha_cutoff) {
+            discard;
+        }
+#endif
+
+#if defined(SPEC_GLOSS)
+        vec4 tex_gloss = texture(dagger_gloss, v_tex_coords);
+        float roughness = tex_gloss.r;
+        float occlusion = tex_gloss.g * tex_gloss.b;
+        vec3 diffuse_color = base_color.rgb;
+        vec3 specular_color = texture(dagger_specular, v_tex_coords).rgb;
+        vec3 emissive = vec3(0.0);
+#elif defined(PACKED)
+        vec4 tex_p = texture(tex_packed, v_tex_coords);
+        float roughness = packed_value(tex_p, roughness_mask, packed_fallback.x);
+        float metalness = packed_value(tex_p, metalness_mask, packed_fallback.y);
+        float occlusion = packed_value(tex_p, occlusion_mask, packed_fallback.z);
+        vec3 diffuse_color = base_color.rgb * (1.0 - metalness);
+        vec3 specular_color = mix(vec3(f0), base_color.rgb, metalness);
+        vec3 emissive = vec3(0.0);
+#else
+        float metalness = clamp(texture(tex_metallic, v_tex_coords).r * metallic_factor, 0.0, 1.0);
+        float roughness = clamp(texture(tex_roughness, v_tex_coords).r * roughness_factor, 0.04, 1.0);
+        float occlusion = texture(tex_occlusion, v_tex_coords).r * occlusion_factor;
+        vec3 diffuse_color = base_color.rgb * (1.0 - metalness);
+        vec3 specular_color = mix(vec3(f0), base_color.rgb, metalness);
+        vec3 emissive = texture(tex_emissive, v_tex_coords).rgb * emissive_factor;
+#endif
+
+        vec3 normal = normalize(v_normal);
+        mat3 tbn = cotangent_frame(normal, v_position, v_tex_coords);
+#ifdef NORMAL_MAP
+        normal = normalize(tbn * (texture(NORMAL_TEXTURE, v_tex_coords).xyz * 2.0 - 1.0));
+#endif
+
+#ifdef ANISOTROPY
+        vec3 tangent = cos(anisotropy_rotation) * tbn[0] + sin(anisotropy_rotation) * tbn[1];
+        tangent = tangent - normal * dot(normal, tangent);
+        if (!(dot(tangent, tangent) > 1e-8)) {
+            tangent = cross(normal, abs(normal.x) < 0.9 ? vec3(1.0, 0.0, 0.0) : vec3(0.0, 1.0, 0.0));
+        }
+        tangent = normalize(tangent);
+        vec3 bitangent = cross(normal, tangent);
+#endif
+
+        vec3 view_dir = normalize(v_position);
+        vec3 temp_color = vec3(0.0);
+#ifdef SPEC_GLOSS
+        vec3 f_avg = specular_color * average_fresnel(vec3(f0));
+#else
+        vec3 f_avg = average_fresnel(specular_color);
+#endif
+
+        for (int i = 0; i <= light_count; i++) {
+            vec3 light_dir;
+            vec3 light_color;
+            if (i < light_count) {
+                light_dir = normalize(lights[i].pos);
+                light_color = lights[i].col * light_attenuation(lights[i], frag_position) * point_shadow(lights[i], frag_position);
+            } else {
+                // The last iteration is the sun, added by the first pass only
+                light_dir = sun_direction;
+                light_color = sun_color * base_pass * sun_shadow(frag_position);
+            }
+            vec3 half_direction = normalize(light_dir + view_dir);
+
+            float alpha = roughness * roughness;
+            float NdotL = max(dot(normal, light_dir),0);
+
+            float NdotV = max(dot(normal, view_dir), 0.001);
+            float NdotH = max(dot(normal, half_direction), 0);
+            float VdotH = max(dot(view_dir, half_direction), 0);
+
+#ifdef ANISOTROPY
+            float d_term = GGX_anisotropic(alpha, anisotropy, NdotH, dot(tangent, half_direction), dot(bitangent, half_direction));
+#else
+            float d_term = GGX_Trowbridge_Reitz(alpha, NdotH);
+#endif
+            float g_term = Schlick_simplified(roughness, NdotL, NdotV);
+#ifdef SPEC_GLOSS
+            vec3 f_term = specular_color * Schlick_approx(VdotH, f0);
+#else
+            vec3 f_term = specular_color + (1 - specular_color) * Schlick_approx(VdotH, 0.0);
+#endif
+            vec3 spec = d_term * g_term * f_term + Multiscatter(roughness, NdotL, NdotV, f_avg);
+
+            vec3 radiance = occlusion * diffuse_color / M_PI + spec;
+#ifdef SHEEN
+            radiance += sheen_color * Sheen_Charlie(sheen_roughness, NdotH, NdotL, NdotV);
+#endif
+#ifdef CLEARCOAT
+            float cc_alpha = clearcoat_roughness * clearcoat_roughness;
+            float cc_fresnel = clearcoat * Schlick_approx(VdotH, 0.04);
+            float cc_spec = cc_fresnel * GGX_Trowbridge_Reitz(cc_alpha, NdotH) * Kelemen_visibility(VdotH);
+            radiance = radiance * (1.0 - cc_fresnel) + cc_spec;
+#endif
+
+            temp_color += NdotL * light_color * radiance;
+        }
+        temp_color += occlusion * ibl_ambient(normal, view_dir, diffuse_color, specular_color, roughness) * base_pass;
+#ifdef ALPHA_BLEND
+        color = vec4(temp_color + emissive * base_pass, base_color.a);
+#else
+        color = vec4(temp_color + emissive * base_pass, 1.0);
+#endif
+    }
+
+"#;
+
+// Depth pass of shadow::ShadowMaps. Cube layers store the distance to the
+// light over far, the cascades (far = 0) the window depth.
+pub static SHADOW_VERT: &'static str = r#"
+    #version 140
+
+    in vec3 position;
+
+    out vec3 world_position;
+
+    uniform mat4 model;
+    uniform mat4 light_matrix;
+
+    void main() {
+        vec4 world = model * vec4(position, 1.0);
+        world_position = world.xyz;
+        gl_Position = light_matrix * world;
+    }
+"#;
+
+pub static SHADOW_FRAG: &'static str = r#"
+    #version 140
+
+    in vec3 world_position;
+
+    out vec4 color;
+
+    uniform vec3 light_position;
+    uniform float far;
+
+    void main() {
+        float depth = far > 0.0 ? length(world_position - light_position) / far : gl_FragCoord.z;
+        color = vec4(depth, 0.0, 0.0, 1.0);
+    }
+"#;
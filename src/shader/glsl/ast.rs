@@ -0,0 +1,114 @@
+use shader::glsl::lexer::Loc;
+
+// Syntax tree of the GLSL subset the checker understands
+
+#[derive(Clone, Debug)]
+pub struct TypeName {
+    pub name: String,
+    // `float[3] x` style array, None when not an array
+    pub array: Option<Option<Expr>>,
+    pub loc: Loc,
+}
+
+#[derive(Clone, Debug)]
+pub struct Declarator {
+    pub name: String,
+    pub array: Option<Option<Expr>>,
+    pub init: Option<Expr>,
+    pub loc: Loc,
+}
+
+#[derive(Clone, Debug)]
+pub struct Declaration {
+    pub qualifiers: Vec<String>,
+    pub ty: TypeName,
+    pub vars: Vec<Declarator>,
+}
+
+#[derive(Clone, Debug)]
+pub struct StructDef {
+    pub name: String,
+    pub fields: Vec<Declaration>,
+    pub loc: Loc,
+}
+
+// uniform Name { ... } [instance];
+#[derive(Clone, Debug)]
+pub struct BlockDef {
+    pub qualifiers: Vec<String>,
+    pub name: String,
+    pub members: Vec<Declaration>,
+    pub instance: Option<Declarator>,
+    pub loc: Loc,
+}
+
+#[derive(Clone, Debug)]
+pub struct Param {
+    pub qualifiers: Vec<String>,
+    pub ty: TypeName,
+    pub name: Option<String>,
+    pub array: Option<Option<Expr>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FunctionDef {
+    pub ret: TypeName,
+    pub name: String,
+    pub params: Vec<Param>,
+    // None for a prototype
+    pub body: Option<Vec<Stmt>>,
+    pub loc: Loc,
+}
+
+#[derive(Clone, Debug)]
+pub enum External {
+    Declaration(Declaration),
+    // A struct, optionally declaring variables of its type
+    Struct(StructDef, Option<Declaration>),
+    Block(BlockDef),
+    Function(FunctionDef),
+    Precision,
+}
+
+#[derive(Clone, Debug)]
+pub enum ExprKind {
+    Ident(String),
+    Int(i64),
+    UInt(i64),
+    Float(f64),
+    Bool(bool),
+    Unary(&'static str, Box<Expr>),
+    // ++ and -- after the operand
+    Postfix(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Assign(&'static str, Box<Expr>, Box<Expr>),
+    Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
+    // Function call or constructor
+    Call(String, Vec<Expr>),
+    // x.length()
+    Method(Box<Expr>, String, Vec<Expr>),
+    Field(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub loc: Loc,
+}
+
+#[derive(Clone, Debug)]
+pub enum Stmt {
+    Block(Vec<Stmt>),
+    Declaration(Declaration),
+    Expr(Expr),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    For(Option<Box<Stmt>>, Option<Expr>, Option<Expr>, Box<Stmt>),
+    While(Expr, Box<Stmt>),
+    DoWhile(Box<Stmt>, Expr),
+    Return(Option<Expr>, Loc),
+    Break(Loc),
+    Continue(Loc),
+    Discard(Loc),
+    Empty,
+}
@@ -0,0 +1,320 @@
+use shader::glsl::types::{Base, Type};
+
+// Builtin variables and functions of GLSL 1.40. Functions that are known but
+// not modelled return Type::Unknown so they never cause false errors.
+
+static FLOAT_UNARY: [&'static str; 28] = ["radians", "degrees", "sin", "cos", "tan", "asin", "acos", "sinh", "cosh",
+                                          "tanh", "asinh", "acosh", "atanh", "exp", "log", "exp2", "log2", "sqrt",
+                                          "inversesqrt", "abs", "sign", "floor", "trunc", "round", "roundEven",
+                                          "ceil", "fract", "normalize"];
+
+static DERIVATIVES: [&'static str; 3] = ["dFdx", "dFdy", "fwidth"];
+
+static UNCHECKED: [&'static str; 16] = ["textureOffset", "textureProj", "textureProjOffset", "textureLodOffset",
+                                        "textureProjLod", "textureGrad", "textureGradOffset", "texelFetchOffset",
+                                        "textureProjGrad", "modf", "isnan", "isinf", "floatBitsToInt",
+                                        "floatBitsToUint", "intBitsToFloat", "uintBitsToFloat"];
+
+fn float() -> Type {
+    Type::Scalar(Base::Float)
+}
+
+// Type of a builtin variable and whether it can be written
+pub fn variable(name: &str, fragment: bool) -> Option<(Type, bool)> {
+    match (name, fragment) {
+        ("gl_Position", false) => Some((Type::Vector(Base::Float, 4), true)),
+        ("gl_PointSize", false) => Some((float(), true)),
+        ("gl_VertexID", false) | ("gl_InstanceID", false) => Some((Type::Scalar(Base::Int), false)),
+        ("gl_FragCoord", true) => Some((Type::Vector(Base::Float, 4), false)),
+        ("gl_FrontFacing", true) => Some((Type::Scalar(Base::Bool), false)),
+        ("gl_PointCoord", true) => Some((Type::Vector(Base::Float, 2), false)),
+        ("gl_FragDepth", true) => Some((float(), true)),
+        _ => None,
+    }
+}
+
+pub fn is_function(name: &str) -> bool {
+    UNCHECKED.contains(&name) || FLOAT_UNARY.contains(&name) || DERIVATIVES.contains(&name) ||
+    match name {
+        "atan" | "pow" | "mod" | "min" | "max" | "clamp" | "mix" | "step" | "smoothstep" | "length" |
+        "distance" | "dot" | "cross" | "reflect" | "refract" | "faceforward" | "matrixCompMult" |
+        "outerProduct" | "transpose" | "inverse" | "determinant" | "lessThan" | "lessThanEqual" |
+        "greaterThan" | "greaterThanEqual" | "equal" | "notEqual" | "any" | "all" | "not" | "texture" |
+        "textureLod" | "texelFetch" | "textureSize" => true,
+        _ => false,
+    }
+}
+
+// Float version of a genType argument, ints convert implicitly
+fn gen(t: &Type) -> Option<Type> {
+    match *t {
+        Type::Scalar(b) if b != Base::Bool => Some(float()),
+        Type::Vector(b, n) if b != Base::Bool => Some(Type::Vector(Base::Float, n)),
+        Type::Unknown => Some(Type::Unknown),
+        _ => None,
+    }
+}
+
+fn same(g: &Type, t: &Type) -> bool {
+    match gen(t) {
+        Some(x) => x == *g || x.is_unknown() || g.is_unknown(),
+        None => false,
+    }
+}
+
+fn same_or_float(g: &Type, t: &Type) -> bool {
+    same(g, t) || same(&float(), t)
+}
+
+// Integer overloads of abs, sign, min, max and clamp: every argument has the
+// base of the first and its shape or is a scalar
+fn integer_overload(args: &[Type]) -> Option<Type> {
+    let first = match args.first() {
+        Some(t) if t.is_integer() => t,
+        _ => return None,
+    };
+    let base = first.base();
+    let ok = args[1..].iter().all(|t| t.base() == base && (t == first || t.components() == Some(1)));
+    if ok {
+        Some(first.clone())
+    } else {
+        None
+    }
+}
+
+fn sampler_coordinates(sampler: &str) -> Option<(Type, Type, Type)> {
+    // Coordinates, texelFetch coordinates and result
+    let v = |n| Type::Vector(Base::Float, n);
+    let iv = |n| Type::Vector(Base::Int, n);
+    let result = if sampler.starts_with('i') {
+        Type::Vector(Base::Int, 4)
+    } else if sampler.starts_with('u') {
+        Type::Vector(Base::UInt, 4)
+    } else if sampler.ends_with("Shadow") {
+        float()
+    } else {
+        v(4)
+    };
+    let name = sampler.trim_left_matches(|c| c == 'i' || c == 'u');
+    let (coords, fetch) = match name {
+        "sampler1D" | "samplerBuffer" => (float(), Type::Scalar(Base::Int)),
+        "sampler2D" | "sampler2DRect" => (v(2), iv(2)),
+        "sampler3D" | "sampler2DArray" => (v(3), iv(3)),
+        "sampler1DArray" => (v(2), iv(2)),
+        "samplerCube" => (v(3), iv(3)),
+        "sampler1DShadow" | "sampler2DShadow" | "sampler2DRectShadow" => (v(3), iv(2)),
+        "samplerCubeShadow" | "sampler2DArrayShadow" => (v(4), iv(3)),
+        _ => return None,
+    };
+    Some((coords, fetch, result))
+}
+
+fn count(name: &str, args: &[Type], min: usize, max: usize) -> Result<(), String> {
+    if args.len() < min || args.len() > max {
+        if min == max {
+            Err(format!("{} takes {} arguments, not {}", name, min, args.len()))
+        } else {
+            Err(format!("{} takes {} to {} arguments, not {}", name, min, max, args.len()))
+        }
+    } else {
+        Ok(())
+    }
+}
+
+fn bad_arguments(name: &str, args: &[Type]) -> String {
+    let names: Vec<String> = args.iter().map(|t| t.to_string()).collect();
+    format!("no overload of {} takes ({})", name, names.join(", "))
+}
+
+// Result of calling the builtin `name`, None if there is no such builtin.
+// `fragment` tells whether the call is in a fragment shader.
+pub fn call(name: &str, args: &[Type], fragment: bool) -> Option<Result<Type, String>> {
+    if !is_function(name) {
+        return None;
+    }
+    if UNCHECKED.contains(&name) {
+        return Some(Ok(Type::Unknown));
+    }
+    if args.is_empty() {
+        return Some(Err(format!("{} takes arguments", name)));
+    }
+    Some(check_call(name, args, fragment))
+}
+
+fn check_call(name: &str, args: &[Type], fragment: bool) -> Result<Type, String> {
+    if args.iter().any(|t| t.is_unknown()) {
+        return Ok(Type::Unknown);
+    }
+    let bad = || Err(bad_arguments(name, args));
+    if DERIVATIVES.contains(&name) && !fragment {
+        return Err(format!("{} is only available in fragment shaders", name));
+    }
+    if FLOAT_UNARY.contains(&name) || DERIVATIVES.contains(&name) {
+        try!(count(name, args, 1, 1));
+        if name == "abs" || name == "sign" {
+            if let Some(t) = integer_overload(args) {
+                return Ok(t);
+            }
+        }
+        return match gen(&args[0]) {
+            Some(g) => Ok(g),
+            None => bad(),
+        };
+    }
+    let g0 = gen(&args[0]);
+    match name {
+        "atan" | "pow" | "distance" | "dot" | "reflect" | "faceforward" | "cross" => {
+            let arity = match name {
+                "atan" => 1,
+                "faceforward" => 3,
+                _ => 2,
+            };
+            try!(count(name, args, arity, if name == "atan" { 2 } else { arity }));
+            let g = match g0 {
+                Some(g) => g,
+                None => return bad(),
+            };
+            if !args.iter().all(|t| same(&g, t)) {
+                return bad();
+            }
+            match name {
+                "distance" | "dot" => Ok(float()),
+                "cross" if g != Type::Vector(Base::Float, 3) => bad(),
+                _ => Ok(g),
+            }
+        }
+        "length" => {
+            try!(count(name, args, 1, 1));
+            match g0 {
+                Some(_) => Ok(float()),
+                None => bad(),
+            }
+        }
+        "refract" => {
+            try!(count(name, args, 3, 3));
+            match g0 {
+                Some(ref g) if same(g, &args[1]) && same(&float(), &args[2]) => Ok(g.clone()),
+                _ => bad(),
+            }
+        }
+        "min" | "max" | "clamp" | "mod" => {
+            try!(count(name, args, if name == "clamp" { 3 } else { 2 }, if name == "clamp" { 3 } else { 2 }));
+            if name != "mod" {
+                if let Some(t) = integer_overload(args) {
+                    return Ok(t);
+                }
+            }
+            match g0 {
+                Some(ref g) if args[1..].iter().all(|t| same_or_float(g, t)) => Ok(g.clone()),
+                _ => bad(),
+            }
+        }
+        "mix" => {
+            try!(count(name, args, 3, 3));
+            match g0 {
+                Some(ref g) if same(g, &args[1]) => {
+                    let selector = match args[2] {
+                        Type::Scalar(Base::Bool) => true,
+                        Type::Vector(Base::Bool, n) => g.components() == Some(n),
+                        ref t => same_or_float(g, t),
+                    };
+                    if selector { Ok(g.clone()) } else { bad() }
+                }
+                _ => bad(),
+            }
+        }
+        "step" => {
+            try!(count(name, args, 2, 2));
+            match gen(&args[1]) {
+                Some(ref g) if same_or_float(g, &args[0]) => Ok(g.clone()),
+                _ => bad(),
+            }
+        }
+        "smoothstep" => {
+            try!(count(name, args, 3, 3));
+            match gen(&args[2]) {
+                Some(ref g) if same_or_float(g, &args[0]) && same_or_float(g, &args[1]) => Ok(g.clone()),
+                _ => bad(),
+            }
+        }
+        "matrixCompMult" => {
+            try!(count(name, args, 2, 2));
+            match args[0] {
+                Type::Matrix(..) if args[0] == args[1] => Ok(args[0].clone()),
+                _ => bad(),
+            }
+        }
+        "outerProduct" => {
+            try!(count(name, args, 2, 2));
+            match (gen(&args[0]), gen(&args[1])) {
+                (Some(Type::Vector(_, r)), Some(Type::Vector(_, c))) => Ok(Type::Matrix(c, r)),
+                _ => bad(),
+            }
+        }
+        "transpose" | "inverse" | "determinant" => {
+            try!(count(name, args, 1, 1));
+            match args[0] {
+                Type::Matrix(c, r) if name == "transpose" => Ok(Type::Matrix(r, c)),
+                Type::Matrix(c, r) if c == r && name == "inverse" => Ok(args[0].clone()),
+                Type::Matrix(c, r) if c == r => Ok(float()),
+                _ => bad(),
+            }
+        }
+        "lessThan" | "lessThanEqual" | "greaterThan" | "greaterThanEqual" | "equal" | "notEqual" => {
+            try!(count(name, args, 2, 2));
+            match (&args[0], &args[1]) {
+                (&Type::Vector(a, n), &Type::Vector(b, m)) if a == b && n == m &&
+                                                              (a != Base::Bool || name == "equal" ||
+                                                               name == "notEqual") => {
+                    Ok(Type::Vector(Base::Bool, n))
+                }
+                _ => bad(),
+            }
+        }
+        "any" | "all" | "not" => {
+            try!(count(name, args, 1, 1));
+            match args[0] {
+                Type::Vector(Base::Bool, _) if name == "not" => Ok(args[0].clone()),
+                Type::Vector(Base::Bool, _) => Ok(Type::Scalar(Base::Bool)),
+                _ => bad(),
+            }
+        }
+        _ => texture_call(name, args),
+    }
+}
+
+fn texture_call(name: &str, args: &[Type]) -> Result<Type, String> {
+    let bad = || Err(bad_arguments(name, args));
+    let (coords, fetch, result) = match args[0] {
+        Type::Sampler(ref s) => {
+            match sampler_coordinates(s) {
+                Some(c) => c,
+                None => return Ok(Type::Unknown),
+            }
+        }
+        _ => return bad(),
+    };
+    let int = Type::Scalar(Base::Int);
+    let ok = match name {
+        "texture" => {
+            (args.len() == 2 || (args.len() == 3 && same(&float(), &args[2]))) && same(&coords, &args[1])
+        }
+        "textureLod" => args.len() == 3 && same(&coords, &args[1]) && same(&float(), &args[2]),
+        "texelFetch" => {
+            args.len() >= 2 && args.len() <= 3 && args[1] == fetch && args[2..].iter().all(|t| *t == int)
+        }
+        _ => {
+            // textureSize
+            let size = match fetch {
+                Type::Vector(_, n) => Type::Vector(Base::Int, n),
+                _ => int.clone(),
+            };
+            return if args.len() <= 2 && args[1..].iter().all(|t| *t == int) {
+                Ok(size)
+            } else {
+                bad()
+            };
+        }
+    };
+    if ok { Ok(result) } else { bad() }
+}
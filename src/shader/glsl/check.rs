@@ -0,0 +1,693 @@
+// Standard Library
+use std::collections::HashMap;
+
+use shader::glsl::ast::*;
+use shader::glsl::builtins;
+use shader::glsl::lexer::Loc;
+use shader::glsl::types::{self, Base, Type};
+
+// Semantic checks of one parsed shader stage: every name is declared before
+// it is used, operands and arguments have matching types, read-only
+// variables are not written and main exists.
+
+// A stage input, output or uniform
+#[derive(Clone, Debug)]
+pub struct Variable {
+    pub name: String,
+    pub ty: Type,
+    pub loc: Loc,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Interface {
+    pub inputs: Vec<Variable>,
+    pub outputs: Vec<Variable>,
+    pub uniforms: Vec<Variable>,
+}
+
+struct Binding {
+    ty: Type,
+    writable: bool,
+}
+
+struct Signature {
+    params: Vec<(Type, bool)>,
+    ret: Type,
+    defined: bool,
+}
+
+pub struct Checker {
+    fragment: bool,
+    structs: HashMap<String, Vec<(String, Type)>>,
+    functions: HashMap<String, Vec<Signature>>,
+    // The first scope holds the globals
+    scopes: Vec<HashMap<String, Binding>>,
+    ret: Type,
+    loops: usize,
+    pub interface: Interface,
+    pub errors: Vec<(Loc, String)>,
+}
+
+fn bool_type() -> Type {
+    Type::Scalar(Base::Bool)
+}
+
+fn has(qualifiers: &[String], name: &str) -> bool {
+    qualifiers.iter().any(|q| q == name)
+}
+
+impl Checker {
+    pub fn new(fragment: bool) -> Checker {
+        Checker {
+            fragment: fragment,
+            structs: HashMap::new(),
+            functions: HashMap::new(),
+            scopes: vec![HashMap::new()],
+            ret: Type::Void,
+            loops: 0,
+            interface: Interface::default(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn error(&mut self, loc: Loc, message: String) {
+        self.errors.push((loc, message));
+    }
+
+    pub fn check(&mut self, unit: &[External]) {
+        for external in unit.iter() {
+            match *external {
+                External::Declaration(ref d) => self.global(d),
+                External::Struct(ref s, ref d) => {
+                    self.struct_def(s);
+                    if let Some(ref d) = *d {
+                        self.global(d);
+                    }
+                }
+                External::Block(ref b) => self.block(b),
+                External::Function(ref f) => self.function(f),
+                External::Precision => {}
+            }
+        }
+        let main = self.functions.get("main").and_then(|s| s.iter().find(|s| s.params.is_empty() && s.defined));
+        match main {
+            Some(s) if s.ret == Type::Void => {}
+            Some(_) => self.errors.push((Loc { id: 0, line: 0 }, "main must return void".to_string())),
+            None => self.errors.push((Loc { id: 0, line: 0 }, "no definition of main()".to_string())),
+        }
+    }
+
+    // Length of an array declaration, 0 when it is not a literal
+    fn array_length(&mut self, size: &Option<Expr>) -> usize {
+        match *size {
+            Some(ref e) => {
+                let t = self.expr(e);
+                if !t.is_integer() && !t.is_unknown() {
+                    self.error(e.loc, format!("array size must be an integer, not {}", t));
+                }
+                match e.kind {
+                    ExprKind::Int(n) | ExprKind::UInt(n) if n > 0 => n as usize,
+                    ExprKind::Int(_) | ExprKind::UInt(_) => {
+                        self.error(e.loc, "array size must be positive".to_string());
+                        0
+                    }
+                    _ => 0,
+                }
+            }
+            None => 0,
+        }
+    }
+
+    fn resolve(&mut self, ty: &TypeName, array: &Option<Option<Expr>>) -> Type {
+        let mut t = match types::from_name(&ty.name) {
+            Some(t) => t,
+            None if self.structs.contains_key(&ty.name) => Type::Struct(ty.name.clone()),
+            None => {
+                self.error(ty.loc, format!("unknown type {}", ty.name));
+                Type::Unknown
+            }
+        };
+        for a in [&ty.array, array].iter() {
+            if let Some(ref size) = **a {
+                let n = self.array_length(size);
+                t = Type::Array(Box::new(t), n);
+            }
+        }
+        t
+    }
+
+    fn declare(&mut self, name: &str, loc: Loc, ty: Type, writable: bool) {
+        if name.starts_with("gl_") {
+            self.error(loc, format!("{} is reserved", name));
+        }
+        let scope = self.scopes.last_mut().unwrap();
+        if scope.contains_key(name) {
+            self.errors.push((loc, format!("redefinition of {}", name)));
+        }
+        scope.insert(name.to_string(),
+                     Binding {
+                         ty: ty,
+                         writable: writable,
+                     });
+    }
+
+    fn global(&mut self, d: &Declaration) {
+        let q = &d.qualifiers;
+        let input = has(q, "in") || has(q, "attribute") || (has(q, "varying") && self.fragment);
+        let output = has(q, "out") || (has(q, "varying") && !self.fragment);
+        let uniform = has(q, "uniform");
+        let constant = has(q, "const");
+        for var in d.vars.iter() {
+            let ty = self.resolve(&d.ty, &var.array);
+            if ty == Type::Void {
+                self.error(var.loc, format!("{} can not be void", var.name));
+            }
+            if (input || output) && var.init.is_some() {
+                self.error(var.loc, format!("stage variable {} can not be initialized", var.name));
+            }
+            if constant && var.init.is_none() {
+                self.error(var.loc, format!("const {} needs an initializer", var.name));
+            }
+            if (input || output || uniform) && !self.allowed_stage_type(&ty, uniform) {
+                self.error(var.loc, format!("{} can not have type {}", var.name, ty));
+            }
+            self.initializer(var, &ty);
+            let v = Variable {
+                name: var.name.clone(),
+                ty: ty.clone(),
+                loc: var.loc,
+            };
+            if input {
+                self.interface.inputs.push(v);
+            } else if output {
+                self.interface.outputs.push(v);
+            } else if uniform {
+                self.interface.uniforms.push(v);
+            }
+            self.declare(&var.name, var.loc, ty, !(input || uniform || constant));
+        }
+    }
+
+    // Samplers and structs may only be uniforms
+    fn allowed_stage_type(&self, ty: &Type, uniform: bool) -> bool {
+        let element = match *ty {
+            Type::Array(ref t, _) => &**t,
+            ref t => t,
+        };
+        match *element {
+            Type::Sampler(_) | Type::Struct(_) => uniform,
+            _ => true,
+        }
+    }
+
+    fn initializer(&mut self, var: &Declarator, ty: &Type) {
+        if let Some(ref init) = var.init {
+            let t = self.expr(init);
+            if !types::converts(&t, ty) {
+                self.error(init.loc, format!("can not initialize {} {} with {}", ty, var.name, t));
+            }
+        }
+    }
+
+    fn members(&mut self, members: &[Declaration]) -> Vec<(String, Type)> {
+        let mut fields = Vec::new();
+        for d in members.iter() {
+            for var in d.vars.iter() {
+                let ty = self.resolve(&d.ty, &var.array);
+                if fields.iter().any(|&(ref n, _)| *n == var.name) {
+                    self.error(var.loc, format!("duplicate member {}", var.name));
+                }
+                fields.push((var.name.clone(), ty));
+            }
+        }
+        fields
+    }
+
+    fn struct_def(&mut self, s: &StructDef) {
+        let fields = self.members(&s.fields);
+        if self.structs.contains_key(&s.name) {
+            self.error(s.loc, format!("redefinition of struct {}", s.name));
+        }
+        self.structs.insert(s.name.clone(), fields);
+    }
+
+    fn block(&mut self, b: &BlockDef) {
+        if !has(&b.qualifiers, "uniform") {
+            self.error(b.loc, format!("block {} must be a uniform block", b.name));
+        }
+        let members = self.members(&b.members);
+        match b.instance {
+            Some(ref instance) => {
+                self.structs.insert(b.name.clone(), members);
+                let ty = TypeName {
+                    name: b.name.clone(),
+                    array: None,
+                    loc: b.loc,
+                };
+                let ty = self.resolve(&ty, &instance.array);
+                self.interface.uniforms.push(Variable {
+                    name: instance.name.clone(),
+                    ty: ty.clone(),
+                    loc: instance.loc,
+                });
+                self.declare(&instance.name, instance.loc, ty, false);
+            }
+            None => {
+                // Members of anonymous blocks are globals
+                for (name, ty) in members.into_iter() {
+                    self.interface.uniforms.push(Variable {
+                        name: name.clone(),
+                        ty: ty.clone(),
+                        loc: b.loc,
+                    });
+                    self.declare(&name, b.loc, ty, false);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, f: &FunctionDef) {
+        let ret = self.resolve(&f.ret, &None);
+        let mut params = Vec::new();
+        for p in f.params.iter() {
+            let ty = self.resolve(&p.ty, &p.array);
+            let out = has(&p.qualifiers, "out") || has(&p.qualifiers, "inout");
+            params.push((ty, out));
+        }
+        if builtins::is_function(&f.name) {
+            self.error(f.loc, format!("{} redefines a builtin function", f.name));
+        }
+        if self.scopes[0].contains_key(&f.name) {
+            self.error(f.loc, format!("{} is already a variable", f.name));
+        }
+        let defined = f.body.is_some();
+        let mut redefined = false;
+        {
+            let signatures = self.functions.entry(f.name.clone()).or_insert_with(Vec::new);
+            let types: Vec<&Type> = params.iter().map(|&(ref t, _)| t).collect();
+            let existing = signatures.iter()
+                                     .position(|s| s.params.iter().map(|&(ref t, _)| t).collect::<Vec<_>>() == types);
+            match existing {
+                Some(i) => {
+                    redefined = (signatures[i].defined && defined) || signatures[i].ret != ret;
+                    signatures[i].defined = signatures[i].defined || defined;
+                }
+                None => {
+                    signatures.push(Signature {
+                        params: params.clone(),
+                        ret: ret.clone(),
+                        defined: defined,
+                    })
+                }
+            }
+        }
+        if redefined {
+            self.error(f.loc, format!("redefinition of {}", f.name));
+        }
+
+        let body = match f.body {
+            Some(ref b) => b,
+            None => return,
+        };
+        self.scopes.push(HashMap::new());
+        for (p, &(ref ty, _)) in f.params.iter().zip(params.iter()) {
+            if let Some(ref name) = p.name {
+                let writable = !has(&p.qualifiers, "const");
+                self.declare(name, f.loc, ty.clone(), writable);
+            }
+        }
+        self.ret = ret;
+        for s in body.iter() {
+            self.statement(s);
+        }
+        self.scopes.pop();
+    }
+
+    fn lookup(&self, name: &str) -> Option<(Type, bool)> {
+        for scope in self.scopes.iter().rev() {
+            if let Some(b) = scope.get(name) {
+                return Some((b.ty.clone(), b.writable));
+            }
+        }
+        builtins::variable(name, self.fragment)
+    }
+
+    fn condition(&mut self, e: &Expr, what: &str) {
+        let t = self.expr(e);
+        if !types::converts(&t, &bool_type()) {
+            self.error(e.loc, format!("{} condition must be bool, not {}", what, t));
+        }
+    }
+
+    fn scoped(&mut self, s: &Stmt) {
+        self.scopes.push(HashMap::new());
+        self.statement(s);
+        self.scopes.pop();
+    }
+
+    fn statement(&mut self, s: &Stmt) {
+        match *s {
+            Stmt::Block(ref stmts) => {
+                self.scopes.push(HashMap::new());
+                for s in stmts.iter() {
+                    self.statement(s);
+                }
+                self.scopes.pop();
+            }
+            Stmt::Declaration(ref d) => {
+                let constant = has(&d.qualifiers, "const");
+                for q in d.qualifiers.iter() {
+                    if q != "const" && q != "highp" && q != "mediump" && q != "lowp" {
+                        self.error(d.ty.loc, format!("local variables can not be {}", q));
+                    }
+                }
+                for var in d.vars.iter() {
+                    let ty = self.resolve(&d.ty, &var.array);
+                    if ty == Type::Void {
+                        self.error(var.loc, format!("{} can not be void", var.name));
+                    }
+                    if constant && var.init.is_none() {
+                        self.error(var.loc, format!("const {} needs an initializer", var.name));
+                    }
+                    self.initializer(var, &ty);
+                    self.declare(&var.name, var.loc, ty, !constant);
+                }
+            }
+            Stmt::Expr(ref e) => {
+                self.expr(e);
+            }
+            Stmt::If(ref c, ref then, ref otherwise) => {
+                self.condition(c, "if");
+                self.scoped(then);
+                if let Some(ref o) = *otherwise {
+                    self.scoped(o);
+                }
+            }
+            Stmt::For(ref init, ref c, ref step, ref body) => {
+                self.scopes.push(HashMap::new());
+                if let Some(ref init) = *init {
+                    self.statement(init);
+                }
+                if let Some(ref c) = *c {
+                    self.condition(c, "for");
+                }
+                if let Some(ref step) = *step {
+                    self.expr(step);
+                }
+                self.loops += 1;
+                self.scoped(body);
+                self.loops -= 1;
+                self.scopes.pop();
+            }
+            Stmt::While(ref c, ref body) => {
+                self.condition(c, "while");
+                self.loops += 1;
+                self.scoped(body);
+                self.loops -= 1;
+            }
+            Stmt::DoWhile(ref body, ref c) => {
+                self.loops += 1;
+                self.scoped(body);
+                self.loops -= 1;
+                self.condition(c, "do-while");
+            }
+            Stmt::Return(ref value, loc) => {
+                let ret = self.ret.clone();
+                match *value {
+                    Some(ref v) => {
+                        let t = self.expr(v);
+                        if ret == Type::Void {
+                            self.error(loc, "void function returns a value".to_string());
+                        } else if !types::converts(&t, &ret) {
+                            self.error(loc, format!("returns {} from a function returning {}", t, ret));
+                        }
+                    }
+                    None if ret != Type::Void => self.error(loc, format!("missing return value of type {}", ret)),
+                    None => {}
+                }
+            }
+            Stmt::Break(loc) | Stmt::Continue(loc) => {
+                if self.loops == 0 {
+                    self.error(loc, "break or continue outside of a loop".to_string());
+                }
+            }
+            Stmt::Discard(loc) => {
+                if !self.fragment {
+                    self.error(loc, "discard outside of a fragment shader".to_string());
+                }
+            }
+            Stmt::Empty => {}
+        }
+    }
+
+    // Err with a description if `e` can not be assigned to
+    fn lvalue(&self, e: &Expr) -> Result<(), String> {
+        match e.kind {
+            ExprKind::Ident(ref name) => {
+                match self.lookup(name) {
+                    Some((_, true)) | None => Ok(()),
+                    Some((_, false)) => Err(format!("{} is read-only", name)),
+                }
+            }
+            ExprKind::Field(ref base, ref field) => {
+                let mut seen = String::new();
+                for c in field.chars() {
+                    if seen.contains(c) && field.len() <= 4 {
+                        return Err(format!("swizzle .{} repeats a component", field));
+                    }
+                    seen.push(c);
+                }
+                self.lvalue(base)
+            }
+            ExprKind::Index(ref base, _) => self.lvalue(base),
+            _ => Err("expression can not be assigned to".to_string()),
+        }
+    }
+
+    fn expr(&mut self, e: &Expr) -> Type {
+        match self.expr_type(e) {
+            Ok(t) => t,
+            Err(message) => {
+                self.error(e.loc, message);
+                Type::Unknown
+            }
+        }
+    }
+
+    fn expr_type(&mut self, e: &Expr) -> Result<Type, String> {
+        match e.kind {
+            ExprKind::Ident(ref name) => {
+                match self.lookup(name) {
+                    Some((t, _)) => Ok(t),
+                    None => Err(format!("undeclared identifier {}", name)),
+                }
+            }
+            ExprKind::Int(_) => Ok(Type::Scalar(Base::Int)),
+            ExprKind::UInt(_) => Ok(Type::Scalar(Base::UInt)),
+            ExprKind::Float(_) => Ok(Type::Scalar(Base::Float)),
+            ExprKind::Bool(_) => Ok(bool_type()),
+            ExprKind::Unary(op, ref operand) | ExprKind::Postfix(op, ref operand) => {
+                let t = self.expr(operand);
+                if t.is_unknown() {
+                    return Ok(t);
+                }
+                let ok = match op {
+                    "!" => t == bool_type(),
+                    "~" => t.is_integer(),
+                    _ => t.is_numeric(),
+                };
+                if !ok {
+                    return Err(format!("no operation '{}' on {}", op, t));
+                }
+                if op == "++" || op == "--" {
+                    try!(self.lvalue(operand));
+                }
+                Ok(t)
+            }
+            ExprKind::Binary(op, ref a, ref b) => {
+                let ta = self.expr(a);
+                let tb = self.expr(b);
+                match op {
+                    "==" | "!=" | "<" | ">" | "<=" | ">=" | "&&" | "||" | "^^" => types::comparison(op, &ta, &tb),
+                    _ => types::arithmetic(op, &ta, &tb),
+                }
+            }
+            ExprKind::Assign(op, ref target, ref value) => {
+                let tt = self.expr(target);
+                let tv = self.expr(value);
+                try!(self.lvalue(target));
+                let result = if op == "=" {
+                    tv.clone()
+                } else {
+                    try!(types::arithmetic(&op[..op.len() - 1], &tt, &tv))
+                };
+                if !types::converts(&result, &tt) {
+                    return Err(format!("can not assign {} to {}", result, tt));
+                }
+                Ok(tt)
+            }
+            ExprKind::Ternary(ref c, ref a, ref b) => {
+                self.condition(c, "?:");
+                let ta = self.expr(a);
+                let tb = self.expr(b);
+                if types::converts(&ta, &tb) {
+                    Ok(tb)
+                } else if types::converts(&tb, &ta) {
+                    Ok(ta)
+                } else {
+                    Err(format!("?: branches have types {} and {}", ta, tb))
+                }
+            }
+            ExprKind::Call(ref name, ref args) => {
+                let types: Vec<Type> = args.iter().map(|a| self.expr(a)).collect();
+                self.call(name, args, &types)
+            }
+            ExprKind::Method(ref base, ref name, ref args) => {
+                let t = self.expr(base);
+                match t {
+                    Type::Array(..) if name == "length" && args.is_empty() => Ok(Type::Scalar(Base::Int)),
+                    Type::Unknown => Ok(Type::Unknown),
+                    _ => Err(format!("{} has no method {}", t, name)),
+                }
+            }
+            ExprKind::Field(ref base, ref field) => {
+                let t = self.expr(base);
+                match t {
+                    Type::Unknown => Ok(Type::Unknown),
+                    Type::Struct(ref s) => {
+                        let fields = &self.structs[s];
+                        match fields.iter().find(|&&(ref n, _)| n == field) {
+                            Some(&(_, ref ty)) => Ok(ty.clone()),
+                            None => Err(format!("{} has no field {}", s, field)),
+                        }
+                    }
+                    Type::Vector(..) => {
+                        match types::swizzle(&t, field) {
+                            Some(ty) => Ok(ty),
+                            None => Err(format!("invalid swizzle .{} on {}", field, t)),
+                        }
+                    }
+                    _ => Err(format!("{} has no field {}", t, field)),
+                }
+            }
+            ExprKind::Index(ref base, ref index) => {
+                let t = self.expr(base);
+                let ti = self.expr(index);
+                if !ti.is_unknown() && !(ti.is_integer() && ti.components() == Some(1)) {
+                    return Err(format!("index must be an integer, not {}", ti));
+                }
+                let (element, length) = match t {
+                    Type::Unknown => return Ok(Type::Unknown),
+                    Type::Array(ref element, n) => ((**element).clone(), n),
+                    Type::Vector(base, n) => (Type::Scalar(base), n),
+                    Type::Matrix(c, r) => (Type::Vector(Base::Float, r), c),
+                    _ => return Err(format!("{} can not be indexed", t)),
+                };
+                if let ExprKind::Int(i) = index.kind {
+                    if i < 0 || (length > 0 && i as usize >= length) {
+                        return Err(format!("index {} is out of range for {}", i, t));
+                    }
+                }
+                Ok(element)
+            }
+        }
+    }
+
+    fn call(&mut self, name: &str, args: &[Expr], types: &[Type]) -> Result<Type, String> {
+        if let Some(t) = types::from_name(name) {
+            return self.construct(&t, types);
+        }
+        if self.structs.contains_key(name) && !self.scopes.iter().any(|s| s.contains_key(name)) {
+            return self.construct(&Type::Struct(name.to_string()), types);
+        }
+        if self.lookup(name).is_some() {
+            return Err(format!("{} is not a function", name));
+        }
+        if let Some(signatures) = self.functions.get(name) {
+            let arity: Vec<&Signature> = signatures.iter().filter(|s| s.params.len() == types.len()).collect();
+            let exact = arity.iter().find(|s| s.params.iter().zip(types.iter()).all(|(&(ref p, _), t)| p == t));
+            let converted = arity.iter().find(|s| {
+                s.params.iter().zip(types.iter()).all(|(&(ref p, _), t)| types::converts(t, p))
+            });
+            let signature = match exact.or(converted) {
+                Some(s) => s,
+                None => {
+                    let names: Vec<String> = types.iter().map(|t| t.to_string()).collect();
+                    return Err(format!("no overload of {} takes ({})", name, names.join(", ")));
+                }
+            };
+            for (&(_, out), arg) in signature.params.iter().zip(args.iter()) {
+                if out {
+                    if let Err(e) = self.lvalue(arg) {
+                        return Err(format!("out argument of {}: {}", name, e));
+                    }
+                }
+            }
+            return Ok(signature.ret.clone());
+        }
+        match builtins::call(name, types, self.fragment) {
+            Some(result) => result,
+            None => Err(format!("undeclared function {}", name)),
+        }
+    }
+
+    fn construct(&self, t: &Type, args: &[Type]) -> Result<Type, String> {
+        if args.iter().any(|a| a.is_unknown()) {
+            return Ok(t.clone());
+        }
+        if args.is_empty() {
+            return Err(format!("{} constructor needs arguments", t));
+        }
+        if let Type::Struct(ref name) = *t {
+            let fields = &self.structs[name];
+            let ok = fields.len() == args.len() &&
+                     fields.iter().zip(args.iter()).all(|(&(_, ref f), a)| types::converts(a, f));
+            return if ok {
+                Ok(t.clone())
+            } else {
+                Err(format!("arguments do not match the fields of {}", name))
+            };
+        }
+        let wanted = match t.components() {
+            Some(n) => n,
+            None => return Err(format!("can not construct {}", t)),
+        };
+        let mut sizes = Vec::new();
+        for a in args.iter() {
+            match a.components() {
+                Some(n) => sizes.push(n),
+                None => return Err(format!("can not construct {} from {}", t, a)),
+            }
+        }
+        if args.len() == 1 && (sizes[0] == 1 || wanted == 1) {
+            return Ok(t.clone());
+        }
+        let matrix_arg = args.iter().any(|a| match *a {
+            Type::Matrix(..) => true,
+            _ => false,
+        });
+        if let Type::Matrix(..) = *t {
+            if args.len() == 1 && matrix_arg {
+                return Ok(t.clone());
+            }
+            if matrix_arg {
+                return Err(format!("{} can only be constructed from one matrix", t));
+            }
+            let total: usize = sizes.iter().sum();
+            return if total == wanted {
+                Ok(t.clone())
+            } else {
+                Err(format!("{} needs {} components, got {}", t, wanted, total))
+            };
+        }
+        let before_last: usize = sizes[..sizes.len() - 1].iter().sum();
+        let total = before_last + sizes[sizes.len() - 1];
+        if before_last >= wanted {
+            Err(format!("too many arguments to construct {}", t))
+        } else if total < wanted {
+            Err(format!("{} needs {} components, got {}", t, wanted, total))
+        } else {
+            Ok(t.clone())
+        }
+    }
+}
@@ -0,0 +1,474 @@
+// Standard Library
+use std::collections::HashMap;
+
+// Tokens and the part of the C preprocessor GLSL uses: object-like macros,
+// #if/#ifdef/#ifndef/#elif/#else/#endif and #line. Includes are already
+// expanded by shader::preprocess at this point.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Ident(String),
+    Int(i64),
+    UInt(i64),
+    Float(f64),
+    Punct(&'static str),
+}
+
+// Source string number and line as reported by a GLSL compiler
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Loc {
+    pub id: u32,
+    pub line: usize,
+}
+
+pub struct Preprocessed {
+    pub tokens: Vec<(Token, Loc)>,
+    pub version: Option<u32>,
+    pub errors: Vec<(Loc, String)>,
+}
+
+static PUNCTS: [&'static str; 47] = ["<<=", ">>=", "++", "--", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=",
+                                     "==", "!=", "<=", ">=", "&&", "||", "^^", "<<", ">>", "+", "-", "*", "/",
+                                     "%", "=", "<", ">", "!", "~", "&", "|", "^", "?", ":", ";", ",", ".", "(",
+                                     ")", "[", "]", "{", "}", "#", "@"];
+
+fn is_ident_start(c: char) -> bool {
+    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+}
+
+fn is_ident_char(c: char) -> bool {
+    is_ident_start(c) || (c >= '0' && c <= '9')
+}
+
+// Replaces comments by spaces, newlines inside block comments are kept so
+// line numbers stay the same
+fn strip_comments(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '/' && i + 1 < chars.len() && chars[i + 1] == '/' {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if chars[i] == '/' && i + 1 < chars.len() && chars[i + 1] == '*' {
+            i += 2;
+            out.push(' ');
+            while i < chars.len() && !(chars[i] == '*' && i + 1 < chars.len() && chars[i + 1] == '/') {
+                if chars[i] == '\n' {
+                    out.push('\n');
+                }
+                i += 1;
+            }
+            i += 2;
+        } else {
+            out.push(chars[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+pub fn lex_line(line: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if is_ident_start(c) {
+            let start = i;
+            while i < chars.len() && is_ident_char(chars[i]) {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().cloned().collect()));
+        } else if c.is_digit(10) || (c == '.' && i + 1 < chars.len() && chars[i + 1].is_digit(10)) {
+            let (token, end) = try!(lex_number(&chars, i));
+            tokens.push(token);
+            i = end;
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 3)].iter().cloned().collect();
+            match PUNCTS.iter().find(|p| rest.starts_with(*p)) {
+                Some(p) => {
+                    tokens.push(Token::Punct(p));
+                    i += p.len();
+                }
+                None => return Err(format!("unexpected character '{}'", c)),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn lex_number(chars: &[char], start: usize) -> Result<(Token, usize), String> {
+    let mut i = start;
+    if chars[i] == '0' && i + 1 < chars.len() && (chars[i + 1] == 'x' || chars[i + 1] == 'X') {
+        i += 2;
+        let digits_start = i;
+        while i < chars.len() && chars[i].is_digit(16) {
+            i += 1;
+        }
+        let digits: String = chars[digits_start..i].iter().cloned().collect();
+        let value = try!(i64::from_str_radix(&digits, 16).map_err(|_| format!("bad hex literal 0x{}", digits)));
+        if i < chars.len() && (chars[i] == 'u' || chars[i] == 'U') {
+            return Ok((Token::UInt(value), i + 1));
+        }
+        return Ok((Token::Int(value), i));
+    }
+    let mut float = false;
+    while i < chars.len() && chars[i].is_digit(10) {
+        i += 1;
+    }
+    if i < chars.len() && chars[i] == '.' {
+        float = true;
+        i += 1;
+        while i < chars.len() && chars[i].is_digit(10) {
+            i += 1;
+        }
+    }
+    if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+        float = true;
+        i += 1;
+        if i < chars.len() && (chars[i] == '+' || chars[i] == '-') {
+            i += 1;
+        }
+        let exponent_start = i;
+        while i < chars.len() && chars[i].is_digit(10) {
+            i += 1;
+        }
+        if exponent_start == i {
+            return Err("missing exponent".to_string());
+        }
+    }
+    let text: String = chars[start..i].iter().cloned().collect();
+    if i < chars.len() && (chars[i] == 'f' || chars[i] == 'F') {
+        float = true;
+        i += 1;
+    }
+    if float {
+        let value = try!(text.parse::<f64>().map_err(|_| format!("bad float literal {}", text)));
+        return Ok((Token::Float(value), i));
+    }
+    // Leading zeros mean octal
+    let value = if text.len() > 1 && text.starts_with('0') {
+        try!(i64::from_str_radix(&text[1..], 8).map_err(|_| format!("bad octal literal {}", text)))
+    } else {
+        try!(text.parse::<i64>().map_err(|_| format!("bad integer literal {}", text)))
+    };
+    if i < chars.len() && (chars[i] == 'u' || chars[i] == 'U') {
+        return Ok((Token::UInt(value), i + 1));
+    }
+    Ok((Token::Int(value), i))
+}
+
+struct Conditional {
+    // Whether the enclosing block is active
+    parent: bool,
+    // Whether this branch is active
+    active: bool,
+    // Whether a branch of this #if was taken already
+    taken: bool,
+}
+
+struct Cpp {
+    macros: HashMap<String, Vec<Token>>,
+    errors: Vec<(Loc, String)>,
+}
+
+impl Cpp {
+    fn expand(&self, tokens: &[Token], expanding: &mut Vec<String>, out: &mut Vec<Token>) {
+        for t in tokens.iter() {
+            if let Token::Ident(ref name) = *t {
+                if let Some(body) = self.macros.get(name) {
+                    if !expanding.contains(name) {
+                        expanding.push(name.clone());
+                        self.expand(body, expanding, out);
+                        expanding.pop();
+                        continue;
+                    }
+                }
+            }
+            out.push(t.clone());
+        }
+    }
+
+    // Value of an #if expression
+    fn condition(&self, tokens: &[Token]) -> Result<bool, String> {
+        // `defined` is resolved before macro expansion
+        let mut resolved = Vec::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            if tokens[i] == Token::Ident("defined".to_string()) {
+                let (name, next) = match (tokens.get(i + 1), tokens.get(i + 2), tokens.get(i + 3)) {
+                    (Some(&Token::Punct("(")), Some(&Token::Ident(ref n)), Some(&Token::Punct(")"))) => (n, i + 4),
+                    (Some(&Token::Ident(ref n)), _, _) => (n, i + 2),
+                    _ => return Err("expected a name after 'defined'".to_string()),
+                };
+                resolved.push(Token::Int(if self.macros.contains_key(name) { 1 } else { 0 }));
+                i = next;
+            } else {
+                resolved.push(tokens[i].clone());
+                i += 1;
+            }
+        }
+        let mut expanded = Vec::new();
+        self.expand(&resolved, &mut Vec::new(), &mut expanded);
+        let mut eval = Eval {
+            tokens: &expanded,
+            pos: 0,
+        };
+        let value = try!(eval.expr(0));
+        if eval.pos != expanded.len() {
+            return Err("unexpected tokens after #if expression".to_string());
+        }
+        Ok(value != 0)
+    }
+}
+
+// Integer expressions of #if, undefined names are 0
+struct Eval<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> Eval<'a> {
+    fn binary_precedence(op: &str) -> Option<u8> {
+        match op {
+            "||" => Some(1),
+            "&&" => Some(2),
+            "|" => Some(3),
+            "^" => Some(4),
+            "&" => Some(5),
+            "==" | "!=" => Some(6),
+            "<" | ">" | "<=" | ">=" => Some(7),
+            "<<" | ">>" => Some(8),
+            "+" | "-" => Some(9),
+            "*" | "/" | "%" => Some(10),
+            _ => None,
+        }
+    }
+
+    fn expr(&mut self, min: u8) -> Result<i64, String> {
+        let mut left = try!(self.unary());
+        loop {
+            let op = match self.tokens.get(self.pos) {
+                Some(&Token::Punct(op)) => op,
+                _ => break,
+            };
+            let precedence = match Eval::binary_precedence(op) {
+                Some(p) if p > min => p,
+                _ => break,
+            };
+            self.pos += 1;
+            let right = try!(self.expr(precedence));
+            left = match op {
+                "||" => ((left != 0) || (right != 0)) as i64,
+                "&&" => ((left != 0) && (right != 0)) as i64,
+                "|" => left | right,
+                "^" => left ^ right,
+                "&" => left & right,
+                "==" => (left == right) as i64,
+                "!=" => (left != right) as i64,
+                "<" => (left < right) as i64,
+                ">" => (left > right) as i64,
+                "<=" => (left <= right) as i64,
+                ">=" => (left >= right) as i64,
+                "<<" => left << (right & 63),
+                ">>" => left >> (right & 63),
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "*" => left.wrapping_mul(right),
+                _ => {
+                    if right == 0 {
+                        return Err("division by zero in #if".to_string());
+                    }
+                    if op == "/" {
+                        left / right
+                    } else {
+                        left % right
+                    }
+                }
+            };
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        let token = match self.tokens.get(self.pos) {
+            Some(t) => t.clone(),
+            None => return Err("incomplete #if expression".to_string()),
+        };
+        self.pos += 1;
+        match token {
+            Token::Int(v) | Token::UInt(v) => Ok(v),
+            Token::Ident(_) => Ok(0),
+            Token::Punct("!") => self.unary().map(|v| (v == 0) as i64),
+            Token::Punct("-") => self.unary().map(|v| -v),
+            Token::Punct("+") => self.unary(),
+            Token::Punct("~") => self.unary().map(|v| !v),
+            Token::Punct("(") => {
+                let v = try!(self.expr(0));
+                match self.tokens.get(self.pos) {
+                    Some(&Token::Punct(")")) => {
+                        self.pos += 1;
+                        Ok(v)
+                    }
+                    _ => Err("missing ')' in #if expression".to_string()),
+                }
+            }
+            _ => Err("unexpected token in #if expression".to_string()),
+        }
+    }
+}
+
+// Runs the preprocessor over `text`, lines before the first #line belong to
+// source string `first_id`
+pub fn preprocess(text: &str, first_id: u32) -> Preprocessed {
+    let mut cpp = Cpp {
+        macros: HashMap::new(),
+        errors: Vec::new(),
+    };
+    let mut tokens = Vec::new();
+    let mut version = None;
+    let mut stack: Vec<Conditional> = Vec::new();
+    let mut loc = Loc {
+        id: first_id,
+        line: 1,
+    };
+
+    for raw in strip_comments(text).lines() {
+        let here = loc;
+        loc.line += 1;
+        let active = stack.last().map(|c| c.parent && c.active).unwrap_or(true);
+        let line = match lex_line(raw) {
+            Ok(l) => l,
+            Err(e) => {
+                if active {
+                    cpp.errors.push((here, e));
+                }
+                continue;
+            }
+        };
+        if line.first() != Some(&Token::Punct("#")) {
+            if active {
+                let mut expanded = Vec::new();
+                cpp.expand(&line, &mut Vec::new(), &mut expanded);
+                tokens.extend(expanded.into_iter().map(|t| (t, here)));
+            }
+            continue;
+        }
+        let directive = match line.get(1) {
+            Some(&Token::Ident(ref d)) => d.clone(),
+            None => continue,
+            _ => {
+                cpp.errors.push((here, "invalid directive".to_string()));
+                continue;
+            }
+        };
+        let args = &line[2..];
+        match &directive[..] {
+            "if" | "ifdef" | "ifndef" => {
+                let value = if !active {
+                    false
+                } else if directive == "if" {
+                    match cpp.condition(args) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            cpp.errors.push((here, e));
+                            false
+                        }
+                    }
+                } else {
+                    let defined = match args.first() {
+                        Some(&Token::Ident(ref n)) => cpp.macros.contains_key(n),
+                        _ => {
+                            cpp.errors.push((here, format!("#{} expects a name", directive)));
+                            false
+                        }
+                    };
+                    defined == (directive == "ifdef")
+                };
+                stack.push(Conditional {
+                    parent: active,
+                    active: value,
+                    taken: value,
+                });
+            }
+            "elif" | "else" | "endif" => {
+                let c = match stack.last_mut() {
+                    Some(c) => c,
+                    None => {
+                        cpp.errors.push((here, format!("#{} without #if", directive)));
+                        continue;
+                    }
+                };
+                if directive == "endif" {
+                    stack.pop();
+                    continue;
+                }
+                let value = if c.taken || !c.parent {
+                    false
+                } else if directive == "elif" {
+                    match cpp.condition(args) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            cpp.errors.push((here, e));
+                            false
+                        }
+                    }
+                } else {
+                    true
+                };
+                c.active = value;
+                c.taken = c.taken || value;
+            }
+            _ if !active => {}
+            "define" => {
+                match (args.first(), args.get(1)) {
+                    (Some(&Token::Ident(ref name)), Some(&Token::Punct("("))) if raw.contains(&format!("{}(", name)) => {
+                        cpp.errors.push((here, format!("function-like macro {} is not supported", name)));
+                    }
+                    (Some(&Token::Ident(ref name)), _) => {
+                        cpp.macros.insert(name.clone(), args[1..].to_vec());
+                    }
+                    _ => cpp.errors.push((here, "#define expects a name".to_string())),
+                }
+            }
+            "undef" => {
+                if let Some(&Token::Ident(ref name)) = args.first() {
+                    cpp.macros.remove(name);
+                }
+            }
+            "line" => {
+                match (args.get(0), args.get(1)) {
+                    (Some(&Token::Int(line)), id) => {
+                        // Numbering of GLSL before 3.30, see shader::preprocess
+                        loc.line = line as usize + 1;
+                        if let Some(&Token::Int(id)) = id {
+                            loc.id = id as u32;
+                        }
+                    }
+                    _ => cpp.errors.push((here, "#line expects a line number".to_string())),
+                }
+            }
+            "version" => {
+                match args.first() {
+                    Some(&Token::Int(v)) if version.is_none() && tokens.is_empty() => version = Some(v as u32),
+                    _ => cpp.errors.push((here, "#version must come first and only once".to_string())),
+                }
+            }
+            "error" => cpp.errors.push((here, "#error".to_string())),
+            "pragma" | "extension" => {}
+            _ => cpp.errors.push((here, format!("unknown directive #{}", directive))),
+        }
+    }
+    if !stack.is_empty() {
+        cpp.errors.push((loc, "missing #endif".to_string()));
+    }
+    Preprocessed {
+        tokens: tokens,
+        version: version,
+        errors: cpp.errors,
+    }
+}
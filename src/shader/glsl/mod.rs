@@ -0,0 +1,213 @@
+// Standard Library
+use std::fmt;
+
+use shader::preprocess::Source;
+
+mod ast;
+mod builtins;
+mod check;
+mod lexer;
+mod parser;
+mod types;
+
+// A small GLSL 1.40 front end, enough to catch the mistakes that otherwise
+// only show up when a driver compiles the shaders: syntax errors, undeclared
+// names, type errors and fragment inputs the vertex shader does not write.
+// It runs on the output of shader::preprocess, so locations name the files
+// the code came from.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Stage {
+    Vertex,
+    Fragment,
+}
+
+fn diagnostic(source: &Source, loc: lexer::Loc, message: String) -> Diagnostic {
+    let file = match source.file(loc.id) {
+        Some(f) => f.to_string(),
+        None => source.files.first().cloned().unwrap_or_else(String::new),
+    };
+    Diagnostic {
+        file: file,
+        line: loc.line,
+        message: message,
+    }
+}
+
+// Checks one stage, the interface is empty if it did not parse
+fn check_stage(source: &Source, stage: Stage) -> (Vec<Diagnostic>, check::Interface) {
+    let pre = lexer::preprocess(&source.text, source.first_id);
+    let mut diagnostics: Vec<Diagnostic> = pre.errors
+                                               .into_iter()
+                                               .map(|(loc, e)| diagnostic(source, loc, e))
+                                               .collect();
+    if pre.version.is_none() {
+        let loc = lexer::Loc {
+            id: source.first_id,
+            line: 1,
+        };
+        diagnostics.push(diagnostic(source, loc, "missing #version".to_string()));
+    }
+    let unit = match parser::parse(&pre.tokens) {
+        Ok(u) => u,
+        Err((loc, e)) => {
+            diagnostics.push(diagnostic(source, loc, e));
+            return (diagnostics, check::Interface::default());
+        }
+    };
+    let mut checker = check::Checker::new(stage == Stage::Fragment);
+    checker.check(&unit);
+    diagnostics.extend(checker.errors.into_iter().map(|(loc, e)| diagnostic(source, loc, e)));
+    (diagnostics, checker.interface)
+}
+
+pub fn validate_stage(source: &Source, stage: Stage) -> Vec<Diagnostic> {
+    check_stage(source, stage).0
+}
+
+// Checks both stages and that they fit together: every fragment input is a
+// vertex output of the same type and uniforms shared by both agree.
+pub fn validate_program(vertex: &Source, fragment: &Source) -> Vec<Diagnostic> {
+    let (mut diagnostics, vertex_interface) = check_stage(vertex, Stage::Vertex);
+    let (fragment_diagnostics, fragment_interface) = check_stage(fragment, Stage::Fragment);
+    diagnostics.extend(fragment_diagnostics);
+
+    for input in fragment_interface.inputs.iter() {
+        let message = match vertex_interface.outputs.iter().find(|o| o.name == input.name) {
+            None => format!("fragment input {} is not an output of the vertex shader", input.name),
+            Some(output) if output.ty != input.ty => {
+                format!("fragment input {} is {} but the vertex shader writes {}",
+                        input.name,
+                        input.ty,
+                        output.ty)
+            }
+            Some(_) => continue,
+        };
+        diagnostics.push(diagnostic(fragment, input.loc, message));
+    }
+    for uniform in fragment_interface.uniforms.iter() {
+        if let Some(other) = vertex_interface.uniforms.iter().find(|u| u.name == uniform.name) {
+            if other.ty != uniform.ty {
+                let message = format!("uniform {} is {} but {} in the vertex shader",
+                                      uniform.name,
+                                      uniform.ty,
+                                      other.ty);
+                diagnostics.push(diagnostic(fragment, uniform.loc, message));
+            }
+        }
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use shader::Sources;
+    use shader::permutation::Features;
+    use shader::preprocess::Preprocessor;
+    use super::validate_program;
+
+    static VERTEX: &'static str = "#version 140
+uniform mat4 matrix;
+in vec3 position;
+out vec3 v_position;
+void main() {
+    v_position = position;
+    gl_Position = matrix * vec4(position, 1.0);
+}
+";
+
+    // Messages for a fragment shader linked against VERTEX
+    fn check(fragment: &str) -> Vec<String> {
+        let p = Preprocessor::new();
+        let vertex = p.process("vertex", VERTEX, 100).unwrap();
+        let fragment = p.process("fragment", fragment, 200).unwrap();
+        validate_program(&vertex, &fragment).iter().map(|d| d.to_string()).collect()
+    }
+
+    #[test]
+    fn builtin_shaders_are_valid() {
+        for sources in Sources::builtin().iter() {
+            for features in Features::combinations() {
+                let diagnostics = sources.validate(&[], features).unwrap();
+                assert!(diagnostics.is_empty(),
+                        "{} {} {:?}: {:?}",
+                        sources.vertex_name,
+                        sources.fragment_name,
+                        features.names(),
+                        diagnostics);
+            }
+        }
+    }
+
+    #[test]
+    fn valid_fragment_shader_passes() {
+        let fragment = "#version 140
+uniform vec3 color;
+in vec3 v_position;
+out vec4 frag_color;
+void main() {
+    frag_color = vec4(color * length(v_position), 1.0);
+}
+";
+        assert_eq!(check(fragment), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_undeclared_uniforms() {
+        let fragment = "#version 140
+in vec3 v_position;
+out vec4 frag_color;
+void main() {
+    frag_color = vec4(tint * v_position, 1.0);
+}
+";
+        let messages = check(fragment);
+        assert_eq!(messages.len(), 1, "{:?}", messages);
+        assert!(messages[0].starts_with("fragment:5: ") && messages[0].contains("tint"),
+                "{:?}",
+                messages);
+    }
+
+    #[test]
+    fn reports_type_errors() {
+        let fragment = "#version 140
+in vec3 v_position;
+out vec4 frag_color;
+void main() {
+    vec2 uv = v_position;
+    frag_color = vec4(uv, 0.0, 1.0);
+}
+";
+        let messages = check(fragment);
+        assert_eq!(messages.len(), 1, "{:?}", messages);
+        assert!(messages[0].starts_with("fragment:5: "), "{:?}", messages);
+    }
+
+    #[test]
+    fn reports_inputs_without_vertex_output() {
+        let fragment = "#version 140
+in vec3 v_position;
+in vec2 v_tex_coord;
+out vec4 frag_color;
+void main() {
+    frag_color = vec4(v_position, v_tex_coord.x);
+}
+";
+        let messages = check(fragment);
+        assert_eq!(messages,
+                   vec!["fragment:3: fragment input v_tex_coord is not an output of the vertex shader".to_string()]);
+    }
+}
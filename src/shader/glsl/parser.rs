@@ -0,0 +1,664 @@
+use shader::glsl::ast::*;
+use shader::glsl::lexer::{Loc, Token};
+use shader::glsl::types;
+
+// Recursive descent parser for the preprocessed tokens of one shader stage.
+// Stops at the first syntax error.
+
+pub type ParseError = (Loc, String);
+
+static QUALIFIERS: [&'static str; 14] = ["const", "in", "out", "inout", "uniform", "attribute", "varying",
+                                         "centroid", "flat", "smooth", "noperspective", "invariant", "highp",
+                                         "mediump"];
+
+fn is_qualifier(name: &str) -> bool {
+    name == "lowp" || name == "layout" || QUALIFIERS.contains(&name)
+}
+
+pub fn parse(tokens: &[(Token, Loc)]) -> Result<Vec<External>, ParseError> {
+    let mut parser = Parser {
+        tokens: tokens,
+        pos: 0,
+        structs: Vec::new(),
+    };
+    let mut externals = Vec::new();
+    while parser.pos < tokens.len() {
+        externals.push(try!(parser.external()));
+    }
+    Ok(externals)
+}
+
+struct Parser<'a> {
+    tokens: &'a [(Token, Loc)],
+    pos: usize,
+    // Struct names declared so far, they start declarations like type names
+    structs: Vec<String>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos).map(|&(ref t, _)| t)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&'a Token> {
+        self.tokens.get(self.pos + offset).map(|&(ref t, _)| t)
+    }
+
+    fn loc(&self) -> Loc {
+        match self.tokens.get(self.pos).or(self.tokens.last()) {
+            Some(&(_, loc)) => loc,
+            None => Loc { id: 0, line: 0 },
+        }
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, ParseError> {
+        let found = match self.peek() {
+            Some(&Token::Ident(ref n)) => format!("'{}'", n),
+            Some(&Token::Punct(p)) => format!("'{}'", p),
+            Some(_) => "a number".to_string(),
+            None => "the end of the file".to_string(),
+        };
+        Err((self.loc(), format!("{}, found {}", message, found)))
+    }
+
+    fn is_punct(&self, p: &str) -> bool {
+        match self.peek() {
+            Some(&Token::Punct(q)) => q == p,
+            _ => false,
+        }
+    }
+
+    fn is_keyword(&self, k: &str) -> bool {
+        match self.peek() {
+            Some(&Token::Ident(ref n)) => n == k,
+            _ => false,
+        }
+    }
+
+    fn accept(&mut self, p: &str) -> bool {
+        if self.is_punct(p) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, p: &str) -> Result<(), ParseError> {
+        if self.accept(p) {
+            Ok(())
+        } else {
+            self.error(format!("expected '{}'", p))
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some(&Token::Ident(ref n)) => {
+                self.pos += 1;
+                Ok(n.clone())
+            }
+            _ => self.error("expected a name".to_string()),
+        }
+    }
+
+    fn is_type(&self, name: &str) -> bool {
+        types::is_type_name(name) || self.structs.iter().any(|s| s == name)
+    }
+
+    fn qualifiers(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut qualifiers = Vec::new();
+        loop {
+            let name = match self.peek() {
+                Some(&Token::Ident(ref n)) if is_qualifier(n) => n.clone(),
+                _ => return Ok(qualifiers),
+            };
+            self.pos += 1;
+            if name == "layout" {
+                // The layout arguments do not matter to the checker
+                try!(self.expect("("));
+                while !self.accept(")") {
+                    if self.peek().is_none() {
+                        return self.error("expected ')'".to_string());
+                    }
+                    self.pos += 1;
+                }
+            } else {
+                qualifiers.push(name);
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Option<Option<Expr>>, ParseError> {
+        if !self.accept("[") {
+            return Ok(None);
+        }
+        if self.accept("]") {
+            return Ok(Some(None));
+        }
+        let size = try!(self.expression());
+        try!(self.expect("]"));
+        Ok(Some(Some(size)))
+    }
+
+    fn type_name(&mut self) -> Result<TypeName, ParseError> {
+        let loc = self.loc();
+        let name = try!(self.ident());
+        if !self.is_type(&name) {
+            self.pos -= 1;
+            return self.error("expected a type".to_string());
+        }
+        let array = try!(self.array());
+        Ok(TypeName {
+            name: name,
+            array: array,
+            loc: loc,
+        })
+    }
+
+    fn external(&mut self) -> Result<External, ParseError> {
+        if self.is_keyword("precision") {
+            while !self.accept(";") {
+                if self.peek().is_none() {
+                    return self.error("expected ';'".to_string());
+                }
+                self.pos += 1;
+            }
+            return Ok(External::Precision);
+        }
+        let qualifiers = try!(self.qualifiers());
+        if self.is_keyword("struct") {
+            let def = try!(self.struct_def());
+            if self.accept(";") {
+                return Ok(External::Struct(def, None));
+            }
+            let ty = TypeName {
+                name: def.name.clone(),
+                array: None,
+                loc: def.loc,
+            };
+            let vars = try!(self.declarators());
+            return Ok(External::Struct(def,
+                                       Some(Declaration {
+                                           qualifiers: qualifiers,
+                                           ty: ty,
+                                           vars: vars,
+                                       })));
+        }
+        if !qualifiers.is_empty() && self.peek_at(1) == Some(&Token::Punct("{")) {
+            return self.block(qualifiers).map(External::Block);
+        }
+
+        let ty = try!(self.type_name());
+        if self.peek_at(1) == Some(&Token::Punct("(")) {
+            return self.function(ty).map(External::Function);
+        }
+        let vars = try!(self.declarators());
+        Ok(External::Declaration(Declaration {
+            qualifiers: qualifiers,
+            ty: ty,
+            vars: vars,
+        }))
+    }
+
+    fn struct_def(&mut self) -> Result<StructDef, ParseError> {
+        self.pos += 1;
+        let loc = self.loc();
+        let name = try!(self.ident());
+        self.structs.push(name.clone());
+        try!(self.expect("{"));
+        let mut fields = Vec::new();
+        while !self.accept("}") {
+            fields.push(try!(self.member()));
+        }
+        Ok(StructDef {
+            name: name,
+            fields: fields,
+            loc: loc,
+        })
+    }
+
+    // Field of a struct or member of a block
+    fn member(&mut self) -> Result<Declaration, ParseError> {
+        let qualifiers = try!(self.qualifiers());
+        let ty = try!(self.type_name());
+        let vars = try!(self.declarators());
+        Ok(Declaration {
+            qualifiers: qualifiers,
+            ty: ty,
+            vars: vars,
+        })
+    }
+
+    fn block(&mut self, qualifiers: Vec<String>) -> Result<BlockDef, ParseError> {
+        let loc = self.loc();
+        let name = try!(self.ident());
+        try!(self.expect("{"));
+        let mut members = Vec::new();
+        while !self.accept("}") {
+            members.push(try!(self.member()));
+        }
+        let instance = if self.is_punct(";") {
+            None
+        } else {
+            let loc = self.loc();
+            let instance_name = try!(self.ident());
+            let array = try!(self.array());
+            Some(Declarator {
+                name: instance_name,
+                array: array,
+                init: None,
+                loc: loc,
+            })
+        };
+        try!(self.expect(";"));
+        Ok(BlockDef {
+            qualifiers: qualifiers,
+            name: name,
+            members: members,
+            instance: instance,
+            loc: loc,
+        })
+    }
+
+    // name [array] [= init], ... ;
+    fn declarators(&mut self) -> Result<Vec<Declarator>, ParseError> {
+        let mut vars = Vec::new();
+        loop {
+            let loc = self.loc();
+            let name = try!(self.ident());
+            let array = try!(self.array());
+            let init = if self.accept("=") {
+                Some(try!(self.assignment()))
+            } else {
+                None
+            };
+            vars.push(Declarator {
+                name: name,
+                array: array,
+                init: init,
+                loc: loc,
+            });
+            if !self.accept(",") {
+                break;
+            }
+        }
+        try!(self.expect(";"));
+        Ok(vars)
+    }
+
+    fn function(&mut self, ret: TypeName) -> Result<FunctionDef, ParseError> {
+        let loc = self.loc();
+        let name = try!(self.ident());
+        try!(self.expect("("));
+        let mut params = Vec::new();
+        if self.is_keyword("void") && self.peek_at(1) == Some(&Token::Punct(")")) {
+            self.pos += 1;
+        }
+        while !self.accept(")") {
+            if !params.is_empty() {
+                try!(self.expect(","));
+            }
+            let qualifiers = try!(self.qualifiers());
+            let ty = try!(self.type_name());
+            let name = match self.peek() {
+                Some(&Token::Ident(_)) => Some(try!(self.ident())),
+                _ => None,
+            };
+            let array = try!(self.array());
+            params.push(Param {
+                qualifiers: qualifiers,
+                ty: ty,
+                name: name,
+                array: array,
+            });
+        }
+        let body = if self.accept(";") {
+            None
+        } else {
+            try!(self.expect("{"));
+            Some(try!(self.statements()))
+        };
+        Ok(FunctionDef {
+            ret: ret,
+            name: name,
+            params: params,
+            body: body,
+            loc: loc,
+        })
+    }
+
+    // Statements up to and including the closing brace
+    fn statements(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        let mut stmts = Vec::new();
+        while !self.accept("}") {
+            if self.peek().is_none() {
+                return self.error("expected '}'".to_string());
+            }
+            stmts.push(try!(self.statement()));
+        }
+        Ok(stmts)
+    }
+
+    fn starts_declaration(&self) -> bool {
+        match self.peek() {
+            Some(&Token::Ident(ref n)) if is_qualifier(n) || n == "struct" => true,
+            Some(&Token::Ident(ref n)) if self.is_type(n) => {
+                match self.peek_at(1) {
+                    Some(&Token::Ident(_)) | Some(&Token::Punct("[")) => true,
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn statement(&mut self) -> Result<Stmt, ParseError> {
+        let loc = self.loc();
+        if self.accept("{") {
+            return self.statements().map(Stmt::Block);
+        }
+        if self.accept(";") {
+            return Ok(Stmt::Empty);
+        }
+        if self.starts_declaration() {
+            let qualifiers = try!(self.qualifiers());
+            let ty = if self.is_keyword("struct") {
+                let def = try!(self.struct_def());
+                return self.error(format!("local struct {} is not supported", def.name));
+            } else {
+                try!(self.type_name())
+            };
+            let vars = try!(self.declarators());
+            return Ok(Stmt::Declaration(Declaration {
+                qualifiers: qualifiers,
+                ty: ty,
+                vars: vars,
+            }));
+        }
+        let keyword = match self.peek() {
+            Some(&Token::Ident(ref n)) => n.clone(),
+            _ => String::new(),
+        };
+        match &keyword[..] {
+            "if" => {
+                self.pos += 1;
+                try!(self.expect("("));
+                let condition = try!(self.expression());
+                try!(self.expect(")"));
+                let then = try!(self.statement());
+                let otherwise = if self.is_keyword("else") {
+                    self.pos += 1;
+                    Some(Box::new(try!(self.statement())))
+                } else {
+                    None
+                };
+                Ok(Stmt::If(condition, Box::new(then), otherwise))
+            }
+            "for" => {
+                self.pos += 1;
+                try!(self.expect("("));
+                let init = if self.accept(";") {
+                    None
+                } else {
+                    Some(Box::new(try!(self.statement())))
+                };
+                let condition = if self.is_punct(";") {
+                    None
+                } else {
+                    Some(try!(self.expression()))
+                };
+                try!(self.expect(";"));
+                let step = if self.is_punct(")") {
+                    None
+                } else {
+                    Some(try!(self.expression()))
+                };
+                try!(self.expect(")"));
+                let body = try!(self.statement());
+                Ok(Stmt::For(init, condition, step, Box::new(body)))
+            }
+            "while" => {
+                self.pos += 1;
+                try!(self.expect("("));
+                let condition = try!(self.expression());
+                try!(self.expect(")"));
+                let body = try!(self.statement());
+                Ok(Stmt::While(condition, Box::new(body)))
+            }
+            "do" => {
+                self.pos += 1;
+                let body = try!(self.statement());
+                if !self.is_keyword("while") {
+                    return self.error("expected 'while'".to_string());
+                }
+                self.pos += 1;
+                try!(self.expect("("));
+                let condition = try!(self.expression());
+                try!(self.expect(")"));
+                try!(self.expect(";"));
+                Ok(Stmt::DoWhile(Box::new(body), condition))
+            }
+            "return" => {
+                self.pos += 1;
+                let value = if self.is_punct(";") {
+                    None
+                } else {
+                    Some(try!(self.expression()))
+                };
+                try!(self.expect(";"));
+                Ok(Stmt::Return(value, loc))
+            }
+            "break" | "continue" | "discard" => {
+                self.pos += 1;
+                try!(self.expect(";"));
+                Ok(match &keyword[..] {
+                    "break" => Stmt::Break(loc),
+                    "continue" => Stmt::Continue(loc),
+                    _ => Stmt::Discard(loc),
+                })
+            }
+            "switch" => self.error("switch statements are not supported".to_string()),
+            _ => {
+                let e = try!(self.expression());
+                try!(self.expect(";"));
+                Ok(Stmt::Expr(e))
+            }
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr, ParseError> {
+        let e = try!(self.assignment());
+        if self.is_punct(",") {
+            return self.error("the comma operator is not supported".to_string());
+        }
+        Ok(e)
+    }
+
+    fn assignment(&mut self) -> Result<Expr, ParseError> {
+        let left = try!(self.ternary());
+        let op = match self.peek() {
+            Some(&Token::Punct(op)) if op == "=" || (op.len() >= 2 && op.ends_with('=') && op != "==" &&
+                                                     op != "!=" && op != "<=" &&
+                                                     op != ">=") => op,
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        let right = try!(self.assignment());
+        let loc = left.loc;
+        Ok(Expr {
+            kind: ExprKind::Assign(op, Box::new(left), Box::new(right)),
+            loc: loc,
+        })
+    }
+
+    fn ternary(&mut self) -> Result<Expr, ParseError> {
+        let condition = try!(self.binary(0));
+        if !self.accept("?") {
+            return Ok(condition);
+        }
+        let then = try!(self.assignment());
+        try!(self.expect(":"));
+        let otherwise = try!(self.assignment());
+        let loc = condition.loc;
+        Ok(Expr {
+            kind: ExprKind::Ternary(Box::new(condition), Box::new(then), Box::new(otherwise)),
+            loc: loc,
+        })
+    }
+
+    fn binary(&mut self, min: u8) -> Result<Expr, ParseError> {
+        let mut left = try!(self.unary());
+        loop {
+            let op = match self.peek() {
+                Some(&Token::Punct(op)) => op,
+                _ => break,
+            };
+            let precedence = match binary_precedence(op) {
+                Some(p) if p > min => p,
+                _ => break,
+            };
+            self.pos += 1;
+            let right = try!(self.binary(precedence));
+            let loc = left.loc;
+            left = Expr {
+                kind: ExprKind::Binary(op, Box::new(left), Box::new(right)),
+                loc: loc,
+            };
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        let loc = self.loc();
+        let op = match self.peek() {
+            Some(&Token::Punct(op)) if op == "+" || op == "-" || op == "!" || op == "~" || op == "++" ||
+                                       op == "--" => op,
+            _ => return self.postfix(),
+        };
+        self.pos += 1;
+        let operand = try!(self.unary());
+        Ok(Expr {
+            kind: ExprKind::Unary(op, Box::new(operand)),
+            loc: loc,
+        })
+    }
+
+    fn postfix(&mut self) -> Result<Expr, ParseError> {
+        let mut e = try!(self.primary());
+        loop {
+            let loc = self.loc();
+            if self.accept("[") {
+                let index = try!(self.expression());
+                try!(self.expect("]"));
+                e = Expr {
+                    kind: ExprKind::Index(Box::new(e), Box::new(index)),
+                    loc: loc,
+                };
+            } else if self.accept(".") {
+                let name = try!(self.ident());
+                if self.accept("(") {
+                    let args = try!(self.arguments());
+                    e = Expr {
+                        kind: ExprKind::Method(Box::new(e), name, args),
+                        loc: loc,
+                    };
+                } else {
+                    e = Expr {
+                        kind: ExprKind::Field(Box::new(e), name),
+                        loc: loc,
+                    };
+                }
+            } else if self.is_punct("++") || self.is_punct("--") {
+                let op = if self.accept("++") {
+                    "++"
+                } else {
+                    self.pos += 1;
+                    "--"
+                };
+                e = Expr {
+                    kind: ExprKind::Postfix(op, Box::new(e)),
+                    loc: loc,
+                };
+            } else {
+                return Ok(e);
+            }
+        }
+    }
+
+    // Arguments after the opening parenthesis
+    fn arguments(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let mut args = Vec::new();
+        if self.is_keyword("void") && self.peek_at(1) == Some(&Token::Punct(")")) {
+            self.pos += 1;
+        }
+        while !self.accept(")") {
+            if !args.is_empty() {
+                try!(self.expect(","));
+            }
+            args.push(try!(self.assignment()));
+        }
+        Ok(args)
+    }
+
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        let loc = self.loc();
+        let token = match self.peek() {
+            Some(t) => t.clone(),
+            None => return self.error("expected an expression".to_string()),
+        };
+        let kind = match token {
+            Token::Int(v) => ExprKind::Int(v),
+            Token::UInt(v) => ExprKind::UInt(v),
+            Token::Float(v) => ExprKind::Float(v),
+            Token::Ident(ref n) if n == "true" => ExprKind::Bool(true),
+            Token::Ident(ref n) if n == "false" => ExprKind::Bool(false),
+            Token::Ident(name) => {
+                self.pos += 1;
+                if self.is_punct("[") && self.is_type(&name) {
+                    return self.error("array constructors are not supported".to_string());
+                }
+                if self.accept("(") {
+                    let args = try!(self.arguments());
+                    return Ok(Expr {
+                        kind: ExprKind::Call(name, args),
+                        loc: loc,
+                    });
+                }
+                return Ok(Expr {
+                    kind: ExprKind::Ident(name),
+                    loc: loc,
+                });
+            }
+            Token::Punct("(") => {
+                self.pos += 1;
+                let e = try!(self.expression());
+                try!(self.expect(")"));
+                return Ok(e);
+            }
+            _ => return self.error("expected an expression".to_string()),
+        };
+        self.pos += 1;
+        Ok(Expr {
+            kind: kind,
+            loc: loc,
+        })
+    }
+}
+
+fn binary_precedence(op: &str) -> Option<u8> {
+    match op {
+        "||" => Some(1),
+        "^^" => Some(2),
+        "&&" => Some(3),
+        "|" => Some(4),
+        "^" => Some(5),
+        "&" => Some(6),
+        "==" | "!=" => Some(7),
+        "<" | ">" | "<=" | ">=" => Some(8),
+        "<<" | ">>" => Some(9),
+        "+" | "-" => Some(10),
+        "*" | "/" | "%" => Some(11),
+        _ => None,
+    }
+}
@@ -0,0 +1,280 @@
+// Standard Library
+use std::fmt;
+
+// GLSL types and the implicit conversions and operator rules of GLSL 1.40
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Base {
+    Bool,
+    Int,
+    UInt,
+    Float,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Type {
+    Void,
+    Scalar(Base),
+    Vector(Base, usize),
+    // Columns and rows
+    Matrix(usize, usize),
+    Sampler(String),
+    Struct(String),
+    // Element type and length, 0 when the length is not known
+    Array(Box<Type>, usize),
+    // Result of an expression that already failed to check, matches anything
+    // so one mistake is reported once
+    Unknown,
+}
+
+static SAMPLERS: [&'static str; 16] = ["sampler1D", "sampler2D", "sampler3D", "samplerCube", "sampler1DShadow",
+                                       "sampler2DShadow", "samplerCubeShadow", "sampler1DArray", "sampler2DArray",
+                                       "sampler2DArrayShadow", "sampler2DRect", "sampler2DRectShadow",
+                                       "samplerBuffer", "isampler2D", "usampler2D", "isampler3D"];
+
+pub fn is_type_name(name: &str) -> bool {
+    from_name(name).is_some() || name == "void"
+}
+
+// Builtin type of a name, structs are resolved by the checker
+pub fn from_name(name: &str) -> Option<Type> {
+    let scalar = match name {
+        "void" => return Some(Type::Void),
+        "bool" => Some(Base::Bool),
+        "int" => Some(Base::Int),
+        "uint" => Some(Base::UInt),
+        "float" => Some(Base::Float),
+        _ => None,
+    };
+    if let Some(base) = scalar {
+        return Some(Type::Scalar(base));
+    }
+    if SAMPLERS.contains(&name) {
+        return Some(Type::Sampler(name.to_string()));
+    }
+    let bytes = name.as_bytes();
+    let last = bytes[bytes.len() - 1];
+    let size = if last >= b'2' && last <= b'4' {
+        (last - b'0') as usize
+    } else {
+        return None;
+    };
+    match &name[..name.len() - 1] {
+        "vec" => Some(Type::Vector(Base::Float, size)),
+        "ivec" => Some(Type::Vector(Base::Int, size)),
+        "uvec" => Some(Type::Vector(Base::UInt, size)),
+        "bvec" => Some(Type::Vector(Base::Bool, size)),
+        "mat" => Some(Type::Matrix(size, size)),
+        "mat2x" => Some(Type::Matrix(2, size)),
+        "mat3x" => Some(Type::Matrix(3, size)),
+        "mat4x" => Some(Type::Matrix(4, size)),
+        _ => None,
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Type::Void => write!(f, "void"),
+            Type::Scalar(base) => write!(f, "{}", base_name(base)),
+            Type::Vector(base, n) => write!(f, "{}vec{}", base_prefix(base), n),
+            Type::Matrix(c, r) if c == r => write!(f, "mat{}", c),
+            Type::Matrix(c, r) => write!(f, "mat{}x{}", c, r),
+            Type::Sampler(ref name) | Type::Struct(ref name) => write!(f, "{}", name),
+            Type::Array(ref t, 0) => write!(f, "{}[]", t),
+            Type::Array(ref t, n) => write!(f, "{}[{}]", t, n),
+            Type::Unknown => write!(f, "<unknown>"),
+        }
+    }
+}
+
+fn base_name(base: Base) -> &'static str {
+    match base {
+        Base::Bool => "bool",
+        Base::Int => "int",
+        Base::UInt => "uint",
+        Base::Float => "float",
+    }
+}
+
+fn base_prefix(base: Base) -> &'static str {
+    match base {
+        Base::Bool => "b",
+        Base::Int => "i",
+        Base::UInt => "u",
+        Base::Float => "",
+    }
+}
+
+impl Type {
+    pub fn base(&self) -> Option<Base> {
+        match *self {
+            Type::Scalar(b) | Type::Vector(b, _) => Some(b),
+            Type::Matrix(..) => Some(Base::Float),
+            _ => None,
+        }
+    }
+
+    // Number of scalars, for constructors
+    pub fn components(&self) -> Option<usize> {
+        match *self {
+            Type::Scalar(_) => Some(1),
+            Type::Vector(_, n) => Some(n),
+            Type::Matrix(c, r) => Some(c * r),
+            _ => None,
+        }
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        *self == Type::Unknown
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        match self.base() {
+            Some(Base::Bool) | None => false,
+            _ => true,
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        match self.base() {
+            Some(Base::Int) | Some(Base::UInt) => match *self {
+                Type::Matrix(..) => false,
+                _ => true,
+            },
+            _ => false,
+        }
+    }
+
+    // Same shape with another base type
+    pub fn with_base(&self, base: Base) -> Type {
+        match *self {
+            Type::Scalar(_) => Type::Scalar(base),
+            Type::Vector(_, n) => Type::Vector(base, n),
+            ref t => t.clone(),
+        }
+    }
+}
+
+// Implicit conversions: int and uint to float, componentwise for vectors
+pub fn converts(from: &Type, to: &Type) -> bool {
+    if from == to || from.is_unknown() || to.is_unknown() {
+        return true;
+    }
+    match (from, to) {
+        (&Type::Scalar(a), &Type::Scalar(Base::Float)) |
+        (&Type::Vector(a, _), &Type::Vector(Base::Float, _)) => {
+            (a == Base::Int || a == Base::UInt) && from.components() == to.components()
+        }
+        (&Type::Array(ref a, n), &Type::Array(ref b, m)) => a == b && (n == m || n == 0 || m == 0),
+        _ => false,
+    }
+}
+
+// Base both operands are converted to
+fn common_base(a: Base, b: Base) -> Option<Base> {
+    if a == b {
+        Some(a)
+    } else if a == Base::Bool || b == Base::Bool {
+        None
+    } else if a == Base::Float || b == Base::Float {
+        Some(Base::Float)
+    } else {
+        None
+    }
+}
+
+fn mismatch(op: &str, a: &Type, b: &Type) -> String {
+    format!("no operation '{}' on {} and {}", op, a, b)
+}
+
+// Result of + - * / % & | ^ << >> applied to a and b
+pub fn arithmetic(op: &str, a: &Type, b: &Type) -> Result<Type, String> {
+    if a.is_unknown() || b.is_unknown() {
+        return Ok(Type::Unknown);
+    }
+    let integer = op == "%" || op == "&" || op == "|" || op == "^" || op == "<<" || op == ">>";
+    if integer {
+        if !a.is_integer() || !b.is_integer() {
+            return Err(mismatch(op, a, b));
+        }
+    } else if !a.is_numeric() || !b.is_numeric() {
+        return Err(mismatch(op, a, b));
+    }
+    // Shifts keep the type of the left operand
+    if op == "<<" || op == ">>" {
+        return match (a, b) {
+            (&Type::Vector(_, n), &Type::Vector(_, m)) if n != m => Err(mismatch(op, a, b)),
+            (&Type::Scalar(_), &Type::Vector(..)) => Err(mismatch(op, a, b)),
+            _ => Ok(a.clone()),
+        };
+    }
+    let base = match common_base(a.base().unwrap(), b.base().unwrap()) {
+        Some(base) => base,
+        None => return Err(mismatch(op, a, b)),
+    };
+    match (a, b) {
+        (&Type::Scalar(_), &Type::Scalar(_)) => Ok(Type::Scalar(base)),
+        (&Type::Scalar(_), &Type::Vector(_, n)) |
+        (&Type::Vector(_, n), &Type::Scalar(_)) => Ok(Type::Vector(base, n)),
+        (&Type::Vector(_, n), &Type::Vector(_, m)) if n == m => Ok(Type::Vector(base, n)),
+        (&Type::Scalar(_), &Type::Matrix(..)) => Ok(b.clone()),
+        (&Type::Matrix(..), &Type::Scalar(_)) => Ok(a.clone()),
+        (&Type::Matrix(c, r), &Type::Vector(_, n)) if op == "*" && c == n => Ok(Type::Vector(Base::Float, r)),
+        (&Type::Vector(_, n), &Type::Matrix(c, r)) if op == "*" && r == n => Ok(Type::Vector(Base::Float, c)),
+        (&Type::Matrix(c1, r1), &Type::Matrix(c2, r2)) if op == "*" && c1 == r2 => Ok(Type::Matrix(c2, r1)),
+        (&Type::Matrix(..), &Type::Matrix(..)) if op != "*" && a == b => Ok(a.clone()),
+        _ => Err(mismatch(op, a, b)),
+    }
+}
+
+// Result of < > <= >= == != && || ^^
+pub fn comparison(op: &str, a: &Type, b: &Type) -> Result<Type, String> {
+    if a.is_unknown() || b.is_unknown() {
+        return Ok(Type::Scalar(Base::Bool));
+    }
+    let ok = match op {
+        "&&" | "||" | "^^" => *a == Type::Scalar(Base::Bool) && *b == Type::Scalar(Base::Bool),
+        "==" | "!=" => {
+            match *a {
+                Type::Void | Type::Sampler(_) => false,
+                _ => converts(a, b) || converts(b, a),
+            }
+        }
+        _ => {
+            match (a, b) {
+                (&Type::Scalar(x), &Type::Scalar(y)) => a.is_numeric() && b.is_numeric() && common_base(x, y).is_some(),
+                _ => false,
+            }
+        }
+    };
+    if ok {
+        Ok(Type::Scalar(Base::Bool))
+    } else {
+        Err(mismatch(op, a, b))
+    }
+}
+
+// Type selected by a swizzle like .xyz or .rg
+pub fn swizzle(t: &Type, fields: &str) -> Option<Type> {
+    let (base, size) = match *t {
+        Type::Vector(base, n) => (base, n),
+        _ => return None,
+    };
+    if fields.is_empty() || fields.len() > 4 {
+        return None;
+    }
+    let sets = ["xyzw", "rgba", "stpq"];
+    let set = match sets.iter().find(|s| fields.chars().all(|c| s.contains(c))) {
+        Some(s) => s,
+        None => return None,
+    };
+    if fields.chars().any(|c| set.find(c).unwrap() >= size) {
+        return None;
+    }
+    if fields.len() == 1 {
+        Some(Type::Scalar(base))
+    } else {
+        Some(Type::Vector(base, fields.len()))
+    }
+}
@@ -1,15 +1,209 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
 use glium;
 
 mod cooktorrance;
+pub mod brdf;
+pub mod glsl;
+pub mod permutation;
+pub mod preprocess;
+pub mod registry;
+pub mod snippets;
 
+use self::glsl::Diagnostic;
+use self::permutation::Features;
+use self::preprocess::PreprocessError;
+
+// Source string numbers of the vertex and fragment shader files, kept apart
+// so a compiler log can be mapped without knowing the stage it came from
+const VERTEX_SOURCE_ID: u32 = 100;
+const FRAGMENT_SOURCE_ID: u32 = 200;
+
+pub enum ShaderError {
+    Io(String),
+    Preprocess(PreprocessError),
+    // Compiler or linker log with locations as file:line
+    Creation(String),
+}
+
+impl fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ShaderError::Io(ref e) => write!(f, "{}", e),
+            ShaderError::Preprocess(ref e) => write!(f, "{}", e),
+            ShaderError::Creation(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl fmt::Debug for ShaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+// Vertex and fragment template of a program, the names show up in errors
+#[derive(Clone, Debug)]
+pub struct Sources {
+    pub vertex_name: String,
+    pub vertex: String,
+    pub fragment_name: String,
+    pub fragment: String,
+}
+
+impl Sources {
+    // The Cook-Torrance shaders compiled into the binary
+    pub fn embedded() -> Sources {
+        Sources {
+            vertex_name: "CT_VERT_PERMUTED".to_string(),
+            vertex: cooktorrance::CT_VERT_PERMUTED.to_string(),
+            fragment_name: "CT_FRAG_PERMUTED".to_string(),
+            fragment: cooktorrance::CT_FRAG_PERMUTED.to_string(),
+        }
+    }
+
+    // Depth pass of shadow::ShadowMaps
+    pub fn shadow() -> Sources {
+        Sources {
+            vertex_name: "SHADOW_VERT".to_string(),
+            vertex: cooktorrance::SHADOW_VERT.to_string(),
+            fragment_name: "SHADOW_FRAG".to_string(),
+            fragment: cooktorrance::SHADOW_FRAG.to_string(),
+        }
+    }
+
+    // Every shader pair in cooktorrance.rs, the permuted ones first
+    pub fn builtin() -> Vec<Sources> {
+        let pair = |vertex_name: &str, vertex: &str, fragment_name: &str, fragment: &str| {
+            Sources {
+                vertex_name: vertex_name.to_string(),
+                vertex: vertex.to_string(),
+                fragment_name: fragment_name.to_string(),
+                fragment: fragment.to_string(),
+            }
+        };
+        vec![Sources::embedded(),
+             pair("CT_VERT", cooktorrance::CT_VERT, "CT_FRAG", cooktorrance::CT_FRAG),
+             pair("CT_VERT", cooktorrance::CT_VERT, "CT_FRAG_DIFF", cooktorrance::CT_FRAG_DIFF),
+             pair("CT_VERT", cooktorrance::CT_VERT, "CT_FRAG_PBR", cooktorrance::CT_FRAG_PBR),
+             Sources::shadow()]
+    }
+
+    // Runs the GLSL checker over one permutation, no GPU needed
+    pub fn validate(&self, includes: &[(String, String)], features: Features) -> Result<Vec<Diagnostic>, ShaderError> {
+        let vertex = try!(permutation::source(&self.vertex_name, &self.vertex, includes, features, VERTEX_SOURCE_ID)
+                              .map_err(ShaderError::Preprocess));
+        let fragment = try!(permutation::source(&self.fragment_name,
+                                                &self.fragment,
+                                                includes,
+                                                features,
+                                                FRAGMENT_SOURCE_ID)
+                                .map_err(ShaderError::Preprocess));
+        Ok(glsl::validate_program(&vertex, &fragment))
+    }
+
+    fn compile(&self,
+               display: &glium::Display,
+               includes: &[(String, String)],
+               features: Features)
+               -> Result<glium::Program, ShaderError> {
+        let vertex = try!(permutation::source(&self.vertex_name, &self.vertex, includes, features, VERTEX_SOURCE_ID)
+                              .map_err(ShaderError::Preprocess));
+        let fragment = try!(permutation::source(&self.fragment_name,
+                                                &self.fragment,
+                                                includes,
+                                                features,
+                                                FRAGMENT_SOURCE_ID)
+                                .map_err(ShaderError::Preprocess));
+        match glium::Program::from_source(display, &vertex.text, &fragment.text, None) {
+            Ok(p) => Ok(p),
+            Err(glium::ProgramCreationError::CompilationError(log)) |
+            Err(glium::ProgramCreationError::LinkingError(log)) => {
+                Err(ShaderError::Creation(fragment.map_log(&vertex.map_log(&log))))
+            }
+            Err(e) => Err(ShaderError::Creation(format!("{:?}", e))),
+        }
+    }
+}
+
+// Permutations of one vertex/fragment pair, compiled the first time a
+// feature set is asked for and shared by every group using it.
 pub struct Program {
-    pub none: glium::Program,
-    pub ambient: glium::Program,
-    pub diffuse: glium::Program,
-    pub ambient_diffuse:glium::Program,
-    pub diffuse_bump:glium::Program,
-    pub ambient_diffuse_bump:glium::Program,
+    name: String,
+    display: glium::Display,
+    sources: Sources,
+    includes: Vec<(String, String)>,
+    // Used when `sources` do not compile
+    fallback: Option<Sources>,
+    cache: RefCell<HashMap<Features, Rc<glium::Program>>>,
+    errors: RefCell<Vec<ShaderError>>,
+}
+
+impl Program {
+    pub fn new(display: &glium::Display) -> Program {
+        Program::from_sources(display, "cooktorrance", Sources::embedded(), Vec::new(), None)
+    }
+
+    pub fn from_sources(display: &glium::Display,
+                        name: &str,
+                        sources: Sources,
+                        includes: Vec<(String, String)>,
+                        fallback: Option<Sources>)
+                        -> Program {
+        Program {
+            name: name.to_string(),
+            display: display.clone(),
+            sources: sources,
+            includes: includes,
+            fallback: fallback,
+            cache: RefCell::new(HashMap::new()),
+            errors: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Permutations that fell back to the embedded sources record the error
+    // here and print it, the draw goes on with the fallback.
+    pub fn get(&self, features: Features) -> Result<Rc<glium::Program>, ShaderError> {
+        if let Some(program) = self.cache.borrow().get(&features) {
+            return Ok(program.clone());
+        }
+        let program = match self.sources.compile(&self.display, &self.includes, features) {
+            Ok(p) => p,
+            Err(e) => {
+                let fallback = match self.fallback {
+                    Some(ref f) => f,
+                    None => return Err(e),
+                };
+                let _ = writeln!(&mut io::stderr(), "{}: {}\nusing the embedded shaders", self.name, e);
+                self.errors.borrow_mut().push(e);
+                try!(fallback.compile(&self.display, &[], features))
+            }
+        };
+        let program = Rc::new(program);
+        self.cache.borrow_mut().insert(features, program.clone());
+        Ok(program)
+    }
+
+    // Errors of permutations that were replaced by the fallback
+    pub fn errors(&self) -> Vec<String> {
+        self.errors.borrow().iter().map(|e| e.to_string()).collect()
+    }
+
+    // Number of compiled permutations
+    pub fn len(&self) -> usize {
+        self.cache.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.borrow().is_empty()
+    }
 }
 
 
@@ -18,15 +212,6 @@ pub fn program_map(display: &glium::Display) -> HashMap<String, Program> {
     let name = String::from("cooktorrance");
     println!("Creating {}", &name);
 
-    let program = Program {
-        none: glium::Program::from_source(display, cooktorrance::CT_VERT, cooktorrance::CT_FRAG_PBR, None).unwrap(),
-        ambient: glium::Program::from_source(display, cooktorrance::CT_VERT, cooktorrance::CT_FRAG_PBR, None).unwrap(),
-        diffuse: glium::Program::from_source(display, cooktorrance::CT_VERT, cooktorrance::CT_FRAG_PBR, None).unwrap(),
-        ambient_diffuse: glium::Program::from_source(display, cooktorrance::CT_VERT, cooktorrance::CT_FRAG_PBR, None).unwrap(),
-        diffuse_bump: glium::Program::from_source(display, cooktorrance::CT_VERT, cooktorrance::CT_FRAG_PBR, None).unwrap(),
-        ambient_diffuse_bump: glium::Program::from_source(display, cooktorrance::CT_VERT, cooktorrance::CT_FRAG_PBR, None).unwrap(),
-        //ambient_diffuse_bump: glium::Program::from_source(display, tex::TEX_VERT, tex::AMBIENT_DIFFUSE_BUMP_FRAG, None).unwrap(),
-    };
-    program_map.insert(name, program);
+    program_map.insert(name, Program::new(display));
     program_map
 }
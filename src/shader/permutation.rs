@@ -0,0 +1,143 @@
+// Standard Library
+use std::ops::BitOr;
+
+use shader::preprocess::{PreprocessError, Source};
+use shader::snippets;
+use util::graphics::{AlphaMode, Lobes, Material, TexturePBR};
+
+// Shader features as a bitset. Every combination is a permutation of
+// CT_VERT_PERMUTED and CT_FRAG_PERMUTED, compiled with one #define per bit.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Features(u32);
+
+// Albedo/specular/normal/gloss textures, see TexturePBR::AlbedoSpecularNormalGloss
+pub const SPEC_GLOSS: Features = Features(1 << 0);
+// Albedo, normal and a packed texture, see TexturePBR::AlbedoNormalPacked
+pub const PACKED: Features = Features(1 << 1);
+pub const NORMAL_MAP: Features = Features(1 << 2);
+// Reads the VertexAttributes stream and tints the base color
+pub const VERTEX_COLOR: Features = Features(1 << 3);
+// Blends positions by the joint matrices, for SkinnedVertex buffers
+pub const SKINNING: Features = Features(1 << 4);
+pub const ALPHA_MASK: Features = Features(1 << 5);
+pub const ALPHA_BLEND: Features = Features(1 << 6);
+pub const CLEARCOAT: Features = Features(1 << 7);
+pub const SHEEN: Features = Features(1 << 8);
+pub const ANISOTROPY: Features = Features(1 << 9);
+
+static DEFINES: [(Features, &'static str); 10] = [(SPEC_GLOSS, "SPEC_GLOSS"),
+                                                   (PACKED, "PACKED"),
+                                                   (NORMAL_MAP, "NORMAL_MAP"),
+                                                   (VERTEX_COLOR, "VERTEX_COLOR"),
+                                                   (SKINNING, "SKINNING"),
+                                                   (ALPHA_MASK, "ALPHA_MASK"),
+                                                   (ALPHA_BLEND, "ALPHA_BLEND"),
+                                                   (CLEARCOAT, "CLEARCOAT"),
+                                                   (SHEEN, "SHEEN"),
+                                                   (ANISOTROPY, "ANISOTROPY")];
+
+impl Features {
+    pub fn empty() -> Features {
+        Features(0)
+    }
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+    pub fn contains(&self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+    pub fn insert(&mut self, other: Features) {
+        self.0 |= other.0;
+    }
+
+    // Every feature set, for checking all permutations
+    pub fn combinations() -> Vec<Features> {
+        (0..1 << DEFINES.len()).map(Features).collect()
+    }
+
+    // Names of the set features, as used in the #defines
+    pub fn names(&self) -> Vec<&'static str> {
+        DEFINES.iter().filter(|&&(f, _)| self.contains(f)).map(|&(_, name)| name).collect()
+    }
+
+    // Features needed to draw a group with the given textures and material.
+    // Groups without any textures use the metallic/roughness permutation
+    // without a normal map.
+    pub fn for_group(tex: &Option<TexturePBR>, material: Option<&Material>, vertex_color: bool) -> Features {
+        let mut features = Features::empty();
+        let mut lobes = Lobes::default();
+        match *tex {
+            Some(TexturePBR::AlbedoSpecularNormalGloss(..)) => features.insert(SPEC_GLOSS | NORMAL_MAP),
+            Some(TexturePBR::AlbedoNormalPacked(..)) => features.insert(PACKED | NORMAL_MAP),
+            Some(TexturePBR::MetallicRoughness(ref mr)) => {
+                if mr.has_normal_map() {
+                    features.insert(NORMAL_MAP);
+                }
+                lobes = mr.lobes;
+            }
+            None => {}
+        }
+        if vertex_color {
+            features.insert(VERTEX_COLOR);
+        }
+        match material.map(|m| m.alpha_mode) {
+            Some(AlphaMode::Mask) => features.insert(ALPHA_MASK),
+            Some(AlphaMode::Blend) => features.insert(ALPHA_BLEND),
+            _ => {}
+        }
+        if lobes.clearcoat != 0.0 {
+            features.insert(CLEARCOAT);
+        }
+        if lobes.sheen_color != [0.0; 3] {
+            features.insert(SHEEN);
+        }
+        if lobes.anisotropy != 0.0 {
+            features.insert(ANISOTROPY);
+        }
+        features
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+    fn bitor(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+// Expands the includes of a template, with one #define per feature.
+// `includes` are added to the snippets, replacing those of the same name.
+pub fn source(name: &str,
+              template: &str,
+              includes: &[(String, String)],
+              features: Features,
+              first_id: u32)
+              -> Result<Source, PreprocessError> {
+    let mut p = snippets::preprocessor();
+    for &(ref include, ref text) in includes.iter() {
+        p = p.source(include, text);
+    }
+    for feature in features.names() {
+        p = p.define(feature);
+    }
+    p.process(name, template, first_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use util::graphics::{AlphaMode, Material};
+    use super::{Features, ALPHA_BLEND, VERTEX_COLOR};
+
+    #[test]
+    fn untextured_groups_are_metallic_roughness() {
+        assert_eq!(Features::for_group(&None, None, false), Features::empty());
+        assert_eq!(Features::for_group(&None, None, true), VERTEX_COLOR);
+    }
+
+    #[test]
+    fn alpha_mode_of_the_material() {
+        let mut material = Material::default();
+        material.alpha_mode = AlphaMode::Blend;
+        assert_eq!(Features::for_group(&None, Some(&material), false), ALPHA_BLEND);
+    }
+}
@@ -0,0 +1,303 @@
+// Standard Library
+use std::collections::HashMap;
+use std::fmt;
+
+// Expands #include "<name>" before the source is handed to the driver.
+//
+// - Files containing "#pragma once" and files wrapped in the usual
+//   #ifndef X / #define X ... #endif guard are only inserted once.
+// - Defines are injected right after the #version line.
+// - Every file gets its own GLSL source string number, set with #line
+//   directives, so compiler logs can be mapped back by Source::map_log.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PreprocessError {
+    // Including file, line and the name that was not found
+    Missing(String, usize, String),
+    // Include chain that leads back to its start
+    Cycle(Vec<String>),
+    // File, line and a description
+    Syntax(String, usize, String),
+}
+
+impl fmt::Display for PreprocessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PreprocessError::Missing(ref file, line, ref name) => {
+                write!(f, "{}:{}: unknown include '{}'", file, line, name)
+            }
+            PreprocessError::Cycle(ref chain) => write!(f, "include cycle {}", chain.join(" -> ")),
+            PreprocessError::Syntax(ref file, line, ref e) => write!(f, "{}:{}: {}", file, line, e),
+        }
+    }
+}
+
+pub struct Preprocessor {
+    sources: HashMap<String, String>,
+    defines: Vec<(String, String)>,
+}
+
+// Preprocessed source and the files its source string numbers refer to
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub text: String,
+    pub first_id: u32,
+    pub files: Vec<String>,
+}
+
+// State of one expansion
+struct Expansion {
+    out: String,
+    files: Vec<String>,
+    stack: Vec<String>,
+    once: Vec<String>,
+    guards: Vec<String>,
+}
+
+impl Preprocessor {
+    pub fn new() -> Preprocessor {
+        Preprocessor {
+            sources: HashMap::new(),
+            defines: Vec::new(),
+        }
+    }
+
+    // Registers a file that can be included, replacing one of the same name
+    pub fn source(mut self, name: &str, text: &str) -> Preprocessor {
+        self.sources.insert(name.to_string(), text.to_string());
+        self
+    }
+
+    pub fn define(self, name: &str) -> Preprocessor {
+        self.define_value(name, "")
+    }
+
+    pub fn define_value(mut self, name: &str, value: &str) -> Preprocessor {
+        self.defines.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn has_source(&self, name: &str) -> bool {
+        self.sources.contains_key(name)
+    }
+
+    // Expands `text`, reported as `name`. The files get the source string
+    // numbers first_id, first_id + 1, ... in the order they are reached, so
+    // the vertex and fragment shader of a program should use distinct ranges.
+    pub fn process(&self, name: &str, text: &str, first_id: u32) -> Result<Source, PreprocessError> {
+        let mut e = Expansion {
+            out: String::with_capacity(text.len()),
+            files: Vec::new(),
+            stack: Vec::new(),
+            once: Vec::new(),
+            guards: Vec::new(),
+        };
+        try!(self.expand(name, text, first_id, true, &mut e));
+        Ok(Source {
+            text: e.out,
+            first_id: first_id,
+            files: e.files,
+        })
+    }
+
+    fn push_defines(&self, out: &mut String) {
+        for &(ref name, ref value) in self.defines.iter() {
+            out.push_str("#define ");
+            out.push_str(name);
+            if !value.is_empty() {
+                out.push(' ');
+                out.push_str(value);
+            }
+            out.push('\n');
+        }
+    }
+
+    fn expand(&self, name: &str, text: &str, first_id: u32, root: bool, e: &mut Expansion) -> Result<(), PreprocessError> {
+        if e.once.iter().any(|f| f == name) {
+            return Ok(());
+        }
+        if e.stack.iter().any(|f| f == name) {
+            let mut chain = e.stack.clone();
+            chain.push(name.to_string());
+            return Err(PreprocessError::Cycle(chain));
+        }
+        if let Some(guard) = guard_macro(text) {
+            if e.guards.contains(&guard) {
+                return Ok(());
+            }
+            e.guards.push(guard);
+        }
+
+        let index = match e.files.iter().position(|f| f == name) {
+            Some(i) => i,
+            None => {
+                e.files.push(name.to_string());
+                e.files.len() - 1
+            }
+        };
+        let id = first_id + index as u32;
+        e.stack.push(name.to_string());
+
+        // GLSL before 3.30 numbers the line after "#line n" as n + 1, so the
+        // directive always names the line that was just consumed
+        let has_version = root && text.lines().any(|l| l.trim().starts_with("#version"));
+        if !has_version {
+            if root {
+                self.push_defines(&mut e.out);
+            }
+            e.out.push_str(&format!("#line 0 {}\n", id));
+        }
+
+        for (i, line) in text.lines().enumerate() {
+            let number = i + 1;
+            let trimmed = line.trim();
+            if trimmed.starts_with("#version") {
+                if !root {
+                    return Err(PreprocessError::Syntax(name.to_string(),
+                                                       number,
+                                                       "#version in an included file".to_string()));
+                }
+                e.out.push_str(line);
+                e.out.push('\n');
+                self.push_defines(&mut e.out);
+                e.out.push_str(&format!("#line {} {}\n", number, id));
+            } else if trimmed.starts_with("#include") {
+                let include = match include_name(&trimmed["#include".len()..]) {
+                    Some(n) => n,
+                    None => {
+                        return Err(PreprocessError::Syntax(name.to_string(),
+                                                           number,
+                                                           "expected #include \"<name>\"".to_string()))
+                    }
+                };
+                let source = match self.sources.get(include) {
+                    Some(s) => s,
+                    None => return Err(PreprocessError::Missing(name.to_string(), number, include.to_string())),
+                };
+                try!(self.expand(include, source, first_id, false, e));
+                e.out.push_str(&format!("#line {} {}\n", number, id));
+            } else if trimmed.starts_with("#pragma") && trimmed["#pragma".len()..].trim() == "once" {
+                // Keeps the line count of the file
+                e.out.push('\n');
+                e.once.push(name.to_string());
+            } else {
+                e.out.push_str(line);
+                e.out.push('\n');
+            }
+        }
+        e.stack.pop();
+        Ok(())
+    }
+}
+
+// "name" or <name>
+fn include_name(rest: &str) -> Option<&str> {
+    let rest = rest.trim();
+    let (open, close) = match rest.chars().next() {
+        Some('"') => ('"', '"'),
+        Some('<') => ('<', '>'),
+        _ => return None,
+    };
+    let inner = &rest[open.len_utf8()..];
+    match inner.find(close) {
+        Some(end) if end > 0 && inner[end + 1..].trim().is_empty() => Some(&inner[..end]),
+        _ => None,
+    }
+}
+
+// X if the file starts with #ifndef X followed by #define X
+fn guard_macro(text: &str) -> Option<String> {
+    let mut directives = text.lines()
+                             .map(|l| l.trim())
+                             .filter(|l| !l.is_empty() && !l.starts_with("//"));
+    let first = match directives.next() {
+        Some(l) => l,
+        None => return None,
+    };
+    let second = match directives.next() {
+        Some(l) => l,
+        None => return None,
+    };
+    let first: Vec<&str> = first.split_whitespace().collect();
+    let second: Vec<&str> = second.split_whitespace().collect();
+    if first.len() == 2 && first[0] == "#ifndef" && second.len() == 2 && second[0] == "#define" &&
+       first[1] == second[1] {
+        Some(first[1].to_string())
+    } else {
+        None
+    }
+}
+
+impl Source {
+    // Name of the file behind a source string number
+    pub fn file(&self, id: u32) -> Option<&str> {
+        if id < self.first_id {
+            return None;
+        }
+        self.files.get((id - self.first_id) as usize).map(|f| &f[..])
+    }
+
+    // Rewrites the locations of a driver log, "12(34)" as printed by NVIDIA
+    // and "12:34" as printed by Mesa and AMD, to "file:34". Numbers that do
+    // not belong to this source are left alone.
+    pub fn map_log(&self, log: &str) -> String {
+        let bytes = log.as_bytes();
+        let mut out = String::with_capacity(log.len());
+        let mut i = 0;
+        let mut copied = 0;
+        while i < bytes.len() {
+            let boundary = i == 0 || !(is_digit(bytes[i - 1]) || is_letter(bytes[i - 1]) || bytes[i - 1] == b'.');
+            if !boundary || !is_digit(bytes[i]) {
+                i += 1;
+                continue;
+            }
+            let id_end = digits_end(bytes, i);
+            let location = if id_end < bytes.len() && (bytes[id_end] == b'(' || bytes[id_end] == b':') {
+                let line_end = digits_end(bytes, id_end + 1);
+                let closed = bytes[id_end] == b':' || (line_end < bytes.len() && bytes[line_end] == b')');
+                if line_end > id_end + 1 && closed {
+                    let id = log[i..id_end].parse::<u32>().ok();
+                    let file = id.and_then(|id| self.file(id));
+                    file.map(|f| (f, &log[id_end + 1..line_end], line_end))
+                } else {
+                    None
+                }
+            } else {
+                None
+            };
+            match location {
+                Some((file, line, line_end)) => {
+                    out.push_str(&log[copied..i]);
+                    out.push_str(file);
+                    out.push(':');
+                    out.push_str(line);
+                    i = if bytes[id_end] == b'(' {
+                        line_end + 1
+                    } else {
+                        line_end
+                    };
+                    copied = i;
+                }
+                None => i = id_end,
+            }
+        }
+        out.push_str(&log[copied..]);
+        out
+    }
+}
+
+fn digits_end(bytes: &[u8], start: usize) -> usize {
+    let mut end = start;
+    while end < bytes.len() && is_digit(bytes[end]) {
+        end += 1;
+    }
+    end
+}
+
+fn is_digit(b: u8) -> bool {
+    b >= b'0' && b <= b'9'
+}
+
+fn is_letter(b: u8) -> bool {
+    (b >= b'a' && b <= b'z') || (b >= b'A' && b <= b'Z') || b == b'_'
+}
@@ -0,0 +1,158 @@
+// Standard Library
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::rc::Rc;
+
+// External Library
+use glium;
+
+use shader::{Program, ShaderError, Sources};
+use shader::permutation::Features;
+
+// Programs found in a shader directory. <name>.vert and <name>.frag form the
+// program <name>, *.glsl files can be included by all of them and replace the
+// embedded snippets of the same name. The embedded "cooktorrance" program is
+// always present; a "cooktorrance" pair on disk replaces it and falls back to
+// the embedded sources if it does not compile.
+pub struct Registry {
+    programs: HashMap<String, Program>,
+}
+
+fn read(path: &Path) -> Result<String, ShaderError> {
+    let mut text = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut text)) {
+        Ok(_) => Ok(text),
+        Err(e) => Err(ShaderError::Io(format!("{}: {}", path.display(), e))),
+    }
+}
+
+impl Registry {
+    pub fn embedded(display: &glium::Display) -> Registry {
+        let mut programs = HashMap::new();
+        programs.insert("cooktorrance".to_string(), Program::new(display));
+        Registry {
+            programs: programs,
+        }
+    }
+
+    // Never fails, files that can not be read or have no partner are
+    // returned as errors next to the registry.
+    pub fn load(display: &glium::Display, dir: &Path) -> (Registry, Vec<ShaderError>) {
+        let mut registry = Registry::embedded(display);
+        let (found, includes, errors) = scan(dir);
+        for (name, sources) in found.into_iter() {
+            let fallback = if registry.programs.contains_key(&name) {
+                Some(Sources::embedded())
+            } else {
+                None
+            };
+            let program = Program::from_sources(display, &name, sources, includes.clone(), fallback);
+            registry.programs.insert(name, program);
+        }
+        (registry, errors)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Program> {
+        self.programs.get(name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.programs.keys().map(|k| &k[..]).collect();
+        names.sort();
+        names
+    }
+
+    // Compiles one permutation of a program by name
+    pub fn compile(&self, name: &str, features: Features) -> Result<Rc<glium::Program>, ShaderError> {
+        match self.programs.get(name) {
+            Some(p) => p.get(features),
+            None => Err(ShaderError::Io(format!("no program named '{}'", name))),
+        }
+    }
+
+    // Compiles a permutation of every program so broken files show up at
+    // startup instead of on first use
+    pub fn compile_all(&self, features: Features) -> Vec<(String, ShaderError)> {
+        let mut errors = Vec::new();
+        for name in self.names() {
+            if let Err(e) = self.compile(name, features) {
+                errors.push((name.to_string(), e));
+            }
+        }
+        errors
+    }
+}
+
+// Shader pairs and includes in `dir` without compiling anything, sorted by
+// name. Used by Registry::load and to validate a directory offline.
+pub fn scan(dir: &Path) -> (Vec<(String, Sources)>, Vec<(String, String)>, Vec<ShaderError>) {
+    let mut found = Vec::new();
+    let mut includes = Vec::new();
+    let mut errors = Vec::new();
+
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => {
+            errors.push(ShaderError::Io(format!("{}: {}", dir.display(), e)));
+            return (found, includes, errors);
+        }
+    };
+    let mut vertex_files = HashMap::new();
+    let mut fragment_files = HashMap::new();
+    for entry in entries {
+        let path = match entry {
+            Ok(e) => e.path(),
+            Err(e) => {
+                errors.push(ShaderError::Io(format!("{}: {}", dir.display(), e)));
+                continue;
+            }
+        };
+        let (stem, extension) = match (path.file_stem().and_then(|s| s.to_str()),
+                                       path.extension().and_then(|s| s.to_str())) {
+            (Some(s), Some(e)) => (s.to_string(), e.to_string()),
+            _ => continue,
+        };
+        let target = match &extension[..] {
+            "vert" => &mut vertex_files,
+            "frag" => &mut fragment_files,
+            "glsl" => {
+                match read(&path) {
+                    Ok(text) => includes.push((format!("{}.glsl", stem), text)),
+                    Err(e) => errors.push(e),
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        match read(&path) {
+            Ok(text) => {
+                target.insert(stem, (path.display().to_string(), text));
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+
+    for (name, (vertex_name, vertex)) in vertex_files.into_iter() {
+        let (fragment_name, fragment) = match fragment_files.remove(&name) {
+            Some(f) => f,
+            None => {
+                errors.push(ShaderError::Io(format!("{}: no matching {}.frag", vertex_name, name)));
+                continue;
+            }
+        };
+        found.push((name,
+                    Sources {
+                        vertex_name: vertex_name,
+                        vertex: vertex,
+                        fragment_name: fragment_name,
+                        fragment: fragment,
+                    }));
+    }
+    for (name, (fragment_name, _)) in fragment_files.into_iter() {
+        errors.push(ShaderError::Io(format!("{}: no matching {}.vert", fragment_name, name)));
+    }
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+    (found, includes, errors)
+}
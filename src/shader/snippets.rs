@@ -0,0 +1,319 @@
+use shader::preprocess::Preprocessor;
+
+// GLSL shared by the shaders in cooktorrance.rs, pulled in with
+// #include "<name>". The CPU versions of the BRDF terms are in shader::brdf.
+
+pub static BRDF: &'static str = r#"
+    #pragma once
+    #ifndef M_PI
+    #define M_PI 3.1415926535897932384626433832795
+    #endif
+
+    float Schlick_Frensel(float VdotH, float spec_reflectance) {
+        return spec_reflectance + (1 - spec_reflectance) * pow(1 - VdotH, 5);
+    }
+
+    float Schlick_approx(float VdotH, float spec_reflectance) {
+        float exponent = (-5.55473 * VdotH - 6.98316) * VdotH;
+        return spec_reflectance + (1 - spec_reflectance) * pow(2, exponent);
+    }
+
+    // Geometry term including NdotL * NdotV, divide by 4 * NdotL * NdotV
+    float Schlick(float roughness, float NdotL, float NdotV) {
+        float k = pow(roughness + 1, 2) / 8;
+        float nl = NdotL / (NdotL * (1 - k) + k);
+        float nv = NdotV / (NdotV * (1 - k) + k);
+
+        return nl * nv;
+    }
+
+    // Geometry term with the 1 / (4 * NdotL * NdotV) of the BRDF folded in
+    float Schlick_simplified(float x, float NdotL, float NdotV) {
+        float k = pow(x + 1, 2) / 8;
+        float nl = 1 / (NdotL * (1 - k) + k);
+        float nv = 1 / (NdotV * (1 - k) + k);
+
+        return nl * nv / 4;
+    }
+
+    float GGX_Trowbridge_Reitz(float alpha, float NdotH) {
+        float divisor = M_PI * pow(pow(NdotH, 2) * (pow(alpha, 2) - 1) + 1, 2);
+
+        return pow(alpha, 2) / divisor;
+    }
+
+    // Burley's anisotropic GGX, equal to GGX_Trowbridge_Reitz for anisotropy 0
+    float GGX_anisotropic(float alpha, float aniso, float NdotH, float TdotH, float BdotH) {
+        float at = max(alpha * (1.0 + aniso), 0.001);
+        float ab = max(alpha * (1.0 - aniso), 0.001);
+        float d = pow(TdotH / at, 2) + pow(BdotH / ab, 2) + pow(NdotH, 2);
+        return 1.0 / (M_PI * at * ab * d * d);
+    }
+
+    // Charlie sheen distribution (Estevez and Kulla) with Neubelt's visibility
+    float Sheen_Charlie(float r, float NdotH, float NdotL, float NdotV) {
+        float inv_r = 1.0 / max(r * r, 0.001);
+        float sin2 = max(1.0 - NdotH * NdotH, 0.0);
+        float d = (2.0 + inv_r) * pow(sin2, 0.5 * inv_r) / (2.0 * M_PI);
+        float v = 1.0 / (4.0 * (NdotL + NdotV - NdotL * NdotV));
+        return d * v;
+    }
+
+    // Kelemen's visibility as used for clearcoat layers
+    float Kelemen_visibility(float VdotH) {
+        return 0.25 / max(VdotH * VdotH, 0.001);
+    }
+"#;
+
+// Point lights as uploaded by util::graphics::Lights::blocks, the array size
+// is MAX_LIGHTS
+pub static LIGHTING: &'static str = r#"
+    #pragma once
+
+    struct PointLight {
+        vec3 pos;
+        // First layer in point_shadows, negative without shadows
+        float shadow;
+        vec3 col;
+        vec3 attn;
+    };
+
+    uniform Block {
+        PointLight lights[64];
+        int light_count;
+    };
+
+    // Directional light of the first pass in view space towards the light,
+    // sun_color is black without one
+    uniform vec3 sun_direction;
+    uniform vec3 sun_color;
+
+    float light_attenuation(PointLight light, vec3 position) {
+        float distance = length(light.pos - position);
+        return 1.0f  / (light.attn[0] + light.attn[1] * distance + light.attn[2] * distance * distance);
+    }
+"#;
+
+pub static TANGENT_FRAME: &'static str = r#"
+    #pragma once
+
+    // Tangent frame from screen space derivatives, see
+    // http://www.thetenthplanet.de/archives/1180
+    mat3 cotangent_frame(vec3 normal, vec3 pos, vec2 uv) {
+            vec3 dp1 = dFdx(pos);
+            vec3 dp2 = dFdy(pos);
+            vec2 duv1 = dFdx(uv);
+            vec2 duv2 = dFdy(uv);
+            vec3 dp2perp = cross(dp2, normal);
+            vec3 dp1perp = cross(normal, dp1);
+            vec3 T = dp2perp * duv1.x + dp1perp * duv2.x;
+            vec3 B = dp2perp * duv1.y + dp1perp * duv2.y;
+            float invmax = inversesqrt(max(dot(T, T), dot(B, B)));
+            return mat3(T * invmax, B * invmax, normal);
+    }
+"#;
+
+pub static TONEMAP: &'static str = r#"
+    #pragma once
+
+    vec3 tonemap_clamp(vec3 color) {
+        return clamp(color, 0.0, 1.0);
+    }
+
+    vec3 tonemap_reinhard(vec3 color) {
+        return color / (color + vec3(1.0));
+    }
+
+    // Fit of the ACES filmic curve by Krzysztof Narkowicz
+    vec3 tonemap_aces(vec3 color) {
+        return clamp((color * (2.51 * color + 0.03)) / (color * (2.43 * color + 0.59) + 0.14), 0.0, 1.0);
+    }
+"#;
+
+// Depth map shadows rendered by shadow::ShadowMaps. Both maps hold values
+// in [0, 1] and 1 where nothing was drawn: the distance to the light over
+// point_shadow_far for point lights, the window depth for the cascades.
+pub static SHADOW: &'static str = r#"
+    #pragma once
+    #include "lighting.glsl"
+
+    uniform sampler2DArray point_shadows;
+    uniform sampler2DArray sun_shadows;
+    uniform Cascades {
+        mat4 cascade_matrices[4];
+        int cascade_count;
+    };
+    uniform float point_shadow_far;
+    uniform float shadow_bias;
+    uniform bool shadows;
+
+    // Face in z and (s, t) as texture::cubemap::direction_to_face
+    vec3 cube_face(vec3 d) {
+        vec3 a = abs(d);
+        if (a.x >= a.y && a.x >= a.z) {
+            return d.x > 0.0 ? vec3(0.5 * vec2(-d.z, -d.y) / a.x + 0.5, 0.0)
+                             : vec3(0.5 * vec2(d.z, -d.y) / a.x + 0.5, 1.0);
+        } else if (a.y >= a.z) {
+            return d.y > 0.0 ? vec3(0.5 * vec2(d.x, d.z) / a.y + 0.5, 2.0)
+                             : vec3(0.5 * vec2(d.x, -d.z) / a.y + 0.5, 3.0);
+        }
+        return d.z > 0.0 ? vec3(0.5 * vec2(d.x, -d.y) / a.z + 0.5, 4.0)
+                         : vec3(0.5 * vec2(-d.x, -d.y) / a.z + 0.5, 5.0);
+    }
+
+    // Percentage closer filtering, the fraction of the 3x3 texels around uv
+    // that are not closer to the light than depth
+    float pcf(sampler2DArray map, vec2 uv, float layer, float depth) {
+        vec2 texel = 1.0 / vec2(textureSize(map, 0).xy);
+        float lit = 0.0;
+        for (int x = -1; x <= 1; x++) {
+            for (int y = -1; y <= 1; y++) {
+                float stored = texture(map, vec3(uv + vec2(x, y) * texel, layer)).r;
+                lit += depth <= stored ? 1.0 : 0.0;
+            }
+        }
+        return lit / 9.0;
+    }
+
+    float point_shadow(PointLight light, vec3 position) {
+        if (!shadows || light.shadow < 0.0) {
+            return 1.0;
+        }
+        vec3 d = position - light.pos;
+        vec3 face = cube_face(d);
+        return pcf(point_shadows, face.xy, light.shadow + face.z, length(d) / point_shadow_far - shadow_bias);
+    }
+
+    // The first cascade covering the point, see shadow::cascade_for
+    float sun_shadow(vec3 position) {
+        if (!shadows) {
+            return 1.0;
+        }
+        float margin = 1.0 / float(textureSize(sun_shadows, 0).x);
+        for (int i = 0; i < cascade_count; i++) {
+            vec3 p = (cascade_matrices[i] * vec4(position, 1.0)).xyz * 0.5 + 0.5;
+            if (all(greaterThan(p.xy, vec2(margin))) && all(lessThan(p.xy, vec2(1.0 - margin))) &&
+                p.z > 0.0 && p.z < 1.0) {
+                return pcf(sun_shadows, p.xy, float(i), p.z - shadow_bias);
+            }
+        }
+        return 1.0;
+    }
+"#;
+
+// Kulla and Conty's compensation for the light single scattering GGX
+// loses, see shader::brdf::multiscatter. energy_lut is baked by
+// texture::multiscatter.
+pub static MULTISCATTER: &'static str = r#"
+    #pragma once
+    #include "brdf.glsl"
+
+    // Directional albedo in red, its average over NdotV in green
+    uniform sampler2D energy_lut;
+    uniform bool multiscatter;
+
+    // Cosine weighted mean of Schlick's Fresnel
+    vec3 average_fresnel(vec3 f0) {
+        return f0 + (1.0 - f0) / 21.0;
+    }
+
+    // Added to the specular BRDF, f_avg is average_fresnel of the specular color
+    vec3 Multiscatter(float roughness, float NdotL, float NdotV, vec3 f_avg) {
+        if (!multiscatter) {
+            return vec3(0.0);
+        }
+        vec2 e_o = textureLod(energy_lut, vec2(NdotV, roughness), 0.0).rg;
+        float e_i = textureLod(energy_lut, vec2(NdotL, roughness), 0.0).r;
+        vec3 f_ms = f_avg * f_avg * e_o.g / (1.0 - f_avg * (1.0 - e_o.g));
+        return f_ms * (1.0 - e_o.r) * (1.0 - e_i) / (M_PI * max(1.0 - e_o.g, 1e-4));
+    }
+"#;
+
+// Ambient term from the maps baked by texture::ibl, equirect_uv matches
+// texture::ibl::direction_to_uv
+pub static IBL: &'static str = r#"
+    #pragma once
+    #include "brdf.glsl"
+    #include "multiscatter.glsl"
+
+    uniform sampler2D irradiance_map;
+    uniform sampler2D prefiltered_map;
+    uniform float prefiltered_levels;
+    // Split sum scale and bias of f0 over (NdotV, roughness), see texture::ibl::BrdfLut
+    uniform sampler2D brdf_lut;
+    uniform float ibl_intensity;
+    // The maps are in world space, normals in view space
+    uniform mat3 view_to_world;
+    // Cosine convolved harmonics, see texture::sh::ShL2::to_uniform
+    uniform SphericalHarmonics {
+        vec4 sh_coefficients[9];
+    };
+    // Diffuse light from the harmonics instead of the irradiance map
+    uniform bool diffuse_sh;
+
+    vec2 equirect_uv(vec3 d) {
+        return vec2(0.5 + atan(d.x, -d.z) / (2.0 * M_PI), acos(clamp(d.y, -1.0, 1.0)) / M_PI);
+    }
+
+    // Same basis as texture::sh::basis
+    vec3 sh_irradiance(vec3 n) {
+        return sh_coefficients[0].rgb * 0.282095
+             + sh_coefficients[1].rgb * 0.488603 * n.y
+             + sh_coefficients[2].rgb * 0.488603 * n.z
+             + sh_coefficients[3].rgb * 0.488603 * n.x
+             + sh_coefficients[4].rgb * 1.092548 * n.x * n.y
+             + sh_coefficients[5].rgb * 1.092548 * n.y * n.z
+             + sh_coefficients[6].rgb * 0.315392 * (3.0 * n.z * n.z - 1.0)
+             + sh_coefficients[7].rgb * 1.092548 * n.x * n.z
+             + sh_coefficients[8].rgb * 0.546274 * (n.x * n.x - n.y * n.y);
+    }
+
+    // Irradiance divided by pi, multiply with the diffuse color
+    vec3 ibl_diffuse(vec3 normal) {
+        vec3 n = view_to_world * normal;
+        if (diffuse_sh) {
+            return max(sh_irradiance(n), vec3(0.0));
+        }
+        return textureLod(irradiance_map, equirect_uv(n), 0.0).rgb;
+    }
+
+    vec3 ibl_specular(vec3 reflected, float roughness) {
+        float lod = roughness * (prefiltered_levels - 1.0);
+        return textureLod(prefiltered_map, equirect_uv(view_to_world * reflected), lod).rgb;
+    }
+
+    // Split sum scale and bias applied to the specular color. With
+    // multiscatter the energy missing from the table is added from the
+    // irradiance as in Fdez-Aguera, see shader::brdf::environment_reflectance.
+    vec3 ibl_ambient(vec3 normal, vec3 view_dir, vec3 diffuse_color, vec3 specular_color, float roughness) {
+        float NdotV = max(dot(normal, view_dir), 0.001);
+        vec2 env = textureLod(brdf_lut, vec2(NdotV, roughness), 0.0).rg;
+        vec3 single = specular_color * env.x + env.y;
+        vec3 irradiance = ibl_diffuse(normal);
+        vec3 specular = ibl_specular(reflect(-view_dir, normal), roughness) * single;
+        if (multiscatter) {
+            float e_ms = 1.0 - env.x - env.y;
+            vec3 f_avg = average_fresnel(specular_color);
+            specular += single * f_avg / (1.0 - e_ms * f_avg) * e_ms * irradiance;
+        }
+        return ibl_intensity * (diffuse_color * irradiance + specular);
+    }
+"#;
+
+// Names the snippets are included by
+pub static SNIPPETS: [(&'static str, &'static str); 7] = [("brdf.glsl", BRDF),
+                                                          ("lighting.glsl", LIGHTING),
+                                                          ("shadow.glsl", SHADOW),
+                                                          ("tangent_frame.glsl", TANGENT_FRAME),
+                                                          ("tonemap.glsl", TONEMAP),
+                                                          ("multiscatter.glsl", MULTISCATTER),
+                                                          ("ibl.glsl", IBL)];
+
+// Preprocessor that knows every snippet
+pub fn preprocessor() -> Preprocessor {
+    let mut p = Preprocessor::new();
+    for &(name, text) in SNIPPETS.iter() {
+        p = p.source(name, text);
+    }
+    p
+}
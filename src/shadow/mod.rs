@@ -0,0 +1,568 @@
+// Standard Library
+use std::f32;
+use std::rc::Rc;
+
+// External Library
+use cgmath::*;
+use glium;
+use glium::Surface;
+use glium::framebuffer::{DepthRenderBuffer, SimpleFrameBuffer};
+use glium::texture::{DepthFormat, MipmapsOption, Texture2dArray, UncompressedFloatFormat};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, Sampler, SamplerWrapFunction, UniformBuffer};
+
+use camera::Camera;
+use shader::{Program, ShaderError, Sources};
+use shader::permutation::Features;
+use texture::cubemap::FACES;
+use util::graphics::{CascadeBlock, DirectionalLight, Lights, MAX_CASCADES, MAX_SHADOW_CUBES};
+use util::math;
+
+// Depth map shadows. Point lights render the six faces of a cube around
+// them, the directional light renders one orthographic map per slice of
+// the view frustum (cascades). All maps are layers of float textures read
+// with percentage closer filtering by the shadow.glsl snippet.
+//
+// The math below needs no display, ShadowPass and CascadeBlock hand the
+// matrices to glium as arrays.
+
+// m * (p, 1) after the perspective divide
+pub fn project(m: &Matrix4<f32>, p: [f32; 3]) -> [f32; 3] {
+    let v = m * &Vector4::new(p[0], p[1], p[2], 1.0);
+    [v.x / v.w, v.y / v.w, v.z / v.w]
+}
+
+// View matrix of an eye at `eye` looking along `forward`
+fn look_along(eye: Vector3<f32>, forward: Vector3<f32>, up: Vector3<f32>) -> Matrix4<f32> {
+    let center = eye + forward;
+    Matrix4::look_at(Point3::new(eye.x, eye.y, eye.z),
+                     Point3::new(center.x, center.y, center.z),
+                     up)
+}
+
+// ***************************************************************************
+// Cascades
+// ***************************************************************************
+
+// Distances of the count + 1 cascade boundaries between near and far.
+// lambda 0 splits evenly, 1 logarithmically so every cascade covers the
+// same range of depth ratios (Zhang et al., Parallel-Split Shadow Maps).
+pub fn cascade_splits(near: f32, far: f32, count: usize, lambda: f32) -> Vec<f32> {
+    (0..count + 1)
+        .map(|i| {
+            let f = i as f32 / count as f32;
+            let log = near * (far / near).powf(f);
+            let uniform = near + (far - near) * f;
+            lambda * log + (1.0 - lambda) * uniform
+        })
+        .collect()
+}
+
+// World space corners of the view frustum between the distances near and
+// far, near plane first, each plane counter clockwise from the bottom left.
+// `view` has to be a rotation and translation like Camera::view.
+pub fn frustum_corners(view: &Matrix4<f32>, fovy: f32, aspect: f32, near: f32, far: f32) -> [[f32; 3]; 8] {
+    let t = view.w.truncate();
+    let to_world = |v: Vector3<f32>| {
+        let d = v - t;
+        [view.x.truncate().dot(d), view.y.truncate().dot(d), view.z.truncate().dot(d)]
+    };
+    let mut corners = [[0.0; 3]; 8];
+    for (plane, &distance) in [near, far].iter().enumerate() {
+        let h = distance * (0.5 * fovy).tan();
+        let w = h * aspect;
+        for (i, &(x, y)) in [(-w, -h), (w, -h), (w, h), (-w, h)].iter().enumerate() {
+            corners[plane * 4 + i] = to_world(Vector3::new(x, y, -distance));
+        }
+    }
+    corners
+}
+
+// Orthographic light matrix for a directional light shining along
+// `direction` that covers the given frustum slice. The slice is fitted
+// with a sphere so the covered area does not change when the camera turns,
+// and the map is moved in whole texels so edges do not crawl when it moves.
+// Casters up to caster_distance in front of the sphere still throw shadows into it.
+pub fn cascade_matrix(direction: [f32; 3],
+                      corners: &[[f32; 3]; 8],
+                      resolution: u32,
+                      caster_distance: f32)
+                      -> Matrix4<f32> {
+    let corners: Vec<Vector3<f32>> = corners.iter().map(|c| Vector3::new(c[0], c[1], c[2])).collect();
+    let center = corners.iter().fold(Vector3::new(0.0, 0.0, 0.0), |sum, &c| sum + c) * (1.0 / 8.0);
+    let radius = corners.iter().fold(0.0f32, |radius, &c| radius.max((c - center).length()));
+    // Rounding keeps the texel size constant despite float noise
+    let radius = (radius * 16.0).ceil() / 16.0;
+
+    let forward = Vector3::new(direction[0], direction[1], direction[2]).normalize();
+    let up = if forward.y.abs() < 0.99 { Vector3::unit_y() } else { Vector3::unit_z() };
+    let right = forward.cross(up).normalize();
+    let up = right.cross(forward);
+    let view = look_along(Vector3::new(0.0, 0.0, 0.0), forward, up);
+
+    let texel = 2.0 * radius / resolution as f32;
+    let x = (right.dot(center) / texel).floor() * texel;
+    let y = (up.dot(center) / texel).floor() * texel;
+    let z = forward.dot(center);
+    &ortho(x - radius, x + radius, y - radius, y + radius, z - radius - caster_distance, z + radius) * &view
+}
+
+// Light matrices of the cascades between the given split distances
+pub fn cascades(view: &Matrix4<f32>,
+                fovy: f32,
+                aspect: f32,
+                splits: &[f32],
+                direction: [f32; 3],
+                resolution: u32,
+                caster_distance: f32)
+                -> Vec<Matrix4<f32>> {
+    splits.windows(2)
+          .map(|w| {
+              let corners = frustum_corners(view, fovy, aspect, w[0], w[1]);
+              cascade_matrix(direction, &corners, resolution, caster_distance)
+          })
+          .collect()
+}
+
+// First cascade whose map covers the point with a texel to spare for the
+// filter, as sun_shadow in shadow.glsl picks it
+pub fn cascade_for(matrices: &[Matrix4<f32>], position: [f32; 3], resolution: u32) -> Option<usize> {
+    let margin = 1.0 / resolution as f32;
+    matrices.iter().position(|m| {
+        let p = project(m, position);
+        let uv = [p[0] * 0.5 + 0.5, p[1] * 0.5 + 0.5, p[2] * 0.5 + 0.5];
+        uv[0] > margin && uv[0] < 1.0 - margin && uv[1] > margin && uv[1] < 1.0 - margin && uv[2] > 0.0 &&
+        uv[2] < 1.0
+    })
+}
+
+// ***************************************************************************
+// Cube faces
+// ***************************************************************************
+
+// Right, up and forward of every face, rendering with these lands texel
+// (s, t) of texture::cubemap::direction_to_face at the same place of the
+// layer, which is what cube_face in shadow.glsl reads
+pub const FACE_AXES: [[[f32; 3]; 3]; FACES] = [[[0.0, 0.0, -1.0], [0.0, -1.0, 0.0], [1.0, 0.0, 0.0]],
+                                               [[0.0, 0.0, 1.0], [0.0, -1.0, 0.0], [-1.0, 0.0, 0.0]],
+                                               [[1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]],
+                                               [[1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, -1.0, 0.0]],
+                                               [[1.0, 0.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, 1.0]],
+                                               [[-1.0, 0.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, -1.0]]];
+
+// The right axis follows from up and forward, look_at rebuilds it
+pub fn cube_face_matrix(face: usize, position: [f32; 3], near: f32, far: f32) -> Matrix4<f32> {
+    let axes = FACE_AXES[face];
+    let up = Vector3::new(axes[1][0], axes[1][1], axes[1][2]);
+    let forward = Vector3::new(axes[2][0], axes[2][1], axes[2][2]);
+    let view = look_along(Vector3::new(position[0], position[1], position[2]), forward, up);
+    &perspective(Rad { s: f32::consts::FRAC_PI_2 }, 1.0, near, far) * &view
+}
+
+// Value stored in the cube layers, distances beyond far are not shadowed
+pub fn point_depth(light: [f32; 3], position: [f32; 3], far: f32) -> f32 {
+    let d = Vector3::new(position[0] - light[0], position[1] - light[1], position[2] - light[2]);
+    d.length() / far
+}
+
+// ***************************************************************************
+// Shadow maps on the GPU
+// ***************************************************************************
+
+#[derive(Copy, Clone, Debug)]
+pub struct ShadowSettings {
+    cascade_resolution: u32,
+    cascade_count: usize,
+    split_lambda: f32,
+    // The cascades end here or at the far plane, whichever is closer
+    shadow_distance: f32,
+    caster_distance: f32,
+    cube_resolution: u32,
+    point_near: f32,
+    point_far: f32,
+    bias: f32,
+}
+
+impl ShadowSettings {
+    pub fn new() -> ShadowSettings {
+        ShadowSettings {
+            cascade_resolution: 1024,
+            cascade_count: 3,
+            split_lambda: 0.75,
+            shadow_distance: 60.0,
+            caster_distance: 50.0,
+            cube_resolution: 512,
+            point_near: 0.05,
+            point_far: 25.0,
+            bias: 0.005,
+        }
+    }
+
+    // Size of every cascade map and the number of cascades, at most MAX_CASCADES
+    pub fn cascades(mut self, resolution: u32, count: usize) -> ShadowSettings {
+        self.cascade_resolution = resolution.max(16);
+        self.cascade_count = count.max(1).min(MAX_CASCADES);
+        self
+    }
+
+    // Blend between even (0) and logarithmic (1) splits, see cascade_splits
+    pub fn split_lambda(mut self, lambda: f32) -> ShadowSettings {
+        self.split_lambda = lambda.max(0.0).min(1.0);
+        self
+    }
+
+    // Distance from the camera beyond which the sun casts no shadows
+    pub fn shadow_distance(mut self, distance: f32) -> ShadowSettings {
+        self.shadow_distance = distance.max(0.0);
+        self
+    }
+
+    // How far outside of the view frustum casters are still rendered
+    pub fn caster_distance(mut self, distance: f32) -> ShadowSettings {
+        self.caster_distance = distance.max(0.0);
+        self
+    }
+
+    // Size of a cube face and the depth range around point lights
+    pub fn cubes(mut self, resolution: u32, near: f32, far: f32) -> ShadowSettings {
+        self.cube_resolution = resolution.max(16);
+        self.point_near = near;
+        self.point_far = far.max(near * 2.0);
+        self
+    }
+
+    // Subtracted from the depth of the shaded point against acne
+    pub fn bias(mut self, bias: f32) -> ShadowSettings {
+        self.bias = bias;
+        self
+    }
+}
+
+// Light matrix of one layer, `far` is 0 for cascades which store the
+// window depth and the distance range of a cube otherwise
+pub struct ShadowPass {
+    pub matrix: [[f32; 4]; 4],
+    pub light_position: [f32; 3],
+    pub far: f32,
+}
+
+pub struct ShadowMaps {
+    settings: ShadowSettings,
+    program: Rc<glium::Program>,
+    // Six layers per shadow casting point light
+    cubes: Texture2dArray,
+    cube_depth: DepthRenderBuffer,
+    cascades: Texture2dArray,
+    cascade_depth: DepthRenderBuffer,
+    cascade_block: UniformBuffer<CascadeBlock>,
+    // Turns the lookups off, the maps are still rendered
+    pub enabled: bool,
+}
+
+impl ShadowMaps {
+    pub fn new(display: &glium::Display, settings: ShadowSettings) -> Result<ShadowMaps, ShaderError> {
+        let program = try!(Program::from_sources(display, "shadow", Sources::shadow(), Vec::new(), None)
+                               .get(Features::empty()));
+        let layers = |resolution: u32, count: usize| {
+            Texture2dArray::empty_with_format(display,
+                                              UncompressedFloatFormat::F32,
+                                              MipmapsOption::NoMipmap,
+                                              resolution,
+                                              resolution,
+                                              count as u32)
+                .unwrap()
+        };
+        let cascade_block = CascadeBlock {
+            cascade_matrices: [[[0.0; 4]; 4]; MAX_CASCADES],
+            cascade_count: 0,
+        };
+        Ok(ShadowMaps {
+            settings: settings,
+            program: program,
+            cubes: layers(settings.cube_resolution, FACES * MAX_SHADOW_CUBES),
+            cube_depth: DepthRenderBuffer::new(display,
+                                               DepthFormat::I24,
+                                               settings.cube_resolution,
+                                               settings.cube_resolution)
+                            .unwrap(),
+            cascades: layers(settings.cascade_resolution, MAX_CASCADES),
+            cascade_depth: DepthRenderBuffer::new(display,
+                                                  DepthFormat::I24,
+                                                  settings.cascade_resolution,
+                                                  settings.cascade_resolution)
+                               .unwrap(),
+            cascade_block: UniformBuffer::new(display, cascade_block).unwrap(),
+            enabled: true,
+        })
+    }
+
+    // Renders every layer the lights need. `draw` is called once per layer
+    // and draws the shadow casters with the given program, usually through
+    // AssetInstance::draw_depth.
+    pub fn render<F>(&mut self,
+                     display: &glium::Display,
+                     lights: &Lights,
+                     sun: Option<&DirectionalLight>,
+                     camera: &Camera,
+                     mut draw: F)
+        where F: FnMut(&mut SimpleFrameBuffer, &glium::Program, &ShadowPass)
+    {
+        let settings = self.settings;
+        for (i, light) in lights.shadow_casters().iter().enumerate() {
+            for face in 0..FACES {
+                let pass = ShadowPass {
+                    matrix: math::to_mat4(&cube_face_matrix(face, light.pos, settings.point_near, settings.point_far)),
+                    light_position: light.pos,
+                    far: settings.point_far,
+                };
+                let layer = self.cubes.main_level().layer((i * FACES + face) as u32).unwrap();
+                let mut target = SimpleFrameBuffer::with_depth_buffer(display, layer, &self.cube_depth).unwrap();
+                target.clear_color_and_depth((1.0, 1.0, 1.0, 1.0), 1.0);
+                draw(&mut target, &*self.program, &pass);
+            }
+        }
+
+        let matrices = match sun {
+            Some(sun) if sun.casts_shadows() => {
+                let perspective = &camera.perspective;
+                let splits = cascade_splits(perspective.near,
+                                            perspective.far.min(settings.shadow_distance).max(perspective.near * 2.0),
+                                            settings.cascade_count,
+                                            settings.split_lambda);
+                cascades(&camera.view(),
+                         perspective.fovy.s,
+                         perspective.aspect,
+                         &splits,
+                         sun.direction(),
+                         settings.cascade_resolution,
+                         settings.caster_distance)
+            }
+            _ => Vec::new(),
+        };
+        let mut block = CascadeBlock {
+            cascade_matrices: [[[0.0; 4]; 4]; MAX_CASCADES],
+            cascade_count: matrices.len() as i32,
+        };
+        for (i, matrix) in matrices.into_iter().enumerate() {
+            let matrix = math::to_mat4(&matrix);
+            let pass = ShadowPass {
+                matrix: matrix,
+                light_position: [0.0; 3],
+                far: 0.0,
+            };
+            let layer = self.cascades.main_level().layer(i as u32).unwrap();
+            let mut target = SimpleFrameBuffer::with_depth_buffer(display, layer, &self.cascade_depth).unwrap();
+            target.clear_color_and_depth((1.0, 1.0, 1.0, 1.0), 1.0);
+            draw(&mut target, &*self.program, &pass);
+            block.cascade_matrices[i] = matrix;
+        }
+        self.cascade_block.write(&block);
+    }
+
+    // Compared texel by texel in shadow.glsl, filtering would blend depths
+    fn sampled<'t>(texture: &'t Texture2dArray) -> Sampler<'t, Texture2dArray> {
+        texture.sampled()
+               .wrap_function(SamplerWrapFunction::Clamp)
+               .minify_filter(MinifySamplerFilter::Nearest)
+               .magnify_filter(MagnifySamplerFilter::Nearest)
+    }
+
+    pub fn cubes(&self) -> Sampler<Texture2dArray> {
+        ShadowMaps::sampled(&self.cubes)
+    }
+
+    pub fn cascades(&self) -> Sampler<Texture2dArray> {
+        ShadowMaps::sampled(&self.cascades)
+    }
+
+    pub fn cascade_block(&self) -> &UniformBuffer<CascadeBlock> {
+        &self.cascade_block
+    }
+
+    // point_shadow_far in shadow.glsl
+    pub fn point_far(&self) -> f32 {
+        self.settings.point_far
+    }
+
+    pub fn bias(&self) -> f32 {
+        self.settings.bias
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use cgmath::*;
+
+    use texture::cubemap::{self, FACES};
+    use util::graphics::{Lights, PointLight, MAX_SHADOW_CUBES};
+    use super::{cascade_for, cascade_matrix, cascade_splits, cascades, cube_face_matrix, frustum_corners,
+                point_depth, project, FACE_AXES};
+
+    fn assert_close(value: f32, expected: f32, tolerance: f32) {
+        assert!((value - expected).abs() <= tolerance,
+                "{} instead of {} (tolerance {})",
+                value,
+                expected,
+                tolerance);
+    }
+
+    // Camera::view for a camera at `eye` turned by `yaw` around y
+    fn camera_view(eye: [f32; 3], yaw: f32) -> Matrix4<f32> {
+        &Matrix4::from(Matrix3::from_angle_y(Rad { s: yaw })) *
+        &Matrix4::from_translation(Vector3::new(-eye[0], -eye[1], -eye[2]))
+    }
+
+    #[test]
+    fn splits() {
+        for &lambda in [0.0f32, 0.5, 0.75, 1.0].iter() {
+            let s = cascade_splits(0.1, 100.0, 4, lambda);
+            assert_eq!(s.len(), 5);
+            assert_close(s[0], 0.1, 1e-6);
+            assert_close(s[4], 100.0, 1e-3);
+            assert!(s.windows(2).all(|w| w[0] < w[1]), "{:?}", s);
+        }
+        let even = cascade_splits(1.0, 9.0, 4, 0.0);
+        for i in 0..5 {
+            assert_close(even[i], 1.0 + 2.0 * i as f32, 1e-5);
+        }
+        let log = cascade_splits(1.0, 16.0, 4, 1.0);
+        for i in 0..4 {
+            assert_close(log[i + 1] / log[i], 2.0, 1e-4);
+        }
+    }
+
+    #[test]
+    fn frustum() {
+        let (fovy, aspect) = (0.69f32, 16.0 / 9.0);
+        let corners = frustum_corners(&camera_view([0.0; 3], 0.0), fovy, aspect, 1.0, 10.0);
+        let h = (0.5 * fovy).tan();
+        assert_close(corners[0][1], -h, 1e-5);
+        assert_close(corners[0][0], -h * aspect, 1e-5);
+        assert_close(corners[2][2], -1.0, 1e-5);
+        assert_close(corners[6][2], -10.0, 1e-4);
+        assert_close(corners[6][0], 10.0 * h * aspect, 1e-4);
+
+        // Back through the view and projection every corner lands on the NDC cube
+        let view = camera_view([3.0, -2.0, 5.0], 1.1);
+        let (near, far) = (0.5f32, 40.0f32);
+        let view_projection = &perspective(Rad { s: fovy }, aspect, near, far) * &view;
+        let expected = [[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]];
+        for (i, corner) in frustum_corners(&view, fovy, aspect, near, far).iter().enumerate() {
+            let p = project(&view_projection, *corner);
+            assert_close(p[0], expected[i % 4][0], 1e-3);
+            assert_close(p[1], expected[i % 4][1], 1e-3);
+            assert_close(p[2], if i < 4 { -1.0 } else { 1.0 }, 1e-3);
+        }
+    }
+
+    #[test]
+    fn cascade_matrices() {
+        let direction = [-0.3f32, -1.0, -0.4];
+        let length = (direction[0] * direction[0] + direction[1] * direction[1] + direction[2] * direction[2]).sqrt();
+        let resolution = 1024;
+        for &yaw in [0.0f32, 0.7, 2.5].iter() {
+            for &eye in [[0.0f32, 0.0, 0.0], [13.37, 2.0, -7.25]].iter() {
+                let corners = frustum_corners(&camera_view(eye, yaw), 0.69, 16.0 / 9.0, 2.0, 15.0);
+                let m = cascade_matrix(direction, &corners, resolution, 50.0);
+                // The slice is inside the cascade
+                for &p in corners.iter() {
+                    assert!(project(&m, p).iter().all(|v| v.abs() <= 1.0), "{:?}", p);
+                }
+                // The texel grid stays put in world space, points fixed in the
+                // world keep their texel as the camera moves
+                let origin = project(&m, [0.0; 3]);
+                for i in 0..2 {
+                    let texel = (origin[i] * 0.5 + 0.5) * resolution as f32;
+                    assert_close(texel - texel.round(), 0.0, 2e-2);
+                }
+                // Casters behind the slice towards the light are still inside
+                let caster = [corners[0][0] - 40.0 * direction[0] / length,
+                              corners[0][1] - 40.0 * direction[1] / length,
+                              corners[0][2] - 40.0 * direction[2] / length];
+                assert!(project(&m, caster)[2] >= -1.0);
+            }
+        }
+
+        // Turning the camera keeps the size of the map, only its position changes
+        let scale = |yaw: f32| {
+            let corners = frustum_corners(&camera_view([1.0, 2.0, 3.0], yaw), 0.69, 16.0 / 9.0, 2.0, 15.0);
+            cascade_matrix(direction, &corners, resolution, 50.0).x.x
+        };
+        for &yaw in [0.3f32, 1.0, PI].iter() {
+            assert_close(scale(yaw), scale(0.0), 1e-6);
+        }
+    }
+
+    #[test]
+    fn cascade_selection() {
+        let view = camera_view([0.0; 3], 0.0);
+        let splits = cascade_splits(0.5, 60.0, 3, 0.75);
+        let matrices = cascades(&view, 0.69, 16.0 / 9.0, &splits, [0.2, -1.0, 0.1], 1024, 50.0);
+        assert_eq!(matrices.len(), 3);
+        // Points in the middle of a slice are in its cascade or a closer one
+        for i in 0..3 {
+            let distance = 0.5 * (splits[i] + splits[i + 1]);
+            let selected = cascade_for(&matrices, [0.0, 0.0, -distance], 1024);
+            assert!(selected.map_or(false, |s| s <= i), "{} in {:?}", distance, selected);
+        }
+        assert_eq!(cascade_for(&matrices, [0.0, 0.0, -1.0], 1024), Some(0));
+        // Points far beyond the last split are unshadowed
+        assert_eq!(cascade_for(&matrices, [0.0, 0.0, -500.0], 1024), None);
+    }
+
+    #[test]
+    fn cube_faces() {
+        // Right x up points backwards
+        for face in 0..FACES {
+            let axes = FACE_AXES[face];
+            let right = Vector3::new(axes[0][0], axes[0][1], axes[0][2]);
+            let back = right.cross(Vector3::new(axes[1][0], axes[1][1], axes[1][2]));
+            assert_close(back.x, -axes[2][0], 1e-6);
+            assert_close(back.y, -axes[2][1], 1e-6);
+            assert_close(back.z, -axes[2][2], 1e-6);
+        }
+
+        // Every direction lands where the shadow.glsl lookup reads it
+        let light = [1.0f32, 2.0, -3.0];
+        let (near, far) = (0.05f32, 25.0f32);
+        let matrices: Vec<Matrix4<f32>> = (0..FACES).map(|f| cube_face_matrix(f, light, near, far)).collect();
+        let mut hit = [false; FACES];
+        for i in 0..7 {
+            for j in 0..7 {
+                for k in 0..7 {
+                    let d = [i as f32 - 3.1, j as f32 - 2.9, k as f32 - 3.05];
+                    let (face, s, t) = cubemap::direction_to_face(d);
+                    let p = project(&matrices[face], [light[0] + d[0], light[1] + d[1], light[2] + d[2]]);
+                    assert_close(0.5 * p[0] + 0.5, s, 1e-4);
+                    assert_close(0.5 * p[1] + 0.5, t, 1e-4);
+                    assert!(p[2].abs() <= 1.0, "{:?} outside the depth range of face {}", d, face);
+                    hit[face] = true;
+                }
+            }
+        }
+        assert!(hit.iter().all(|&h| h));
+
+        assert_close(point_depth(light, [1.0, 2.0, 2.0], far), 5.0 / far, 1e-6);
+        // Points beyond far are never shadowed
+        assert!(point_depth(light, [1.0, 2.0, 30.0], far) > 1.0);
+    }
+
+    #[test]
+    fn layers() {
+        let mut lights = Lights::new();
+        for i in 0..MAX_SHADOW_CUBES + 2 {
+            let mut light = PointLight::new([i as f32, 0.0, 0.0], [1.0; 3], 1.0, 0.0, 0.0);
+            // The second light casts no shadows
+            light.set_cast_shadows(i != 1);
+            lights.add(light);
+        }
+        let block = &lights.blocks()[0];
+        let expected = [Some(0), None, Some(6), Some(12), Some(18), None];
+        for (i, &layer) in expected.iter().enumerate() {
+            assert_eq!(block.lights[i].shadow_layer(), layer);
+        }
+        let casters: Vec<f32> = lights.shadow_casters().iter().map(|l| l.pos[0]).collect();
+        assert_eq!(casters, vec![0.0, 2.0, 3.0, 4.0]);
+    }
+}
@@ -0,0 +1,363 @@
+// Standard Library
+use std::fmt;
+use std::path::Path;
+
+// External Library
+use image::{GrayImage, Luma, Rgba, RgbaImage};
+
+use texture::load_rgba;
+use texture::pack::{Channel, ChannelLayout};
+
+// Conversion between the albedo/specular/gloss textures drawn with
+// TexturePBR::AlbedoSpecularNormalGloss and base color plus a packed
+// roughness/metalness/occlusion texture for TexturePBR::AlbedoNormalPacked.
+// Uses the diffuse and specular colors of the glTF metal/rough model.
+
+// Reflectance of dielectrics assumed by the metallic workflow
+pub const DIELECTRIC_F0: f32 = 0.04;
+
+// Meaning of the red channel of the gloss texture
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GlossEncoding {
+    // What CT_FRAG_PBR reads, see ChannelLayout::legacy_gloss
+    Roughness,
+    // 1 - roughness, as exported by most texturing tools
+    Glossiness,
+}
+
+#[derive(Debug)]
+pub enum ConvertError {
+    SizeMismatch((u32, u32), (u32, u32)),
+    Load(String),
+    Save(String),
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConvertError::SizeMismatch(a, b) => {
+                write!(f, "texture sizes differ: {}x{} and {}x{}", a.0, a.1, b.0, b.1)
+            }
+            ConvertError::Load(ref e) => write!(f, "could not load {}", e),
+            ConvertError::Save(ref e) => write!(f, "could not save {}", e),
+        }
+    }
+}
+
+pub struct SpecGloss {
+    pub albedo: RgbaImage,
+    pub specular: RgbaImage,
+    // Roughness or glossiness in r, occlusion is g * b as in CT_FRAG_PBR
+    pub gloss: RgbaImage,
+}
+
+pub struct MetalRough {
+    pub base_color: RgbaImage,
+    // Roughness, metalness and occlusion as described by `layout`
+    pub packed: RgbaImage,
+    pub layout: ChannelLayout,
+}
+
+#[inline]
+fn to_f32(v: u8) -> f32 {
+    v as f32 / 255.0
+}
+
+#[inline]
+fn to_u8(v: f32) -> u8 {
+    (v.max(0.0).min(1.0) * 255.0 + 0.5) as u8
+}
+
+fn perceived_brightness(c: [f32; 3]) -> f32 {
+    (0.299 * c[0] * c[0] + 0.587 * c[1] * c[1] + 0.114 * c[2] * c[2]).sqrt()
+}
+
+// Metalness for which a gray base color reproduces both brightnesses,
+// the positive root of f0 m^2 + (d / (1 - f0) + s - 2 f0) m + f0 - s = 0
+fn solve_metallic(diffuse: f32, specular: f32) -> f32 {
+    if specular < DIELECTRIC_F0 {
+        return 0.0;
+    }
+    let a = DIELECTRIC_F0;
+    let b = diffuse / (1.0 - DIELECTRIC_F0) + specular - 2.0 * DIELECTRIC_F0;
+    let c = DIELECTRIC_F0 - specular;
+    let d = (b * b - 4.0 * a * c).max(0.0);
+    ((-b + d.sqrt()) / (2.0 * a)).max(0.0).min(1.0)
+}
+
+// Diffuse and specular color to base color and metalness, the inverse of
+// metal_to_spec. Base colors from the diffuse and specular side are mixed
+// by metalness squared, as they disagree if the input was not metal/rough.
+pub fn spec_to_metal(diffuse: [f32; 3], specular: [f32; 3]) -> ([f32; 3], f32) {
+    let metallic = solve_metallic(perceived_brightness(diffuse), perceived_brightness(specular));
+
+    let mut base = [0.0; 3];
+    for i in 0..3 {
+        let from_diffuse = diffuse[i] / (1.0 - DIELECTRIC_F0) / (1.0 - metallic).max(1e-4);
+        let from_specular = (specular[i] - DIELECTRIC_F0 * (1.0 - metallic)) / metallic.max(1e-4);
+        let t = metallic * metallic;
+        base[i] = (from_diffuse + (from_specular - from_diffuse) * t).max(0.0).min(1.0);
+    }
+    (base, metallic)
+}
+
+// Base color and metalness to diffuse and specular color
+pub fn metal_to_spec(base: [f32; 3], metallic: f32) -> ([f32; 3], [f32; 3]) {
+    let mut diffuse = [0.0; 3];
+    let mut specular = [0.0; 3];
+    for i in 0..3 {
+        specular[i] = DIELECTRIC_F0 + (base[i] - DIELECTRIC_F0) * metallic;
+        diffuse[i] = base[i] * (1.0 - DIELECTRIC_F0) * (1.0 - metallic);
+    }
+    (diffuse, specular)
+}
+
+fn check_size(a: &RgbaImage, b: &RgbaImage) -> Result<(), ConvertError> {
+    if a.dimensions() != b.dimensions() {
+        Err(ConvertError::SizeMismatch(a.dimensions(), b.dimensions()))
+    } else {
+        Ok(())
+    }
+}
+
+fn rgb(p: &Rgba<u8>) -> [f32; 3] {
+    [to_f32(p.data[0]), to_f32(p.data[1]), to_f32(p.data[2])]
+}
+
+impl SpecGloss {
+    pub fn load(albedo: &Path, specular: &Path, gloss: &Path) -> Result<SpecGloss, ConvertError> {
+        fn load(path: &Path) -> Result<RgbaImage, ConvertError> {
+            load_rgba(path).ok_or(ConvertError::Load(path.to_string_lossy().into_owned()))
+        }
+        Ok(SpecGloss {
+            albedo: try!(load(albedo)),
+            specular: try!(load(specular)),
+            gloss: try!(load(gloss)),
+        })
+    }
+
+    pub fn save(&self, albedo: &Path, specular: &Path, gloss: &Path) -> Result<(), ConvertError> {
+        for &(img, path) in [(&self.albedo, albedo), (&self.specular, specular), (&self.gloss, gloss)].iter() {
+            try!(img.save(path).map_err(|e| ConvertError::Save(format!("{}: {}", path.display(), e))));
+        }
+        Ok(())
+    }
+
+    pub fn to_metal_rough(&self, encoding: GlossEncoding, layout: ChannelLayout) -> Result<MetalRough, ConvertError> {
+        try!(check_size(&self.albedo, &self.specular));
+        try!(check_size(&self.albedo, &self.gloss));
+        let (w, h) = self.albedo.dimensions();
+        let mut base_color = RgbaImage::new(w, h);
+        let mut packed = RgbaImage::new(w, h);
+        for (x, y, a) in self.albedo.enumerate_pixels() {
+            let g = self.gloss.get_pixel(x, y);
+            let (base, metallic) = spec_to_metal(rgb(a), rgb(self.specular.get_pixel(x, y)));
+            let roughness = match encoding {
+                GlossEncoding::Roughness => to_f32(g.data[0]),
+                GlossEncoding::Glossiness => 1.0 - to_f32(g.data[0]),
+            };
+            base_color.put_pixel(x, y, Rgba { data: [to_u8(base[0]), to_u8(base[1]), to_u8(base[2]), a.data[3]] });
+            let occlusion = to_f32(g.data[1]) * to_f32(g.data[2]);
+            packed.put_pixel(x, y, Rgba { data: layout.texel(roughness, metallic, occlusion) });
+        }
+        Ok(MetalRough {
+            base_color: base_color,
+            packed: packed,
+            layout: layout,
+        })
+    }
+}
+
+impl MetalRough {
+    pub fn load(base_color: &Path, packed: &Path, layout: ChannelLayout) -> Result<MetalRough, ConvertError> {
+        fn load(path: &Path) -> Result<RgbaImage, ConvertError> {
+            load_rgba(path).ok_or(ConvertError::Load(path.to_string_lossy().into_owned()))
+        }
+        Ok(MetalRough {
+            base_color: try!(load(base_color)),
+            packed: try!(load(packed)),
+            layout: layout,
+        })
+    }
+
+    pub fn save(&self, base_color: &Path, packed: &Path) -> Result<(), ConvertError> {
+        for &(img, path) in [(&self.base_color, base_color), (&self.packed, packed)].iter() {
+            try!(img.save(path).map_err(|e| ConvertError::Save(format!("{}: {}", path.display(), e))));
+        }
+        Ok(())
+    }
+
+    // (roughness, metalness, occlusion) of a texel
+    fn parameters(&self, x: u32, y: u32) -> (f32, f32, f32) {
+        let p = self.packed.get_pixel(x, y).data;
+        let texel = [to_f32(p[0]), to_f32(p[1]), to_f32(p[2]), to_f32(p[3])];
+        let l = &self.layout;
+        (l.value(Channel::Roughness, texel),
+         l.value(Channel::Metalness, texel),
+         l.value(Channel::Occlusion, texel))
+    }
+
+    pub fn to_spec_gloss(&self, encoding: GlossEncoding) -> Result<SpecGloss, ConvertError> {
+        try!(check_size(&self.base_color, &self.packed));
+        let (w, h) = self.base_color.dimensions();
+        let mut albedo = RgbaImage::new(w, h);
+        let mut specular = RgbaImage::new(w, h);
+        let mut gloss = RgbaImage::new(w, h);
+        for (x, y, b) in self.base_color.enumerate_pixels() {
+            let (roughness, metallic, occlusion) = self.parameters(x, y);
+            let (d, s) = metal_to_spec(rgb(b), metallic);
+            let r = match encoding {
+                GlossEncoding::Roughness => roughness,
+                GlossEncoding::Glossiness => 1.0 - roughness,
+            };
+            albedo.put_pixel(x, y, Rgba { data: [to_u8(d[0]), to_u8(d[1]), to_u8(d[2]), b.data[3]] });
+            specular.put_pixel(x, y, Rgba { data: [to_u8(s[0]), to_u8(s[1]), to_u8(s[2]), 255] });
+            gloss.put_pixel(x, y, Rgba { data: [to_u8(r), to_u8(occlusion), 255, 255] });
+        }
+        Ok(SpecGloss {
+            albedo: albedo,
+            specular: specular,
+            gloss: gloss,
+        })
+    }
+}
+
+// Per pixel difference after converting forth and back, in 0-1 units.
+// `image` holds the largest channel difference of every pixel.
+pub struct RoundTrip {
+    pub max: f32,
+    pub mean: f32,
+    pub rmse: f32,
+    pub image: GrayImage,
+}
+
+fn compare(a: &[&RgbaImage], b: &[&RgbaImage]) -> RoundTrip {
+    let (w, h) = a[0].dimensions();
+    let mut image = GrayImage::new(w, h);
+    let (mut max, mut sum, mut sum2, mut count) = (0.0f32, 0.0f64, 0.0f64, 0usize);
+    for y in 0..h {
+        for x in 0..w {
+            let mut pixel_max = 0.0f32;
+            for (ia, ib) in a.iter().zip(b.iter()) {
+                let (pa, pb) = (ia.get_pixel(x, y).data, ib.get_pixel(x, y).data);
+                for c in 0..4 {
+                    let d = (to_f32(pa[c]) - to_f32(pb[c])).abs();
+                    pixel_max = pixel_max.max(d);
+                    sum += d as f64;
+                    sum2 += (d * d) as f64;
+                    count += 1;
+                }
+            }
+            max = max.max(pixel_max);
+            image.put_pixel(x, y, Luma { data: [to_u8(pixel_max)] });
+        }
+    }
+    let n = count.max(1) as f64;
+    RoundTrip {
+        max: max,
+        mean: (sum / n) as f32,
+        rmse: (sum2 / n).sqrt() as f32,
+        image: image,
+    }
+}
+
+// spec/gloss -> metal/rough -> spec/gloss
+pub fn round_trip_spec_gloss(input: &SpecGloss,
+                             encoding: GlossEncoding,
+                             layout: ChannelLayout)
+                             -> Result<(SpecGloss, RoundTrip), ConvertError> {
+    let back = try!(try!(input.to_metal_rough(encoding, layout)).to_spec_gloss(encoding));
+    let report = compare(&[&input.albedo, &input.specular, &input.gloss],
+                         &[&back.albedo, &back.specular, &back.gloss]);
+    Ok((back, report))
+}
+
+// metal/rough -> spec/gloss -> metal/rough
+pub fn round_trip_metal_rough(input: &MetalRough,
+                              encoding: GlossEncoding)
+                              -> Result<(MetalRough, RoundTrip), ConvertError> {
+    let back = try!(try!(input.to_spec_gloss(encoding)).to_metal_rough(encoding, input.layout));
+    let report = compare(&[&input.base_color, &input.packed], &[&back.base_color, &back.packed]);
+    Ok((back, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use texture::pack::ChannelLayout;
+    use super::{metal_to_spec, round_trip_spec_gloss, spec_to_metal, to_f32, GlossEncoding, MetalRough, SpecGloss,
+                DIELECTRIC_F0};
+
+    fn pixel(c: [f32; 3]) -> Rgba<u8> {
+        let u = |v: f32| (v * 255.0 + 0.5) as u8;
+        Rgba { data: [u(c[0]), u(c[1]), u(c[2]), 255] }
+    }
+
+    #[test]
+    fn dielectrics_are_not_metallic() {
+        let albedo = [0.5, 0.3, 0.2];
+        let (diffuse, specular) = metal_to_spec(albedo, 0.0);
+        assert_eq!(specular, [DIELECTRIC_F0; 3]);
+        let (base, metallic) = spec_to_metal(diffuse, specular);
+        assert_eq!(metallic, 0.0);
+        for i in 0..3 {
+            assert!((base[i] - albedo[i]).abs() < 1e-5, "{:?}", base);
+        }
+    }
+
+    #[test]
+    fn pure_metals_are_metallic() {
+        // Metals have no diffuse color, their specular color is the base color
+        let gold = [1.0, 0.78, 0.34];
+        let (base, metallic) = spec_to_metal([0.0; 3], gold);
+        assert!((metallic - 1.0).abs() < 1e-5, "{}", metallic);
+        for i in 0..3 {
+            assert!((base[i] - gold[i]).abs() < 1e-4, "{:?}", base);
+        }
+        assert_eq!(metal_to_spec(gold, 1.0), ([0.0; 3], gold));
+    }
+
+    #[test]
+    fn glossiness_is_one_minus_roughness() {
+        let roughness = 0.2;
+        let metal_rough = MetalRough {
+            base_color: RgbaImage::from_fn(1, 1, |_, _| pixel([0.5; 3])),
+            packed: RgbaImage::from_fn(1, 1, |_, _| Rgba { data: ChannelLayout::default().texel(roughness, 0.0, 1.0) }),
+            layout: ChannelLayout::default(),
+        };
+        let gloss = to_f32(metal_rough.to_spec_gloss(GlossEncoding::Glossiness).unwrap().gloss.get_pixel(0, 0).data[0]);
+        assert!((gloss - (1.0 - roughness)).abs() <= 1.0 / 255.0, "{}", gloss);
+        let rough = to_f32(metal_rough.to_spec_gloss(GlossEncoding::Roughness).unwrap().gloss.get_pixel(0, 0).data[0]);
+        assert!((rough - roughness).abs() <= 1.0 / 255.0, "{}", rough);
+    }
+
+    #[test]
+    fn round_trip_stays_within_the_report() {
+        // Spec/gloss textures that came out of the metal/rough model
+        let base = |x: u32, y: u32| [x as f32 / 7.0, y as f32 / 7.0, 0.5];
+        let metallic = |x: u32, _| if x < 4 { 0.0 } else { 1.0 };
+        let input = SpecGloss {
+            albedo: RgbaImage::from_fn(8, 8, |x, y| pixel(metal_to_spec(base(x, y), metallic(x, y)).0)),
+            specular: RgbaImage::from_fn(8, 8, |x, y| pixel(metal_to_spec(base(x, y), metallic(x, y)).1)),
+            gloss: RgbaImage::from_fn(8, 8, |x, y| pixel([(x + y) as f32 / 14.0, 1.0, 1.0])),
+        };
+        let (back, report) = round_trip_spec_gloss(&input, GlossEncoding::Glossiness, ChannelLayout::default())
+                                 .unwrap();
+        assert!(report.max < 0.05, "{}", report.max);
+        assert!(report.mean <= report.rmse && report.rmse <= report.max);
+        for &(a, b) in [(&input.albedo, &back.albedo), (&input.specular, &back.specular), (&input.gloss, &back.gloss)]
+                           .iter() {
+            for y in 0..8 {
+                for x in 0..8 {
+                    let (pa, pb) = (a.get_pixel(x, y).data, b.get_pixel(x, y).data);
+                    for c in 0..4 {
+                        let d = (to_f32(pa[c]) - to_f32(pb[c])).abs();
+                        assert!(d <= report.max);
+                        assert!(d <= to_f32(report.image.get_pixel(x, y).data[0]) + 0.5 / 255.0);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use texture::hdr::HdrImage;
+use texture::ibl;
+use util::math::normalize;
+
+// Cube maps on the CPU, six square faces in the order and orientation of
+// GL_TEXTURE_CUBE_MAP_POSITIVE_X and following: +X, -X, +Y, -Y, +Z, -Z.
+// Row 0 of a face is t = 0 as uploaded by glium.
+
+pub const FACES: usize = 6;
+
+// Direction through (s, t) in [0, 1]^2 on a face
+pub fn face_direction(face: usize, s: f32, t: f32) -> [f32; 3] {
+    let a = 2.0 * s - 1.0;
+    let b = 2.0 * t - 1.0;
+    normalize(match face {
+        0 => [1.0, -b, -a],
+        1 => [-1.0, -b, a],
+        2 => [a, 1.0, b],
+        3 => [a, -1.0, -b],
+        4 => [a, -b, 1.0],
+        _ => [-a, -b, -1.0],
+    })
+}
+
+// Face and (s, t) a direction hits, the inverse of face_direction
+pub fn direction_to_face(d: [f32; 3]) -> (usize, f32, f32) {
+    let (x, y, z) = (d[0], d[1], d[2]);
+    let (face, sc, tc, ma) = if x.abs() >= y.abs() && x.abs() >= z.abs() {
+        if x > 0.0 { (0, -z, -y, x) } else { (1, z, -y, -x) }
+    } else if y.abs() >= z.abs() {
+        if y > 0.0 { (2, x, z, y) } else { (3, x, -z, -y) }
+    } else if z > 0.0 {
+        (4, x, -y, z)
+    } else {
+        (5, -x, -y, -z)
+    };
+    (face, 0.5 * (sc / ma + 1.0), 0.5 * (tc / ma + 1.0))
+}
+
+// Solid angle of the face area between the origin and (x, y) in [-1, 1]^2
+fn area_element(x: f32, y: f32) -> f32 {
+    (x * y).atan2((x * x + y * y + 1.0).sqrt())
+}
+
+pub struct CubeMap {
+    faces: Vec<HdrImage>,
+}
+
+impl CubeMap {
+    // Six square faces of the same size
+    pub fn from_faces(faces: Vec<HdrImage>) -> Option<CubeMap> {
+        if faces.len() != FACES {
+            return None;
+        }
+        let size = faces[0].width;
+        if size == 0 || faces.iter().any(|f| f.width != size || f.height != size) {
+            return None;
+        }
+        Some(CubeMap { faces: faces })
+    }
+
+    pub fn from_equirect(image: &HdrImage, size: u32) -> CubeMap {
+        // Texels of the source should not be much smaller than the ones of the faces
+        let source = ibl::resample(image, 4 * size, 2 * size);
+        let mut faces = Vec::with_capacity(FACES);
+        for face in 0..FACES {
+            faces.push(HdrImage::from_fn(size, size, |x, y| {
+                let s = (x as f32 + 0.5) / size as f32;
+                let t = (y as f32 + 0.5) / size as f32;
+                ibl::sample(&source, face_direction(face, s, t))
+            }));
+        }
+        CubeMap { faces: faces }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.faces[0].width
+    }
+
+    pub fn face(&self, face: usize) -> &HdrImage {
+        &self.faces[face]
+    }
+
+    pub fn texel_direction(&self, face: usize, x: u32, y: u32) -> [f32; 3] {
+        let size = self.size() as f32;
+        face_direction(face, (x as f32 + 0.5) / size, (y as f32 + 0.5) / size)
+    }
+
+    // The same for every face, the six faces add up to 4 pi
+    pub fn texel_solid_angle(&self, x: u32, y: u32) -> f32 {
+        let step = 2.0 / self.size() as f32;
+        let (x0, y0) = (x as f32 * step - 1.0, y as f32 * step - 1.0);
+        let (x1, y1) = (x0 + step, y0 + step);
+        area_element(x0, y0) - area_element(x0, y1) - area_element(x1, y0) + area_element(x1, y1)
+    }
+
+    // Nearest texel in the direction
+    pub fn sample(&self, d: [f32; 3]) -> [f32; 3] {
+        let (face, s, t) = direction_to_face(d);
+        let size = self.size();
+        let x = ((s * size as f32) as u32).min(size - 1);
+        let y = ((t * size as f32) as u32).min(size - 1);
+        self.faces[face].get(x, y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use texture::hdr::HdrImage;
+    use super::{direction_to_face, face_direction, CubeMap, FACES};
+
+    #[test]
+    fn texels_cover_the_sphere() {
+        let cube = CubeMap::from_faces(vec![HdrImage::new(16, 16); FACES]).unwrap();
+        let mut total = 0.0;
+        for y in 0..cube.size() {
+            for x in 0..cube.size() {
+                total += FACES as f32 * cube.texel_solid_angle(x, y);
+            }
+        }
+        assert!((total - 4.0 * PI).abs() < 1e-4, "{}", total);
+    }
+
+    #[test]
+    fn directions_map_back_to_their_face() {
+        for face in 0..FACES {
+            for &(s, t) in [(0.5f32, 0.5f32), (0.1, 0.8), (0.9, 0.3)].iter() {
+                let (f, s1, t1) = direction_to_face(face_direction(face, s, t));
+                assert!(f == face && (s1 - s).abs() < 1e-5 && (t1 - t).abs() < 1e-5,
+                        "face {} at ({}, {}) came back as {} at ({}, {})",
+                        face,
+                        s,
+                        t,
+                        f,
+                        s1,
+                        t1);
+            }
+        }
+    }
+
+    #[test]
+    fn needs_six_square_faces() {
+        assert!(CubeMap::from_faces(vec![HdrImage::new(4, 4); 5]).is_none());
+        assert!(CubeMap::from_faces(vec![HdrImage::new(4, 2); FACES]).is_none());
+        assert!(CubeMap::from_faces(vec![HdrImage::new(0, 0); FACES]).is_none());
+    }
+}
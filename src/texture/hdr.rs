@@ -0,0 +1,389 @@
+// Standard Library
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+// Radiance RGBE (.hdr) images, the format environment maps are usually
+// shipped in. Reads flat, old style and new style run length encoded
+// scanlines in the standard -Y +X orientation, writes new style ones.
+
+#[derive(Debug)]
+pub enum HdrError {
+    Io(String),
+    Format(String),
+}
+
+impl fmt::Display for HdrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HdrError::Io(ref e) => write!(f, "{}", e),
+            HdrError::Format(ref e) => write!(f, "not a Radiance HDR image: {}", e),
+        }
+    }
+}
+
+// Linear RGB texels, rows from top to bottom
+#[derive(Clone, Debug, PartialEq)]
+pub struct HdrImage {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<[f32; 3]>,
+}
+
+impl HdrImage {
+    pub fn new(width: u32, height: u32) -> HdrImage {
+        HdrImage {
+            width: width,
+            height: height,
+            data: vec![[0.0; 3]; (width * height) as usize],
+        }
+    }
+
+    pub fn from_fn<F>(width: u32, height: u32, f: F) -> HdrImage
+        where F: Fn(u32, u32) -> [f32; 3]
+    {
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                data.push(f(x, y));
+            }
+        }
+        HdrImage {
+            width: width,
+            height: height,
+            data: data,
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, x: u32, y: u32) -> [f32; 3] {
+        self.data[(y * self.width + x) as usize]
+    }
+
+    #[inline]
+    pub fn set(&mut self, x: u32, y: u32, color: [f32; 3]) {
+        self.data[(y * self.width + x) as usize] = color;
+    }
+}
+
+fn to_rgbe(c: [f32; 3]) -> [u8; 4] {
+    let v = c[0].max(c[1]).max(c[2]);
+    if !(v > 1e-32) {
+        return [0; 4];
+    }
+    // v = m * 2^e with m in [0.5, 1)
+    let mut e = v.log2().floor() as i32 + 1;
+    let mut scale = 2.0f32.powi(-e);
+    if v * scale >= 1.0 {
+        e += 1;
+        scale *= 0.5;
+    }
+    if e < -128 || e > 127 {
+        return if e > 127 { [255, 255, 255, 255] } else { [0; 4] };
+    }
+    let scale = scale * 256.0;
+    [(c[0].max(0.0) * scale) as u8,
+     (c[1].max(0.0) * scale) as u8,
+     (c[2].max(0.0) * scale) as u8,
+     (e + 128) as u8]
+}
+
+fn from_rgbe(rgbe: [u8; 4]) -> [f32; 3] {
+    if rgbe[3] == 0 {
+        return [0.0; 3];
+    }
+    let scale = 2.0f32.powi(rgbe[3] as i32 - 136);
+    [(rgbe[0] as f32 + 0.5) * scale, (rgbe[1] as f32 + 0.5) * scale, (rgbe[2] as f32 + 0.5) * scale]
+}
+
+fn format_error<T>(message: &str) -> Result<T, HdrError> {
+    Err(HdrError::Format(message.to_string()))
+}
+
+// Returns the next header line without the newline
+fn read_line<'a>(bytes: &'a [u8], pos: &mut usize) -> Result<&'a [u8], HdrError> {
+    let start = *pos;
+    match bytes[start..].iter().position(|&b| b == b'\n') {
+        Some(len) => {
+            *pos = start + len + 1;
+            Ok(&bytes[start..start + len])
+        }
+        None => format_error("unexpected end of header"),
+    }
+}
+
+// Parses "-Y <height> +X <width>"
+fn parse_resolution(line: &str) -> Option<(u32, u32)> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.len() != 4 || words[0] != "-Y" || words[2] != "+X" {
+        return None;
+    }
+    match (words[1].parse(), words[3].parse()) {
+        (Ok(h), Ok(w)) => Some((w, h)),
+        _ => None,
+    }
+}
+
+// Flat scanline, possibly using the old style (1, 1, 1, n) repeats
+fn read_flat(bytes: &[u8], pos: &mut usize, first: [u8; 4], line: &mut [[u8; 4]]) -> Result<(), HdrError> {
+    let mut x = 0;
+    let mut shift = 0;
+    let mut pixel = first;
+    loop {
+        if pixel[0] == 1 && pixel[1] == 1 && pixel[2] == 1 {
+            if x == 0 {
+                return format_error("repeat at the start of a scanline");
+            }
+            // Longer runs than 2^32 pixels would not fit a scanline anyway
+            if shift > 24 {
+                return format_error("repeat count overflows");
+            }
+            let count = (pixel[3] as usize) << shift;
+            if x + count > line.len() {
+                return format_error("run past the end of a scanline");
+            }
+            let previous = line[x - 1];
+            for p in line[x..x + count].iter_mut() {
+                *p = previous;
+            }
+            x += count;
+            shift += 8;
+        } else {
+            line[x] = pixel;
+            x += 1;
+            shift = 0;
+        }
+        if x == line.len() {
+            return Ok(());
+        }
+        if *pos + 4 > bytes.len() {
+            return format_error("unexpected end of data");
+        }
+        pixel = [bytes[*pos], bytes[*pos + 1], bytes[*pos + 2], bytes[*pos + 3]];
+        *pos += 4;
+    }
+}
+
+// New style scanline, the four components are run length encoded one after another
+fn read_rle(bytes: &[u8], pos: &mut usize, line: &mut [[u8; 4]]) -> Result<(), HdrError> {
+    for c in 0..4 {
+        let mut x = 0;
+        while x < line.len() {
+            if *pos >= bytes.len() {
+                return format_error("unexpected end of data");
+            }
+            let count = bytes[*pos] as usize;
+            *pos += 1;
+            if count > 128 {
+                let count = count - 128;
+                if x + count > line.len() || *pos >= bytes.len() {
+                    return format_error("bad run length");
+                }
+                let value = bytes[*pos];
+                *pos += 1;
+                for p in line[x..x + count].iter_mut() {
+                    p[c] = value;
+                }
+                x += count;
+            } else {
+                if count == 0 || x + count > line.len() || *pos + count > bytes.len() {
+                    return format_error("bad literal length");
+                }
+                for (p, &value) in line[x..x + count].iter_mut().zip(bytes[*pos..*pos + count].iter()) {
+                    p[c] = value;
+                }
+                *pos += count;
+                x += count;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn decode(bytes: &[u8]) -> Result<HdrImage, HdrError> {
+    let mut pos = 0;
+    let magic = try!(read_line(bytes, &mut pos));
+    if magic != b"#?RADIANCE" && magic != b"#?RGBE" {
+        return format_error("missing #?RADIANCE");
+    }
+    loop {
+        let line = try!(read_line(bytes, &mut pos));
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with(b"FORMAT=") && line != b"FORMAT=32-bit_rle_rgbe" {
+            return format_error("only 32-bit_rle_rgbe is supported");
+        }
+    }
+    let resolution = try!(read_line(bytes, &mut pos));
+    let (width, height) = match parse_resolution(&String::from_utf8_lossy(resolution)) {
+        Some(r) => r,
+        None => return format_error("only -Y <height> +X <width> images are supported"),
+    };
+    if width == 0 || height == 0 {
+        return format_error("empty image");
+    }
+
+    let mut image = HdrImage::new(width, height);
+    let mut line = vec![[0u8; 4]; width as usize];
+    for y in 0..height {
+        if pos + 4 > bytes.len() {
+            return format_error("unexpected end of data");
+        }
+        let first = [bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]];
+        pos += 4;
+        let rle = first[0] == 2 && first[1] == 2 && first[2] < 128 &&
+                  ((first[2] as u32) << 8 | first[3] as u32) == width;
+        if rle && width >= 8 && width < 0x8000 {
+            try!(read_rle(bytes, &mut pos, &mut line));
+        } else {
+            try!(read_flat(bytes, &mut pos, first, &mut line));
+        }
+        for (x, &rgbe) in line.iter().enumerate() {
+            image.set(x as u32, y, from_rgbe(rgbe));
+        }
+    }
+    Ok(image)
+}
+
+// Runs of at least 3 equal bytes are encoded as runs, the rest as literals
+fn write_rle(out: &mut Vec<u8>, values: &[u8]) {
+    let mut x = 0;
+    while x < values.len() {
+        let mut run = 1;
+        while x + run < values.len() && run < 127 && values[x + run] == values[x] {
+            run += 1;
+        }
+        if run >= 3 {
+            out.push(128 + run as u8);
+            out.push(values[x]);
+            x += run;
+            continue;
+        }
+        let start = x;
+        while x < values.len() && x - start < 128 {
+            if x + 2 < values.len() && values[x] == values[x + 1] && values[x] == values[x + 2] {
+                break;
+            }
+            x += 1;
+        }
+        out.push((x - start) as u8);
+        out.extend_from_slice(&values[start..x]);
+    }
+}
+
+pub fn encode(image: &HdrImage) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n");
+    out.extend_from_slice(format!("-Y {} +X {}\n", image.height, image.width).as_bytes());
+    let rle = image.width >= 8 && image.width < 0x8000;
+    let mut channel = vec![0u8; image.width as usize];
+    for row in image.data.chunks(image.width.max(1) as usize) {
+        let rgbe: Vec<[u8; 4]> = row.iter().map(|&c| to_rgbe(c)).collect();
+        if !rle {
+            for p in rgbe.iter() {
+                out.extend_from_slice(p);
+            }
+            continue;
+        }
+        out.extend_from_slice(&[2, 2, (image.width >> 8) as u8, (image.width & 0xff) as u8]);
+        for c in 0..4 {
+            for (v, p) in channel.iter_mut().zip(rgbe.iter()) {
+                *v = p[c];
+            }
+            write_rle(&mut out, &channel);
+        }
+    }
+    out
+}
+
+pub fn load(path: &Path) -> Result<HdrImage, HdrError> {
+    let mut bytes = Vec::new();
+    let read = File::open(path).and_then(|mut f| f.read_to_end(&mut bytes));
+    if let Err(e) = read {
+        return Err(HdrError::Io(format!("{}: {}", path.display(), e)));
+    }
+    decode(&bytes)
+}
+
+pub fn save(path: &Path, image: &HdrImage) -> Result<(), HdrError> {
+    File::create(path)
+        .and_then(|mut f| f.write_all(&encode(image)))
+        .map_err(|e| HdrError::Io(format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, HdrError, HdrImage};
+
+    fn header(width: u32, height: u32) -> Vec<u8> {
+        format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width).into_bytes()
+    }
+
+    fn is_format_error(result: Result<HdrImage, HdrError>) -> bool {
+        match result {
+            Err(HdrError::Format(_)) => true,
+            _ => false,
+        }
+    }
+
+    fn assert_reads_back(image: &HdrImage) {
+        let decoded = decode(&encode(image)).unwrap();
+        assert_eq!((decoded.width, decoded.height), (image.width, image.height));
+        // The channels share an exponent, the error is relative to the largest
+        for (a, b) in image.data.iter().zip(decoded.data.iter()) {
+            let largest = a[0].max(a[1]).max(a[2]);
+            for i in 0..3 {
+                assert!((a[i] - b[i]).abs() <= largest / 128.0, "{:?} {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn images_read_back() {
+        assert_reads_back(&HdrImage::from_fn(20, 3, |x, y| [x as f32, y as f32 * 0.25, 1.0]));
+        // Runs of equal pixels for the run length encoder
+        assert_reads_back(&HdrImage::from_fn(70, 4, |x, y| {
+            if x % 16 < 6 { [0.25, 0.5, 1.0] } else { [0.01 * x as f32, 100.0 / (1 + y) as f32, 1e-3] }
+        }));
+        // Scanlines shorter than 8 pixels are stored flat
+        assert_reads_back(&HdrImage::from_fn(5, 3, |x, y| [x as f32 + 0.5, 0.01 * y as f32, 100.0]));
+        assert_reads_back(&HdrImage::new(16, 2));
+    }
+
+    #[test]
+    fn old_style_repeats_fill_the_scanline() {
+        let mut bytes = header(4, 1);
+        bytes.extend_from_slice(&[128, 64, 32, 129, 1, 1, 1, 3]);
+        let image = decode(&bytes).unwrap();
+        assert!(image.data.iter().all(|c| *c == image.data[0]));
+        // Mantissas are read at the middle of their step
+        assert!((image.data[0][0] - 128.5 / 128.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn rejects_garbage_and_truncated_data() {
+        assert!(decode(b"P6\n4 4\n255\n").is_err());
+        let mut bytes = header(2, 2);
+        bytes.extend_from_slice(&[128, 64, 32, 129]);
+        assert!(is_format_error(decode(&bytes)));
+    }
+
+    #[test]
+    fn rejects_overflowing_repeats() {
+        let mut bytes = header(4, 1);
+        bytes.extend_from_slice(&[128, 64, 32, 129]);
+        // Every repeat shifts the count of the next one by 8 bits
+        for _ in 0..12 {
+            bytes.extend_from_slice(&[1, 1, 1, 0]);
+        }
+        assert!(is_format_error(decode(&bytes)));
+    }
+
+    #[test]
+    fn rejects_empty_images() {
+        assert!(is_format_error(decode(&header(0, 4))));
+        assert!(is_format_error(decode(&header(4, 0))));
+    }
+}
@@ -0,0 +1,563 @@
+// Standard Library
+use std::borrow::Cow;
+use std::f32::consts::PI;
+
+// External Library
+use glium;
+use glium::texture::{ClientFormat, MipmapsOption, RawImage2d, Texture2d, UncompressedFloatFormat};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, Sampler, SamplerWrapFunction, UniformBuffer};
+
+use shader::brdf;
+use texture::hdr::HdrImage;
+use texture::sh::ShL2;
+use util::graphics::SphericalHarmonics;
+use util::math::dot;
+
+// Image based lighting. An environment is baked into a diffuse irradiance
+// map and a mip chain prefiltered with the GGX distribution for increasing
+// roughness, both read by the ibl.glsl snippet. All maps are
+// equirectangular with +Y up, the center column looks down -Z.
+
+#[inline]
+fn mix(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t]
+}
+
+// Same mapping as equirect_uv in ibl.glsl, v = 0 is the top row
+pub fn direction_to_uv(d: [f32; 3]) -> [f32; 2] {
+    [0.5 + d[0].atan2(-d[2]) / (2.0 * PI), d[1].max(-1.0).min(1.0).acos() / PI]
+}
+
+pub fn uv_to_direction(uv: [f32; 2]) -> [f32; 3] {
+    let phi = (uv[0] - 0.5) * 2.0 * PI;
+    let (sin_theta, cos_theta) = (uv[1] * PI).sin_cos();
+    [sin_theta * phi.sin(), cos_theta, -sin_theta * phi.cos()]
+}
+
+// Direction through the center of a texel
+pub fn texel_direction(image: &HdrImage, x: u32, y: u32) -> [f32; 3] {
+    uv_to_direction([(x as f32 + 0.5) / image.width as f32, (y as f32 + 0.5) / image.height as f32])
+}
+
+// Bilinear lookup, wraps around horizontally and clamps at the poles
+pub fn sample(image: &HdrImage, d: [f32; 3]) -> [f32; 3] {
+    let uv = direction_to_uv(d);
+    let x = uv[0] * image.width as f32 - 0.5;
+    let y = (uv[1] * image.height as f32 - 0.5).max(0.0).min(image.height as f32 - 1.0);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let w = image.width as i32;
+    let column = |x: i32| (((x % w) + w) % w) as u32;
+    let (x0, x1) = (column(x0 as i32), column(x0 as i32 + 1));
+    let y0 = y0 as u32;
+    let y1 = (y0 + 1).min(image.height - 1);
+    mix(mix(image.get(x0, y0), image.get(x1, y0), tx),
+        mix(image.get(x0, y1), image.get(x1, y1), tx),
+        ty)
+}
+
+// Averages 2x2 blocks, odd sizes repeat the last row or column
+pub fn downsample(image: &HdrImage) -> HdrImage {
+    let width = (image.width / 2).max(1);
+    let height = (image.height / 2).max(1);
+    HdrImage::from_fn(width, height, |x, y| {
+        let mut sum = [0.0; 3];
+        for &(dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+            let c = image.get((2 * x + dx).min(image.width - 1), (2 * y + dy).min(image.height - 1));
+            for i in 0..3 {
+                sum[i] += c[i] * 0.25;
+            }
+        }
+        sum
+    })
+}
+
+// Halves the image while it is at least twice the requested size, then
+// samples it bilinearly so large environments do not alias
+pub fn resample(image: &HdrImage, width: u32, height: u32) -> HdrImage {
+    let mut source = image.clone();
+    while source.width >= 2 * width && source.height >= 2 * height {
+        source = downsample(&source);
+    }
+    let target = HdrImage::new(width, height);
+    HdrImage::from_fn(width, height, |x, y| sample(&source, texel_direction(&target, x, y)))
+}
+
+// Trilinear lookup in a chain of halving images
+fn sample_lod(chain: &[HdrImage], d: [f32; 3], lod: f32) -> [f32; 3] {
+    let lod = lod.max(0.0).min((chain.len() - 1) as f32);
+    let level = lod.floor() as usize;
+    if level + 1 == chain.len() {
+        return sample(&chain[level], d);
+    }
+    mix(sample(&chain[level], d), sample(&chain[level + 1], d), lod - level as f32)
+}
+
+// Roughness the given level of the prefiltered chain is filtered for, the
+// shader picks the level as roughness * (levels - 1)
+pub fn level_roughness(level: usize, levels: usize) -> f32 {
+    if levels < 2 {
+        0.0
+    } else {
+        level as f32 / (levels - 1) as f32
+    }
+}
+
+// Split sum BRDF table, texel (x, y) holds brdf::split_sum for NdotV and
+// roughness at the texel centers (x + 0.5) / size and (y + 0.5) / size.
+// The table depends on no environment and is baked once per IblBaker.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BrdfLut {
+    pub size: u32,
+    pub data: Vec<[f32; 2]>,
+}
+
+impl BrdfLut {
+    pub fn bake(size: u32, samples: u32) -> BrdfLut {
+        let mut data = Vec::with_capacity((size * size) as usize);
+        for y in 0..size {
+            let roughness = (y as f32 + 0.5) / size as f32;
+            for x in 0..size {
+                data.push(brdf::split_sum((x as f32 + 0.5) / size as f32, roughness, samples));
+            }
+        }
+        BrdfLut {
+            size: size,
+            data: data,
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> [f32; 2] {
+        self.data[(y * self.size + x) as usize]
+    }
+
+    // Bilinear lookup clamped to the edge texels, as the shader reads it
+    pub fn lookup(&self, n_dot_v: f32, roughness: f32) -> [f32; 2] {
+        let last = (self.size - 1) as f32;
+        let x = (n_dot_v * self.size as f32 - 0.5).max(0.0).min(last);
+        let y = (roughness * self.size as f32 - 0.5).max(0.0).min(last);
+        let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+        let (x1, y1) = ((x0 + 1).min(self.size - 1), (y0 + 1).min(self.size - 1));
+        let (tx, ty) = (x - x0 as f32, y - y0 as f32);
+        let mut out = [0.0; 2];
+        for i in 0..2 {
+            let top = self.get(x0, y0)[i] + (self.get(x1, y0)[i] - self.get(x0, y0)[i]) * tx;
+            let bottom = self.get(x0, y1)[i] + (self.get(x1, y1)[i] - self.get(x0, y1)[i]) * tx;
+            out[i] = top + (bottom - top) * ty;
+        }
+        out
+    }
+
+    // Scale in red and bias in green for texture::hdr::save. RGBE shares one
+    // exponent per texel, so the bias is only kept to about 1/256 of the scale.
+    pub fn to_hdr(&self) -> HdrImage {
+        HdrImage::from_fn(self.size, self.size, |x, y| {
+            let v = self.get(x, y);
+            [v[0], v[1], 0.0]
+        })
+    }
+
+    pub fn from_hdr(image: &HdrImage) -> Option<BrdfLut> {
+        if image.width == 0 || image.width != image.height {
+            return None;
+        }
+        Some(BrdfLut {
+            size: image.width,
+            data: image.data.iter().map(|c| [c[0], c[1]]).collect(),
+        })
+    }
+}
+
+pub struct IblMaps {
+    // Cosine weighted integral of the environment divided by pi, the diffuse
+    // color is multiplied in directly
+    pub irradiance: HdrImage,
+    // Level 0 is the environment itself, every level is half the size of the previous one
+    pub prefiltered: Vec<HdrImage>,
+    // Cheaper stand-in for the irradiance map
+    pub sh: ShL2,
+    pub brdf_lut: BrdfLut,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct IblBaker {
+    irradiance_width: u32,
+    source_width: u32,
+    prefiltered_width: u32,
+    levels: usize,
+    samples: u32,
+    lut_size: u32,
+    lut_samples: u32,
+}
+
+impl IblBaker {
+    pub fn new() -> IblBaker {
+        IblBaker {
+            irradiance_width: 32,
+            source_width: 64,
+            prefiltered_width: 256,
+            levels: 6,
+            samples: 64,
+            lut_size: 64,
+            lut_samples: 512,
+        }
+    }
+
+    // Width of the irradiance map and of the environment copy it is integrated from
+    pub fn irradiance_width(mut self, width: u32, source_width: u32) -> IblBaker {
+        self.irradiance_width = width.max(2);
+        self.source_width = source_width.max(2);
+        self
+    }
+
+    // Width of the first level, the chain stops before levels get narrower than 4
+    pub fn prefiltered_width(mut self, width: u32, levels: usize) -> IblBaker {
+        self.prefiltered_width = width.max(4);
+        self.levels = levels.max(1);
+        self
+    }
+
+    // GGX samples per texel of the prefiltered levels
+    pub fn samples(mut self, samples: u32) -> IblBaker {
+        self.samples = samples.max(1);
+        self
+    }
+
+    // Resolution of the split sum table and GGX samples per texel
+    pub fn brdf_lut(mut self, size: u32, samples: u32) -> IblBaker {
+        self.lut_size = size.max(2);
+        self.lut_samples = samples.max(1);
+        self
+    }
+
+    pub fn bake(&self, environment: &HdrImage) -> IblMaps {
+        let source = resample(environment, self.source_width, self.source_width / 2);
+        IblMaps {
+            irradiance: self.irradiance(environment),
+            prefiltered: self.prefiltered(environment),
+            sh: ShL2::from_equirect(&source),
+            brdf_lut: BrdfLut::bake(self.lut_size, self.lut_samples),
+        }
+    }
+
+    // Brute force integral over every texel of a small copy of the environment.
+    // Dividing by the integrated weights instead of pi keeps a constant
+    // environment constant despite the coarse quadrature.
+    pub fn irradiance(&self, environment: &HdrImage) -> HdrImage {
+        let source = resample(environment, self.source_width, self.source_width / 2);
+        let d_phi = 2.0 * PI / source.width as f32;
+        let d_theta = PI / source.height as f32;
+        let mut texels = Vec::with_capacity(source.data.len());
+        for y in 0..source.height {
+            let solid_angle = d_phi * d_theta * ((y as f32 + 0.5) * d_theta).sin();
+            for x in 0..source.width {
+                texels.push((texel_direction(&source, x, y), solid_angle, source.get(x, y)));
+            }
+        }
+
+        let target = HdrImage::new(self.irradiance_width, self.irradiance_width / 2);
+        HdrImage::from_fn(target.width, target.height, |x, y| {
+            let normal = texel_direction(&target, x, y);
+            let mut sum = [0.0f64; 3];
+            let mut weight = 0.0f64;
+            for &(d, solid_angle, color) in texels.iter() {
+                let w = dot(normal, d).max(0.0) * solid_angle;
+                if w > 0.0 {
+                    for i in 0..3 {
+                        sum[i] += (color[i] * w) as f64;
+                    }
+                    weight += w as f64;
+                }
+            }
+            [(sum[0] / weight) as f32, (sum[1] / weight) as f32, (sum[2] / weight) as f32]
+        })
+    }
+
+    // Split sum prefiltering with normal = view = reflection. Samples are
+    // read from a lower resolution copy when they cover more than a texel,
+    // which keeps the chain free of fireflies with few samples.
+    pub fn prefiltered(&self, environment: &HdrImage) -> Vec<HdrImage> {
+        let mut chain = vec![resample(environment, self.prefiltered_width, self.prefiltered_width / 2)];
+        while chain[chain.len() - 1].width > 4 {
+            let next = downsample(&chain[chain.len() - 1]);
+            chain.push(next);
+        }
+        let texel_solid_angle = 4.0 * PI / (chain[0].width * chain[0].height) as f32;
+
+        let count = self.levels.min(chain.len());
+        let mut levels = vec![chain[0].clone()];
+        for level in 1..count {
+            let roughness = level_roughness(level, count);
+            let alpha = roughness * roughness;
+            let size = &chain[level];
+            let image = HdrImage::from_fn(size.width, size.height, |x, y| {
+                let normal = texel_direction(size, x, y);
+                let mut sum = [0.0; 3];
+                let mut weight = 0.0;
+                for i in 0..self.samples {
+                    let h = brdf::importance_sample_ggx(brdf::hammersley(i, self.samples), alpha, normal);
+                    let n_dot_h = dot(normal, h);
+                    let l = [2.0 * n_dot_h * h[0] - normal[0],
+                             2.0 * n_dot_h * h[1] - normal[1],
+                             2.0 * n_dot_h * h[2] - normal[2]];
+                    let n_dot_l = dot(normal, l);
+                    if n_dot_l <= 0.0 {
+                        continue;
+                    }
+                    // pdf of l is D * NdotH / (4 * VdotH) = D / 4
+                    let pdf = brdf::ggx_trowbridge_reitz(alpha, n_dot_h) / 4.0;
+                    let sample_solid_angle = 1.0 / (self.samples as f32 * pdf);
+                    let lod = 0.5 * (sample_solid_angle / texel_solid_angle).log2() + 1.0;
+                    let color = sample_lod(&chain, l, lod);
+                    for c in 0..3 {
+                        sum[c] += color[c] * n_dot_l;
+                    }
+                    weight += n_dot_l;
+                }
+                [sum[0] / weight, sum[1] / weight, sum[2] / weight]
+            });
+            levels.push(image);
+        }
+        levels
+    }
+}
+
+// Baked maps uploaded as half float textures
+pub struct Environment {
+    irradiance: Texture2d,
+    prefiltered: Texture2d,
+    levels: usize,
+    harmonics: UniformBuffer<SphericalHarmonics>,
+    brdf_lut: Texture2d,
+    // Scales the ambient term, 0 turns image based lighting off
+    pub intensity: f32,
+    // Diffuse light from the harmonics instead of the irradiance map
+    pub diffuse_sh: bool,
+}
+
+fn raw_rgb(image: &HdrImage) -> RawImage2d<'static, f32> {
+    let data: Vec<f32> = image.data.iter().flat_map(|c| c.iter().cloned()).collect();
+    RawImage2d::from_raw_rgb(data, (image.width, image.height))
+}
+
+// Two channel half float texture of a table, sample it with clamp to edge
+pub fn upload_lut(display: &glium::Display, lut: &BrdfLut) -> Texture2d {
+    let raw = RawImage2d {
+        data: Cow::Owned(lut.data.iter().flat_map(|v| v.iter().cloned()).collect()),
+        width: lut.size,
+        height: lut.size,
+        format: ClientFormat::F32F32,
+    };
+    Texture2d::with_format(display, raw, UncompressedFloatFormat::F16F16, MipmapsOption::NoMipmap).unwrap()
+}
+
+impl Environment {
+    pub fn upload(display: &glium::Display, maps: &IblMaps) -> Environment {
+        let irradiance = Texture2d::with_format(display,
+                                                raw_rgb(&maps.irradiance),
+                                                UncompressedFloatFormat::F16F16F16,
+                                                MipmapsOption::NoMipmap)
+                             .unwrap();
+        let prefiltered = Texture2d::with_format(display,
+                                                 raw_rgb(&maps.prefiltered[0]),
+                                                 UncompressedFloatFormat::F16F16F16,
+                                                 MipmapsOption::EmptyMipmaps)
+                              .unwrap();
+        // Levels below the baked ones are never read but have to be defined
+        let mut level = maps.prefiltered[0].clone();
+        for i in 1..prefiltered.get_mipmap_levels() {
+            level = match maps.prefiltered.get(i as usize) {
+                Some(l) => l.clone(),
+                None => downsample(&level),
+            };
+            let rect = glium::Rect {
+                left: 0,
+                bottom: 0,
+                width: level.width,
+                height: level.height,
+            };
+            prefiltered.mipmap(i).unwrap().write(rect, raw_rgb(&level));
+        }
+        Environment {
+            irradiance: irradiance,
+            prefiltered: prefiltered,
+            levels: maps.prefiltered.len(),
+            harmonics: UniformBuffer::new(display, maps.sh.to_uniform()).unwrap(),
+            brdf_lut: upload_lut(display, &maps.brdf_lut),
+            intensity: 1.0,
+            diffuse_sh: false,
+        }
+    }
+
+    pub fn irradiance(&self) -> Sampler<Texture2d> {
+        self.irradiance
+            .sampled()
+            .wrap_function(SamplerWrapFunction::Repeat)
+            .minify_filter(MinifySamplerFilter::Linear)
+            .magnify_filter(MagnifySamplerFilter::Linear)
+    }
+
+    pub fn prefiltered(&self) -> Sampler<Texture2d> {
+        self.prefiltered
+            .sampled()
+            .wrap_function(SamplerWrapFunction::Repeat)
+            .minify_filter(MinifySamplerFilter::LinearMipmapLinear)
+            .magnify_filter(MagnifySamplerFilter::Linear)
+    }
+
+    pub fn brdf_lut(&self) -> Sampler<Texture2d> {
+        self.brdf_lut
+            .sampled()
+            .wrap_function(SamplerWrapFunction::Clamp)
+            .minify_filter(MinifySamplerFilter::Linear)
+            .magnify_filter(MagnifySamplerFilter::Linear)
+    }
+
+    // Number of baked levels, prefiltered_levels in ibl.glsl
+    pub fn levels(&self) -> f32 {
+        self.levels as f32
+    }
+
+    pub fn harmonics(&self) -> &UniformBuffer<SphericalHarmonics> {
+        &self.harmonics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shader::brdf;
+    use texture::hdr::{self, HdrImage};
+    use super::{direction_to_uv, sample, texel_direction, uv_to_direction, BrdfLut, IblBaker};
+
+    // White sky above the horizon, black ground below
+    fn sky() -> HdrImage {
+        HdrImage::from_fn(64, 32, |_, y| if y < 16 { [1.0; 3] } else { [0.0; 3] })
+    }
+
+    fn baker() -> IblBaker {
+        IblBaker::new().irradiance_width(16, 32).prefiltered_width(64, 4).samples(32)
+    }
+
+    #[test]
+    fn brdf_lut_holds_the_split_sum() {
+        let lut = BrdfLut::bake(16, 128);
+        for y in 0..lut.size {
+            for x in 0..lut.size {
+                let v = lut.get(x, y);
+                let expected = brdf::split_sum((x as f32 + 0.5) / 16.0, (y as f32 + 0.5) / 16.0, 128);
+                assert_eq!(v, expected);
+                assert!(v[0] >= 0.0 && v[1] >= 0.0 && v[0] + v[1] <= 1.0 + 1e-3, "{:?}", v);
+            }
+        }
+        // Smooth surfaces seen head on reflect almost everything, the
+        // closed form of the roughest row is 2 (1 - ln 2) / (1 + NdotV)
+        let v = lut.get(15, 0);
+        assert!(v[0] + v[1] > 0.95 && v[1] < 0.01, "{:?}", v);
+        let fine = BrdfLut::bake(64, 1024);
+        let v = fine.get(63, 63);
+        let expected = 2.0 * (1.0 - 2.0f32.ln()) / (1.0 + 127.0 / 128.0);
+        assert!((v[0] + v[1] - expected).abs() < 0.02, "{:?} {}", v, expected);
+    }
+
+    #[test]
+    fn brdf_lut_lookup_and_hdr() {
+        let lut = BrdfLut::bake(16, 128);
+        assert!((lut.lookup(5.5 / 16.0, 9.5 / 16.0)[0] - lut.get(5, 9)[0]).abs() < 1e-6);
+        let between = 0.5 * (lut.get(5, 9)[1] + lut.get(6, 9)[1]);
+        assert!((lut.lookup(6.0 / 16.0, 9.5 / 16.0)[1] - between).abs() < 1e-6);
+        // RGBE keeps about 8 bits of the larger channel
+        let decoded = BrdfLut::from_hdr(&hdr::decode(&hdr::encode(&lut.to_hdr())).unwrap()).unwrap();
+        for (a, b) in lut.data.iter().zip(decoded.data.iter()) {
+            assert!((a[0] - b[0]).abs() <= 1.0 / 128.0 && (a[1] - b[1]).abs() <= 1.0 / 128.0);
+        }
+    }
+
+    #[test]
+    fn constant_environment_stays_constant() {
+        let environment = HdrImage::from_fn(64, 32, |_, _| [0.5, 1.0, 2.0]);
+        let irradiance = baker().irradiance(&environment);
+        assert_eq!((irradiance.width, irradiance.height), (16, 8));
+        let prefiltered = baker().prefiltered(&environment);
+        for image in Some(&irradiance).into_iter().chain(prefiltered.iter()) {
+            for c in image.data.iter() {
+                for (value, expected) in c.iter().zip([0.5, 1.0, 2.0].iter()) {
+                    assert!((value - expected).abs() < 1e-3 * expected, "{:?}", c);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn irradiance_follows_the_cosine() {
+        let irradiance = baker().irradiance(&sky());
+        for y in 0..irradiance.height {
+            let d = texel_direction(&irradiance, 0, y);
+            // Cosine weighted fraction of the sky seen from a surface facing d
+            let expected = 0.5 + 0.5 * d[1];
+            let value = irradiance.get(0, y)[0];
+            assert!((value - expected).abs() < 0.05, "row {}: {} instead of {}", y, value, expected);
+        }
+    }
+
+    #[test]
+    fn equirect_mapping() {
+        assert!((uv_to_direction([0.5, 0.5])[2] + 1.0).abs() < 1e-6);
+        assert!((uv_to_direction([0.3, 0.0])[1] - 1.0).abs() < 1e-6);
+        for &d in [[0.6f32, 0.0, 0.8], [0.0, 0.6, -0.8], [-0.48, -0.6, 0.64]].iter() {
+            let back = uv_to_direction(direction_to_uv(d));
+            for i in 0..3 {
+                assert!((back[i] - d[i]).abs() < 1e-5, "{:?} {:?}", d, back);
+            }
+        }
+    }
+
+    #[test]
+    fn prefiltered_levels_halve_and_blur() {
+        let prefiltered = baker().prefiltered(&sky());
+        let sizes: Vec<(u32, u32)> = prefiltered.iter().map(|l| (l.width, l.height)).collect();
+        assert_eq!(sizes, vec![(64, 32), (32, 16), (16, 8), (8, 4)]);
+        // The first level is the environment, the rough ones spread the
+        // horizon over more rows
+        let spread = |image: &HdrImage| {
+            (0..image.height).filter(|&y| {
+                let v = image.get(0, y)[0];
+                v > 0.05 && v < 0.95
+            }).count() as f32 / image.height as f32
+        };
+        assert_eq!(spread(&prefiltered[0]), 0.0);
+        for pair in prefiltered.windows(2).skip(1) {
+            assert!(spread(&pair[1]) > spread(&pair[0]), "{} {}", spread(&pair[0]), spread(&pair[1]));
+        }
+        // Still mostly white straight up and black straight down
+        for level in prefiltered.iter() {
+            assert!(level.get(0, 0)[0] > 0.8 && level.get(0, level.height - 1)[0] < 0.2);
+        }
+    }
+
+    // A small bright spot spreads out and dims with every level
+    #[test]
+    fn spot_dims_with_every_level() {
+        let spot = direction_to_uv([1.0, 0.0, 0.0]);
+        let image = HdrImage::from_fn(128, 64, |x, y| {
+            let dx = (x as f32 + 0.5) / 128.0 - spot[0];
+            let dy = (y as f32 + 0.5) / 64.0 - spot[1];
+            if dx.abs() < 0.02 && dy.abs() < 0.04 { [100.0; 3] } else { [0.1; 3] }
+        });
+        let mut previous = ::std::f32::MAX;
+        for level in baker().prefiltered(&image).iter() {
+            let peak = sample(level, [1.0, 0.0, 0.0])[0];
+            assert!(peak < previous && peak > sample(level, [-1.0, 0.0, 0.0])[0], "{} {}", peak, previous);
+            previous = peak;
+        }
+    }
+
+    #[test]
+    fn harmonics_match_the_irradiance_map() {
+        let maps = baker().bake(&sky());
+        for &d in [[0.0f32, 1.0, 0.0], [0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [-0.48, 0.6, -0.64]].iter() {
+            let from_sh = maps.sh.irradiance(d)[0] / ::std::f32::consts::PI;
+            let from_map = sample(&maps.irradiance, d)[0];
+            assert!((from_sh - from_map).abs() < 0.06, "{:?}: {} {}", d, from_sh, from_map);
+        }
+    }
+}
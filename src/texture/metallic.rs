@@ -0,0 +1,197 @@
+// Standard Library
+use std::collections::HashMap;
+use std::path::Path;
+
+// External Library
+use glium;
+use glium::texture::Texture2d;
+use image;
+
+use texture::upload_rgba;
+use util::graphics::{Material, Lobes};
+
+// Key of the 1x1 white texture bound to every slot that has no map
+pub const DEFAULT_WHITE: &'static str = "default_white";
+
+pub fn insert_defaults(display: &glium::Display, texture_map: &mut HashMap<String, Texture2d>) {
+    let white = image::RgbaImage::from_pixel(1, 1, image::Rgba { data: [255, 255, 255, 255] });
+    texture_map.insert(DEFAULT_WHITE.to_string(), upload_rgba(display, white));
+}
+
+// Texture of a material map, looked up by file name
+pub fn lookup<'a>(file: &Option<String>, texture_map: &'a HashMap<String, Texture2d>) -> Option<&'a Texture2d> {
+    file.as_ref()
+        .and_then(|f| Path::new(f).file_name())
+        .and_then(|f| f.to_str())
+        .and_then(|f| texture_map.get(f))
+}
+
+// A material parameter that is read from a map, a constant, or both.
+// The factor scales the map, scalar maps are read from the red channel.
+#[derive(Copy, Clone)]
+pub struct Slot<'a, T: Copy> {
+    pub texture: Option<&'a Texture2d>,
+    pub factor: T,
+}
+
+impl<'a, T: Copy> Slot<'a, T> {
+    pub fn value(factor: T) -> Slot<'a, T> {
+        Slot {
+            texture: None,
+            factor: factor,
+        }
+    }
+    pub fn map(texture: &'a Texture2d, factor: T) -> Slot<'a, T> {
+        Slot {
+            texture: Some(texture),
+            factor: factor,
+        }
+    }
+}
+
+// Textures and factors of the metallic/roughness model. Missing maps are
+// replaced by the white texture, so the factor alone decides the value.
+#[derive(Copy, Clone)]
+pub struct MetallicRoughness<'a> {
+    white: &'a Texture2d,
+    pub base_color: Slot<'a, [f32; 4]>,
+    pub metallic: Slot<'a, f32>,
+    pub roughness: Slot<'a, f32>,
+    pub occlusion: Slot<'a, f32>,
+    pub emissive: Slot<'a, [f32; 3]>,
+    pub normal: Option<&'a Texture2d>,
+    pub lobes: Lobes,
+}
+
+impl<'a> MetallicRoughness<'a> {
+    pub fn new(white: &'a Texture2d) -> MetallicRoughness<'a> {
+        MetallicRoughness {
+            white: white,
+            base_color: Slot::value([1.0, 1.0, 1.0, 1.0]),
+            metallic: Slot::value(0.0),
+            roughness: Slot::value(0.5),
+            occlusion: Slot::value(1.0),
+            emissive: Slot::value([0.0, 0.0, 0.0]),
+            normal: None,
+            lobes: Lobes::default(),
+        }
+    }
+
+    // Uses the white texture registered by insert_defaults
+    pub fn with_defaults(texture_map: &'a HashMap<String, Texture2d>) -> Option<MetallicRoughness<'a>> {
+        texture_map.get(DEFAULT_WHITE).map(MetallicRoughness::new)
+    }
+
+    // Factors and maps of a material, texture names are looked up by file name
+    pub fn from_material(white: &'a Texture2d,
+                         material: &Material,
+                         texture_map: &'a HashMap<String, Texture2d>)
+                         -> MetallicRoughness<'a> {
+        let lookup = |file: &Option<String>| self::lookup(file, texture_map);
+        let c = material.base_color;
+        let t = &material.textures;
+        MetallicRoughness {
+            white: white,
+            base_color: Slot {
+                texture: lookup(&t.base_color),
+                factor: [c[0], c[1], c[2], material.alpha],
+            },
+            metallic: Slot {
+                texture: lookup(&t.metallic),
+                factor: material.metallic,
+            },
+            roughness: Slot {
+                texture: lookup(&t.roughness),
+                factor: material.roughness,
+            },
+            occlusion: Slot {
+                texture: lookup(&t.occlusion),
+                factor: 1.0,
+            },
+            emissive: Slot {
+                texture: lookup(&t.emissive),
+                factor: material.emissive,
+            },
+            normal: lookup(&t.normal),
+            lobes: material.lobes,
+        }
+    }
+
+    pub fn base_color(mut self, factor: [f32; 4]) -> MetallicRoughness<'a> {
+        self.base_color.factor = factor;
+        self
+    }
+    pub fn base_color_map(mut self, texture: &'a Texture2d) -> MetallicRoughness<'a> {
+        self.base_color.texture = Some(texture);
+        self
+    }
+    pub fn metallic(mut self, factor: f32) -> MetallicRoughness<'a> {
+        self.metallic.factor = factor;
+        self
+    }
+    // Resets the factor to 1 so the map is used as is
+    pub fn metallic_map(mut self, texture: &'a Texture2d) -> MetallicRoughness<'a> {
+        self.metallic = Slot::map(texture, 1.0);
+        self
+    }
+    pub fn roughness(mut self, factor: f32) -> MetallicRoughness<'a> {
+        self.roughness.factor = factor;
+        self
+    }
+    pub fn roughness_map(mut self, texture: &'a Texture2d) -> MetallicRoughness<'a> {
+        self.roughness = Slot::map(texture, 1.0);
+        self
+    }
+    pub fn occlusion(mut self, factor: f32) -> MetallicRoughness<'a> {
+        self.occlusion.factor = factor;
+        self
+    }
+    pub fn occlusion_map(mut self, texture: &'a Texture2d) -> MetallicRoughness<'a> {
+        self.occlusion = Slot::map(texture, 1.0);
+        self
+    }
+    pub fn emissive(mut self, factor: [f32; 3]) -> MetallicRoughness<'a> {
+        self.emissive.factor = factor;
+        self
+    }
+    pub fn emissive_map(mut self, texture: &'a Texture2d) -> MetallicRoughness<'a> {
+        self.emissive = Slot::map(texture, [1.0; 3]);
+        self
+    }
+    pub fn lobes(mut self, lobes: Lobes) -> MetallicRoughness<'a> {
+        self.lobes = lobes;
+        self
+    }
+    pub fn normal_map(mut self, texture: &'a Texture2d) -> MetallicRoughness<'a> {
+        self.normal = Some(texture);
+        self
+    }
+
+    // Texture to bind for a slot
+    pub fn texture<T: Copy>(&self, slot: &Slot<'a, T>) -> &'a Texture2d {
+        slot.texture.unwrap_or(self.white)
+    }
+    pub fn normal_texture(&self) -> &'a Texture2d {
+        self.normal.unwrap_or(self.white)
+    }
+    pub fn has_normal_map(&self) -> bool {
+        self.normal.is_some()
+    }
+
+    // Used to sort groups by their textures
+    pub fn first_texture(&self) -> &'a Texture2d {
+        self.base_color
+            .texture
+            .or(self.normal)
+            .or(self.roughness.texture)
+            .or(self.metallic.texture)
+            .unwrap_or(self.white)
+    }
+}
+
+// Maps a Blinn-Phong exponent to a roughness with a similar highlight width,
+// the shaders square the roughness to get the GGX alpha.
+pub fn roughness_from_exponent(ns: f32) -> f32 {
+    let alpha = (2.0 / (ns.max(0.0) + 2.0)).sqrt();
+    alpha.sqrt()
+}
@@ -0,0 +1,54 @@
+// Standard Library
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+// External Library
+use glium;
+use image;
+
+use assets::image_format;
+
+pub mod pack;
+pub mod normalmap;
+pub mod metallic;
+pub mod convert;
+pub mod hdr;
+pub mod ibl;
+pub mod cubemap;
+pub mod sh;
+pub mod multiscatter;
+
+
+// CPU side texture tools. Everything in here works on image buffers and
+// does not need a display, the results are uploaded like any other texture.
+pub fn load_luma(path: &Path) -> Option<image::GrayImage> {
+    let path = PathBuf::from(path);
+    let format = match image_format(&path) {
+        Some(f) => f,
+        None => return None,
+    };
+    let file = match File::open(&path) {
+        Ok(f) => f,
+        Err(_) => return None,
+    };
+    image::load(file, format).ok().map(|i| i.to_luma())
+}
+
+pub fn load_rgba(path: &Path) -> Option<image::RgbaImage> {
+    let path = PathBuf::from(path);
+    let format = match image_format(&path) {
+        Some(f) => f,
+        None => return None,
+    };
+    let file = match File::open(&path) {
+        Ok(f) => f,
+        Err(_) => return None,
+    };
+    image::load(file, format).ok().map(|i| i.to_rgba())
+}
+
+pub fn upload_rgba(display: &glium::Display, image: image::RgbaImage) -> glium::texture::Texture2d {
+    let image_dim = image.dimensions();
+    let image = glium::texture::RawImage2d::from_raw_rgba_reversed(image.into_raw(), image_dim);
+    glium::texture::Texture2d::new(display, image).unwrap()
+}
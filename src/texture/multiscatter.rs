@@ -0,0 +1,129 @@
+// External Library
+use glium;
+use glium::texture::Texture2d;
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter, Sampler, SamplerWrapFunction};
+
+use shader::brdf;
+use texture::ibl::{self, BrdfLut};
+
+// Tables for the multiple scattering compensation of the multiscatter.glsl
+// snippet. Single scattering GGX loses the light that bounces between
+// microfacets more than once, up to half of it for rough metals.
+
+// Texel (x, y) holds the directional albedo brdf::specular_albedo at
+// NdotV (x + 0.5) / size and roughness (y + 0.5) / size in the first
+// channel and its cosine weighted average over the row in the second.
+pub fn bake(size: u32, samples: u32) -> BrdfLut {
+    let mut data = Vec::with_capacity((size * size) as usize);
+    for y in 0..size {
+        let roughness = (y as f32 + 0.5) / size as f32;
+        let row: Vec<f32> = (0..size)
+                                .map(|x| brdf::specular_albedo((x as f32 + 0.5) / size as f32, roughness, samples))
+                                .collect();
+        // 2 * integral of E(mu) * mu over mu, midpoint rule on the texels
+        let average = row.iter()
+                         .enumerate()
+                         .map(|(x, e)| 2.0 * e * (x as f32 + 0.5) / size as f32 / size as f32)
+                         .fold(0.0, |a, b| a + b);
+        data.extend(row.into_iter().map(|e| [e, average]));
+    }
+    BrdfLut {
+        size: size,
+        data: data,
+    }
+}
+
+// Compensation lobe of a light at n_dot_l as the shader computes it, add
+// to cook_torrance before multiplying with NdotL
+pub fn compensation(table: &BrdfLut, roughness: f32, n_dot_l: f32, n_dot_v: f32, f_avg: f32) -> f32 {
+    let e_o = table.lookup(n_dot_v, roughness);
+    let e_i = table.lookup(n_dot_l, roughness);
+    brdf::multiscatter(e_o[0], e_i[0], e_o[1], f_avg)
+}
+
+// The table uploaded for the multiscatter.glsl snippet
+pub struct EnergyCompensation {
+    table: Texture2d,
+    // Turns the compensation of direct and image based lighting on and off
+    pub enabled: bool,
+}
+
+impl EnergyCompensation {
+    pub fn upload(display: &glium::Display, table: &BrdfLut) -> EnergyCompensation {
+        EnergyCompensation {
+            table: ibl::upload_lut(display, table),
+            enabled: true,
+        }
+    }
+
+    pub fn table(&self) -> Sampler<Texture2d> {
+        self.table
+            .sampled()
+            .wrap_function(SamplerWrapFunction::Clamp)
+            .minify_filter(MinifySamplerFilter::Linear)
+            .magnify_filter(MagnifySamplerFilter::Linear)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shader::brdf;
+    use super::{bake, compensation};
+
+    fn view_dir(n_dot_v: f32) -> [f32; 3] {
+        [(1.0 - n_dot_v * n_dot_v).sqrt(), 0.0, n_dot_v]
+    }
+
+    #[test]
+    fn table_holds_the_directional_albedo() {
+        let table = bake(32, 512);
+        for &y in [9u32, 19, 31].iter() {
+            let roughness = (y as f32 + 0.5) / 32.0;
+            for &x in [4u32, 15, 28].iter() {
+                let n_dot_v = (x as f32 + 0.5) / 32.0;
+                let albedo = brdf::directional_albedo(roughness, 1.0, n_dot_v, 400);
+                assert!((table.get(x, y)[0] - albedo).abs() < 0.01, "{:?} {}", table.get(x, y), albedo);
+            }
+        }
+        // Rough metals are what the compensation is for
+        assert!(brdf::directional_albedo(1.0, 1.0, 0.5, 400) < 0.9);
+    }
+
+    // With f0 = 1 nothing is absorbed, so the compensated lobe has to
+    // reflect all light for every roughness and view angle
+    #[test]
+    fn white_furnace() {
+        let table = bake(32, 512);
+        let normal = [0.0, 0.0, 1.0];
+        for &roughness in [0.4f32, 0.6, 0.8, 1.0].iter() {
+            for &n_dot_v in [0.2f32, 0.5, 0.9].iter() {
+                let v = view_dir(n_dot_v);
+                let single = brdf::directional_albedo(roughness, 1.0, n_dot_v, 400);
+                let furnace = brdf::integrate_hemisphere(400, |l| {
+                    brdf::cook_torrance(roughness, 1.0, normal, l, v) +
+                    compensation(&table, roughness, l[2], n_dot_v, 1.0)
+                });
+                // Lookups past the last texel center clamp, roughness 1 reads 1 - 0.5 / 32
+                assert!((furnace - 1.0).abs() < 0.015, "roughness {} NdotV {}: {}", roughness, n_dot_v, furnace);
+                assert!(single <= furnace);
+                let dielectric = brdf::integrate_hemisphere(200, |l| {
+                    brdf::cook_torrance(roughness, 0.04, normal, l, v) +
+                    compensation(&table, roughness, l[2], n_dot_v, brdf::average_fresnel(0.04))
+                });
+                assert!(dielectric <= 1.0, "roughness {} NdotV {}: {}", roughness, n_dot_v, dielectric);
+            }
+        }
+    }
+
+    // Fdez-Aguera's compensation of the ambient term
+    #[test]
+    fn ambient_white_furnace() {
+        for &roughness in [0.4f32, 0.6, 0.8, 1.0].iter() {
+            for &n_dot_v in [0.2f32, 0.5, 0.9].iter() {
+                let env = brdf::split_sum(n_dot_v, roughness, 512);
+                assert!((brdf::environment_reflectance(env, 1.0, true) - 1.0).abs() < 1e-5);
+                assert!(brdf::environment_reflectance(env, 0.5, false) <= brdf::environment_reflectance(env, 0.5, true));
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+// Standard Library
+use std::cmp;
+use std::collections::HashMap;
+use std::path::Path;
+
+// External Library
+use glium;
+use image;
+use image::{GrayImage, RgbaImage};
+
+use texture::{load_luma, upload_rgba};
+
+
+// Gradient kernel used to differentiate the height field
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Filter {
+    Sobel,
+    Scharr,
+}
+
+impl Filter {
+    // Weights of the x derivative, the y derivative is the transpose.
+    // The second value normalizes the response to height units per texel.
+    fn kernel(&self) -> ([[f32; 3]; 3], f32) {
+        match *self {
+            Filter::Sobel => ([[-1.0, 0.0, 1.0], [-2.0, 0.0, 2.0], [-1.0, 0.0, 1.0]], 8.0),
+            Filter::Scharr => ([[-3.0, 0.0, 3.0], [-10.0, 0.0, 10.0], [-3.0, 0.0, 3.0]], 32.0),
+        }
+    }
+}
+
+// How texels outside of the image are addressed
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Edge {
+    Wrap,
+    Clamp,
+}
+
+// OpenGL normal maps store +Y in green, DirectX ones store -Y
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GreenChannel {
+    OpenGl,
+    DirectX,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct NormalMapper {
+    strength: f32,
+    filter: Filter,
+    edge: Edge,
+    green: GreenChannel,
+}
+
+impl NormalMapper {
+    pub fn new() -> NormalMapper {
+        NormalMapper {
+            strength: 1.0,
+            filter: Filter::Sobel,
+            edge: Edge::Wrap,
+            green: GreenChannel::OpenGl,
+        }
+    }
+
+    pub fn strength(mut self, strength: f32) -> NormalMapper {
+        self.strength = strength;
+        self
+    }
+    pub fn filter(mut self, filter: Filter) -> NormalMapper {
+        self.filter = filter;
+        self
+    }
+    pub fn edge(mut self, edge: Edge) -> NormalMapper {
+        self.edge = edge;
+        self
+    }
+    pub fn green(mut self, green: GreenChannel) -> NormalMapper {
+        self.green = green;
+        self
+    }
+
+    #[inline]
+    fn sample(&self, height: &GrayImage, x: i64, y: i64) -> f32 {
+        let (w, h) = height.dimensions();
+        let (w, h) = (w as i64, h as i64);
+        let (x, y) = match self.edge {
+            Edge::Wrap => (((x % w) + w) % w, ((y % h) + h) % h),
+            Edge::Clamp => (cmp::max(0, cmp::min(x, w - 1)), cmp::max(0, cmp::min(y, h - 1))),
+        };
+        height.get_pixel(x as u32, y as u32).data[0] as f32 / 255.0
+    }
+
+    // Tangent space normal at a texel, x points right and y up in texture space
+    pub fn normal_at(&self, height: &GrayImage, x: u32, y: u32) -> [f32; 3] {
+        let (kernel, norm) = self.filter.kernel();
+        let mut dx = 0.0;
+        let mut dy = 0.0;
+        for j in 0..3 {
+            for i in 0..3 {
+                let s = self.sample(height, x as i64 + i as i64 - 1, y as i64 + j as i64 - 1);
+                dx += kernel[j][i] * s;
+                dy += kernel[i][j] * s;
+            }
+        }
+        dx /= norm;
+        dy /= norm;
+
+        // Image rows grow downwards, so the texture space y gradient is -dy
+        let ny = match self.green {
+            GreenChannel::OpenGl => self.strength * dy,
+            GreenChannel::DirectX => -self.strength * dy,
+        };
+        let n = [-self.strength * dx, ny, 1.0];
+        let len = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+        [n[0] / len, n[1] / len, n[2] / len]
+    }
+
+    pub fn convert(&self, height: &GrayImage) -> RgbaImage {
+        let (w, h) = height.dimensions();
+        RgbaImage::from_fn(w, h, |x, y| {
+            let n = self.normal_at(height, x, y);
+            let encode = |v: f32| ((v * 0.5 + 0.5) * 255.0).round().max(0.0).min(255.0) as u8;
+            image::Rgba { data: [encode(n[0]), encode(n[1]), encode(n[2]), 255] }
+        })
+    }
+
+    // Converts the height image and stores the normal map in the texture map
+    // under `name`, so it can be looked up like a loaded *_Normals texture.
+    pub fn register(&self,
+                    display: &glium::Display,
+                    height: &GrayImage,
+                    name: &str,
+                    texture_map: &mut HashMap<String, glium::texture::Texture2d>) {
+        let normal = self.convert(height);
+        texture_map.insert(name.to_string(), upload_rgba(display, normal));
+    }
+
+    // Same as register but reads the height map (e.g. a map_Bump texture) from disk
+    pub fn register_file(&self,
+                         display: &glium::Display,
+                         path: &Path,
+                         name: &str,
+                         texture_map: &mut HashMap<String, glium::texture::Texture2d>)
+                         -> bool {
+        if let Some(height) = load_luma(path) {
+            self.register(display, &height, name, texture_map);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma};
+    use super::{Edge, Filter, GreenChannel, NormalMapper};
+
+    // 8x8 height field with height(x, y) in 1/255 steps
+    fn heights<F>(height: F) -> GrayImage
+        where F: Fn(u32, u32) -> u32
+    {
+        GrayImage::from_fn(8, 8, |x, y| Luma { data: [height(x, y) as u8] })
+    }
+
+    fn assert_close(a: [f32; 3], b: [f32; 3]) {
+        for i in 0..3 {
+            assert!((a[i] - b[i]).abs() < 1e-5, "{:?} instead of {:?}", a, b);
+        }
+    }
+
+    // Tangent of the angle between the normal and the z axis along x or y
+    fn slope(n: [f32; 3], axis: usize) -> f32 {
+        n[axis] / n[2]
+    }
+
+    #[test]
+    fn flat_height_points_up() {
+        let flat = heights(|_, _| 100);
+        for &filter in [Filter::Sobel, Filter::Scharr].iter() {
+            assert_close(NormalMapper::new().filter(filter).normal_at(&flat, 3, 5), [0.0, 0.0, 1.0]);
+        }
+        let map = NormalMapper::new().convert(&flat);
+        assert_eq!(map.get_pixel(0, 0).data, [128, 128, 255, 255]);
+    }
+
+    #[test]
+    fn rising_ramp_tilts_against_x() {
+        let n = NormalMapper::new().normal_at(&heights(|x, _| 10 * x), 4, 4);
+        assert!(n[0] < 0.0 && n[1].abs() < 1e-5, "{:?}", n);
+        assert!((slope(n, 0) + 10.0 / 255.0).abs() < 1e-5, "{:?}", n);
+    }
+
+    #[test]
+    fn directx_flips_green() {
+        let ramp = heights(|_, y| 10 * y);
+        let opengl = NormalMapper::new().green(GreenChannel::OpenGl).normal_at(&ramp, 4, 4);
+        let directx = NormalMapper::new().green(GreenChannel::DirectX).normal_at(&ramp, 4, 4);
+        assert!(opengl[1].abs() > 0.01);
+        assert_close(opengl, [directx[0], -directx[1], directx[2]]);
+    }
+
+    #[test]
+    fn sobel_and_scharr_agree() {
+        let ramp = heights(|x, y| 10 * x + 20 * y);
+        let sobel = NormalMapper::new().filter(Filter::Sobel).normal_at(&ramp, 3, 3);
+        let scharr = NormalMapper::new().filter(Filter::Scharr).normal_at(&ramp, 3, 3);
+        assert_close(sobel, scharr);
+    }
+
+    #[test]
+    fn wrap_and_clamp_differ_at_the_border() {
+        let ramp = heights(|x, _| 10 * x);
+        let wrap = NormalMapper::new().edge(Edge::Wrap);
+        let clamp = NormalMapper::new().edge(Edge::Clamp);
+        // Wrapping sees the top of the ramp left of the first column
+        assert!(wrap.normal_at(&ramp, 0, 4)[0] > 0.0);
+        assert!(clamp.normal_at(&ramp, 0, 4)[0] < 0.0);
+        assert_close(wrap.normal_at(&ramp, 4, 4), clamp.normal_at(&ramp, 4, 4));
+    }
+
+    #[test]
+    fn strength_scales_the_tilt() {
+        let ramp = heights(|x, y| 10 * x + 5 * y);
+        let weak = NormalMapper::new().normal_at(&ramp, 4, 4);
+        let strong = NormalMapper::new().strength(2.0).normal_at(&ramp, 4, 4);
+        for axis in 0..2 {
+            assert!((slope(strong, axis) - 2.0 * slope(weak, axis)).abs() < 1e-5, "{:?} {:?}", weak, strong);
+        }
+    }
+}
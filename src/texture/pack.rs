@@ -0,0 +1,331 @@
+// Standard Library
+use std::path::Path;
+
+// External Library
+use image;
+use image::{GrayImage, RgbaImage};
+
+use texture::load_luma;
+
+
+// The surface parameters that can be packed into a single texture.
+// Zero and One fill a channel with a constant.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Channel {
+    Roughness,
+    Metalness,
+    Occlusion,
+    Height,
+    Zero,
+    One,
+}
+
+impl Channel {
+    // Letters of the packed_layout key in .pbrmtl files
+    pub fn from_letter(letter: &str) -> Option<Channel> {
+        match letter {
+            "r" => Some(Channel::Roughness),
+            "m" => Some(Channel::Metalness),
+            "o" => Some(Channel::Occlusion),
+            "h" => Some(Channel::Height),
+            "0" => Some(Channel::Zero),
+            "1" => Some(Channel::One),
+            _ => None,
+        }
+    }
+
+    pub fn letter(&self) -> &'static str {
+        match *self {
+            Channel::Roughness => "r",
+            Channel::Metalness => "m",
+            Channel::Occlusion => "o",
+            Channel::Height => "h",
+            Channel::Zero => "0",
+            Channel::One => "1",
+        }
+    }
+
+    // Value used when no source image was supplied for this channel
+    pub fn default_value(&self) -> u8 {
+        match *self {
+            Channel::Roughness => 255,
+            Channel::Metalness => 0,
+            Channel::Occlusion => 255,
+            Channel::Height => 128,
+            Channel::Zero => 0,
+            Channel::One => 255,
+        }
+    }
+}
+
+// Declares which parameter lives in which channel of a packed texture.
+// The same layout is handed to the shader so it can pick the channels.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ChannelLayout {
+    pub r: Channel,
+    pub g: Channel,
+    pub b: Channel,
+    pub a: Channel,
+}
+
+impl ChannelLayout {
+    pub fn new(r: Channel, g: Channel, b: Channel, a: Channel) -> ChannelLayout {
+        ChannelLayout {
+            r: r,
+            g: g,
+            b: b,
+            a: a,
+        }
+    }
+
+    // Layout the Dagger gloss texture uses, roughness in r and two
+    // occlusion like terms in g and b that multiply to tex_gloss.g * tex_gloss.b.
+    pub fn legacy_gloss() -> ChannelLayout {
+        ChannelLayout::new(Channel::Roughness,
+                           Channel::Occlusion,
+                           Channel::Occlusion,
+                           Channel::One)
+    }
+
+    pub fn channels(&self) -> [Channel; 4] {
+        [self.r, self.g, self.b, self.a]
+    }
+
+    pub fn index_of(&self, channel: Channel) -> Option<usize> {
+        self.channels().iter().position(|c| *c == channel)
+    }
+
+    // Whether channel i holds a parameter that an earlier channel holds too
+    fn repeats(&self, i: usize) -> bool {
+        let c = self.channels()[i];
+        c != Channel::Zero && c != Channel::One && self.index_of(c) != Some(i)
+    }
+
+    // Selects every channel holding `channel`. The value of a parameter is
+    // the product of its channels, packed_value in the shader computes it
+    // from the mask. If the parameter is not packed the mask is zero.
+    pub fn mask(&self, channel: Channel) -> [f32; 4] {
+        let mut mask = [0.0; 4];
+        for (i, c) in self.channels().iter().enumerate() {
+            if *c == channel {
+                mask[i] = 1.0;
+            }
+        }
+        mask
+    }
+
+    // Value of a parameter in a texel as the shader reads it
+    pub fn value(&self, channel: Channel, texel: [f32; 4]) -> f32 {
+        let mask = self.mask(channel);
+        let mut value = self.fallback(channel);
+        for i in 0..4 {
+            if mask[i] != 0.0 {
+                value *= texel[i];
+            }
+        }
+        value
+    }
+
+    // Packs one texel, channels without a value get their default. A
+    // parameter stored twice goes into the first channel and 1 into the rest.
+    pub fn texel(&self, roughness: f32, metalness: f32, occlusion: f32) -> [u8; 4] {
+        let mut texel = [0u8; 4];
+        for (i, c) in self.channels().iter().enumerate() {
+            let value = match *c {
+                _ if self.repeats(i) => 1.0,
+                Channel::Roughness => roughness,
+                Channel::Metalness => metalness,
+                Channel::Occlusion => occlusion,
+                _ => c.default_value() as f32 / 255.0,
+            };
+            texel[i] = (value.max(0.0).min(1.0) * 255.0 + 0.5) as u8;
+        }
+        texel
+    }
+
+    // Factor the masked channels are multiplied with, lets the shader fall
+    // back to the default of a parameter missing from the layout.
+    pub fn fallback(&self, channel: Channel) -> f32 {
+        if let Some(_) = self.index_of(channel) {
+            1.0
+        } else {
+            channel.default_value() as f32 / 255.0
+        }
+    }
+}
+
+impl Default for ChannelLayout {
+    fn default() -> Self {
+        ChannelLayout::new(Channel::Roughness,
+                           Channel::Metalness,
+                           Channel::Occlusion,
+                           Channel::Height)
+    }
+}
+
+#[derive(Debug)]
+pub enum PackError {
+    NoInput,
+    SizeMismatch((u32, u32), (u32, u32)),
+    Load(String),
+    Save(String),
+}
+
+pub struct ChannelPacker {
+    layout: ChannelLayout,
+    roughness: Option<GrayImage>,
+    metalness: Option<GrayImage>,
+    occlusion: Option<GrayImage>,
+    height: Option<GrayImage>,
+}
+
+impl ChannelPacker {
+    pub fn new(layout: ChannelLayout) -> ChannelPacker {
+        ChannelPacker {
+            layout: layout,
+            roughness: None,
+            metalness: None,
+            occlusion: None,
+            height: None,
+        }
+    }
+
+    pub fn roughness(mut self, image: GrayImage) -> ChannelPacker {
+        self.roughness = Some(image);
+        self
+    }
+    pub fn metalness(mut self, image: GrayImage) -> ChannelPacker {
+        self.metalness = Some(image);
+        self
+    }
+    pub fn occlusion(mut self, image: GrayImage) -> ChannelPacker {
+        self.occlusion = Some(image);
+        self
+    }
+    pub fn height(mut self, image: GrayImage) -> ChannelPacker {
+        self.height = Some(image);
+        self
+    }
+
+    pub fn layout(&self) -> ChannelLayout {
+        self.layout
+    }
+
+    fn source(&self, channel: Channel) -> Option<&GrayImage> {
+        match channel {
+            Channel::Roughness => self.roughness.as_ref(),
+            Channel::Metalness => self.metalness.as_ref(),
+            Channel::Occlusion => self.occlusion.as_ref(),
+            Channel::Height => self.height.as_ref(),
+            Channel::Zero | Channel::One => None,
+        }
+    }
+
+    fn dimensions(&self) -> Result<(u32, u32), PackError> {
+        let mut dim = None;
+        for img in [&self.roughness, &self.metalness, &self.occlusion, &self.height].iter() {
+            if let Some(ref img) = **img {
+                match dim {
+                    None => dim = Some(img.dimensions()),
+                    Some(d) => {
+                        if d != img.dimensions() {
+                            return Err(PackError::SizeMismatch(d, img.dimensions()));
+                        }
+                    }
+                }
+            }
+        }
+        dim.ok_or(PackError::NoInput)
+    }
+
+    pub fn pack(&self) -> Result<RgbaImage, PackError> {
+        let (width, height) = try!(self.dimensions());
+        let channels = self.layout.channels();
+        let sources = [self.source(channels[0]),
+                       self.source(channels[1]),
+                       self.source(channels[2]),
+                       self.source(channels[3])];
+
+        Ok(RgbaImage::from_fn(width, height, |x, y| {
+            let mut texel = [0u8; 4];
+            for i in 0..4 {
+                texel[i] = match sources[i] {
+                    // Repeated parameters would be multiplied with themselves
+                    _ if self.layout.repeats(i) => 255,
+                    Some(img) => img.get_pixel(x, y).data[0],
+                    None => channels[i].default_value(),
+                };
+            }
+            image::Rgba { data: texel }
+        }))
+    }
+}
+
+// Loads the given grayscale images, packs them and writes the result to `out`.
+pub fn pack_files(layout: ChannelLayout,
+                  roughness: Option<&Path>,
+                  metalness: Option<&Path>,
+                  occlusion: Option<&Path>,
+                  height: Option<&Path>,
+                  out: &Path)
+                  -> Result<ChannelLayout, PackError> {
+    fn load(path: &Path) -> Result<GrayImage, PackError> {
+        load_luma(path).ok_or(PackError::Load(path.to_string_lossy().into_owned()))
+    }
+
+    let mut packer = ChannelPacker::new(layout);
+    if let Some(p) = roughness {
+        packer = packer.roughness(try!(load(p)));
+    }
+    if let Some(p) = metalness {
+        packer = packer.metalness(try!(load(p)));
+    }
+    if let Some(p) = occlusion {
+        packer = packer.occlusion(try!(load(p)));
+    }
+    if let Some(p) = height {
+        packer = packer.height(try!(load(p)));
+    }
+
+    let packed = try!(packer.pack());
+    try!(packed.save(out).map_err(|e| PackError::Save(format!("{}", e))));
+    Ok(layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Channel, ChannelLayout};
+
+    fn unpack(layout: &ChannelLayout, texel: [u8; 4]) -> [f32; 3] {
+        let t = [texel[0] as f32 / 255.0, texel[1] as f32 / 255.0, texel[2] as f32 / 255.0, texel[3] as f32 / 255.0];
+        [layout.value(Channel::Roughness, t), layout.value(Channel::Metalness, t), layout.value(Channel::Occlusion, t)]
+    }
+
+    #[test]
+    fn legacy_gloss_multiplies_green_and_blue() {
+        let layout = ChannelLayout::legacy_gloss();
+        assert_eq!(layout.mask(Channel::Occlusion), [0.0, 1.0, 1.0, 0.0]);
+        let occlusion = layout.value(Channel::Occlusion, [0.3, 0.5, 0.8, 1.0]);
+        assert!((occlusion - 0.4).abs() < 1e-6);
+        // Metalness is not packed and falls back to its default
+        assert_eq!(layout.value(Channel::Metalness, [0.3, 0.5, 0.8, 1.0]), 0.0);
+    }
+
+    #[test]
+    fn texels_read_back() {
+        let layouts = [ChannelLayout::default(),
+                       ChannelLayout::legacy_gloss(),
+                       ChannelLayout::new(Channel::Zero, Channel::Occlusion, Channel::Roughness, Channel::Zero)];
+        for layout in layouts.iter() {
+            let texel = layout.texel(0.2, 0.6, 0.4);
+            let values = unpack(layout, texel);
+            let metalness = if layout.index_of(Channel::Metalness).is_some() { 0.6 } else { 0.0 };
+            for (value, expected) in values.iter().zip([0.2, metalness, 0.4].iter()) {
+                assert!((value - expected).abs() < 1.0 / 255.0, "{:?}: {:?}", layout, values);
+            }
+        }
+        // Constants stay constants when repeated
+        let zeros = ChannelLayout::new(Channel::Roughness, Channel::Zero, Channel::Zero, Channel::One);
+        assert_eq!(zeros.texel(0.0, 0.0, 0.0), [0, 0, 0, 255]);
+    }
+}
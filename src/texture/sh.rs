@@ -0,0 +1,327 @@
+// Standard Library
+use std::f32::consts::PI;
+
+use texture::cubemap::{CubeMap, FACES};
+use texture::hdr::HdrImage;
+use texture::ibl;
+use util::graphics::SphericalHarmonics;
+
+// Real spherical harmonics up to band 2. Nine coefficients per channel are
+// enough to reproduce the irradiance of any environment within a few
+// percent (Ramamoorthi and Hanrahan, An Efficient Representation for
+// Irradiance Environment Maps), which makes them a cheap diffuse ambient
+// term. The order is l = 0, then m = -l..l for l = 1 and 2.
+
+pub const COEFFICIENTS: usize = 9;
+
+// Clamped cosine convolution per band divided by pi
+const BAND_FACTORS: [f32; 3] = [1.0, 2.0 / 3.0, 0.25];
+
+#[inline]
+fn band(i: usize) -> usize {
+    match i {
+        0 => 0,
+        1...3 => 1,
+        _ => 2,
+    }
+}
+
+// The basis functions at a normalized direction, same as sh_irradiance in ibl.glsl
+pub fn basis(d: [f32; 3]) -> [f32; COEFFICIENTS] {
+    let (x, y, z) = (d[0], d[1], d[2]);
+    [0.282095,
+     0.488603 * y,
+     0.488603 * z,
+     0.488603 * x,
+     1.092548 * x * y,
+     1.092548 * y * z,
+     0.315392 * (3.0 * z * z - 1.0),
+     1.092548 * x * z,
+     0.546274 * (x * x - y * y)]
+}
+
+// Column major like the matrices handed to glium
+#[inline]
+fn transform(m: [[f32; 3]; 3], d: [f32; 3]) -> [f32; 3] {
+    [m[0][0] * d[0] + m[1][0] * d[1] + m[2][0] * d[2],
+     m[0][1] * d[0] + m[1][1] * d[1] + m[2][1] * d[2],
+     m[0][2] * d[0] + m[1][2] * d[1] + m[2][2] * d[2]]
+}
+
+#[inline]
+fn transposed(m: [[f32; 3]; 3], d: [f32; 3]) -> [f32; 3] {
+    [m[0][0] * d[0] + m[0][1] * d[1] + m[0][2] * d[2],
+     m[1][0] * d[0] + m[1][1] * d[1] + m[1][2] * d[2],
+     m[2][0] * d[0] + m[2][1] * d[1] + m[2][2] * d[2]]
+}
+
+// Solves a * x = b by Gaussian elimination with partial pivoting
+fn solve5(mut a: [[f64; 5]; 5], mut b: [f64; 5]) -> [f64; 5] {
+    for col in 0..5 {
+        let mut pivot = col;
+        for row in col + 1..5 {
+            if a[row][col].abs() > a[pivot][col].abs() {
+                pivot = row;
+            }
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col + 1..5 {
+            let f = a[row][col] / a[col][col];
+            for k in col..5 {
+                a[row][k] -= f * a[col][k];
+            }
+            b[row] -= f * b[col];
+        }
+    }
+    let mut x = [0.0; 5];
+    for col in (0..5).rev() {
+        let mut sum = b[col];
+        for k in col + 1..5 {
+            sum -= a[col][k] * x[k];
+        }
+        x[col] = sum / a[col][col];
+    }
+    x
+}
+
+// Directions band 2 is rotated through, any five that tell its basis
+// functions apart would do
+const ROTATION_DIRECTIONS: [[f32; 3]; 5] = [[1.0, 0.0, 0.0],
+                                            [0.0, 0.0, 1.0],
+                                            [0.70710678, 0.70710678, 0.0],
+                                            [0.70710678, 0.0, 0.70710678],
+                                            [0.0, 0.70710678, 0.70710678]];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ShL2 {
+    pub coefficients: [[f32; 3]; COEFFICIENTS],
+}
+
+impl ShL2 {
+    pub fn zero() -> ShL2 {
+        ShL2 { coefficients: [[0.0; 3]; COEFFICIENTS] }
+    }
+
+    // Projects (direction, solid angle, color) samples covering the sphere.
+    // The solid angles are rescaled to add up to 4 pi, so a constant
+    // environment stays constant however coarse the sampling.
+    fn project<I>(samples: I) -> ShL2
+        where I: Iterator<Item = ([f32; 3], f32, [f32; 3])>
+    {
+        let mut sum = [[0.0f64; 3]; COEFFICIENTS];
+        let mut total = 0.0f64;
+        for (d, solid_angle, color) in samples {
+            let y = basis(d);
+            for i in 0..COEFFICIENTS {
+                for c in 0..3 {
+                    sum[i][c] += (y[i] * solid_angle * color[c]) as f64;
+                }
+            }
+            total += solid_angle as f64;
+        }
+        let scale = if total > 0.0 { 4.0 * PI as f64 / total } else { 0.0 };
+        let mut sh = ShL2::zero();
+        for i in 0..COEFFICIENTS {
+            for c in 0..3 {
+                sh.coefficients[i][c] = (sum[i][c] * scale) as f32;
+            }
+        }
+        sh
+    }
+
+    // Projection of an equirectangular environment as used by texture::ibl
+    pub fn from_equirect(image: &HdrImage) -> ShL2 {
+        let d_phi = 2.0 * PI / image.width as f32;
+        let d_theta = PI / image.height as f32;
+        let texels = (0..image.height).flat_map(|y| (0..image.width).map(move |x| (x, y)));
+        ShL2::project(texels.map(|(x, y)| {
+            let solid_angle = d_phi * d_theta * ((y as f32 + 0.5) * d_theta).sin();
+            (ibl::texel_direction(image, x, y), solid_angle, image.get(x, y))
+        }))
+    }
+
+    pub fn from_cubemap(cube: &CubeMap) -> ShL2 {
+        let size = cube.size();
+        let texels = (0..FACES).flat_map(|f| (0..size * size).map(move |i| (f, i % size, i / size)));
+        ShL2::project(texels.map(|(f, x, y)| {
+            (cube.texel_direction(f, x, y), cube.texel_solid_angle(x, y), cube.face(f).get(x, y))
+        }))
+    }
+
+    // The projected function in a normalized direction
+    pub fn evaluate(&self, d: [f32; 3]) -> [f32; 3] {
+        let y = basis(d);
+        let mut color = [0.0; 3];
+        for i in 0..COEFFICIENTS {
+            for c in 0..3 {
+                color[c] += self.coefficients[i][c] * y[i];
+            }
+        }
+        color
+    }
+
+    // Convolution with the clamped cosine divided by pi. Evaluating the
+    // result gives the light a white Lambertian surface reflects, the same
+    // quantity as the irradiance map of texture::ibl.
+    pub fn convolve_cosine(&self) -> ShL2 {
+        let mut sh = *self;
+        for i in 0..COEFFICIENTS {
+            for c in 0..3 {
+                sh.coefficients[i][c] *= BAND_FACTORS[band(i)];
+            }
+        }
+        sh
+    }
+
+    // Irradiance at a surface facing `normal`
+    pub fn irradiance(&self, normal: [f32; 3]) -> [f32; 3] {
+        let e = self.convolve_cosine().evaluate(normal);
+        [e[0] * PI, e[1] * PI, e[2] * PI]
+    }
+
+    // The harmonics of the environment turned by the rotation `m`, so that
+    // rotate(m).evaluate(m * d) == evaluate(d)
+    pub fn rotate(&self, m: [[f32; 3]; 3]) -> ShL2 {
+        let mut sh = *self;
+        // Band 1 is a vector of the x, y and z coefficients
+        let c = &self.coefficients;
+        for ch in 0..3 {
+            let v = transform(m, [c[3][ch], c[1][ch], c[2][ch]]);
+            sh.coefficients[3][ch] = v[0];
+            sh.coefficients[1][ch] = v[1];
+            sh.coefficients[2][ch] = v[2];
+        }
+        // Band 2 has to reproduce the rotated function in five directions
+        let mut a = [[0.0f64; 5]; 5];
+        let mut b = [[0.0f64; 5]; 3];
+        for (k, &d) in ROTATION_DIRECTIONS.iter().enumerate() {
+            let y = basis(d);
+            let rotated = basis(transposed(m, d));
+            for j in 0..5 {
+                a[k][j] = y[4 + j] as f64;
+            }
+            for ch in 0..3 {
+                for j in 0..5 {
+                    b[ch][k] += (c[4 + j][ch] * rotated[4 + j]) as f64;
+                }
+            }
+        }
+        for ch in 0..3 {
+            let x = solve5(a, b[ch]);
+            for j in 0..5 {
+                sh.coefficients[4 + j][ch] = x[j] as f32;
+            }
+        }
+        sh
+    }
+
+    // Cosine convolved coefficients as read by sh_irradiance in ibl.glsl
+    pub fn to_uniform(&self) -> SphericalHarmonics {
+        let convolved = self.convolve_cosine();
+        let mut uniform = SphericalHarmonics { sh_coefficients: [[0.0; 4]; COEFFICIENTS] };
+        for (u, c) in uniform.sh_coefficients.iter_mut().zip(convolved.coefficients.iter()) {
+            *u = [c[0], c[1], c[2], 0.0];
+        }
+        uniform
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use texture::cubemap::CubeMap;
+    use texture::hdr::HdrImage;
+    use texture::ibl;
+    use super::{transform, ShL2, COEFFICIENTS};
+
+    fn assert_close(a: [f32; 3], b: [f32; 3], tolerance: f32) {
+        for i in 0..3 {
+            assert!((a[i] - b[i]).abs() <= tolerance, "{:?} instead of {:?}", a, b);
+        }
+    }
+
+    fn directions() -> Vec<[f32; 3]> {
+        let mut directions = Vec::new();
+        for i in 0..5 {
+            for j in 0..8 {
+                let cos_theta = -0.9 + 0.45 * i as f32;
+                let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                let phi = 0.3 + j as f32 * PI / 4.0;
+                directions.push([sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin()]);
+            }
+        }
+        directions
+    }
+
+    // Smooth environment with all three bands
+    fn environment(d: [f32; 3]) -> [f32; 3] {
+        [1.0 + d[1], 0.5 + 0.5 * d[0], d[2] * d[2] + 0.5 * d[0] * d[1]]
+    }
+
+    #[test]
+    fn constant_environment_has_constant_irradiance() {
+        let color = [0.25, 1.0, 4.0];
+        let sh = ShL2::from_equirect(&HdrImage::from_fn(128, 64, |_, _| color));
+        // The midpoint rule leaves a little in the zonal band 2 coefficient
+        for i in 1..COEFFICIENTS {
+            assert_close(sh.coefficients[i], [0.0; 3], 4e-3);
+        }
+        for &d in directions().iter() {
+            assert_close(sh.evaluate(d), color, 4e-3);
+            assert_close(sh.irradiance(d), [PI * color[0], PI * color[1], PI * color[2]], 4e-3);
+        }
+    }
+
+    #[test]
+    fn rotation_turns_the_function() {
+        let mut sh = ShL2::zero();
+        for i in 0..COEFFICIENTS {
+            sh.coefficients[i] = [0.1 * i as f32 + 0.2, 0.5 - 0.15 * i as f32, ((i * 7) % 5) as f32 - 2.0];
+        }
+        // 40 degrees around x followed by 70 degrees around y, column major
+        let (sx, cx) = 0.7f32.sin_cos();
+        let (sy, cy) = 1.2f32.sin_cos();
+        let rx = [[1.0, 0.0, 0.0], [0.0, cx, sx], [0.0, -sx, cx]];
+        let ry = [[cy, 0.0, -sy], [0.0, 1.0, 0.0], [sy, 0.0, cy]];
+        let m = [transform(ry, rx[0]), transform(ry, rx[1]), transform(ry, rx[2])];
+        let rotated = sh.rotate(m);
+        for &d in directions().iter() {
+            assert_close(rotated.evaluate(transform(m, d)), sh.evaluate(d), 1e-4);
+        }
+    }
+
+    #[test]
+    fn equirect_and_cubemap_agree() {
+        let size = HdrImage::new(128, 64);
+        let image = HdrImage::from_fn(128, 64, |x, y| environment(ibl::texel_direction(&size, x, y)));
+        let from_equirect = ShL2::from_equirect(&image);
+        let from_cubemap = ShL2::from_cubemap(&CubeMap::from_equirect(&image, 32));
+        for i in 0..COEFFICIENTS {
+            assert_close(from_cubemap.coefficients[i], from_equirect.coefficients[i], 0.01);
+        }
+        // Both reproduce the environment, which has no bands above 2
+        for &d in directions().iter() {
+            assert_close(from_equirect.evaluate(d), environment(d), 0.01);
+        }
+    }
+
+    #[test]
+    fn irradiance_scales_the_bands() {
+        // The clamped cosine scales band 0, 1 and 2 by pi, 2 pi / 3 and pi / 4
+        let f = |d: [f32; 3]| 1.0 + 2.0 * d[1] + 3.0 * d[0] * d[2] - 0.5 * (3.0 * d[2] * d[2] - 1.0);
+        let convolved = |d: [f32; 3]| {
+            PI * (1.0 + 2.0 / 3.0 * 2.0 * d[1] + 0.25 * (3.0 * d[0] * d[2] - 0.5 * (3.0 * d[2] * d[2] - 1.0)))
+        };
+        let size = HdrImage::new(128, 64);
+        let sh = ShL2::from_equirect(&HdrImage::from_fn(128, 64, |x, y| [f(ibl::texel_direction(&size, x, y)); 3]));
+        for &d in directions().iter() {
+            let e = convolved(d);
+            assert_close(sh.irradiance(d), [e, e, e], 3e-2);
+        }
+        // f averages to 1, the uniform holds the convolved band 0 coefficient
+        let uniform = sh.to_uniform();
+        assert!((uniform.sh_coefficients[0][1] * 0.282095 - 1.0).abs() < 1e-2);
+    }
+}
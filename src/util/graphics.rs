@@ -8,6 +8,11 @@ use cgmath;
 use glium;
 
 use util::math;
+use texture::pack::ChannelLayout;
+use texture::metallic::{MetallicRoughness, roughness_from_exponent};
+use texture::ibl::Environment;
+use texture::multiscatter::EnergyCompensation;
+use shadow::ShadowMaps;
 
 
 pub enum TexturePBR<'a> {
@@ -15,11 +20,20 @@ pub enum TexturePBR<'a> {
                               &'a glium::texture::Texture2d,
                               &'a glium::texture::Texture2d,
                               &'a glium::texture::Texture2d),
+    // Albedo, normal and a packed texture whose channels are described by the layout
+    AlbedoNormalPacked(&'a glium::texture::Texture2d,
+                       &'a glium::texture::Texture2d,
+                       &'a glium::texture::Texture2d,
+                       ChannelLayout),
+    // Metallic/roughness model, every slot is a map, a factor or both
+    MetallicRoughness(MetallicRoughness<'a>),
 }
 impl<'a> TexturePBR<'a> {
     fn get_texture_adress(&self) -> &glium::texture::Texture2d {
         match *self {
             TexturePBR::AlbedoSpecularNormalGloss(ref a, _, _, _) => a,
+            TexturePBR::AlbedoNormalPacked(ref a, _, _, _) => a,
+            TexturePBR::MetallicRoughness(ref mr) => mr.first_texture(),
         }
     }
 }
@@ -83,13 +97,249 @@ impl Vertex {
 }
 implement_vertex!(Vertex, position, normal, texture);
 
+// Vertex with a per vertex color and a second uv set. texcoord1 holds
+// detail or lightmap coordinates, meshes without colors use white.
 #[derive(Copy, Clone)]
+pub struct VertexExt {
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    pub texture: [f32; 2],
+    pub texcoord1: [f32; 2],
+    pub color: [f32; 4],
+}
+
+impl VertexExt {
+    pub fn new(position: [f32; 3],
+               normal: [f32; 3],
+               texture: [f32; 2],
+               texcoord1: [f32; 2],
+               color: [f32; 4])
+               -> VertexExt {
+        VertexExt {
+            position: position,
+            normal: normal,
+            texture: texture,
+            texcoord1: texcoord1,
+            color: color,
+        }
+    }
+
+    // Copies the first uv set into the second one
+    pub fn from_vertex(v: &Vertex, color: [f32; 4]) -> VertexExt {
+        VertexExt::new(v.position, v.normal, v.texture, v.texture, color)
+    }
+
+    pub fn to_vertex(&self) -> Vertex {
+        Vertex::new(self.position, self.normal, self.texture)
+    }
+
+    pub fn attributes(&self) -> VertexAttributes {
+        VertexAttributes::new(self.texcoord1, self.color)
+    }
+
+    // Splits into the two buffers an Asset draws from
+    pub fn split(vertices: &[VertexExt]) -> (Vec<Vertex>, Vec<VertexAttributes>) {
+        (vertices.iter().map(|v| v.to_vertex()).collect(),
+         vertices.iter().map(|v| v.attributes()).collect())
+    }
+}
+implement_vertex!(VertexExt, position, normal, texture, texcoord1, color);
+
+// Second vertex stream holding the optional attributes, drawn together with
+// a Vertex buffer so morphing and skinning keep working on the base layout.
+#[derive(Copy, Clone)]
+pub struct VertexAttributes {
+    pub texcoord1: [f32; 2],
+    pub color: [f32; 4],
+}
+
+impl VertexAttributes {
+    pub fn new(texcoord1: [f32; 2], color: [f32; 4]) -> VertexAttributes {
+        VertexAttributes {
+            texcoord1: texcoord1,
+            color: color,
+        }
+    }
+}
+implement_vertex!(VertexAttributes, texcoord1, color);
+
+// Vertex of a skinned mesh, up to four joints influence each vertex.
+// The weights are expected to sum up to one.
+#[derive(Copy, Clone)]
+pub struct SkinnedVertex {
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    pub texture: [f32; 2],
+    pub joints: [u32; 4],
+    pub weights: [f32; 4],
+}
+
+impl SkinnedVertex {
+    pub fn new(position: [f32; 3],
+               normal: [f32; 3],
+               texture: [f32; 2],
+               joints: [u32; 4],
+               weights: [f32; 4])
+               -> SkinnedVertex {
+        SkinnedVertex {
+            position: position,
+            normal: normal,
+            texture: texture,
+            joints: joints,
+            weights: weights,
+        }
+    }
+
+    // Vertex fully bound to a single joint
+    pub fn from_vertex(v: &Vertex, joint: u32) -> SkinnedVertex {
+        SkinnedVertex::new(v.position, v.normal, v.texture, [joint, 0, 0, 0], [1.0, 0.0, 0.0, 0.0])
+    }
+}
+implement_vertex!(SkinnedVertex, position, normal, texture, joints, weights);
+
+// Has to match the array size in CT_VERT_PERMUTED
+pub const MAX_JOINTS: usize = 64;
+
+#[derive(Copy, Clone)]
+pub struct JointMatrices {
+    pub joint_matrices: [[[f32; 4]; 4]; MAX_JOINTS],
+}
+implement_uniform_block!(JointMatrices, joint_matrices);
+
+impl JointMatrices {
+    pub fn new(matrices: &[cgmath::Matrix4<f32>]) -> JointMatrices {
+        assert!(matrices.len() <= MAX_JOINTS, "Skeleton exceeds MAX_JOINTS");
+        let identity = [[1.0, 0.0, 0.0, 0.0],
+                        [0.0, 1.0, 0.0, 0.0],
+                        [0.0, 0.0, 1.0, 0.0],
+                        [0.0, 0.0, 0.0, 1.0]];
+        let mut m = [identity; MAX_JOINTS];
+        for (i, matrix) in matrices.iter().enumerate() {
+            m[i] = math::to_mat4(matrix);
+        }
+        JointMatrices { joint_matrices: m }
+    }
+}
+
+// Cosine convolved L2 spherical harmonics, see texture::sh::ShL2::to_uniform.
+// vec3 arrays are padded to vec4 in std140 blocks.
+#[derive(Copy, Clone)]
+pub struct SphericalHarmonics {
+    pub sh_coefficients: [[f32; 4]; 9],
+}
+implement_uniform_block!(SphericalHarmonics, sh_coefficients);
+
+// Has to match the array size in the shadow.glsl snippet
+pub const MAX_CASCADES: usize = 4;
+
+// Light matrices of the cascades of the directional light, see shadow::cascades
+#[derive(Copy, Clone)]
+pub struct CascadeBlock {
+    pub cascade_matrices: [[[f32; 4]; 4]; MAX_CASCADES],
+    pub cascade_count: i32,
+}
+implement_uniform_block!(CascadeBlock, cascade_matrices, cascade_count);
+
+// File names of the maps used by a material, resolved against the texture map
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MaterialTextures {
+    pub base_color: Option<String>,
+    pub metallic: Option<String>,
+    pub roughness: Option<String>,
+    pub normal: Option<String>,
+    pub occlusion: Option<String>,
+    pub emissive: Option<String>,
+    // Specular color and gloss of the spec/gloss workflow, drawn with
+    // TexturePBR::AlbedoSpecularNormalGloss when base color and normal maps are set
+    pub specular: Option<String>,
+    pub gloss: Option<String>,
+    // Roughness, metalness and occlusion in one texture, drawn with
+    // TexturePBR::AlbedoNormalPacked when base color and normal maps are set
+    pub packed: Option<String>,
+    pub packed_layout: ChannelLayout,
+}
+
+// Optional lobes on top of the base GGX lobe, all of them are off at 0.
+// The CPU versions are in shader::brdf.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Lobes {
+    // Strength of a dielectric coat with f0 = 0.04, as on car paint
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    // Retroreflective fabric sheen
+    pub sheen_color: [f32; 3],
+    pub sheen_roughness: f32,
+    // -1 to 1, stretches the highlight along the tangent for positive values
+    pub anisotropy: f32,
+    // Rotation of the tangent around the normal in radians
+    pub anisotropy_rotation: f32,
+}
+
+impl Default for Lobes {
+    fn default() -> Lobes {
+        Lobes {
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.03,
+            sheen_color: [0.0; 3],
+            sheen_roughness: 0.3,
+            anisotropy: 0.0,
+            anisotropy_rotation: 0.0,
+        }
+    }
+}
+
+// How the alpha of the base color is used. Masked fragments below
+// Material::alpha_cutoff are discarded, blended groups are drawn after all
+// opaque ones.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AlphaMode {
+    Opaque,
+    Mask,
+    Blend,
+}
+
+// ka/kd/ks are the legacy Phong terms still read by CT_FRAG, everything
+// else describes the metallic/roughness model. Stored as .pbrmtl files,
+// see assets::material.
+#[derive(Clone, Debug, PartialEq)]
 pub struct Material {
+    pub name: String,
     pub ka: [f32; 3],
     pub kd: [f32; 3],
     pub ks: [f32; 3],
+    pub base_color: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+    pub ior: f32,
+    pub emissive: [f32; 3],
+    pub alpha: f32,
+    pub alpha_mode: AlphaMode,
+    // Only used by AlphaMode::Mask
+    pub alpha_cutoff: f32,
+    pub lobes: Lobes,
+    pub textures: MaterialTextures,
+}
+
+impl Default for Material {
+    fn default() -> Material {
+        Material {
+            name: String::new(),
+            ka: [0.0; 3],
+            kd: [1.0; 3],
+            ks: [0.0; 3],
+            base_color: [1.0; 3],
+            metallic: 0.0,
+            roughness: 0.5,
+            ior: 1.5,
+            emissive: [0.0; 3],
+            alpha: 1.0,
+            alpha_mode: AlphaMode::Opaque,
+            alpha_cutoff: 0.5,
+            lobes: Lobes::default(),
+            textures: MaterialTextures::default(),
+        }
+    }
 }
-implement_uniform_block!(Material, ka, kd, ks);
 
 impl Material {
     pub fn new(ka: [f32; 3], kd: [f32; 3], ks: [f32; 3]) -> Material {
@@ -97,17 +347,46 @@ impl Material {
             ka: ka,
             kd: kd,
             ks: ks,
+            base_color: kd,
+            ..Default::default()
         }
     }
     pub fn from(material: &obj::Material) -> Material {
-
+        let kd = material.kd.unwrap_or([0.0; 3]);
         Material {
+            name: material.name.clone(),
             ka: material.ka.unwrap_or([0.0; 3]),
-            kd: material.kd.unwrap_or([0.0; 3]),
+            kd: kd,
             ks: material.ks.unwrap_or([0.0; 3]),
+            base_color: kd,
+            roughness: material.ns.map(roughness_from_exponent).unwrap_or(0.5),
+            ior: material.ni.unwrap_or(1.5),
+            emissive: material.ke.unwrap_or([0.0; 3]),
+            alpha: material.d.unwrap_or(1.0),
+            alpha_mode: if material.d.unwrap_or(1.0) < 1.0 {
+                AlphaMode::Blend
+            } else {
+                AlphaMode::Opaque
+            },
+            textures: MaterialTextures {
+                base_color: material.map_kd.clone(),
+                normal: material.map_bump.clone(),
+                occlusion: material.map_ka.clone(),
+                emissive: material.map_ke.clone(),
+                specular: material.map_ks.clone(),
+                gloss: material.map_ns.clone(),
+                ..Default::default()
+            },
+            ..Default::default()
         }
     }
 
+    // Reflectance at normal incidence for a dielectric with this ior
+    pub fn f0(&self) -> f32 {
+        let r = (self.ior - 1.0) / (self.ior + 1.0);
+        r * r
+    }
+
     pub fn gen_random() -> Material {
         use rand::distributions::{IndependentSample, Range};
         let range = rand::distributions::Range::new(0.0, 1.0);
@@ -117,38 +396,95 @@ impl Material {
             ka: [range.ind_sample(&mut rng), range.ind_sample(&mut rng), range.ind_sample(&mut rng)],
             kd: [range.ind_sample(&mut rng), range.ind_sample(&mut rng), range.ind_sample(&mut rng)],
             ks: [range.ind_sample(&mut rng), range.ind_sample(&mut rng), range.ind_sample(&mut rng)],
+            base_color: [range.ind_sample(&mut rng), range.ind_sample(&mut rng), range.ind_sample(&mut rng)],
+            metallic: range.ind_sample(&mut rng),
+            roughness: range.ind_sample(&mut rng),
+            ..Default::default()
         }
     }
 }
 
 
 #[derive(Copy, Clone)]
-pub struct BaseUniform {
+pub struct BaseUniform<'a> {
     pub model: [[f32; 4]; 4],
     pub modelview: [[f32; 4]; 4],
     pub modelviewperspective: [[f32; 4]; 4],
     pub normalmatrix: [[f32; 3]; 3],
-    pub lights: Lights,
-    pub ior: f32,
+    pub lights: LightBlock,
+    // Index of the block in Lights::blocks, passes after the first are
+    // added on top and skip the emissive term
+    pub light_pass: usize,
+    // Overrides the reflectance of every material, None uses Material::f0
+    pub ior: Option<f32>,
+    // Ambient lighting, without an environment the ambient term is black
+    pub environment: Option<&'a Environment>,
+    // Rotates view space normals into the world space of the environment
+    pub view_to_world: [[f32; 3]; 3],
+    // Multiple scattering table, without one rough metals lose energy
+    pub energy: Option<&'a EnergyCompensation>,
+    // Directional light in view space pointing towards it, black without one
+    pub sun_direction: [f32; 3],
+    pub sun_color: [f32; 3],
+    pub shadows: Option<&'a ShadowMaps>,
 }
 
-impl BaseUniform {
+impl<'a> BaseUniform<'a> {
     pub fn new(m: &cgmath::Matrix4<f32>,
                mv: &cgmath::Matrix4<f32>,
                mvp: &cgmath::Matrix4<f32>,
                nm: [[f32; 3]; 3],
-               lights: Lights,
-               ior: f32)
-               -> BaseUniform {
+               lights: LightBlock,
+               light_pass: usize,
+               ior: Option<f32>)
+               -> BaseUniform<'a> {
         BaseUniform {
             model: math::to_mat4(m),
             modelview: math::to_mat4(mv),
             modelviewperspective: math::to_mat4(mvp),
             normalmatrix: nm,
             lights: lights,
+            light_pass: light_pass,
             ior: ior,
+            environment: None,
+            view_to_world: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            energy: None,
+            sun_direction: [0.0, 0.0, 1.0],
+            sun_color: [0.0; 3],
+            shadows: None,
         }
     }
+
+    pub fn with_environment(mut self,
+                            environment: &'a Environment,
+                            view: &cgmath::Matrix4<f32>)
+                            -> BaseUniform<'a> {
+        self.environment = Some(environment);
+        self.view_to_world = math::view_to_world(view);
+        self
+    }
+
+    pub fn with_energy_compensation(mut self, energy: &'a EnergyCompensation) -> BaseUniform<'a> {
+        self.energy = Some(energy);
+        self
+    }
+
+    // The sun is lit in the first pass only
+    pub fn with_sun(mut self, sun: &DirectionalLight, view: &cgmath::Matrix4<f32>) -> BaseUniform<'a> {
+        let d = sun.direction();
+        let v = math::from_mat4(view);
+        let towards = [-(v[0][0] * d[0] + v[1][0] * d[1] + v[2][0] * d[2]),
+                       -(v[0][1] * d[0] + v[1][1] * d[1] + v[2][1] * d[2]),
+                       -(v[0][2] * d[0] + v[1][2] * d[1] + v[2][2] * d[2])];
+        self.sun_direction = math::normalize(towards);
+        self.sun_color = sun.color();
+        self
+    }
+
+    pub fn with_shadows(mut self, shadows: &'a ShadowMaps) -> BaseUniform<'a> {
+        self.shadows = Some(shadows);
+        self
+    }
 }
 
 #[derive(Copy,Clone)]
@@ -156,6 +492,7 @@ pub struct DirectionalLight {
     pub position: [f32; 3],
     direction: [f32; 3],
     color: [f32; 3],
+    cast_shadows: bool,
 }
 implement_uniform_block!(DirectionalLight, position, direction, color);
 
@@ -165,6 +502,7 @@ impl DirectionalLight {
             position: position,
             direction: direction,
             color: color,
+            cast_shadows: false,
         }
     }
     pub fn set_position(&mut self, p: [f32; 3]) {
@@ -175,21 +513,41 @@ impl DirectionalLight {
         self.direction = d;
     }
 
-    pub fn set_color(&mut self, p: [f32; 3]) {
-        self.position = p;
+    pub fn set_color(&mut self, c: [f32; 3]) {
+        self.color = c;
+    }
+
+    // Direction the light travels in
+    pub fn direction(&self) -> [f32; 3] {
+        self.direction
+    }
+
+    pub fn color(&self) -> [f32; 3] {
+        self.color
+    }
+
+    // Cascaded shadow maps, see shadow::ShadowMaps
+    pub fn set_cast_shadows(&mut self, cast: bool) {
+        self.cast_shadows = cast;
+    }
+
+    pub fn casts_shadows(&self) -> bool {
+        self.cast_shadows
     }
 }
 
 #[derive(Copy, Clone)]
 pub struct PointLight {
     pub pos: [f32; 3],
-    _pad: f32,
+    // Negative for lights without shadows, Lights::blocks replaces it with
+    // the first of the six layers of the light in shadow::ShadowMaps
+    shadow: f32,
     pub col: [f32; 3],
     _pad1: f32,
     pub attn: [f32; 3],
     _pad2: f32,
 }
-implement_uniform_block!(PointLight, pos, col, attn);
+implement_uniform_block!(PointLight, pos, shadow, col, attn);
 
 impl PointLight {
     pub fn new(position: [f32; 3],
@@ -200,7 +558,7 @@ impl PointLight {
                -> PointLight {
         PointLight {
             pos: position,
-            _pad: 0.0,
+            shadow: -1.0,
             col: color,
             _pad1: 0.0,
             attn: [constant, linear, quadratic],
@@ -217,47 +575,157 @@ impl PointLight {
     pub fn set_attenuation(&mut self, constant: f32, linear: f32, quadratic: f32) {
         self.attn = [constant, linear, quadratic];
     }
+
+    // Only the first MAX_SHADOW_CUBES lights casting shadows get a shadow map
+    pub fn set_cast_shadows(&mut self, cast: bool) {
+        self.shadow = if cast { 0.0 } else { -1.0 };
+    }
+
+    pub fn casts_shadows(&self) -> bool {
+        self.shadow >= 0.0
+    }
+
+    // First layer of the light's cube in the point shadow maps
+    pub fn shadow_layer(&self) -> Option<usize> {
+        if self.shadow >= 0.0 {
+            Some(self.shadow as usize)
+        } else {
+            None
+        }
+    }
 }
 
+// Has to match the array size in the lighting.glsl snippet. Scenes with more
+// lights are drawn in several additive passes, see Lights::blocks.
+pub const MAX_LIGHTS: usize = 64;
+
+// Point lights with a shadow cube, the rest casts no shadows
+pub const MAX_SHADOW_CUBES: usize = 4;
+
+// Point lights of one pass as uploaded to the shader
 #[derive(Copy, Clone)]
-pub struct Lights {
-    pub lights: [PointLight; 5],
+pub struct LightBlock {
+    pub lights: [PointLight; MAX_LIGHTS],
+    pub light_count: i32,
 }
 
-implement_uniform_block!(Lights, lights);
+implement_uniform_block!(LightBlock, lights, light_count);
+
+// Handle returned by Lights::add, stays valid when other lights are removed
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LightId(u32);
+
+// Any number of point lights, uploaded in blocks of MAX_LIGHTS
+#[derive(Clone, Default)]
+pub struct Lights {
+    lights: Vec<(LightId, PointLight)>,
+    next_id: u32,
+}
 
 impl Lights {
-    pub fn gen_random() -> Lights {
-        use std::mem;
+    pub fn new() -> Lights {
+        Lights::default()
+    }
+
+    // The three white lights the viewer starts with
+    pub fn three_point() -> Lights {
+        let mut lights = Lights::new();
+        // The key light casts shadows
+        let mut key = PointLight::new([4.0, 3.0, -4.0], [1.0, 1.0, 1.0], 1.0, 0.045, 0.0075);
+        key.set_cast_shadows(true);
+        lights.add(key);
+        lights.add(PointLight::new([0.0, 3.0, 4.0], [1.0, 1.0, 1.0], 1.0, 0.045, 0.0075));
+        lights.add(PointLight::new([-4.0, 3.0, -4.0], [1.0, 1.0, 1.0], 1.0, 0.045, 0.0075));
+        lights
+    }
+
+    pub fn gen_random(count: usize) -> Lights {
         use rand::distributions::{Range, IndependentSample};
         let position_range = Range::new(-10.0, 10.0);
         let range = Range::new(0.0, 1.0);
         let mut rng = rand::thread_rng();
 
-        let mut l: [PointLight; 5] = unsafe { mem::uninitialized() };
-        for i in 0..5 {
-            l[i] = PointLight::new([position_range.ind_sample(&mut rng),
-                                    position_range.ind_sample(&mut rng),
-                                    position_range.ind_sample(&mut rng)],
-                                   [range.ind_sample(&mut rng),
-                                    range.ind_sample(&mut rng),
-                                    range.ind_sample(&mut rng)],
-                                   1.0,
-                                   0.045,
-                                   0.0075)
+        let mut lights = Lights::new();
+        for _ in 0..count {
+            lights.add(PointLight::new([position_range.ind_sample(&mut rng),
+                                        position_range.ind_sample(&mut rng),
+                                        position_range.ind_sample(&mut rng)],
+                                       [range.ind_sample(&mut rng),
+                                        range.ind_sample(&mut rng),
+                                        range.ind_sample(&mut rng)],
+                                       1.0,
+                                       0.045,
+                                       0.0075));
         }
-        Lights { lights: l }
+        lights
+    }
+
+    pub fn add(&mut self, light: PointLight) -> LightId {
+        let id = LightId(self.next_id);
+        self.next_id += 1;
+        self.lights.push((id, light));
+        id
     }
-}
 
-impl Default for Lights {
-    fn default() -> Self {
-        Lights {
-            lights: [PointLight::new([4.0, 3.0, -4.0], [1.0, 1.0, 1.0], 1.0, 0.045, 0.0075),
-                     PointLight::new([0.0, 3.0, 4.0], [1.0, 1.0, 1.0], 1.0, 0.045, 0.0075),
-                     PointLight::new([-4.0, 3.0, -4.0], [1.0, 1.0, 1.0], 1.0, 0.045, 0.0075),
-                     PointLight::new([0.0, 0.0, 0.0], [0.0, 0.0, 0.0], 1.0, 0.045, 0.0075),
-                     PointLight::new([0.0, 0.0, 0.0], [0.0, 0.0, 0.0], 1.0, 0.045, 0.0075)],
+    pub fn remove(&mut self, id: LightId) -> Option<PointLight> {
+        match self.lights.iter().position(|&(i, _)| i == id) {
+            Some(index) => Some(self.lights.remove(index).1),
+            None => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, id: LightId) -> Option<&mut PointLight> {
+        self.lights.iter_mut().find(|l| l.0 == id).map(|l| &mut l.1)
+    }
+
+    pub fn clear(&mut self) {
+        self.lights.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    pub fn to_vec(&self) -> Vec<PointLight> {
+        self.lights.iter().map(|&(_, l)| l).collect()
+    }
+
+    // The lights that get a shadow cube, in the order of their layers
+    pub fn shadow_casters(&self) -> Vec<PointLight> {
+        self.lights.iter().map(|&(_, l)| l).filter(|l| l.casts_shadows()).take(MAX_SHADOW_CUBES).collect()
+    }
+
+    // One block per pass, never empty so unlit scenes still draw emissive
+    // surfaces. Pass 0 is drawn normally, later passes are added on top.
+    pub fn blocks(&self) -> Vec<LightBlock> {
+        let unused = PointLight::new([0.0; 3], [0.0; 3], 1.0, 0.0, 0.0);
+        let mut blocks = Vec::new();
+        let mut casters = 0;
+        for chunk in self.lights.chunks(MAX_LIGHTS) {
+            let mut block = LightBlock {
+                lights: [unused; MAX_LIGHTS],
+                light_count: chunk.len() as i32,
+            };
+            for (i, &(_, light)) in chunk.iter().enumerate() {
+                block.lights[i] = light;
+                if light.casts_shadows() {
+                    let cast = casters < MAX_SHADOW_CUBES;
+                    block.lights[i].shadow = if cast { (6 * casters) as f32 } else { -1.0 };
+                    casters += 1;
+                }
+            }
+            blocks.push(block);
+        }
+        if blocks.is_empty() {
+            blocks.push(LightBlock {
+                lights: [unused; MAX_LIGHTS],
+                light_count: 0,
+            });
         }
+        blocks
     }
 }
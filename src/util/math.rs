@@ -59,6 +59,12 @@ pub fn from_mat4(mat: &Matrix4<f32>) -> [[f32; 3]; 3] {
      [mat[2][0], mat[2][1], mat[2][2]]]
 }
 
+// Inverse of the rotation part of a view matrix without scaling
+#[inline]
+pub fn view_to_world(view: &Matrix4<f32>) -> [[f32; 3]; 3] {
+    from_mat4(&view.transpose())
+}
+
 #[inline]
 pub fn quaternion_to_mat4(q: Quaternion<f32>) -> Matrix4<f32> {
     let mut mat: Matrix4<f32> = Matrix::zero();
@@ -79,3 +85,30 @@ pub fn quaternion_to_mat4(q: Quaternion<f32>) -> Matrix4<f32> {
 
     mat
 }
+
+// Vectors stored as arrays like the vertex data and the uniforms
+
+#[inline]
+pub fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+#[inline]
+pub fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+#[inline]
+pub fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+// Zero vectors are returned as they are instead of turning into NaNs
+#[inline]
+pub fn normalize(a: [f32; 3]) -> [f32; 3] {
+    let len = dot(a, a).sqrt();
+    if len == 0.0 {
+        return a;
+    }
+    [a[0] / len, a[1] / len, a[2] / len]
+}